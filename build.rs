@@ -0,0 +1,11 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto/torrenter.proto");
+
+    // Compile the proto files in Rust so protoc doesn't need to be installed.
+    let file_descriptors = protox::compile(["proto/torrenter.proto"], ["proto"])?;
+    tonic_build::configure()
+        .build_client(false)
+        .compile_fds(file_descriptors)?;
+
+    Ok(())
+}
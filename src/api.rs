@@ -0,0 +1,365 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::{Json, Router};
+use axum::extract::{Path, State};
+use axum::http::{header, StatusCode};
+use axum::response::Html;
+use axum::routing::{get, post};
+use serde_derive::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::metrics;
+use crate::disk::Rename;
+use crate::session::{self, AddTorrentOptions, Session};
+use crate::speed::{SAMPLE_INTERVAL, SpeedHistory};
+use crate::stats::LifetimeStats;
+use crate::tracker::TrackerHealth;
+use crate::utils::{info_hash_from_hex, to_hex};
+
+/// Single page web UI used to add torrents and watch their progress.
+const WEB_UI: &str = include_str!("../web/index.html");
+
+type ApiError = (StatusCode, String);
+
+#[derive(Debug, Serialize)]
+struct TorrentJson {
+    info_hash: String,
+    name: String,
+    size: u64,
+    downloaded: u64,
+    progress: f32,
+    finished: bool,
+    label: Option<String>,
+    download_rate: u64,
+    upload_rate: u64,
+    eta_secs: Option<u64>,
+    save_path: PathBuf,
+    lifetime: LifetimeJson,
+}
+
+#[derive(Debug, Serialize)]
+struct LifetimeJson {
+    downloaded: u64,
+    uploaded: u64,
+    ratio: f64,
+    active_secs: u64,
+    /// Unix time the torrent finished.
+    completed_at: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct FileJson {
+    /// Index to rename the file with.
+    index: usize,
+    path: String,
+    length: u64,
+    downloaded: u64,
+    progress: f32,
+}
+
+#[derive(Debug, Serialize)]
+struct PeerJson {
+    addr: String,
+    /// Where the peer was found: tracker, dht, pex, lsd, incoming or manual.
+    source: &'static str,
+    encrypted: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct TrackerJson {
+    url: String,
+    /// pending, working, failing or disabled.
+    health: &'static str,
+    announces: u32,
+    /// Failed announces in a row.
+    failures: u32,
+    /// Peers returned by the last announce.
+    last_peers: Option<usize>,
+    /// Milliseconds the tracker took to answer the last announce which went through.
+    response_time_ms: Option<u64>,
+    /// Size of the swarm from the last announce, or a scrape when the announce left it out.
+    seeders: Option<u64>,
+    leechers: Option<u64>,
+    /// Why the last announce failed.
+    last_error: Option<String>,
+    /// Seconds until the next announce, None once the tracker is disabled.
+    next_announce_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct MagnetJson {
+    magnet: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SessionStatsJson {
+    torrents: usize,
+    downloaded: u64,
+    download_rate_limit: u64,
+    upload_rate_limit: u64,
+    listen_port: u16,
+    lifetime: LifetimeJson,
+}
+
+#[derive(Debug, Serialize)]
+struct SpeedHistoryJson {
+    interval_secs: u64,
+    download: Vec<u64>,
+    upload: Vec<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AddTorrentJson {
+    /// Path of a torrent file or a magnet link.
+    path: String,
+    /// Directory to download into instead of the save path of the config.
+    save_path: Option<PathBuf>,
+    /// Indexes of the files which aren't downloaded.
+    #[serde(default)]
+    skip_files: Vec<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RenameJson {
+    /// Index of the file to rename, the folder of the torrent is renamed when it's missing.
+    file: Option<usize>,
+    /// New path of the file within the torrent with `/` between folders, or the new name of the folder.
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PeerLimitJson {
+    /// Address of the peer, as listed in the peers of the torrent.
+    addr: SocketAddr,
+    /// Bytes per second the peer can send, 0 for unlimited.
+    download_rate: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct AddedTorrentJson {
+    info_hash: String,
+    /// Whether the torrent was already in the session, with how many of the trackers and web seeds were new to it.
+    duplicate: bool,
+    new_trackers: usize,
+    new_web_seeds: usize,
+}
+
+/// Build the REST API routes, optionally serving the web UI on `/`.
+pub fn router(session: Arc<Session>, web_ui: bool) -> Router {
+    let mut router = Router::new()
+        .route("/torrents", get(list_torrents).post(add_torrent))
+        .route("/torrents/:hash/files", get(torrent_files))
+        .route("/torrents/:hash/peers", get(torrent_peers))
+        .route("/torrents/:hash/peers/limit", post(limit_peer))
+        .route("/torrents/:hash/trackers", get(torrent_trackers))
+        .route("/torrents/:hash/magnet", get(torrent_magnet))
+        .route("/torrents/:hash/rename", post(rename_torrent))
+        .route("/torrents/:hash/speed", get(torrent_speed))
+        .route("/session/stats", get(session_stats))
+        .route("/session/speed", get(session_speed))
+        .route("/metrics", get(prometheus_metrics));
+
+    if web_ui {
+        router = router.route("/", get(|| async { Html(WEB_UI) }));
+    }
+
+    return router.with_state(session);
+}
+
+/// Serve the REST API until the server fails.
+pub async fn serve(session: Arc<Session>, addr: SocketAddr, web_ui: bool) -> anyhow::Result<()> {
+    info!("REST API listening on {}", addr);
+
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, router(session, web_ui)).await?;
+
+    Ok(())
+}
+
+async fn list_torrents(State(session): State<Arc<Session>>) -> Json<Vec<TorrentJson>> {
+    return Json(session.list().into_iter().map(build_torrent_json).collect());
+}
+
+async fn add_torrent(State(session): State<Arc<Session>>, Json(body): Json<AddTorrentJson>) -> Result<Json<AddedTorrentJson>, ApiError> {
+    let options = AddTorrentOptions {
+        save_path: body.save_path,
+        skip_files: body.skip_files,
+        ..Default::default()
+    };
+
+    let added = if body.path.starts_with("magnet:") {
+        session.add_magnet(&body.path, options).await
+    } else {
+        session.add_torrent(&body.path, options)
+    };
+    let added = added
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{:#}", e)))?;
+
+    Ok(Json(AddedTorrentJson {
+        info_hash: to_hex(&added.info_hash),
+        duplicate: added.duplicate,
+        new_trackers: added.new_trackers,
+        new_web_seeds: added.new_web_seeds,
+    }))
+}
+
+async fn torrent_files(State(session): State<Arc<Session>>, Path(hash): Path<String>) -> Result<Json<Vec<FileJson>>, ApiError> {
+    let info_hash = info_hash_from_hex(&hash)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let files = session.files(&info_hash)
+        .ok_or((StatusCode::NOT_FOUND, String::from("Torrent isn't in the session")))?;
+
+    Ok(Json(files.into_iter().map(|f| FileJson {
+        index: f.index,
+        path: f.path,
+        length: f.length,
+        downloaded: f.downloaded,
+        progress: f.progress,
+    }).collect()))
+}
+
+async fn torrent_peers(State(session): State<Arc<Session>>, Path(hash): Path<String>) -> Result<Json<Vec<PeerJson>>, ApiError> {
+    let info_hash = info_hash_from_hex(&hash)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let peers = session.peers(&info_hash)
+        .ok_or((StatusCode::NOT_FOUND, String::from("Torrent isn't in the session")))?;
+
+    Ok(Json(peers.into_iter().map(|p| PeerJson {
+        addr: p.addr.to_string(),
+        source: p.source.name(),
+        encrypted: p.encrypted,
+    }).collect()))
+}
+
+async fn limit_peer(State(session): State<Arc<Session>>, Path(hash): Path<String>, Json(body): Json<PeerLimitJson>) -> Result<StatusCode, ApiError> {
+    let info_hash = info_hash_from_hex(&hash)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    match session.set_peer_rate_limit(&info_hash, body.addr, body.download_rate) {
+        Some(true) => Ok(StatusCode::NO_CONTENT),
+        Some(false) => Err((StatusCode::NOT_FOUND, String::from("Peer isn't connected"))),
+        None => Err((StatusCode::NOT_FOUND, String::from("Torrent isn't in the session"))),
+    }
+}
+
+async fn torrent_trackers(State(session): State<Arc<Session>>, Path(hash): Path<String>) -> Result<Json<Vec<TrackerJson>>, ApiError> {
+    let info_hash = info_hash_from_hex(&hash)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let trackers = session.trackers(&info_hash)
+        .ok_or((StatusCode::NOT_FOUND, String::from("Torrent isn't in the session")))?;
+
+    let now = Instant::now();
+    Ok(Json(trackers.into_iter().map(|t| TrackerJson {
+        health: t.health.name(),
+        announces: t.announces,
+        failures: t.failures,
+        response_time_ms: t.response_time.map(|time| time.as_millis() as u64),
+        seeders: t.seeders,
+        leechers: t.leechers,
+        next_announce_secs: (t.health != TrackerHealth::Disabled).then(|| t.next_announce.saturating_duration_since(now).as_secs()),
+        last_peers: t.last_result.clone().and_then(|result| result.ok()),
+        last_error: t.last_result.and_then(|result| result.err()),
+        url: t.url,
+    }).collect()))
+}
+
+async fn rename_torrent(State(session): State<Arc<Session>>, Path(hash): Path<String>, Json(body): Json<RenameJson>) -> Result<StatusCode, ApiError> {
+    let info_hash = info_hash_from_hex(&hash)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    if session.status(&info_hash).is_none() {
+        return Err((StatusCode::NOT_FOUND, String::from("Torrent isn't in the session")));
+    }
+
+    let rename = match body.file {
+        Some(index) => Rename::File { index, path: body.name.split('/').map(String::from).collect() },
+        None => Rename::Root(body.name),
+    };
+
+    session.rename(&info_hash, rename).await
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{:#}", e)))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn torrent_magnet(State(session): State<Arc<Session>>, Path(hash): Path<String>) -> Result<Json<MagnetJson>, ApiError> {
+    let info_hash = info_hash_from_hex(&hash)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let magnet = session.magnet_link(&info_hash)
+        .ok_or((StatusCode::NOT_FOUND, String::from("Torrent isn't in the session")))?;
+
+    Ok(Json(MagnetJson { magnet }))
+}
+
+async fn session_stats(State(session): State<Arc<Session>>) -> Json<SessionStatsJson> {
+    let stats = session.stats();
+
+    Json(SessionStatsJson {
+        torrents: stats.torrents,
+        downloaded: stats.downloaded,
+        download_rate_limit: stats.download_rate_limit,
+        upload_rate_limit: stats.upload_rate_limit,
+        listen_port: stats.listen_port,
+        lifetime: build_lifetime_json(&stats.lifetime),
+    })
+}
+
+async fn torrent_speed(State(session): State<Arc<Session>>, Path(hash): Path<String>) -> Result<Json<SpeedHistoryJson>, ApiError> {
+    let info_hash = info_hash_from_hex(&hash)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let history = session.speed_history(Some(&info_hash))
+        .ok_or((StatusCode::NOT_FOUND, String::from("Torrent isn't in the session")))?;
+
+    Ok(Json(build_speed_json(&history)))
+}
+
+async fn session_speed(State(session): State<Arc<Session>>) -> Json<SpeedHistoryJson> {
+    return Json(build_speed_json(&session.speed_history(None).unwrap()));
+}
+
+async fn prometheus_metrics(State(session): State<Arc<Session>>) -> ([(header::HeaderName, &'static str); 1], String) {
+    return ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], metrics::render(&session));
+}
+
+fn build_speed_json(history: &SpeedHistory) -> SpeedHistoryJson {
+    SpeedHistoryJson {
+        interval_secs: SAMPLE_INTERVAL.as_secs(),
+        download: history.download(),
+        upload: history.upload(),
+    }
+}
+
+fn build_torrent_json(status: session::TorrentStatus) -> TorrentJson {
+    TorrentJson {
+        info_hash: to_hex(&status.info_hash),
+        name: status.name,
+        size: status.size,
+        downloaded: status.downloaded,
+        progress: status.progress,
+        finished: status.finished,
+        label: status.label,
+        download_rate: status.download_rate,
+        upload_rate: status.upload_rate,
+        eta_secs: status.eta.map(|eta| eta.as_secs()),
+        save_path: status.save_path,
+        lifetime: build_lifetime_json(&status.lifetime),
+    }
+}
+
+fn build_lifetime_json(lifetime: &LifetimeStats) -> LifetimeJson {
+    LifetimeJson {
+        downloaded: lifetime.downloaded,
+        uploaded: lifetime.uploaded,
+        ratio: lifetime.ratio(),
+        active_secs: lifetime.active_secs,
+        completed_at: lifetime.completed_at,
+    }
+}
@@ -0,0 +1,409 @@
+//! Bencode encoding and decoding.
+//!
+//! `Value` holds any bencoded data, the `Decoder` reads tokens one at a time so large or untrusted input
+//! can be walked without building a `Value`, and the `Encoder` writes canonical bencode with dictionary
+//! keys in sorted order.
+
+use std::collections::BTreeMap;
+
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+use serde_bytes::Bytes;
+
+/// Any bencoded value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+/// Limits applied while decoding, so malicious input can't exhaust the stack or memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Deepest nesting of lists and dictionaries.
+    pub max_depth: usize,
+    /// Largest input accepted, in bytes.
+    pub max_len: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_depth: 64,
+            max_len: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// A single element read by the `Decoder`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token<'a> {
+    Int(i64),
+    Bytes(&'a [u8]),
+    ListStart,
+    DictStart,
+    /// End of the innermost list or dictionary.
+    End,
+}
+
+/// Pull decoder reading one token at a time from a buffer.
+pub struct Decoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+    depth: usize,
+    limits: Limits,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(input: &'a [u8]) -> anyhow::Result<Decoder<'a>> {
+        return Decoder::with_limits(input, Limits::default());
+    }
+
+    pub fn with_limits(input: &'a [u8], limits: Limits) -> anyhow::Result<Decoder<'a>> {
+        if input.len() > limits.max_len {
+            anyhow::bail!("Error: Bencoded input of {} bytes is over the limit of {} bytes", input.len(), limits.max_len);
+        }
+
+        Ok(Decoder {
+            input,
+            pos: 0,
+            depth: 0,
+            limits,
+        })
+    }
+
+    /// Position of the next token in the input.
+    pub fn position(&self) -> usize {
+        return self.pos;
+    }
+
+    /// Whether all of the input has been read.
+    pub fn is_finished(&self) -> bool {
+        return self.pos == self.input.len();
+    }
+
+    /// Read the next token.
+    pub fn next_token(&mut self) -> anyhow::Result<Token<'a>> {
+        let token = match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                let digits = self.take_until(b'e')?;
+                Token::Int(parse_int(digits)?)
+            }
+            b'l' | b'd' => {
+                if self.depth == self.limits.max_depth {
+                    anyhow::bail!("Error: Bencode nested deeper than {} levels", self.limits.max_depth);
+                }
+                self.depth += 1;
+                self.pos += 1;
+                if self.input[self.pos - 1] == b'l' { Token::ListStart } else { Token::DictStart }
+            }
+            b'e' => {
+                if self.depth == 0 {
+                    anyhow::bail!("Error: Unexpected end of a list or dictionary at {}", self.pos);
+                }
+                self.depth -= 1;
+                self.pos += 1;
+                Token::End
+            }
+            b'0'..=b'9' => {
+                let digits = self.take_until(b':')?;
+                let len = std::str::from_utf8(digits)?.parse::<usize>()?;
+                if len > self.input.len() - self.pos {
+                    anyhow::bail!("Error: Byte string of {} bytes goes past the end of the input", len);
+                }
+                self.pos += len;
+                Token::Bytes(&self.input[self.pos - len..self.pos])
+            }
+            byte => anyhow::bail!("Error: Unexpected byte {:?} at {}", byte as char, self.pos),
+        };
+
+        return Ok(token);
+    }
+
+    /// Skip over the next value and return its raw bytes, used to hash the info dictionary as it was encoded.
+    pub fn raw_value(&mut self) -> anyhow::Result<&'a [u8]> {
+        let start = self.pos;
+        let depth = self.depth;
+
+        loop {
+            match self.next_token()? {
+                Token::ListStart | Token::DictStart => {}
+                Token::End if self.depth < depth => anyhow::bail!("Error: Expected a value at {}", start),
+                _ => {}
+            }
+            if self.depth == depth {
+                break;
+            }
+        }
+
+        return Ok(&self.input[start..self.pos]);
+    }
+
+    /// Read the next value.
+    pub fn value(&mut self) -> anyhow::Result<Value> {
+        let value = match self.next_token()? {
+            Token::Int(i) => Value::Int(i),
+            Token::Bytes(bytes) => Value::Bytes(bytes.to_vec()),
+            Token::ListStart => {
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.value()?);
+                }
+                self.next_token()?;
+                Value::List(list)
+            }
+            Token::DictStart => {
+                let mut dict = BTreeMap::new();
+                while self.peek()? != b'e' {
+                    let key = self.key()?;
+                    dict.insert(key.to_vec(), self.value()?);
+                }
+                self.next_token()?;
+                Value::Dict(dict)
+            }
+            Token::End => anyhow::bail!("Error: Expected a value at {}", self.pos - 1),
+        };
+
+        return Ok(value);
+    }
+
+    /// Read a dictionary key.
+    pub fn key(&mut self) -> anyhow::Result<&'a [u8]> {
+        return match self.next_token()? {
+            Token::Bytes(key) => Ok(key),
+            _ => anyhow::bail!("Error: Dictionary keys must be byte strings"),
+        };
+    }
+
+    fn peek(&self) -> anyhow::Result<u8> {
+        return match self.input.get(self.pos) {
+            Some(&byte) => Ok(byte),
+            None => anyhow::bail!("Error: Unexpected end of the bencoded input"),
+        };
+    }
+
+    fn take_until(&mut self, delimiter: u8) -> anyhow::Result<&'a [u8]> {
+        let start = self.pos;
+        let len = self.input[start..].iter().position(|&b| b == delimiter)
+            .ok_or_else(|| anyhow::anyhow!("Error: Missing {:?} after {}", delimiter as char, start))?;
+
+        self.pos += len + 1;
+        return Ok(&self.input[start..start + len]);
+    }
+}
+
+/// Parse an integer, rejecting the leading zeroes and `-0` the spec doesn't allow.
+fn parse_int(digits: &[u8]) -> anyhow::Result<i64> {
+    let text = std::str::from_utf8(digits)?;
+    let unsigned = text.strip_prefix('-').unwrap_or(text);
+
+    if unsigned.is_empty() || (unsigned.starts_with('0') && text != "0") {
+        anyhow::bail!("Error: Invalid bencoded integer {:?}", text);
+    }
+
+    return Ok(text.parse::<i64>()?);
+}
+
+/// Writes canonical bencode.
+#[derive(Debug, Default)]
+pub struct Encoder {
+    buffer: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        return Encoder::default();
+    }
+
+    pub fn int(&mut self, i: i64) -> &mut Encoder {
+        self.buffer.extend(format!("i{}e", i).as_bytes());
+        return self;
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) -> &mut Encoder {
+        self.buffer.extend(format!("{}:", bytes.len()).as_bytes());
+        self.buffer.extend(bytes);
+        return self;
+    }
+
+    /// Write bytes which are already bencoded, such as a value from `Decoder::raw_value`.
+    pub fn raw(&mut self, raw: &[u8]) -> &mut Encoder {
+        self.buffer.extend(raw);
+        return self;
+    }
+
+    pub fn value(&mut self, value: &Value) -> &mut Encoder {
+        match value {
+            Value::Int(i) => self.int(*i),
+            Value::Bytes(bytes) => self.bytes(bytes),
+            Value::List(list) => {
+                self.buffer.push(b'l');
+                for item in list {
+                    self.value(item);
+                }
+                self.buffer.push(b'e');
+                self
+            }
+            Value::Dict(dict) => {
+                self.buffer.push(b'd');
+                for (key, item) in dict {
+                    self.bytes(key).value(item);
+                }
+                self.buffer.push(b'e');
+                self
+            }
+        }
+    }
+
+    /// Start a list or dictionary, its items are written until `end` is called.
+    pub fn begin_list(&mut self) -> &mut Encoder {
+        self.buffer.push(b'l');
+        return self;
+    }
+
+    pub fn begin_dict(&mut self) -> &mut Encoder {
+        self.buffer.push(b'd');
+        return self;
+    }
+
+    pub fn end(&mut self) -> &mut Encoder {
+        self.buffer.push(b'e');
+        return self;
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        return self.buffer;
+    }
+}
+
+impl Value {
+    /// Decode a single value taking up the whole input.
+    pub fn decode(input: &[u8]) -> anyhow::Result<Value> {
+        return Value::decode_with_limits(input, Limits::default());
+    }
+
+    pub fn decode_with_limits(input: &[u8], limits: Limits) -> anyhow::Result<Value> {
+        let mut decoder = Decoder::with_limits(input, limits)?;
+        let value = decoder.value()?;
+
+        if !decoder.is_finished() {
+            anyhow::bail!("Error: Trailing data after the bencoded value at {}", decoder.position());
+        }
+
+        return Ok(value);
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.value(self);
+        return encoder.finish();
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Value>> {
+        return match self {
+            Value::Dict(dict) => Some(dict),
+            _ => None,
+        };
+    }
+
+    pub fn as_dict_mut(&mut self) -> Option<&mut BTreeMap<Vec<u8>, Value>> {
+        return match self {
+            Value::Dict(dict) => Some(dict),
+            _ => None,
+        };
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        return match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        };
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        return match self {
+            Value::Int(i) => Some(*i),
+            _ => None,
+        };
+    }
+}
+
+/// Lets a `Value` be part of a struct serialized with `serde_bencode`.
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Int(i) => serializer.serialize_i64(*i),
+            Value::Bytes(bytes) => serializer.serialize_bytes(bytes),
+            Value::List(list) => {
+                let mut seq = serializer.serialize_seq(Some(list.len()))?;
+                for item in list {
+                    seq.serialize_element(item)?;
+                }
+                seq.end()
+            }
+            Value::Dict(dict) => {
+                let mut map = serializer.serialize_map(Some(dict.len()))?;
+                for (key, item) in dict {
+                    map.serialize_entry(Bytes::new(key), item)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+/// Raw bytes of each entry of a top level dictionary, in the order they were encoded.
+///
+/// Lets a key be replaced without re-encoding the others, which keeps the info hash intact.
+pub fn raw_dict_entries(input: &[u8]) -> anyhow::Result<Vec<(&[u8], &[u8])>> {
+    let mut decoder = Decoder::new(input)?;
+    if decoder.next_token()? != Token::DictStart {
+        anyhow::bail!("Error: Expected a bencoded dictionary");
+    }
+
+    let mut entries = Vec::new();
+    while decoder.peek()? != b'e' {
+        let key = decoder.key()?;
+        entries.push((key, decoder.raw_value()?));
+    }
+    decoder.next_token()?;
+
+    if !decoder.is_finished() {
+        anyhow::bail!("Error: Trailing data after the bencoded dictionary at {}", decoder.position());
+    }
+
+    return Ok(entries);
+}
+
+
+#[test]
+fn test_decode_encode() {
+    let input = b"d4:listli1ei-20e3:fooe6:nestedd1:a0:e3:numi42ee";
+    let value = Value::decode(input).unwrap();
+
+    let dict = value.as_dict().unwrap();
+    assert_eq!(dict[&b"num".to_vec()].as_int(), Some(42));
+    assert_eq!(dict[&b"list".to_vec()], Value::List(vec![Value::Int(1), Value::Int(-20), Value::Bytes(b"foo".to_vec())]));
+    assert_eq!(value.encode(), input.to_vec());
+
+    // Keys are written sorted whatever order they were read in.
+    assert_eq!(Value::decode(b"d1:bi2e1:ai1ee").unwrap().encode(), b"d1:ai1e1:bi2ee".to_vec());
+
+    let entries = raw_dict_entries(input).unwrap();
+    assert_eq!(entries[1], (&b"nested"[..], &b"d1:a0:e"[..]));
+}
+
+
+#[test]
+fn test_decode_invalid() {
+    for input in [&b"i01e"[..], b"i-0e", b"ie", b"5:abc", b"l", b"e", b"i1ei2e", b"di1ei2ee", b"x"] {
+        assert!(Value::decode(input).is_err(), "{:?} should be invalid", std::str::from_utf8(input));
+    }
+
+    let deep = [vec![b'l'; 10], vec![b'e'; 10]].concat();
+    assert!(Value::decode_with_limits(&deep, Limits { max_depth: 9, ..Default::default() }).is_err());
+    assert!(Value::decode_with_limits(&deep, Limits { max_depth: 10, ..Default::default() }).is_ok());
+    assert!(Value::decode_with_limits(b"3:abc", Limits { max_len: 4, ..Default::default() }).is_err());
+}
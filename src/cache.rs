@@ -0,0 +1,339 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::message_handlers::PieceChannelPayload;
+
+/// Blocks to be written one after the other, kept as they came so they can be written with a vectored write
+/// instead of being copied together.
+#[derive(Debug, Default)]
+pub struct WriteRun {
+    offset: u64,
+    length: u64,
+    blocks: Vec<Vec<u8>>,
+}
+
+impl WriteRun {
+    pub fn offset(&self) -> u64 {
+        return self.offset;
+    }
+
+    pub fn length(&self) -> u64 {
+        return self.length;
+    }
+
+    fn end(&self) -> u64 {
+        return self.offset + self.length;
+    }
+
+    pub fn contains(&self, offset: u64) -> bool {
+        return self.offset <= offset && offset < self.end();
+    }
+
+    /// Add the blocks of the run which starts where this one ends.
+    fn append(&mut self, mut next: WriteRun) {
+        self.length += next.length;
+        self.blocks.append(&mut next.blocks);
+    }
+
+    /// Parts of the blocks with the `len` bytes starting `start` bytes into the run.
+    pub fn slices(&self, start: usize, len: usize) -> Vec<&[u8]> {
+        let end = start + len;
+        let mut slices = Vec::new();
+        let mut position = 0;
+
+        for block in &self.blocks {
+            let block_end = position + block.len();
+            if block_end > start && position < end {
+                slices.push(&block[start.max(position) - position..end.min(block_end) - position]);
+            }
+            position = block_end;
+        }
+
+        return slices;
+    }
+}
+
+impl From<PieceChannelPayload> for WriteRun {
+    fn from(payload: PieceChannelPayload) -> WriteRun {
+        return WriteRun {
+            offset: payload.offset,
+            length: payload.block.len() as u64,
+            blocks: vec![payload.block],
+        };
+    }
+}
+
+/// Blocks waiting to be written, kept until their piece is complete or the cache is full so the disk gets a few
+/// large sequential writes instead of one for every block.
+#[derive(Debug)]
+pub struct WriteCache {
+    /// Contiguous runs of blocks by offset, a new block next to a run is added to it.
+    runs: BTreeMap<u64, WriteRun>,
+    bytes: u64,
+    capacity: u64,
+    piece_length: u64,
+    torrent_size: u64,
+}
+
+impl WriteCache {
+    pub fn new(capacity: u64, piece_length: u64, torrent_size: u64) -> WriteCache {
+        return WriteCache {
+            runs: BTreeMap::new(),
+            bytes: 0,
+            capacity,
+            piece_length,
+            torrent_size,
+        };
+    }
+
+    /// Add a block, returning what should be written now.
+    pub fn insert(&mut self, payload: PieceChannelPayload) -> Vec<WriteRun> {
+        let block_offset = payload.offset;
+        let mut run = WriteRun::from(payload);
+
+        // A block which is cached already, like one which came from two peers in endgame, is only counted once.
+        if self.is_cached(run.offset, run.length) {
+            return Vec::new();
+        }
+
+        // The runs it partly overlaps are written first, so the new block overwrites them instead of being spliced in.
+        let overlapping: Vec<u64> = self.runs.range(..run.end())
+            .filter(|(_, cached)| cached.end() > run.offset)
+            .map(|(&start, _)| start)
+            .collect();
+        let mut ready = Vec::new();
+        for start in overlapping {
+            let cached = self.runs.remove(&start).unwrap();
+            self.bytes -= cached.length;
+            ready.push(cached);
+        }
+        self.bytes += run.length;
+
+        // Add to the run ending where the block starts.
+        let previous = self.runs.range(..run.offset).next_back()
+            .filter(|(_, previous)| previous.end() == run.offset)
+            .map(|(&start, _)| start);
+        if let Some(start) = previous {
+            let mut previous = self.runs.remove(&start).unwrap();
+            previous.append(run);
+            run = previous;
+        }
+
+        // And take in the run starting where the block ends.
+        if let Some(next) = self.runs.remove(&run.end()) {
+            run.append(next);
+        }
+
+        if self.bytes >= self.capacity {
+            self.runs.insert(run.offset, run);
+            ready.extend(self.drain());
+            return ready;
+        }
+
+        // Write the run once it has a whole piece, so pieces can be hashed from the disk.
+        let piece_start = block_offset / self.piece_length * self.piece_length;
+        let piece_end = (piece_start + self.piece_length).min(self.torrent_size);
+        if run.offset <= piece_start && run.end() >= piece_end {
+            self.bytes -= run.length;
+            ready.push(run);
+            return ready;
+        }
+
+        self.runs.insert(run.offset, run);
+        return ready;
+    }
+
+    /// Whether the `length` bytes at `offset` are all within a cached run.
+    pub fn is_cached(&self, offset: u64, length: u64) -> bool {
+        return self.run_containing(offset).is_some_and(|run| run.end() >= offset + length);
+    }
+
+    /// Whether any of the `length` bytes at `offset` are cached.
+    pub fn overlaps(&self, offset: u64, length: u64) -> bool {
+        return self.runs.range(..offset + length).next_back().is_some_and(|(_, run)| run.end() > offset);
+    }
+
+    /// The cached run a byte of the torrent is in.
+    pub fn run_containing(&self, offset: u64) -> Option<&WriteRun> {
+        return self.runs.range(..=offset).next_back().map(|(_, run)| run).filter(|run| run.contains(offset));
+    }
+
+    /// Take every cached run, in order.
+    pub fn drain(&mut self) -> Vec<WriteRun> {
+        self.bytes = 0;
+        return std::mem::take(&mut self.runs).into_values().collect();
+    }
+}
+
+
+#[test]
+fn test_write_cache() {
+    let block = |offset, len| PieceChannelPayload { offset, block: vec![offset as u8; len] };
+    let offsets = |runs: Vec<WriteRun>| -> Vec<(u64, u64)> {
+        runs.iter().map(|run| (run.offset, run.length)).collect()
+    };
+
+    let mut cache = WriteCache::new(100, 20, 90);
+
+    // The first piece is written once both of its halves are in.
+    assert!(cache.insert(block(10, 10)).is_empty());
+    assert!(cache.insert(block(20, 10)).is_empty());
+    assert_eq!(cache.run_containing(25).map(|run| run.offset), Some(10));
+    assert!(cache.run_containing(5).is_none());
+    assert_eq!(offsets(cache.insert(block(0, 10))), vec![(0, 30)]);
+
+    // The last piece is shorter.
+    assert_eq!(offsets(cache.insert(block(80, 10))), vec![(80, 10)]);
+
+    // Everything is written once the cache is full.
+    let mut cache = WriteCache::new(15, 20, 90);
+    assert!(cache.insert(block(40, 5)).is_empty());
+    assert!(cache.insert(block(60, 5)).is_empty());
+    assert_eq!(offsets(cache.insert(block(45, 5))), vec![(40, 10), (60, 5)]);
+    assert!(cache.drain().is_empty());
+}
+
+
+#[test]
+fn test_write_cache_duplicates() {
+    let block = |offset, len, value| PieceChannelPayload { offset, block: vec![value; len] };
+    let offsets = |runs: Vec<WriteRun>| -> Vec<(u64, u64)> {
+        runs.iter().map(|run| (run.offset, run.length)).collect()
+    };
+
+    // A block received twice doesn't count twice towards the capacity, or end up twice in its run.
+    let mut cache = WriteCache::new(25, 40, 120);
+    assert!(cache.insert(block(0, 10, 1)).is_empty());
+    assert!(cache.insert(block(0, 10, 1)).is_empty());
+    assert!(cache.insert(block(50, 10, 2)).is_empty());
+    assert_eq!(cache.run_containing(0).map(|run| run.length), Some(10));
+    assert!(cache.is_cached(0, 10) && !cache.is_cached(5, 10));
+    assert!(cache.overlaps(5, 10) && !cache.overlaps(10, 40));
+
+    // A block partly over a run has the run written first, so it overwrites it on disk.
+    assert_eq!(offsets(cache.insert(block(5, 10, 3))), vec![(0, 10)]);
+    assert_eq!(offsets(cache.drain()), vec![(5, 10), (50, 10)]);
+}
+
+
+#[test]
+fn test_write_run_slices() {
+    let mut run = WriteRun::from(PieceChannelPayload { offset: 100, block: vec![1, 2, 3] });
+    run.append(WriteRun::from(PieceChannelPayload { offset: 103, block: vec![4, 5] }));
+    run.append(WriteRun::from(PieceChannelPayload { offset: 105, block: vec![6, 7, 8, 9] }));
+
+    assert_eq!(run.length(), 9);
+    assert_eq!(run.slices(2, 5), vec![&[3][..], &[4, 5], &[6, 7]]);
+    assert_eq!(run.slices(5, 4), vec![&[6, 7, 8, 9][..]]);
+}
+
+
+/// Pieces recently read for uploads, so a piece requested by many peers is read from the disk once.
+///
+/// The least recently used piece is dropped once the cache is over its capacity.
+#[derive(Debug)]
+pub struct ReadCache {
+    pieces: HashMap<u64, (u64, Vec<u8>)>,
+    /// Last use of each cached piece, oldest first.
+    uses: BTreeMap<u64, u64>,
+    tick: u64,
+    bytes: u64,
+    capacity: u64,
+    piece_length: u64,
+}
+
+impl ReadCache {
+    pub fn new(capacity: u64, piece_length: u64) -> ReadCache {
+        return ReadCache {
+            pieces: HashMap::new(),
+            uses: BTreeMap::new(),
+            tick: 0,
+            bytes: 0,
+            capacity,
+            piece_length,
+        };
+    }
+
+    /// The piece a range is in, `None` when it spans several pieces or caching is turned off.
+    pub fn piece_of(&self, offset: u64, length: u64) -> Option<u64> {
+        let index = offset / self.piece_length;
+        if self.capacity == 0 || length == 0 || (offset + length - 1) / self.piece_length != index {
+            return None;
+        }
+
+        return Some(index);
+    }
+
+    /// Copy a range out of the cache if its piece is there.
+    pub fn get(&mut self, offset: u64, length: u64) -> Option<Vec<u8>> {
+        let index = self.piece_of(offset, length)?;
+        let (last_use, piece) = self.pieces.get_mut(&index)?;
+
+        let start = (offset - index * self.piece_length) as usize;
+        let block = piece.get(start..start + length as usize)?.to_vec();
+
+        self.uses.remove(last_use);
+        self.tick += 1;
+        *last_use = self.tick;
+        self.uses.insert(self.tick, index);
+
+        return Some(block);
+    }
+
+    pub fn insert(&mut self, index: u64, piece: Vec<u8>) {
+        self.remove(index);
+        if piece.len() as u64 > self.capacity {
+            return;
+        }
+
+        self.tick += 1;
+        self.bytes += piece.len() as u64;
+        self.uses.insert(self.tick, index);
+        self.pieces.insert(index, (self.tick, piece));
+
+        while self.bytes > self.capacity {
+            let (_, oldest) = self.uses.pop_first().unwrap();
+            let (_, piece) = self.pieces.remove(&oldest).unwrap();
+            self.bytes -= piece.len() as u64;
+        }
+    }
+
+    /// Drop the pieces a write to the range makes stale.
+    pub fn invalidate(&mut self, offset: u64, length: u64) {
+        if self.pieces.is_empty() || length == 0 {
+            return;
+        }
+
+        for index in offset / self.piece_length..=(offset + length - 1) / self.piece_length {
+            self.remove(index);
+        }
+    }
+
+    fn remove(&mut self, index: u64) {
+        if let Some((last_use, piece)) = self.pieces.remove(&index) {
+            self.uses.remove(&last_use);
+            self.bytes -= piece.len() as u64;
+        }
+    }
+}
+
+
+#[test]
+fn test_read_cache() {
+    let mut cache = ReadCache::new(20, 10);
+
+    assert_eq!(cache.piece_of(12, 8), Some(1));
+    assert_eq!(cache.piece_of(8, 4), None);
+
+    cache.insert(0, vec![0; 10]);
+    cache.insert(1, (10..20).collect());
+    assert_eq!(cache.get(12, 3), Some(vec![12, 13, 14]));
+
+    // Piece 0 is the least recently used so it makes room for piece 2.
+    cache.insert(2, vec![2; 10]);
+    assert_eq!(cache.get(0, 1), None);
+    assert_eq!(cache.get(19, 1), Some(vec![19]));
+
+    cache.invalidate(25, 10);
+    assert_eq!(cache.get(20, 1), None);
+    assert_eq!(cache.get(10, 1), Some(vec![10]));
+}
@@ -0,0 +1,234 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use rayon::prelude::*;
+use sha1::{Digest as _, Sha1};
+
+use crate::create::{hash_piece, read_range, SourceFile};
+use crate::utils::to_hex;
+use crate::utils::torrents::Torrent;
+
+/// Result of hashing the data of a torrent on disk.
+#[derive(Debug)]
+pub struct CheckResult {
+    /// Whether each piece matches its hash.
+    pub pieces: Vec<bool>,
+    pub files: Vec<FileCheck>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FileCheck {
+    pub path: String,
+    pub length: u64,
+    /// Pieces of the file which match, a piece shared with another file counts for both.
+    pub valid_pieces: usize,
+    pub total_pieces: usize,
+}
+
+impl CheckResult {
+    pub fn valid_pieces(&self) -> usize {
+        return self.pieces.iter().filter(|&&valid| valid).count();
+    }
+
+    pub fn is_complete(&self) -> bool {
+        return self.pieces.iter().all(|&valid| valid);
+    }
+}
+
+impl FileCheck {
+    pub fn progress(&self) -> f32 {
+        if self.total_pieces == 0 {
+            return 100.0;
+        }
+        return self.valid_pieces as f32 / self.total_pieces as f32 * 100.0;
+    }
+}
+
+/// Hash the data of a torrent found in `save_path` and check every piece against the torrent.
+///
+/// Files are looked up where a download would put them, so missing or short files only fail
+/// the pieces they're part of.
+pub fn check_data(torrent: &Torrent, save_path: &Path) -> CheckResult {
+    let files = source_files(torrent, save_path);
+    let piece_length = torrent.info.piece_length;
+    let num_pieces = torrent.info.pieces.len() / 20;
+
+    let pieces: Vec<bool> = (0..num_pieces).into_par_iter()
+        .map(|index| {
+            let begin = index as u64 * piece_length;
+            let length = piece_length.min(torrent.size - begin);
+
+            match read_range(&files, begin, length) {
+                Ok(piece) => hash_piece(&piece)[..] == torrent.info.pieces[index * 20..index * 20 + 20],
+                Err(_) => false,
+            }
+        })
+        .collect();
+
+    let mut file_checks = Vec::new();
+    let mut offset = 0;
+    for file in &files {
+        let first_piece = (offset / piece_length) as usize;
+        let last_piece = if file.length == 0 { first_piece } else { ((offset + file.length - 1) / piece_length) as usize + 1 };
+        offset += file.length;
+
+        // Pad files are only there to align the others.
+        if file.disk_path.is_none() {
+            continue;
+        }
+
+        file_checks.push(FileCheck {
+            path: file.torrent_path.join("/"),
+            length: file.length,
+            valid_pieces: pieces[first_piece..last_piece].iter().filter(|&&valid| valid).count(),
+            total_pieces: last_piece - first_piece,
+        });
+    }
+
+    return CheckResult { pieces, files: file_checks };
+}
+
+/// SHA-1 and SHA-256 of a downloaded file, as hex.
+#[derive(Debug, Clone)]
+pub struct FileChecksum {
+    pub path: String,
+    pub sha1: String,
+    pub sha256: String,
+}
+
+/// Hash every file of a torrent found in `save_path`, in parallel.
+pub fn file_checksums(torrent: &Torrent, save_path: &Path) -> anyhow::Result<Vec<FileChecksum>> {
+    return source_files(torrent, save_path).par_iter()
+        .filter_map(|file| Some((file.torrent_path.join("/"), file.disk_path.clone()?)))
+        .map(|(path, disk_path)| {
+            let (sha1, sha256) = hash_file(&disk_path)?;
+            Ok(FileChecksum { path, sha1, sha256 })
+        })
+        .collect();
+}
+
+/// Write `<name>.sha1` and `<name>.sha256` next to the torrent's data, in the format `sha1sum -c` and `sha256sum -c` read.
+///
+/// Paths are relative to the torrent's directory for multi file torrents, where the checksum files are written.
+pub fn write_checksum_files(torrent: &Torrent, save_path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let checksums = file_checksums(torrent, save_path)?;
+
+    let dir = if torrent.info.files.is_some() { save_path.join(&torrent.info.name) } else { save_path.to_path_buf() };
+    let sha1_path = dir.join(format!("{}.sha1", torrent.info.name));
+    let sha256_path = dir.join(format!("{}.sha256", torrent.info.name));
+
+    fs::write(&sha1_path, format_checksums(&checksums, |c| &c.sha1))?;
+    fs::write(&sha256_path, format_checksums(&checksums, |c| &c.sha256))?;
+
+    return Ok(vec![sha1_path, sha256_path]);
+}
+
+/// One `<hash>  <path>` line per file.
+pub fn format_checksums(checksums: &[FileChecksum], hash: impl Fn(&FileChecksum) -> &String) -> String {
+    return checksums.iter().map(|c| format!("{}  {}\n", hash(c), c.path)).collect();
+}
+
+fn hash_file(path: &Path) -> anyhow::Result<(String, String)> {
+    let mut file = File::open(path).with_context(|| format!("Unable to open {:?}", path))?;
+    let mut sha1 = Sha1::new();
+    let mut sha256 = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        sha1.update(&buffer[..read]);
+        sha256.input(&buffer[..read]);
+    }
+
+    let sha1_hash = sha1.finalize();
+    let mut sha256_hash = [0; 32];
+    sha256.result(&mut sha256_hash);
+
+    return Ok((to_hex(&sha1_hash), to_hex(&sha256_hash)));
+}
+
+
+#[test]
+fn test_file_checksums() {
+    let dir = Path::new("test-files/checksums");
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir).unwrap();
+    fs::write(dir.join("abc.txt"), b"abc").unwrap();
+
+    let torrent = Torrent {
+        info: crate::utils::torrents::Info { name: String::from("abc.txt"), ..Default::default() },
+        size: 3,
+        ..Default::default()
+    };
+
+    let checksums = file_checksums(&torrent, dir).unwrap();
+    assert_eq!(checksums[0].sha1, "a9993e364706816aba3e25717850c26c9cd0d89d");
+    assert_eq!(checksums[0].sha256, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    assert_eq!(format_checksums(&checksums, |c| &c.sha1), "a9993e364706816aba3e25717850c26c9cd0d89d  abc.txt\n");
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+
+/// Where each file of the torrent is on disk, in torrent order.
+fn source_files(torrent: &Torrent, save_path: &Path) -> Vec<SourceFile> {
+    let root = save_path.join(&torrent.info.name);
+
+    return match &torrent.info.files {
+        Some(files) => files.iter().map(|f| SourceFile {
+            disk_path: if f.is_pad() { None } else { Some(f.path.iter().fold(root.clone(), |path, component| path.join(component))) },
+            torrent_path: f.path.clone(),
+            length: f.length,
+        }).collect(),
+        None => vec![SourceFile {
+            disk_path: Some(root),
+            torrent_path: vec![torrent.info.name.clone()],
+            length: torrent.size,
+        }],
+    };
+}
+
+
+#[test]
+fn test_check_data() {
+    use std::fs;
+    use crate::create::{create_torrent, CreateOptions};
+    use crate::utils::torrents::BLOCK_LEN;
+
+    let dir = Path::new("test-files/check");
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir.join("data")).unwrap();
+
+    fs::write(dir.join("data/a.bin"), vec![1; 20000]).unwrap();
+    fs::write(dir.join("data/b.bin"), vec![2; 30000]).unwrap();
+    fs::write(dir.join("data/c.bin"), vec![3; 100]).unwrap();
+
+    let metainfo = create_torrent(&CreateOptions {
+        path: dir.join("data"),
+        piece_length: Some(BLOCK_LEN),
+        ..Default::default()
+    }).unwrap();
+    let torrent = Torrent::from_bytes(&metainfo).unwrap();
+
+    let result = check_data(&torrent, dir);
+    assert!(result.is_complete());
+
+    // Corrupt the end of b.bin, which shares its last piece with c.bin, and remove c.bin.
+    fs::write(dir.join("data/b.bin"), [vec![2; 29500], vec![0; 500]].concat()).unwrap();
+    fs::remove_file(dir.join("data/c.bin")).unwrap();
+
+    let result = check_data(&torrent, dir);
+    assert_eq!(result.pieces, vec![true, true, true, false]);
+    assert_eq!(result.files[0].progress(), 100.0);
+    assert_eq!(result.files[1], FileCheck { path: String::from("b.bin"), length: 30000, valid_pieces: 2, total_pieces: 3 });
+    assert_eq!(result.files[2].valid_pieces, 0);
+
+    let _ = fs::remove_dir_all(dir);
+}
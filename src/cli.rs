@@ -0,0 +1,217 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::create::TorrentVersion;
+
+/// Command line torrent client.
+#[derive(Debug, Parser)]
+#[command(name = "torrenter", version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Torrent files, magnet links or info hashes to download.
+    pub torrents: Vec<String>,
+
+    /// Config file to use instead of ~/.config/torrenter/config.toml.
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Port used for peer connections.
+    #[arg(short, long)]
+    pub port: Option<u16>,
+
+    /// Download limit in bytes per second, 0 for unlimited.
+    #[arg(long)]
+    pub download_limit: Option<u64>,
+
+    /// Upload limit in bytes per second, 0 for unlimited.
+    #[arg(long)]
+    pub upload_limit: Option<u64>,
+
+    /// Directory torrents are downloaded into.
+    #[arg(short, long)]
+    pub save_path: Option<PathBuf>,
+
+    /// Directory watched for new .torrent files.
+    #[arg(long)]
+    pub watch_dir: Option<PathBuf>,
+
+    /// Proxy used for HTTP requests.
+    #[arg(long)]
+    pub proxy: Option<String>,
+
+    /// Shell command run when a torrent finishes downloading.
+    #[arg(long)]
+    pub on_complete: Option<String>,
+
+    /// Show a desktop notification when a torrent finishes or fails.
+    #[arg(long)]
+    pub notify: bool,
+
+    /// Log filter such as `info` or `torrenter=debug`.
+    #[arg(long)]
+    pub log_level: Option<String>,
+
+    /// Output logs as JSON lines.
+    #[arg(long)]
+    pub log_json: bool,
+
+    /// Don't start the gRPC control service.
+    #[arg(long)]
+    pub no_rpc: bool,
+
+    /// Don't start the REST API.
+    #[arg(long)]
+    pub no_api: bool,
+
+    /// Serve the REST API without the web UI.
+    #[arg(long)]
+    pub no_web_ui: bool,
+
+    /// Only use IPv4 for peers, trackers and the DHT.
+    #[arg(long, conflicts_with = "ipv6_only")]
+    pub ipv4_only: bool,
+
+    /// Only use IPv6 for peers, trackers and the DHT.
+    #[arg(long)]
+    pub ipv6_only: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Download torrents, the same as giving them without a command.
+    Add {
+        /// Torrent files, magnet links or 40 character hex info hashes, found through the DHT.
+        #[arg(required = true)]
+        torrents: Vec<String>,
+
+        /// Directory these torrents are downloaded into, instead of the save path of the config.
+        #[arg(short, long)]
+        save_path: Option<PathBuf>,
+    },
+    /// Create a .torrent file from a file or directory.
+    Create(CreateArgs),
+    /// Change the trackers, comment or private flag of a .torrent file.
+    Edit(EditArgs),
+    /// Show what's in a .torrent file.
+    Inspect {
+        /// Torrent file to read.
+        torrent: String,
+
+        /// Print as JSON.
+        #[arg(long)]
+        json: bool,
+
+        /// Ask the trackers how many seeders and leechers the torrent has.
+        #[arg(long)]
+        scrape: bool,
+    },
+    /// Hash data on disk and check it against a .torrent file.
+    Verify {
+        /// Torrent file to check against.
+        torrent: String,
+
+        /// Directory holding the torrent's data, like the save path of a download.
+        #[arg(short, long)]
+        data: PathBuf,
+    },
+    /// Print the SHA-1 or SHA-256 checksum of every file of a downloaded torrent.
+    Checksums {
+        /// Torrent file the data belongs to.
+        torrent: String,
+
+        /// Directory holding the torrent's data, like the save path of a download.
+        #[arg(short, long)]
+        data: PathBuf,
+
+        /// Print SHA-1 instead of SHA-256 checksums.
+        #[arg(long)]
+        sha1: bool,
+    },
+    /// Print the magnet link of a .torrent file.
+    ExportMagnet {
+        /// Torrent file to read.
+        torrent: String,
+    },
+    /// Get the metadata of a magnet link from the swarm and write it as a .torrent file, without downloading the data.
+    FetchTorrent {
+        /// Magnet link or 40 character hex info hash.
+        magnet: String,
+
+        /// Where to write the torrent, defaults to `<name>.torrent`.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Print info hashes DHT nodes store peers for, from a random part of the DHT.
+    DhtSamples {
+        /// Only ask this node, as `host:port`.
+        #[arg(long)]
+        node: Option<String>,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct CreateArgs {
+    /// File or directory to create the torrent from.
+    pub path: PathBuf,
+
+    /// Where to write the torrent, defaults to `<name>.torrent`.
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Piece length in bytes, picked from the total size if not given.
+    #[arg(long)]
+    pub piece_length: Option<u64>,
+
+    /// Tracker URL, can be given multiple times.
+    #[arg(short, long = "tracker")]
+    pub trackers: Vec<String>,
+
+    /// Web seed URL, can be given multiple times.
+    #[arg(short, long = "web-seed")]
+    pub web_seeds: Vec<String>,
+
+    /// Comment stored in the torrent.
+    #[arg(long)]
+    pub comment: Option<String>,
+
+    /// Mark the torrent as private so only the trackers are used to find peers.
+    #[arg(long)]
+    pub private: bool,
+
+    /// Protocol version of the torrent, hybrid torrents work with both v1 and v2 clients.
+    #[arg(long, value_enum, default_value = "v1")]
+    pub format: TorrentVersion,
+}
+
+#[derive(Debug, Args)]
+pub struct EditArgs {
+    /// Torrent file to edit.
+    pub torrent: PathBuf,
+
+    /// Where to write the edited torrent, defaults to overwriting it.
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Tracker URL replacing the current ones, can be given multiple times.
+    #[arg(short, long = "tracker")]
+    pub trackers: Vec<String>,
+
+    /// Remove every tracker.
+    #[arg(long, conflicts_with = "trackers")]
+    pub clear_trackers: bool,
+
+    /// New comment, an empty one removes it.
+    #[arg(long)]
+    pub comment: Option<String>,
+
+    /// Mark the torrent as private, this changes its info hash.
+    #[arg(long)]
+    pub private: bool,
+
+    /// Remove the private flag, this changes its info hash.
+    #[arg(long, conflicts_with = "private")]
+    pub public: bool,
+}
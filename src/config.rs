@@ -0,0 +1,630 @@
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use regex::Regex;
+use serde_derive::Deserialize;
+
+use crate::cli::Cli;
+use crate::disk::{Allocation, DiskBackend, Durability};
+use crate::dns::FamilyResolver;
+use crate::encryption::EncryptionPolicy;
+use crate::interface::{AddressFamily, Interface};
+use crate::events::EventKind;
+use crate::rss::{FeedConfig, FeedFilter};
+use crate::tracker::TrackerAuth;
+use crate::utils::PEER_ID_LEN;
+use crate::webhooks::WebhookConfig;
+
+/// Settings of the client, loaded from `~/.config/torrenter/config.toml`.
+///
+/// Every field is optional in the file, missing fields use the defaults below
+/// and command line flags take precedence over the file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Port used for peer connections and announced to trackers, the next few ports are tried when it's taken.
+    pub listen_port: u16,
+
+    /// Pick a random port between the two, both included, instead of `listen_port`.
+    pub listen_port_range: Option<[u16; 2]>,
+
+    /// Bytes per second, 0 means unlimited.
+    pub download_rate_limit: u64,
+    pub upload_rate_limit: u64,
+
+    /// Directory torrents are downloaded into unless another one is given when adding them.
+    pub save_path: PathBuf,
+
+    /// Directory finished torrents are moved to, they stay in the save path when this isn't set.
+    pub complete_path: Option<PathBuf>,
+
+    /// Directory watched for new .torrent files.
+    pub watch_dir: Option<PathBuf>,
+
+    /// Directory the session keeps its state in, like the torrent files of magnet links once their metadata is
+    /// fetched. Defaults to `$XDG_DATA_HOME/torrenter`, falling back to `~/.local/share/torrenter`.
+    pub data_dir: Option<PathBuf>,
+
+    /// Proxy used for HTTP requests, for example `socks5://127.0.0.1:1080`.
+    pub proxy: Option<String>,
+
+    /// Maximum amount of peers a single torrent downloads from at once.
+    pub max_peers_per_torrent: usize,
+
+    /// Peers sending less than this many bytes per second are replaced once a torrent has `max_peers_per_torrent`
+    /// peers and knows of others, 0 keeps them.
+    pub min_peer_rate: u64,
+
+    /// Bytes per second a single peer can send, within the download limit of the session, 0 means unlimited.
+    pub peer_download_rate_limit: u64,
+
+    /// Bytes per second sent to a single peer, within the upload limit of the session, 0 means unlimited.
+    pub peer_upload_rate_limit: u64,
+
+    /// Bytes per second a peer which went a while without sending the blocks requested from it can send from then on,
+    /// so it doesn't hold on to many requests again, 0 leaves it to the other limits.
+    pub snubbed_peer_rate_limit: u64,
+
+    /// Peers asked for in each announce, fewer are asked for once a torrent gets close to `max_peers_per_torrent`.
+    pub num_want: u32,
+
+    /// Send a bitfield with some of our pieces left out and have messages for them afterwards, so finished torrents
+    /// don't stand out to peers watching for seeds.
+    pub lazy_bitfield: bool,
+
+    /// Don't send have messages for pieces a peer already has.
+    pub suppress_haves: bool,
+
+    /// When connections to peers are encrypted, see `encryption::EncryptionPolicy`.
+    pub encryption: EncryptionPolicy,
+
+    /// Local address or name of the interface, like `10.8.0.2` or `tun0`, connections to peers and trackers are made
+    /// from. Connections fail while it's gone instead of going out through another interface.
+    pub outgoing_interface: Option<String>,
+
+    /// Addresses to listen for peers on instead of the listen port, like a LAN IPv4 address and a global IPv6 one,
+    /// each with the port announced for it.
+    pub listen: Vec<ListenConfig>,
+
+    /// Keep peer, tracker and DHT traffic to `ipv4` or `ipv6`, the DHT node is on the IPv6 DHT with `ipv6`.
+    pub address_family: AddressFamily,
+
+    /// Address trackers publish for us instead of the one they see, for setups behind a proxy or VPN. It's learned from
+    /// the peers when it isn't set.
+    pub external_ip: Option<IpAddr>,
+
+    /// Let peers on the local network go past the rate limits, local transfers don't use up internet bandwidth.
+    pub exempt_lan_peers: bool,
+
+    /// Shell command run when a torrent finishes downloading, see `hooks::run_on_complete`.
+    pub on_complete: Option<String>,
+
+    /// Show a desktop notification when a torrent finishes or fails, only when running in a terminal.
+    pub desktop_notifications: bool,
+
+    /// Write `.sha1` and `.sha256` checksum files next to a torrent's data once it finishes.
+    pub write_checksums: bool,
+
+    /// Log filter such as `info` or `torrenter=debug`, the `RUST_LOG` environment variable takes precedence.
+    pub log_level: String,
+
+    /// Output logs as JSON lines instead of human readable text.
+    pub log_json: bool,
+
+    pub rpc: RpcConfig,
+    pub api: ApiConfig,
+    pub disk: DiskConfig,
+    pub dht: DhtConfig,
+    pub client: ClientConfig,
+    pub socket: SocketConfig,
+    pub incoming: IncomingConfig,
+    pub tracker_auth: Vec<TrackerAuth>,
+    pub feeds: Vec<FeedToml>,
+    pub webhooks: Vec<WebhookConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcConfig {
+    pub enabled: bool,
+    pub addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    pub enabled: bool,
+    pub addr: SocketAddr,
+    pub web_ui: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiskConfig {
+    /// How files are read and written, see `disk::DiskBackend`.
+    pub backend: DiskBackend,
+    /// How space for the files is taken, see `disk::Allocation`.
+    pub allocation: Allocation,
+    /// Bytes of blocks kept in memory until their piece is complete, downloads slow down once the disk can't keep up.
+    pub write_cache_size: u64,
+    /// Bytes of recently read pieces kept in memory for peers requesting the same pieces, 0 turns it off.
+    pub read_cache_size: u64,
+    /// Pieces which can be waiting for their hash check before peers stop being asked for new blocks.
+    pub max_unverified_pieces: u64,
+    /// When written files are fsynced, see `disk::Durability`.
+    pub fsync: Durability,
+    pub fsync_interval_secs: u64,
+    /// Download files as `<name>.part`, renaming them once all their pieces are verified.
+    pub part_files: bool,
+    /// Create the symlinks of a torrent once it finishes, they're left out when this is off.
+    pub symlinks: bool,
+    /// Read every piece back from disk and hash it again once it's written, so storage corrupting data silently
+    /// fails the piece instead of it being claimed in the resume data.
+    pub verify_writes: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DhtConfig {
+    /// Run a DHT node on the UDP side of the listen port.
+    pub enabled: bool,
+    /// Only send queries without answering any, for running behind a NAT or firewall which doesn't let them in.
+    pub read_only: bool,
+    /// `host:port` of the nodes the DHT starts from, the nodes of torrents are used as well.
+    pub bootstrap_nodes: Vec<String>,
+}
+
+/// How we present ourselves to trackers and peers, private trackers only let some clients in.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// User-Agent of HTTP requests.
+    pub user_agent: String,
+    /// Start of our peer id, like `-qB4650-`, the rest of its 20 bytes is random.
+    pub peer_id_prefix: String,
+}
+
+/// An address peers can connect to.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenConfig {
+    /// Address and port to listen on, like `192.168.1.5:6881` or `[2001:db8::5]:6881`, port 0 takes any free one.
+    pub addr: SocketAddr,
+    /// Port given to trackers for the address, like the one a router forwards to it, the bound port when it's unset.
+    #[serde(default)]
+    pub announce_port: Option<u16>,
+}
+
+/// Options of the sockets of peer connections, both the ones we make and the ones we accept.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketConfig {
+    /// Send small messages like requests right away instead of waiting to fill a packet (TCP_NODELAY).
+    pub nodelay: bool,
+    /// Bytes of the kernel's send buffer, 0 keeps the OS default.
+    pub send_buffer_size: usize,
+    /// Bytes of the kernel's receive buffer, 0 keeps the OS default.
+    pub recv_buffer_size: usize,
+    /// IP TOS byte, or traffic class on IPv6, with the DSCP in its upper 6 bits, 0 leaves packets unmarked.
+    pub tos: u8,
+    /// Seconds a connection is idle before keepalive probes are sent, 0 sends none.
+    pub keepalive_secs: u64,
+}
+
+/// How much of the peers connecting to us is taken, so a busy swarm can't flood the listen port.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IncomingConfig {
+    /// Connections accepted per second, the others wait their turn, 0 is unlimited.
+    pub accepts_per_sec: u64,
+    /// Peers handshaking at once, connections coming in beyond it are dropped, 0 is unlimited.
+    pub max_handshakes: usize,
+    /// Failed handshakes in a row an IP is dropped after for `ban_secs`, 0 never drops any.
+    pub max_failed_handshakes: u32,
+    pub ban_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeedToml {
+    pub url: String,
+    #[serde(default = "default_feed_interval")]
+    pub interval_secs: u64,
+    #[serde(default)]
+    pub filters: Vec<FeedFilterToml>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeedFilterToml {
+    pub pattern: String,
+    pub save_path: Option<PathBuf>,
+    pub label: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen_port: 6682,
+            listen_port_range: None,
+            download_rate_limit: 0,
+            upload_rate_limit: 0,
+            save_path: PathBuf::new(),
+            complete_path: None,
+            watch_dir: None,
+            data_dir: None,
+            proxy: None,
+            max_peers_per_torrent: 30,
+            min_peer_rate: 1024,
+            peer_download_rate_limit: 0,
+            peer_upload_rate_limit: 0,
+            snubbed_peer_rate_limit: 0,
+            num_want: 50,
+            lazy_bitfield: false,
+            suppress_haves: false,
+            encryption: EncryptionPolicy::default(),
+            outgoing_interface: None,
+            listen: Vec::new(),
+            address_family: AddressFamily::default(),
+            external_ip: None,
+            exempt_lan_peers: true,
+            on_complete: None,
+            desktop_notifications: false,
+            write_checksums: false,
+            log_level: String::from("info"),
+            log_json: false,
+            rpc: RpcConfig::default(),
+            api: ApiConfig::default(),
+            disk: DiskConfig::default(),
+            dht: DhtConfig::default(),
+            client: ClientConfig::default(),
+            socket: SocketConfig::default(),
+            incoming: IncomingConfig::default(),
+            tracker_auth: Vec::new(),
+            feeds: Vec::new(),
+            webhooks: Vec::new(),
+        }
+    }
+}
+
+impl Default for RpcConfig {
+    fn default() -> RpcConfig {
+        RpcConfig {
+            enabled: true,
+            addr: "127.0.0.1:50051".parse().unwrap(),
+        }
+    }
+}
+
+impl Default for ApiConfig {
+    fn default() -> ApiConfig {
+        ApiConfig {
+            enabled: true,
+            addr: "127.0.0.1:8080".parse().unwrap(),
+            web_ui: true,
+        }
+    }
+}
+
+impl Default for DiskConfig {
+    fn default() -> DiskConfig {
+        DiskConfig {
+            backend: DiskBackend::default(),
+            allocation: Allocation::default(),
+            write_cache_size: 16 * 1024 * 1024,
+            read_cache_size: 32 * 1024 * 1024,
+            max_unverified_pieces: 16,
+            fsync: Durability::default(),
+            fsync_interval_secs: 30,
+            part_files: false,
+            symlinks: true,
+            verify_writes: false,
+        }
+    }
+}
+
+impl Default for DhtConfig {
+    fn default() -> DhtConfig {
+        DhtConfig {
+            enabled: true,
+            read_only: false,
+            bootstrap_nodes: vec![
+                String::from("router.bittorrent.com:6881"),
+                String::from("dht.transmissionbt.com:6881"),
+                String::from("router.utorrent.com:6881"),
+            ],
+        }
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            user_agent: format!("torrenter/{}", env!("CARGO_PKG_VERSION")),
+            peer_id_prefix: String::from("-R~0001-"),
+        }
+    }
+}
+
+impl Default for IncomingConfig {
+    fn default() -> IncomingConfig {
+        IncomingConfig {
+            accepts_per_sec: 50,
+            max_handshakes: 32,
+            max_failed_handshakes: 5,
+            ban_secs: 3600,
+        }
+    }
+}
+
+fn default_feed_interval() -> u64 {
+    return 15 * 60;
+}
+
+impl Config {
+    /// Load the config file.
+    ///
+    /// When no path is given the default location is used, and it's fine for it not to exist.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Config> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_config_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default()),
+            },
+        };
+
+        if !required && !path.exists() {
+            return Ok(Config::default());
+        }
+
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Unable to read config file {:?}", path))?;
+
+        return Config::from_toml(&contents)
+            .with_context(|| format!("Invalid config file {:?}", path));
+    }
+
+    pub fn from_toml(contents: &str) -> anyhow::Result<Config> {
+        let config: Config = toml::from_str(contents)?;
+
+        // Fail early on bad filters rather than when the feed is polled.
+        config.feed_configs()?;
+
+        if config.client.peer_id_prefix.len() > PEER_ID_LEN {
+            anyhow::bail!("The peer id prefix {:?} is longer than a peer id", config.client.peer_id_prefix);
+        }
+
+        let event_names: Vec<&str> = [EventKind::Added, EventKind::Completed, EventKind::Error, EventKind::TrackerFailure]
+            .iter().map(|kind| kind.as_str()).collect();
+
+        for webhook in &config.webhooks {
+            if let Some(event) = webhook.events.iter().find(|e| !event_names.contains(&e.as_str())) {
+                anyhow::bail!("Unknown event {:?} for webhook {}, expected one of {:?}", event, webhook.url, event_names);
+            }
+        }
+
+        return Ok(config);
+    }
+
+    /// Override the settings with the flags given on the command line.
+    pub fn apply_cli(&mut self, cli: &Cli) {
+        if let Some(port) = cli.port {
+            self.listen_port = port;
+            self.listen_port_range = None;
+        }
+        if let Some(limit) = cli.download_limit {
+            self.download_rate_limit = limit;
+        }
+        if let Some(limit) = cli.upload_limit {
+            self.upload_rate_limit = limit;
+        }
+        if let Some(path) = &cli.save_path {
+            self.save_path = path.clone();
+        }
+        if let Some(dir) = &cli.watch_dir {
+            self.watch_dir = Some(dir.clone());
+        }
+        if let Some(proxy) = &cli.proxy {
+            self.proxy = Some(proxy.clone());
+        }
+        if let Some(command) = &cli.on_complete {
+            self.on_complete = Some(command.clone());
+        }
+        if cli.notify {
+            self.desktop_notifications = true;
+        }
+        if let Some(level) = &cli.log_level {
+            self.log_level = level.clone();
+        }
+        if cli.log_json {
+            self.log_json = true;
+        }
+        if cli.no_rpc {
+            self.rpc.enabled = false;
+        }
+        if cli.no_api {
+            self.api.enabled = false;
+        }
+        if cli.no_web_ui {
+            self.api.web_ui = false;
+        }
+        if cli.ipv4_only {
+            self.address_family = AddressFamily::Ipv4;
+        }
+        if cli.ipv6_only {
+            self.address_family = AddressFamily::Ipv6;
+        }
+    }
+
+    /// Convert the feeds of the file into the config used by the feed watcher.
+    pub fn feed_configs(&self) -> anyhow::Result<Vec<FeedConfig>> {
+        let mut feeds = Vec::new();
+
+        for feed in &self.feeds {
+            let mut filters = Vec::new();
+            for filter in &feed.filters {
+                filters.push(FeedFilter {
+                    pattern: Regex::new(&filter.pattern)
+                        .with_context(|| format!("Invalid filter for feed {}", feed.url))?,
+                    save_path: filter.save_path.clone(),
+                    label: filter.label.clone(),
+                });
+            }
+
+            feeds.push(FeedConfig {
+                url: feed.url.clone(),
+                interval: Duration::from_secs(feed.interval_secs),
+                filters,
+            });
+        }
+
+        return Ok(feeds);
+    }
+
+    /// The data directory of the config, or the default one when it isn't set.
+    pub fn data_dir(&self) -> Option<PathBuf> {
+        return self.data_dir.clone().or_else(default_data_dir);
+    }
+
+    pub fn dht_enabled(&self) -> bool {
+        return self.dht.enabled;
+    }
+
+    /// Whether the DHT node is on the IPv6 DHT, only when IPv4 is off.
+    pub fn dht_ipv6(&self) -> bool {
+        return self.address_family == AddressFamily::Ipv6;
+    }
+
+    pub fn interface(&self) -> Interface {
+        return Interface::from_config(self.outgoing_interface.as_deref());
+    }
+
+    /// Build the client used for HTTP requests, going through the proxy if there is one.
+    pub fn http_client(&self) -> anyhow::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder().user_agent(&self.client.user_agent);
+
+        match self.interface() {
+            Interface::Any => {}
+            Interface::Addr(addr) => builder = builder.local_address(addr),
+            // Bound to the device itself, so requests fail while it's gone.
+            #[cfg(target_os = "linux")]
+            Interface::Name(name) => builder = builder.interface(&name),
+            #[cfg(not(target_os = "linux"))]
+            interface => builder = builder.local_address(interface.local_addr(false)?),
+        }
+
+        // Hosts resolve to the addresses of the allowed family only.
+        if self.address_family != AddressFamily::Any {
+            builder = builder.dns_resolver(Arc::new(FamilyResolver(self.address_family)));
+        }
+
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy.as_str()).context("Invalid proxy")?);
+        }
+
+        return Ok(builder.build()?);
+    }
+}
+
+/// `$XDG_CONFIG_HOME/torrenter/config.toml`, falling back to `~/.config/torrenter/config.toml`.
+fn default_config_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+
+    return Some(config_dir.join("torrenter").join("config.toml"));
+}
+
+
+/// `$XDG_DATA_HOME/torrenter`, falling back to `~/.local/share/torrenter`.
+fn default_data_dir() -> Option<PathBuf> {
+    let data_dir = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local").join("share"),
+    };
+
+    return Some(data_dir.join("torrenter"));
+}
+
+
+#[test]
+fn test_config_from_toml() {
+    let config = Config::from_toml(r#"
+        listen_port = 7000
+        download_rate_limit = 1048576
+        data_dir = "/var/lib/torrenter"
+
+        [api]
+        web_ui = false
+
+        [client]
+        peer_id_prefix = "-qB4650-"
+
+        [socket]
+        nodelay = true
+        tos = 0x20
+
+        [[listen]]
+        addr = "192.168.1.5:6881"
+
+        [[listen]]
+        addr = "[2001:db8::5]:6881"
+        announce_port = 16881
+
+        [[feeds]]
+        url = "https://example.com/rss"
+
+        [[feeds.filters]]
+        pattern = "(?i)debian"
+        label = "debian"
+    "#).unwrap();
+
+    assert_eq!(config.listen_port, 7000);
+    assert_eq!(config.download_rate_limit, 1048576);
+    assert_eq!(config.upload_rate_limit, 0);
+    assert_eq!(config.data_dir(), Some(PathBuf::from("/var/lib/torrenter")));
+    assert!(config.api.enabled);
+    assert!(!config.api.web_ui);
+    assert!(config.rpc.enabled);
+    assert_eq!(config.client.peer_id_prefix, "-qB4650-");
+    assert!(config.client.user_agent.starts_with("torrenter/"));
+    assert!(config.socket.nodelay);
+    assert_eq!(config.socket.tos, 0x20);
+    assert_eq!(config.socket.keepalive_secs, 0);
+    assert_eq!(config.listen[0], ListenConfig { addr: "192.168.1.5:6881".parse().unwrap(), announce_port: None });
+    assert_eq!(config.listen[1].announce_port, Some(16881));
+
+    let feeds = config.feed_configs().unwrap();
+    assert_eq!(feeds[0].interval, Duration::from_secs(900));
+    assert!(feeds[0].filters[0].pattern.is_match("Debian 12"));
+
+    // Typos and invalid filters are reported instead of being ignored.
+    assert!(Config::from_toml("listen_prot = 7000").is_err());
+    assert!(Config::from_toml("[client]\npeer_id_prefix = \"-this-is-far-too-long-\"").is_err());
+    assert!(Config::from_toml("[[webhooks]]\nurl = \"a\"\nevents = [\"finished\"]").is_err());
+    assert!(Config::from_toml("[[feeds]]\nurl = \"a\"\n[[feeds.filters]]\npattern = \"(\"").is_err());
+}
+
+
+#[test]
+fn test_config_apply_cli() {
+    use clap::Parser;
+
+    let mut config = Config::from_toml("listen_port = 7000\nupload_rate_limit = 10").unwrap();
+    let cli = Cli::parse_from(&["torrenter", "--port", "7001", "--no-api", "--ipv6-only", "file.torrent"]);
+    config.apply_cli(&cli);
+
+    assert_eq!(config.listen_port, 7001);
+    assert_eq!(config.upload_rate_limit, 10);
+    assert!(!config.api.enabled);
+    assert_eq!(config.address_family, AddressFamily::Ipv6);
+    assert!(config.dht_enabled() && config.dht_ipv6());
+
+    assert!(Cli::try_parse_from(&["torrenter", "--ipv4-only", "--ipv6-only"]).is_err());
+}
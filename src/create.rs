@@ -0,0 +1,531 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use rayon::prelude::*;
+use serde_bencode::ser;
+use serde_bytes::ByteBuf;
+use serde_derive::Serialize;
+use sha1::Digest as _;
+use torrenter::bencode::Value;
+
+use crate::utils::torrents::{BLOCK_LEN, DlFile};
+
+/// Smallest and largest piece length picked automatically.
+const MIN_PIECE_LEN: u64 = 16 * 1024;
+const MAX_PIECE_LEN: u64 = 16 * 1024 * 1024;
+
+/// Amount of pieces aimed for when picking the piece length automatically.
+const TARGET_PIECES: u64 = 1500;
+
+/// Which versions of the protocol a created torrent supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum TorrentVersion {
+    /// SHA-1 pieces over the concatenated files.
+    #[default]
+    V1,
+    /// SHA-256 merkle trees per file (BEP 52).
+    V2,
+    /// Both, with pad files so every v1 file starts on a piece boundary.
+    Hybrid,
+}
+
+impl TorrentVersion {
+    fn has_v1(&self) -> bool {
+        return *self != TorrentVersion::V2;
+    }
+
+    fn has_v2(&self) -> bool {
+        return *self != TorrentVersion::V1;
+    }
+}
+
+/// Everything needed to create a torrent file from data on disk.
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    /// File or directory the torrent is created from.
+    pub path: PathBuf,
+    /// Picked from the total size when None, must be a power of two.
+    pub piece_length: Option<u64>,
+    pub trackers: Vec<String>,
+    pub web_seeds: Vec<String>,
+    pub comment: Option<String>,
+    pub private: bool,
+    pub version: TorrentVersion,
+}
+
+/// The metainfo dictionary as written to a .torrent file.
+#[derive(Debug, Serialize)]
+struct MetaInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    announce: Option<String>,
+    #[serde(rename = "announce-list")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    announce_list: Option<Vec<Vec<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+    #[serde(rename = "created by")]
+    created_by: String,
+    #[serde(rename = "creation date")]
+    creation_date: i64,
+    info: InfoDict,
+    #[serde(rename = "piece layers")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    piece_layers: Option<Value>,
+    #[serde(rename = "url-list")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url_list: Option<Vec<String>>,
+}
+
+/// The info dictionary, with the v1 and v2 keys both optional so it can hold either or both.
+#[derive(Debug, Serialize)]
+struct InfoDict {
+    #[serde(rename = "file tree")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_tree: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<DlFile>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length: Option<u64>,
+    #[serde(rename = "meta version")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    meta_version: Option<u8>,
+    name: String,
+    #[serde(rename = "piece length")]
+    piece_length: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pieces: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    private: Option<u8>,
+}
+
+/// A file to hash, in the order it appears in the torrent.
+///
+/// Pad files have no path on disk and read as zeroes.
+#[derive(Debug, Clone)]
+pub(crate) struct SourceFile {
+    pub(crate) disk_path: Option<PathBuf>,
+    pub(crate) torrent_path: Vec<String>,
+    pub(crate) length: u64,
+}
+
+/// Merkle roots of a v2 file, the piece layer is only stored for files longer than a piece.
+struct FileHashes {
+    pieces_root: [u8; 32],
+    piece_layer: Vec<u8>,
+}
+
+/// Create the bencoded metainfo of a file or directory.
+///
+/// Pieces are hashed in parallel, each one reading its own range of the files.
+pub fn create_torrent(options: &CreateOptions) -> anyhow::Result<Vec<u8>> {
+    let name = options.path.file_name()
+        .context("Unable to get the name of the path")?
+        .to_string_lossy()
+        .into_owned();
+
+    let is_dir = options.path.is_dir();
+    let files = if is_dir {
+        let mut files = Vec::new();
+        walk_dir(&options.path, &mut Vec::new(), &mut files)?;
+        files
+    } else {
+        vec![SourceFile {
+            disk_path: Some(options.path.clone()),
+            torrent_path: vec![name.clone()],
+            length: fs::metadata(&options.path).with_context(|| format!("Unable to read {:?}", options.path))?.len(),
+        }]
+    };
+
+    let total_length: u64 = files.iter().map(|f| f.length).sum();
+    if total_length == 0 {
+        anyhow::bail!("Error: Can't create a torrent without any data");
+    }
+
+    let piece_length = match options.piece_length {
+        Some(piece_length) if piece_length.is_power_of_two() && piece_length >= BLOCK_LEN => piece_length,
+        Some(piece_length) => anyhow::bail!("Error: The piece length must be a power of two of at least {} bytes", BLOCK_LEN),
+        None => pick_piece_length(total_length),
+    };
+
+    let mut info = InfoDict {
+        file_tree: None,
+        files: None,
+        length: None,
+        meta_version: None,
+        name,
+        piece_length,
+        pieces: None,
+        private: if options.private { Some(1) } else { None },
+    };
+    let mut piece_layers = None;
+
+    if options.version.has_v2() {
+        let hashes = files.iter()
+            .map(|file| hash_file_v2(file, piece_length))
+            .collect::<anyhow::Result<Vec<FileHashes>>>()?;
+
+        let mut layers = BTreeMap::new();
+        for file_hashes in &hashes {
+            if !file_hashes.piece_layer.is_empty() {
+                layers.insert(file_hashes.pieces_root.to_vec(), Value::Bytes(file_hashes.piece_layer.clone()));
+            }
+        }
+
+        info.meta_version = Some(2);
+        info.file_tree = Some(build_file_tree(&files, &hashes, is_dir));
+        piece_layers = Some(Value::Dict(layers));
+    }
+
+    if options.version.has_v1() {
+        let layout = if options.version == TorrentVersion::Hybrid { add_pad_files(&files, piece_length) } else { files };
+        let layout_length: u64 = layout.iter().map(|f| f.length).sum();
+
+        info.pieces = Some(ByteBuf::from(hash_pieces_v1(&layout, layout_length, piece_length)?.concat()));
+
+        if is_dir {
+            info.files = Some(layout.iter().map(|f| DlFile {
+                path: f.torrent_path.clone(),
+                length: f.length,
+                md5sum: None,
+                attr: if f.disk_path.is_none() { Some(String::from("p")) } else { None },
+                symlink_path: None,
+            }).collect());
+        } else {
+            info.length = Some(total_length);
+        }
+    }
+
+    let metainfo = MetaInfo {
+        announce: options.trackers.first().cloned(),
+        announce_list: if options.trackers.len() > 1 {
+            Some(options.trackers.iter().map(|tracker| vec![tracker.clone()]).collect())
+        } else {
+            None
+        },
+        comment: options.comment.clone(),
+        created_by: format!("torrenter/{}", env!("CARGO_PKG_VERSION")),
+        creation_date: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64),
+        info,
+        piece_layers,
+        url_list: if options.web_seeds.is_empty() { None } else { Some(options.web_seeds.clone()) },
+    };
+
+    return Ok(ser::to_bytes(&metainfo)?);
+}
+
+/// Hash the v1 pieces in parallel, each one reading its own range of the files.
+fn hash_pieces_v1(files: &[SourceFile], total_length: u64, piece_length: u64) -> anyhow::Result<Vec<[u8; 20]>> {
+    let num_pieces = total_length.div_ceil(piece_length);
+
+    return (0..num_pieces).into_par_iter()
+        .map(|index| {
+            let begin = index * piece_length;
+            let length = piece_length.min(total_length - begin);
+            let piece = read_range(files, begin, length)?;
+            Ok(hash_piece(&piece))
+        })
+        .collect();
+}
+
+/// Insert a pad file after every file but the last, so each file starts on a piece boundary like in v2.
+fn add_pad_files(files: &[SourceFile], piece_length: u64) -> Vec<SourceFile> {
+    let mut layout = Vec::new();
+
+    for (i, file) in files.iter().enumerate() {
+        layout.push(file.clone());
+
+        let padding = (piece_length - file.length % piece_length) % piece_length;
+        if padding > 0 && i + 1 < files.len() {
+            layout.push(SourceFile {
+                disk_path: None,
+                torrent_path: vec![String::from(".pad"), padding.to_string()],
+                length: padding,
+            });
+        }
+    }
+
+    return layout;
+}
+
+/// Build the merkle tree of a single file from SHA-256 hashes of its 16 KiB blocks.
+///
+/// Each piece is hashed in parallel into its own sub tree, padded with zero hashes up to the piece length.
+fn hash_file_v2(file: &SourceFile, piece_length: u64) -> anyhow::Result<FileHashes> {
+    if file.length == 0 {
+        return Ok(FileHashes { pieces_root: [0; 32], piece_layer: Vec::new() });
+    }
+
+    let files = std::slice::from_ref(file);
+    let num_pieces = file.length.div_ceil(piece_length);
+    let blocks_per_piece = (piece_length / BLOCK_LEN) as usize;
+
+    let leaves_per_piece = (0..num_pieces).into_par_iter()
+        .map(|index| {
+            let begin = index * piece_length;
+            let piece = read_range(files, begin, piece_length.min(file.length - begin))?;
+            Ok(piece.chunks(BLOCK_LEN as usize).map(sha256).collect::<Vec<[u8; 32]>>())
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    // A file of a single piece or less has its tree padded to the amount of blocks it has, not to a full piece.
+    if num_pieces == 1 {
+        let leaves = &leaves_per_piece[0];
+        return Ok(FileHashes { pieces_root: merkle_root(leaves, leaves.len().next_power_of_two(), [0; 32]), piece_layer: Vec::new() });
+    }
+
+    let layer: Vec<[u8; 32]> = leaves_per_piece.iter()
+        .map(|leaves| merkle_root(leaves, blocks_per_piece, [0; 32]))
+        .collect();
+
+    let pad_piece = merkle_root(&[], blocks_per_piece, [0; 32]);
+    let pieces_root = merkle_root(&layer, layer.len().next_power_of_two(), pad_piece);
+
+    return Ok(FileHashes { pieces_root, piece_layer: layer.concat() });
+}
+
+/// Root of a merkle tree with `width` leaves, the ones past the given hashes being `pad`.
+fn merkle_root(hashes: &[[u8; 32]], width: usize, pad: [u8; 32]) -> [u8; 32] {
+    let mut layer = hashes.to_vec();
+    layer.resize(width, pad);
+
+    while layer.len() > 1 {
+        layer = layer.chunks(2).map(|pair| sha256(&[pair[0], pair[1]].concat())).collect();
+    }
+
+    return layer[0];
+}
+
+/// Build the v2 `file tree`, nesting a dictionary per path component down to the file's length and root.
+fn build_file_tree(files: &[SourceFile], hashes: &[FileHashes], is_dir: bool) -> Value {
+    let mut tree = BTreeMap::new();
+
+    for (file, file_hashes) in files.iter().zip(hashes) {
+        let mut attributes = BTreeMap::new();
+        attributes.insert(b"length".to_vec(), Value::Int(file.length as i64));
+        if file.length > 0 {
+            attributes.insert(b"pieces root".to_vec(), Value::Bytes(file_hashes.pieces_root.to_vec()));
+        }
+
+        let mut leaf = BTreeMap::new();
+        leaf.insert(Vec::new(), Value::Dict(attributes));
+
+        // A single file torrent has the file itself at the root of the tree, named like the torrent.
+        let components = if is_dir { &file.torrent_path[..] } else { &file.torrent_path[..1] };
+
+        let mut node = &mut tree;
+        for component in &components[..components.len() - 1] {
+            let child = node.entry(component.as_bytes().to_vec()).or_insert_with(|| Value::Dict(BTreeMap::new()));
+            node = match child {
+                Value::Dict(dict) => dict,
+                _ => unreachable!(),
+            };
+        }
+        node.insert(components[components.len() - 1].as_bytes().to_vec(), Value::Dict(leaf));
+    }
+
+    return Value::Dict(tree);
+}
+
+/// Pick a power of two piece length giving roughly `TARGET_PIECES` pieces.
+fn pick_piece_length(total_length: u64) -> u64 {
+    let piece_length = (total_length / TARGET_PIECES).next_power_of_two();
+    return piece_length.clamp(MIN_PIECE_LEN, MAX_PIECE_LEN);
+}
+
+/// Collect every file in a directory recursively, sorted by path so the result is reproducible.
+fn walk_dir(dir: &Path, prefix: &mut Vec<String>, files: &mut Vec<SourceFile>) -> anyhow::Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .with_context(|| format!("Unable to read directory {:?}", dir))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        prefix.push(entry.file_name().to_string_lossy().into_owned());
+
+        if path.is_dir() {
+            walk_dir(&path, prefix, files)?;
+        } else {
+            files.push(SourceFile {
+                length: entry.metadata()?.len(),
+                disk_path: Some(path),
+                torrent_path: prefix.clone(),
+            });
+        }
+
+        prefix.pop();
+    }
+
+    Ok(())
+}
+
+/// Read `length` bytes starting at `begin`, as if all the files were one continuous file.
+pub(crate) fn read_range(files: &[SourceFile], begin: u64, length: u64) -> anyhow::Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(length as usize);
+    let mut file_offset = 0;
+    let end = begin + length;
+
+    for file in files {
+        let file_end = file_offset + file.length;
+
+        if file_end > begin && file_offset < end {
+            let read_start = begin.max(file_offset) - file_offset;
+            let read_len = end.min(file_end) - file_offset - read_start;
+
+            match &file.disk_path {
+                Some(disk_path) => {
+                    let mut handle = File::open(disk_path).with_context(|| format!("Unable to open {:?}", disk_path))?;
+                    handle.seek(SeekFrom::Start(read_start))?;
+                    handle.take(read_len).read_to_end(&mut buffer)?;
+                }
+                None => buffer.resize(buffer.len() + read_len as usize, 0),
+            }
+        }
+
+        file_offset = file_end;
+        if file_offset >= end {
+            break;
+        }
+    }
+
+    if buffer.len() as u64 != length {
+        anyhow::bail!("Error: Files changed while creating the torrent");
+    }
+
+    return Ok(buffer);
+}
+
+pub(crate) fn hash_piece(piece: &[u8]) -> [u8; 20] {
+    return sha1::Sha1::digest(piece).into();
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+
+    let mut hash: [u8; 32] = [0; 32];
+    hasher.result(&mut hash);
+    return hash;
+}
+
+
+#[test]
+fn test_pick_piece_length() {
+    assert_eq!(pick_piece_length(1000), MIN_PIECE_LEN);
+    assert_eq!(pick_piece_length(700 * 1024 * 1024), 512 * 1024);
+    assert_eq!(pick_piece_length(1024 * 1024 * 1024 * 1024), MAX_PIECE_LEN);
+}
+
+
+#[test]
+fn test_create_torrent() {
+    use crate::utils::torrents::Torrent;
+
+    let dir = PathBuf::from("test-files/create/data");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("sub")).unwrap();
+
+    // Three files spread over three pieces, with a piece crossing file boundaries.
+    fs::write(dir.join("b.bin"), vec![2; 20000]).unwrap();
+    fs::write(dir.join("a.bin"), vec![1; 10000]).unwrap();
+    fs::write(dir.join("sub").join("c.bin"), vec![3; 5000]).unwrap();
+
+    let metainfo = create_torrent(&CreateOptions {
+        path: dir.clone(),
+        piece_length: Some(BLOCK_LEN),
+        trackers: vec![String::from("udp://tracker.example.com:80")],
+        private: true,
+        ..Default::default()
+    }).unwrap();
+
+    let torrent = Torrent::from_bytes(&metainfo).unwrap();
+    assert_eq!(torrent.info.name, "data");
+    assert_eq!(torrent.size, 35000);
+    assert_eq!(torrent.info.private, Some(1));
+    assert_eq!(torrent.announce, Some(String::from("udp://tracker.example.com:80")));
+
+    let files = torrent.info.files.unwrap();
+    assert_eq!(files.iter().map(|f| f.path.join("/")).collect::<Vec<_>>(), vec!["a.bin", "b.bin", "sub/c.bin"]);
+
+    let mut expected = vec![1; 10000];
+    expected.extend(vec![2; 20000]);
+    expected.extend(vec![3; 5000]);
+
+    assert_eq!(torrent.info.pieces.len(), 3 * 20);
+    assert_eq!(&torrent.info.pieces[0..20], &hash_piece(&expected[0..16384]));
+    assert_eq!(&torrent.info.pieces[40..60], &hash_piece(&expected[32768..]));
+
+    let _ = fs::remove_dir_all("test-files/create");
+}
+
+
+#[test]
+fn test_create_hybrid_torrent() {
+    use crate::utils::torrents::Torrent;
+
+    let dir = PathBuf::from("test-files/create-hybrid/data");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    // One file of three pieces followed by one smaller than a block.
+    let big: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+    fs::write(dir.join("a.bin"), &big).unwrap();
+    fs::write(dir.join("b.bin"), vec![7; 100]).unwrap();
+
+    let metainfo = create_torrent(&CreateOptions {
+        path: dir.clone(),
+        piece_length: Some(BLOCK_LEN),
+        version: TorrentVersion::Hybrid,
+        ..Default::default()
+    }).unwrap();
+
+    // The v1 side pads the first file up to the next piece boundary.
+    let torrent = Torrent::from_bytes(&metainfo).unwrap();
+    let raw_info = Value::decode(&metainfo).unwrap().as_dict().unwrap()[&b"info".to_vec()].encode();
+    assert_eq!(torrent.info_hash_v2, Some(sha256(&raw_info)));
+    assert_eq!(torrent.truncated_info_hash_v2().unwrap(), sha256(&raw_info)[..20]);
+
+    let files = torrent.info.files.unwrap();
+    assert_eq!(files.len(), 3);
+    assert_eq!(files[1].path, vec![".pad", "9152"]);
+    assert_eq!(files[1].attr, Some(String::from("p")));
+    assert_eq!(torrent.info.pieces.len(), 4 * 20);
+
+    let mut padded = big.clone();
+    padded.resize(49152, 0);
+    assert_eq!(&torrent.info.pieces[40..60], &hash_piece(&padded[32768..]));
+
+    let value = Value::decode(&metainfo).unwrap();
+    let dict = match value { Value::Dict(dict) => dict, _ => panic!() };
+    let info = match &dict[&b"info".to_vec()] { Value::Dict(info) => info, _ => panic!() };
+    assert_eq!(info[&b"meta version".to_vec()], Value::Int(2));
+
+    let tree = match &info[&b"file tree".to_vec()] { Value::Dict(tree) => tree, _ => panic!() };
+    let root_of = |name: &[u8]| match &tree[&name.to_vec()] {
+        Value::Dict(leaf) => match &leaf[&Vec::new()] {
+            Value::Dict(attributes) => attributes[&b"pieces root".to_vec()].clone(),
+            _ => panic!(),
+        },
+        _ => panic!(),
+    };
+
+    // A file within a single block has that block's hash as its root.
+    assert_eq!(root_of(b"b.bin"), Value::Bytes(sha256(&[7; 100]).to_vec()));
+
+    let layer: Vec<[u8; 32]> = big.chunks(BLOCK_LEN as usize).map(sha256).collect();
+    let pad = merkle_root(&[], 1, [0; 32]);
+    let expected_root = merkle_root(&layer, 4, pad);
+    assert_eq!(root_of(b"a.bin"), Value::Bytes(expected_root.to_vec()));
+
+    let layers = match &dict[&b"piece layers".to_vec()] { Value::Dict(layers) => layers, _ => panic!() };
+    assert_eq!(layers[&expected_root.to_vec()], Value::Bytes(layer.concat()));
+
+    let _ = fs::remove_dir_all("test-files/create-hybrid");
+}
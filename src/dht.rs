@@ -0,0 +1,1160 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use rand::seq::IteratorRandom;
+use sha1::{Digest, Sha1};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::JoinSet;
+use tracing::{debug, info};
+
+use crate::config::DhtConfig;
+use crate::interface::Interface;
+use crate::krpc::{Body, Message, Query, Response, ERROR_PROTOCOL};
+use crate::tracker::{ExternalIp, Scrape};
+use crate::utils::{is_local_addr, to_hex};
+
+/// Id of a DHT node, and the key info hashes are looked up by (BEP 5).
+pub type NodeId = [u8; 20];
+
+/// Nodes kept in each bucket of the routing table.
+pub const BUCKET_SIZE: usize = 8;
+
+/// A node not heard from for this long can be replaced by a new one.
+const STALE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// How long a node has to answer a query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Peers announced to us are forgotten after this long unless they announce again.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// Peers kept for each info hash, the oldest one makes room for a new one.
+const MAX_PEERS_PER_HASH: usize = 100;
+
+/// Info hashes peers are kept for, announces of other info hashes are turned down once there are this many.
+const MAX_STORED_HASHES: usize = 1000;
+
+/// Time between changes of the secret announce tokens are made from, the previous secret stays valid as well.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+/// Largest KRPC message read, anything longer is cut off and fails to decode.
+const MAX_MESSAGE_LEN: usize = 2048;
+
+/// Queries a lookup has waiting for an answer at once.
+const LOOKUP_PARALLELISM: usize = 3;
+
+/// Queries after which a lookup stops, even if it's still finding closer nodes.
+const MAX_LOOKUP_QUERIES: usize = 64;
+
+/// Info hashes given in answer to a `sample_infohashes`, so the response fits in a packet with the nodes.
+const MAX_SAMPLES: usize = 20;
+
+/// How long the same samples are given out, the querying node is told to come back after that.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The routing table is bootstrapped again while it has fewer nodes than this.
+const MIN_NODES: usize = BUCKET_SIZE;
+
+/// Bits of an IPv4 or IPv6 address which make up the start of a node id (BEP 42).
+const IPV4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+const IPV6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+/// CRC-32C (Castagnoli) of the data, which BEP 42 derives node ids from.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+        }
+    }
+
+    return !crc;
+}
+
+/// The CRC the first 21 bits of the id of a node on `ip` come from, with `r` the 3 random bits of the id.
+fn id_crc(ip: IpAddr, r: u8) -> u32 {
+    let mut masked = match ip {
+        IpAddr::V6(ip) if ip.to_ipv4_mapped().is_none() => {
+            ip.octets()[..8].iter().zip(IPV6_MASK).map(|(byte, mask)| byte & mask).collect::<Vec<u8>>()
+        }
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().unwrap().octets().iter().zip(IPV4_MASK).map(|(byte, mask)| byte & mask).collect(),
+        IpAddr::V4(ip) => ip.octets().iter().zip(IPV4_MASK).map(|(byte, mask)| byte & mask).collect(),
+    };
+    masked[0] |= (r & 0x7) << 5;
+
+    return crc32c(&masked);
+}
+
+/// A node id for our external address, random where BEP 42 leaves it random.
+///
+/// The id is fully random while the address isn't known, it's made again once it is.
+pub fn node_id(ip: Option<IpAddr>) -> NodeId {
+    let random: NodeId = rand::random();
+    return match ip {
+        Some(ip) => node_id_from(ip, random),
+        None => random,
+    };
+}
+
+/// The id of a node on `ip`, taking the bits BEP 42 doesn't fix from `random`.
+fn node_id_from(ip: IpAddr, random: NodeId) -> NodeId {
+    let crc = id_crc(ip, random[19]);
+
+    let mut id = random;
+    id[0] = (crc >> 24) as u8;
+    id[1] = (crc >> 16) as u8;
+    id[2] = ((crc >> 8) as u8 & 0xf8) | (random[2] & 0x7);
+
+    return id;
+}
+
+/// Whether a node's id fits its address (BEP 42), nodes on the local network can have any id.
+pub fn is_valid_id(id: &NodeId, ip: IpAddr) -> bool {
+    if is_local_addr(ip) {
+        return true;
+    }
+
+    let crc = id_crc(ip, id[19]);
+    return id[0] == (crc >> 24) as u8 && id[1] == (crc >> 16) as u8 && id[2] & 0xf8 == (crc >> 8) as u8 & 0xf8;
+}
+
+/// XOR distance between two ids, compared as big endian numbers.
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut distance = [0; 20];
+    for i in 0..20 {
+        distance[i] = a[i] ^ b[i];
+    }
+
+    return distance;
+}
+
+/// Leading bits two ids share, 160 for the same id.
+fn common_bits(a: &NodeId, b: &NodeId) -> usize {
+    let distance = distance(a, b);
+    return match distance.iter().position(|&byte| byte != 0) {
+        Some(i) => i * 8 + distance[i].leading_zeros() as usize,
+        None => 160,
+    };
+}
+
+/// A node of the routing table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    /// Whether the id fits the node's address, the nodes which don't are used last and replaced first so a node can't
+    /// pick ids to take over part of the table.
+    pub conforming: bool,
+    pub last_seen: Instant,
+}
+
+/// The nodes we know of, in buckets by how close they are to our id (BEP 5).
+///
+/// Every bucket but the one of nodes sharing all 160 bits has room for `BUCKET_SIZE` nodes, which is the same as
+/// splitting the bucket our id falls in each time it's full.
+#[derive(Debug)]
+pub struct RoutingTable {
+    id: NodeId,
+    /// Nodes by how many leading bits their id shares with ours.
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> RoutingTable {
+        RoutingTable {
+            id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        return self.id;
+    }
+
+    /// Take a new id, once our external address is known, keeping the nodes which still fit.
+    pub fn set_id(&mut self, id: NodeId) {
+        let nodes: Vec<Node> = self.buckets.drain(..).flatten().collect();
+        self.id = id;
+        self.buckets = vec![Vec::new(); 160];
+
+        for node in nodes {
+            self.insert_node(node);
+        }
+    }
+
+    /// Add a node we heard from, or mark it as seen when it's already known. Returns whether it's in the table.
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddr, now: Instant) -> bool {
+        return self.insert_node(Node { id, addr, conforming: is_valid_id(&id, addr.ip()), last_seen: now });
+    }
+
+    fn insert_node(&mut self, node: Node) -> bool {
+        if node.id == self.id {
+            return false;
+        }
+
+        let bucket = &mut self.buckets[common_bits(&self.id, &node.id).min(159)];
+        if let Some(known) = bucket.iter_mut().find(|known| known.id == node.id) {
+            // Nodes keep their address, another node could claim the id otherwise.
+            if known.addr == node.addr {
+                known.last_seen = known.last_seen.max(node.last_seen);
+            }
+            return true;
+        }
+
+        if bucket.len() < BUCKET_SIZE {
+            bucket.push(node);
+            return true;
+        }
+
+        // A full bucket makes room by dropping a non-conforming node for a conforming one, or else a stale node.
+        let replaced = match node.conforming {
+            true => bucket.iter().position(|known| !known.conforming),
+            false => None,
+        };
+        let replaced = replaced.or_else(|| {
+            bucket.iter().enumerate()
+                .filter(|(_, known)| node.last_seen.saturating_duration_since(known.last_seen) >= STALE_AFTER)
+                .filter(|(_, known)| node.conforming || !known.conforming)
+                .min_by_key(|(_, known)| known.last_seen)
+                .map(|(i, _)| i)
+        });
+
+        return match replaced {
+            Some(i) => {
+                bucket[i] = node;
+                true
+            }
+            None => false,
+        };
+    }
+
+    /// Forget a node which stopped answering.
+    pub fn remove(&mut self, id: &NodeId) {
+        let bucket = &mut self.buckets[common_bits(&self.id, id).min(159)];
+        bucket.retain(|node| node.id != *id);
+    }
+
+    /// Same as `remove` when only the address of the node is known.
+    pub fn remove_addr(&mut self, addr: SocketAddr) {
+        for bucket in &mut self.buckets {
+            bucket.retain(|node| node.addr != addr);
+        }
+    }
+
+    /// The `count` nodes closest to the target, nodes with ids which don't fit their address only make up for missing
+    /// conforming ones.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<&Node> = self.buckets.iter().flatten().collect();
+        nodes.sort_by_key(|node| (!node.conforming, distance(&node.id, target)));
+
+        return nodes.into_iter().take(count).cloned().collect();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.buckets.iter().map(|bucket| bucket.len()).sum();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+}
+
+
+/// Bytes of the bloom filters of a DHT scrape, a bit set for each peer's address (BEP 33).
+const BLOOM_LEN: usize = 256;
+
+/// Set of addresses, only as big as it takes to estimate how many there are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter(Box<[u8; BLOOM_LEN]>);
+
+impl Default for BloomFilter {
+    fn default() -> BloomFilter {
+        return BloomFilter(Box::new([0; BLOOM_LEN]));
+    }
+}
+
+impl BloomFilter {
+    pub fn from_bytes(bytes: &[u8]) -> Option<BloomFilter> {
+        return bytes.try_into().ok().map(|bytes| BloomFilter(Box::new(bytes)));
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        return self.0.as_slice();
+    }
+
+    /// Set the two bits the SHA-1 of the address picks.
+    pub fn insert(&mut self, ip: IpAddr) {
+        let hash = match ip {
+            IpAddr::V4(ip) => Sha1::digest(ip.octets()),
+            IpAddr::V6(ip) => Sha1::digest(ip.octets()),
+        };
+
+        for index in [u16::from_le_bytes([hash[0], hash[1]]), u16::from_le_bytes([hash[2], hash[3]])] {
+            let index = index as usize % (BLOOM_LEN * 8);
+            self.0[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    /// Add the addresses of another filter, the filters of several nodes cover more of the swarm.
+    pub fn union(&mut self, other: &BloomFilter) {
+        for (byte, other) in self.0.iter_mut().zip(other.0.iter()) {
+            *byte |= *other;
+        }
+    }
+
+    /// Estimated number of addresses in the filter.
+    pub fn estimate(&self) -> u64 {
+        let bits = (BLOOM_LEN * 8) as f64;
+        // A full filter gives an infinite estimate, this caps it at the largest one it can give.
+        let zeros = (self.0.iter().map(|byte| byte.count_zeros()).sum::<u32>() as f64).max(1.0);
+
+        return ((zeros / bits).ln() / (2.0 * (1.0 - 1.0 / bits).ln())).round() as u64;
+    }
+}
+
+#[derive(Debug, Clone)]
+struct StoredPeer {
+    addr: SocketAddr,
+    announced: Instant,
+    /// Whether the peer announced it has the whole torrent.
+    seed: bool,
+}
+
+/// Peers which announced themselves to us with `announce_peer`, by info hash.
+#[derive(Debug, Default)]
+pub struct PeerStorage {
+    peers: HashMap<[u8; 20], Vec<StoredPeer>>,
+}
+
+impl PeerStorage {
+    pub fn announce(&mut self, info_hash: [u8; 20], addr: SocketAddr, seed: bool, now: Instant) {
+        if !self.peers.contains_key(&info_hash) && self.peers.len() >= MAX_STORED_HASHES {
+            self.expire(now);
+            if self.peers.len() >= MAX_STORED_HASHES {
+                return;
+            }
+        }
+
+        let peers = self.peers.entry(info_hash).or_default();
+        peers.retain(|peer| peer.addr != addr && now.saturating_duration_since(peer.announced) < PEER_TTL);
+        if peers.len() >= MAX_PEERS_PER_HASH {
+            peers.remove(0);
+        }
+        peers.push(StoredPeer { addr, announced: now, seed });
+    }
+
+    fn live(&self, info_hash: &[u8; 20], now: Instant) -> impl Iterator<Item = &StoredPeer> {
+        return self.peers.get(info_hash).into_iter().flatten().filter(move |peer| now.saturating_duration_since(peer.announced) < PEER_TTL);
+    }
+
+    pub fn peers(&self, info_hash: &[u8; 20], now: Instant) -> Vec<SocketAddr> {
+        return self.live(info_hash, now).map(|peer| peer.addr).collect();
+    }
+
+    /// Bloom filters of the seeds and of the other peers of an info hash, for a scrape.
+    pub fn filters(&self, info_hash: &[u8; 20], now: Instant) -> (BloomFilter, BloomFilter) {
+        let (mut seeds, mut peers) = (BloomFilter::default(), BloomFilter::default());
+        for peer in self.live(info_hash, now) {
+            if peer.seed {
+                seeds.insert(peer.addr.ip());
+            } else {
+                peers.insert(peer.addr.ip());
+            }
+        }
+
+        return (seeds, peers);
+    }
+
+    /// Up to `count` of the info hashes peers are stored for, picked at random.
+    pub fn sample(&self, count: usize) -> Vec<[u8; 20]> {
+        return self.peers.keys().copied().choose_multiple(&mut rand::thread_rng(), count);
+    }
+
+    /// Forget the peers which haven't announced again for `PEER_TTL`.
+    pub fn expire(&mut self, now: Instant) {
+        for peers in self.peers.values_mut() {
+            peers.retain(|peer| now.saturating_duration_since(peer.announced) < PEER_TTL);
+        }
+        self.peers.retain(|_, peers| !peers.is_empty());
+    }
+
+    pub fn len(&self) -> usize {
+        return self.peers.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.peers.is_empty();
+    }
+}
+
+/// Tokens given out with `get_peers` responses, a node has to send one back to announce itself so it can't announce
+/// addresses other than its own.
+#[derive(Debug)]
+struct Tokens {
+    /// The current secret and the one before it.
+    secrets: [[u8; 20]; 2],
+    changed: Instant,
+}
+
+impl Tokens {
+    fn new(now: Instant) -> Tokens {
+        Tokens {
+            secrets: [rand::random(), rand::random()],
+            changed: now,
+        }
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.changed) >= TOKEN_ROTATION {
+            self.secrets = [rand::random(), self.secrets[0]];
+            self.changed = now;
+        }
+    }
+
+    fn token(&self, ip: IpAddr) -> Vec<u8> {
+        return make_token(&self.secrets[0], ip);
+    }
+
+    fn is_valid(&self, token: &[u8], ip: IpAddr) -> bool {
+        return self.secrets.iter().any(|secret| make_token(secret, ip) == token);
+    }
+}
+
+fn make_token(secret: &[u8; 20], ip: IpAddr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(secret);
+    match ip {
+        IpAddr::V4(ip) => hasher.update(ip.octets()),
+        IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+
+    return hasher.finalize()[..8].to_vec();
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum CandidateState {
+    Waiting,
+    Queried,
+    Responded(Response),
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+struct Candidate {
+    /// Unknown for the nodes a lookup starts from, like the bootstrap nodes.
+    id: Option<NodeId>,
+    addr: SocketAddr,
+    state: CandidateState,
+}
+
+/// An iterative lookup of the nodes closest to a target (BEP 5), querying closer and closer nodes until the closest
+/// ones it knows of have all answered.
+#[derive(Debug)]
+pub struct Lookup {
+    target: NodeId,
+    candidates: Vec<Candidate>,
+    queries: usize,
+}
+
+impl Lookup {
+    pub fn new(target: NodeId) -> Lookup {
+        Lookup {
+            target,
+            candidates: Vec::new(),
+            queries: 0,
+        }
+    }
+
+    /// Add a node to query, unless it's there already.
+    pub fn add(&mut self, id: Option<NodeId>, addr: SocketAddr) {
+        if !self.candidates.iter().any(|candidate| candidate.addr == addr) {
+            self.candidates.push(Candidate { id, addr, state: CandidateState::Waiting });
+        }
+    }
+
+    /// The closest nodes which haven't failed to answer, the ones a lookup is still looking at.
+    fn frontier(&self) -> Vec<&Candidate> {
+        let mut known: Vec<&Candidate> = self.candidates.iter()
+            .filter(|candidate| candidate.id.is_some() && candidate.state != CandidateState::Failed)
+            .collect();
+        known.sort_by_key(|candidate| distance(&candidate.id.unwrap(), &self.target));
+        known.truncate(BUCKET_SIZE);
+
+        return known;
+    }
+
+    /// The nodes to query next, so there are up to `LOOKUP_PARALLELISM` queries waiting for an answer. Nodes without an
+    /// id come first, then the closest ones.
+    pub fn next(&mut self) -> Vec<SocketAddr> {
+        if self.queries >= MAX_LOOKUP_QUERIES {
+            return Vec::new();
+        }
+
+        let in_flight = self.candidates.iter().filter(|candidate| candidate.state == CandidateState::Queried).count();
+        let frontier: Vec<SocketAddr> = self.frontier().iter().map(|candidate| candidate.addr).collect();
+        let mut next: Vec<&mut Candidate> = self.candidates.iter_mut()
+            .filter(|candidate| candidate.state == CandidateState::Waiting)
+            .filter(|candidate| candidate.id.is_none() || frontier.contains(&candidate.addr))
+            .collect();
+        let target = self.target;
+        next.sort_by_key(|candidate| candidate.id.map(|id| distance(&id, &target)));
+
+        let count = LOOKUP_PARALLELISM.saturating_sub(in_flight).min(MAX_LOOKUP_QUERIES - self.queries);
+        let next: Vec<SocketAddr> = next.into_iter().take(count).map(|candidate| {
+            candidate.state = CandidateState::Queried;
+            candidate.addr
+        }).collect();
+        self.queries += next.len();
+
+        return next;
+    }
+
+    /// A node answered, the nodes it gave are queried next if they're closer.
+    pub fn responded(&mut self, addr: SocketAddr, response: Response) {
+        for (id, node_addr) in &response.nodes {
+            self.add(Some(*id), *node_addr);
+        }
+
+        if let Some(candidate) = self.candidates.iter_mut().find(|candidate| candidate.addr == addr) {
+            candidate.id = Some(response.id);
+            candidate.state = CandidateState::Responded(response);
+        }
+    }
+
+    pub fn failed(&mut self, addr: SocketAddr) {
+        if let Some(candidate) = self.candidates.iter_mut().find(|candidate| candidate.addr == addr) {
+            candidate.state = CandidateState::Failed;
+        }
+    }
+
+    /// Whether every query has been answered and there's no closer node left to query.
+    pub fn is_done(&self) -> bool {
+        let in_flight = self.candidates.iter().any(|candidate| candidate.state == CandidateState::Queried);
+        let frontier = self.frontier();
+        let waiting = self.candidates.iter()
+            .filter(|candidate| candidate.state == CandidateState::Waiting)
+            .any(|candidate| candidate.id.is_none() || frontier.iter().any(|closest| closest.addr == candidate.addr));
+
+        return !in_flight && (!waiting || self.queries >= MAX_LOOKUP_QUERIES);
+    }
+
+    /// The closest nodes which answered, with their responses.
+    pub fn closest(&self) -> Vec<(NodeId, SocketAddr, Response)> {
+        let mut responded = self.responses();
+        responded.truncate(BUCKET_SIZE);
+        return responded;
+    }
+
+    /// Every node which answered with its response, closest first.
+    pub fn responses(&self) -> Vec<(NodeId, SocketAddr, Response)> {
+        let mut responded: Vec<(NodeId, SocketAddr, Response)> = self.candidates.iter()
+            .filter_map(|candidate| match &candidate.state {
+                CandidateState::Responded(response) => Some((response.id, candidate.addr, response.clone())),
+                _ => None,
+            })
+            .collect();
+        responded.sort_by_key(|(id, _, _)| distance(id, &self.target));
+
+        return responded;
+    }
+}
+
+/// Host and port of a bootstrap node, such as `router.bittorrent.com:6881`.
+fn parse_host_port(node: &str) -> anyhow::Result<(String, u16)> {
+    let (host, port) = node.rsplit_once(':').with_context(|| format!("Error: The DHT node {:?} has no port", node))?;
+    let port = port.parse::<u16>().with_context(|| format!("Error: Invalid port in the DHT node {:?}", node))?;
+
+    return Ok((host.trim_start_matches('[').trim_end_matches(']').to_owned(), port));
+}
+
+struct DhtState {
+    table: RoutingTable,
+    storage: PeerStorage,
+    tokens: Tokens,
+    /// Queries waiting for their response by transaction id, with the node they were sent to.
+    pending: HashMap<Vec<u8>, (SocketAddr, oneshot::Sender<Message>)>,
+    next_transaction: u16,
+    /// Info hashes given out to `sample_infohashes` queries until `SAMPLE_INTERVAL` after they were picked.
+    samples: Vec<[u8; 20]>,
+    sampled: Option<Instant>,
+}
+
+impl DhtState {
+    /// Answer a query of the node at `from`.
+    fn answer(&mut self, transaction: Vec<u8>, query: Query, from: SocketAddr, now: Instant) -> Message {
+        self.tokens.rotate(now);
+        let mut response = Response { id: self.table.id(), ..Default::default() };
+
+        match query {
+            Query::Ping => {}
+            Query::FindNode { target } => {
+                response.nodes = self.table.closest(&target, BUCKET_SIZE).into_iter().map(|node| (node.id, node.addr)).collect();
+            }
+            Query::GetPeers { info_hash, scrape } => {
+                if scrape {
+                    let (seeds, peers) = self.storage.filters(&info_hash, now);
+                    response.seeds = Some(seeds);
+                    response.peers = Some(peers);
+                }
+                response.values = self.storage.peers(&info_hash, now);
+                response.nodes = self.table.closest(&info_hash, BUCKET_SIZE).into_iter().map(|node| (node.id, node.addr)).collect();
+                response.token = Some(self.tokens.token(from.ip()));
+            }
+            Query::AnnouncePeer { info_hash, port, implied_port, token, seed } => {
+                if !self.tokens.is_valid(&token, from.ip()) {
+                    return Message::error(transaction, ERROR_PROTOCOL, "Bad token");
+                }
+                let port = if implied_port { from.port() } else { port };
+                self.storage.announce(info_hash, SocketAddr::new(from.ip(), port), seed, now);
+            }
+            Query::SampleInfohashes { target } => {
+                // Picked again early while there are more info hashes than the samples show.
+                let stale = self.sampled.is_none_or(|sampled| now.saturating_duration_since(sampled) >= SAMPLE_INTERVAL);
+                if stale || self.samples.len() < MAX_SAMPLES.min(self.storage.len()) {
+                    self.storage.expire(now);
+                    self.samples = self.storage.sample(MAX_SAMPLES);
+                    self.sampled = Some(now);
+                }
+                let left = SAMPLE_INTERVAL.saturating_sub(now.saturating_duration_since(self.sampled.unwrap()));
+
+                response.nodes = self.table.closest(&target, BUCKET_SIZE).into_iter().map(|node| (node.id, node.addr)).collect();
+                response.samples = self.samples.clone();
+                response.num = Some(self.storage.len() as i64);
+                response.interval = Some(left.as_secs() as i64);
+            }
+        }
+
+        return Message::response(transaction, response, from);
+    }
+}
+
+/// Our node of the mainline DHT, on the same UDP port as the one peers connect to over TCP.
+///
+/// A read-only node only sends queries, it doesn't answer any and tells other nodes so they leave it out of their
+/// routing tables (BEP 43). It's for running behind a NAT or firewall which doesn't let queries in anyway.
+///
+/// The node is on the IPv4 or the IPv6 DHT, which are separate networks with their own nodes (BEP 32).
+pub struct Dht {
+    socket: UdpSocket,
+    read_only: bool,
+    ipv6: bool,
+    /// Nodes the routing table is filled from when it's empty.
+    bootstrap_nodes: Vec<(String, u16)>,
+    bootstrapping: AtomicBool,
+    /// Our address, which the node id is made from.
+    external_ip: Arc<ExternalIp>,
+    state: Mutex<DhtState>,
+}
+
+impl Dht {
+    pub fn bind(config: &DhtConfig, interface: &Interface, port: u16, external_ip: Arc<ExternalIp>, ipv6: bool) -> anyhow::Result<Dht> {
+        let socket = if ipv6 { interface.bind_udp6(port)? } else { interface.bind_udp(port)? };
+        socket.set_nonblocking(true)?;
+        let now = Instant::now();
+        let bootstrap_nodes = config.bootstrap_nodes.iter().map(|node| parse_host_port(node)).collect::<anyhow::Result<_>>()?;
+
+        return Ok(Dht {
+            socket: UdpSocket::from_std(socket)?,
+            read_only: config.read_only,
+            ipv6,
+            bootstrap_nodes,
+            bootstrapping: AtomicBool::new(false),
+            state: Mutex::new(DhtState {
+                table: RoutingTable::new(node_id(external_ip.get(ipv6))),
+                storage: PeerStorage::default(),
+                tokens: Tokens::new(now),
+                pending: HashMap::new(),
+                next_transaction: rand::random(),
+                samples: Vec::new(),
+                sampled: None,
+            }),
+            external_ip,
+        });
+    }
+
+    pub fn id(&self) -> NodeId {
+        return self.state.lock().unwrap().table.id();
+    }
+
+    /// Nodes in the routing table.
+    pub fn nodes(&self) -> usize {
+        return self.state.lock().unwrap().table.len();
+    }
+
+    /// Whether the routing table has too few nodes for lookups to go anywhere, and needs bootstrapping again.
+    pub fn needs_bootstrap(&self) -> bool {
+        return self.nodes() < MIN_NODES && !self.bootstrapping.load(Ordering::Relaxed);
+    }
+
+    /// Fill the routing table by looking up our own id, starting from the bootstrap nodes and the `extra` ones, like
+    /// the nodes of a torrent.
+    pub async fn bootstrap(self: Arc<Dht>, extra: Vec<(String, u16)>) {
+        // The nodes of a torrent are still worth adding while another bootstrap runs.
+        if extra.is_empty() && self.bootstrapping.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        let start = self.resolve_bootstrap_nodes(&extra).await;
+        let id = self.id();
+        let found = self.lookup(id, Query::FindNode { target: id }, start).await.closest();
+        info!("DHT bootstrapped with {} nodes, {} close to us", self.nodes(), found.len());
+        if extra.is_empty() {
+            self.bootstrapping.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Addresses of the bootstrap nodes and the `extra` ones, of the family of the node.
+    async fn resolve_bootstrap_nodes(&self, extra: &[(String, u16)]) -> Vec<SocketAddr> {
+        let mut addrs = Vec::new();
+        for (host, port) in self.bootstrap_nodes.iter().chain(extra) {
+            match tokio::net::lookup_host((host.as_str(), *port)).await {
+                Ok(resolved) => addrs.extend(resolved.filter(|addr| addr.is_ipv6() == self.ipv6)),
+                Err(e) => debug!("Unable to resolve the DHT node {}:{}: {}", host, port, e),
+            }
+        }
+
+        return addrs;
+    }
+
+    /// Run a lookup of the target with the query, from the `start` nodes and the closest ones of the routing table.
+    pub async fn lookup(self: &Arc<Dht>, target: NodeId, query: Query, start: Vec<SocketAddr>) -> Lookup {
+        let mut lookup = Lookup::new(target);
+        for addr in start {
+            lookup.add(None, addr);
+        }
+        for node in self.state.lock().unwrap().table.closest(&target, BUCKET_SIZE) {
+            lookup.add(Some(node.id), node.addr);
+        }
+
+        let mut queries = JoinSet::new();
+        loop {
+            for addr in lookup.next() {
+                let (dht, query) = (self.clone(), query.clone());
+                queries.spawn(async move { (addr, dht.query(addr, query).await) });
+            }
+            if lookup.is_done() {
+                break;
+            }
+
+            match queries.join_next().await {
+                Some(Ok((addr, Ok(mut response)))) => {
+                    // Only the nodes of our own DHT can be reached.
+                    response.nodes.retain(|(_, node)| node.is_ipv6() == self.ipv6);
+                    lookup.responded(addr, response);
+                }
+                Some(Ok((addr, Err(e)))) => {
+                    debug!("DHT lookup: {:#}", e);
+                    lookup.failed(addr);
+                }
+                Some(Err(e)) => debug!("DHT query panicked: {}", e),
+                None => break,
+            }
+        }
+
+        return lookup;
+    }
+
+    /// Peers of a torrent stored by the nodes around its info hash, starting from the bootstrap nodes as well while the
+    /// routing table has too few nodes.
+    pub async fn get_peers(self: &Arc<Dht>, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        let start = if self.nodes() < MIN_NODES { self.resolve_bootstrap_nodes(&[]).await } else { Vec::new() };
+        let lookup = self.lookup(info_hash, Query::GetPeers { info_hash, scrape: false }, start).await;
+
+        let mut peers: Vec<SocketAddr> = lookup.responses().into_iter().flat_map(|(_, _, response)| response.values).collect();
+        peers.sort_unstable();
+        peers.dedup();
+
+        return peers;
+    }
+
+    /// Info hashes stored by the nodes around a target (BEP 51), a random target gives a random part of the DHT.
+    pub async fn sample_infohashes(self: &Arc<Dht>, target: NodeId) -> Vec<[u8; 20]> {
+        let lookup = self.lookup(target, Query::SampleInfohashes { target }, Vec::new()).await;
+
+        let mut samples: Vec<[u8; 20]> = lookup.responses().into_iter().flat_map(|(_, _, response)| response.samples).collect();
+        samples.sort_unstable();
+        samples.dedup();
+
+        return samples;
+    }
+
+    /// Info hashes a single node stores, such as `router.example.com:6881`.
+    pub async fn sample_node(&self, node: &str) -> anyhow::Result<Vec<[u8; 20]>> {
+        let (host, port) = parse_host_port(node)?;
+        let addr = tokio::net::lookup_host((host.as_str(), port)).await?.next().with_context(|| format!("Error: {} has no address", node))?;
+
+        let response = self.query(addr, Query::SampleInfohashes { target: rand::random() }).await?;
+        if response.num.is_none() {
+            anyhow::bail!("Error: {} doesn't support sample_infohashes", node);
+        }
+
+        return Ok(response.samples);
+    }
+
+    /// Estimate the seeders and leechers of a torrent from the nodes closest to its info hash (BEP 33), which gives the
+    /// same as a tracker scrape apart from the downloads.
+    pub async fn scrape(self: &Arc<Dht>, info_hash: [u8; 20]) -> anyhow::Result<Scrape> {
+        let lookup = self.lookup(info_hash, Query::GetPeers { info_hash, scrape: true }, Vec::new()).await;
+
+        let (mut seeds, mut peers) = (BloomFilter::default(), BloomFilter::default());
+        let mut scraped = 0;
+        for (_, _, response) in lookup.closest() {
+            if let (Some(node_seeds), Some(node_peers)) = (&response.seeds, &response.peers) {
+                seeds.union(node_seeds);
+                peers.union(node_peers);
+                scraped += 1;
+            }
+        }
+        if scraped == 0 {
+            anyhow::bail!("Error: No DHT node close to the torrent answered the scrape");
+        }
+
+        return Ok(Scrape { seeders: seeds.estimate(), leechers: peers.estimate(), downloads: 0 });
+    }
+
+    /// Answer the queries of other nodes and hand responses to the queries waiting for them, forever.
+    pub async fn run(self: Arc<Dht>) {
+        info!("DHT node {} is on port {} over IPv{}{}", to_hex(&self.id()), self.socket.local_addr().map(|addr| addr.port()).unwrap_or(0),
+            if self.ipv6 { 6 } else { 4 }, if self.read_only { ", read-only" } else { "" });
+
+        let mut buffer = vec![0; MAX_MESSAGE_LEN];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(e) => {
+                    debug!("Unable to receive a DHT message: {}", e);
+                    continue;
+                }
+            };
+
+            let message = match Message::decode(&buffer[..len]) {
+                Ok(message) => message,
+                Err(e) => {
+                    debug!("Invalid DHT message from {}: {:#}", from, e);
+                    continue;
+                }
+            };
+
+            if let Some(reply) = self.handle(message, from, Instant::now()) {
+                if let Err(e) = self.socket.send_to(&reply.encode(), from).await {
+                    debug!("Unable to answer {}: {}", from, e);
+                }
+            }
+        }
+    }
+
+    /// Handle a message of the node at `from`, returning the reply to send back if there is one.
+    fn handle(&self, message: Message, from: SocketAddr, now: Instant) -> Option<Message> {
+        let mut state = self.state.lock().unwrap();
+
+        return match message.body {
+            Body::Query { .. } if self.read_only => None,
+            Body::Query { id, query } => {
+                // Read-only nodes don't answer queries, so there's no use routing lookups through them.
+                if !message.read_only {
+                    state.table.insert(id, from, now);
+                }
+                Some(state.answer(message.transaction, query, from, now))
+            }
+            Body::Response(_) | Body::Error { .. } => {
+                if state.pending.get(&message.transaction).map(|(addr, _)| *addr) != Some(from) {
+                    return None;
+                }
+                let (_, sender) = state.pending.remove(&message.transaction).unwrap();
+
+                if let Body::Response(response) = &message.body {
+                    state.table.insert(response.id, from, now);
+                }
+                if let Some(ip) = message.ip {
+                    self.external_ip.vote(ip.ip());
+                    self.update_id(&mut state);
+                }
+
+                let _ = sender.send(message);
+                None
+            }
+        };
+    }
+
+    /// Take a new node id when ours doesn't fit our external address, once it's known or when it changes.
+    fn update_id(&self, state: &mut DhtState) {
+        if let Some(ip) = self.external_ip.get(self.ipv6) {
+            if !is_valid_id(&state.table.id(), ip) {
+                state.table.set_id(node_id(Some(ip)));
+                info!("DHT node id is now {} for {}", to_hex(&state.table.id()), ip);
+            }
+        }
+    }
+
+    /// Send a query and wait for its response, an error response or no response are errors.
+    pub async fn query(&self, addr: SocketAddr, query: Query) -> anyhow::Result<Response> {
+        let (sender, receiver) = oneshot::channel();
+        let message = {
+            let mut state = self.state.lock().unwrap();
+            state.next_transaction = state.next_transaction.wrapping_add(1);
+            let transaction = state.next_transaction.to_be_bytes().to_vec();
+            state.pending.insert(transaction.clone(), (addr, sender));
+            Message::query(transaction, state.table.id(), query, self.read_only)
+        };
+
+        let sent = self.socket.send_to(&message.encode(), addr).await;
+        let result = match sent {
+            Ok(_) => tokio::time::timeout(QUERY_TIMEOUT, receiver).await.ok().and_then(|response| response.ok()),
+            Err(_) => None,
+        };
+        let mut state = self.state.lock().unwrap();
+        state.pending.remove(&message.transaction);
+        sent?;
+
+        return match result {
+            Some(Message { body: Body::Response(response), .. }) => Ok(response),
+            Some(Message { body: Body::Error { code, message }, .. }) => anyhow::bail!("Error: {} answered with error {}: {}", addr, code, message),
+            _ => {
+                // Nodes which stop answering make room for others.
+                state.table.remove_addr(addr);
+                anyhow::bail!("Error: {} didn't answer", addr)
+            }
+        };
+    }
+}
+
+
+#[test]
+fn test_node_id() {
+    // Examples of BEP 42, with the random byte at the end of each id.
+    let examples = [
+        ("124.31.75.21", 1, [0x5f, 0xbf, 0xbf]),
+        ("21.75.31.124", 86, [0x5a, 0x3c, 0xe9]),
+        ("65.23.51.170", 22, [0xa5, 0xd4, 0x32]),
+        ("84.124.73.14", 65, [0x1b, 0x03, 0x21]),
+        ("43.213.53.83", 90, [0xe5, 0x6f, 0x6c]),
+    ];
+
+    for (ip, r, start) in examples {
+        let ip: IpAddr = ip.parse().unwrap();
+        let mut random = [0; 20];
+        random[19] = r;
+
+        let id = node_id_from(ip, random);
+        assert_eq!(id[..2], start[..2]);
+        assert_eq!(id[2] & 0xf8, start[2] & 0xf8);
+        assert_eq!(id[19], r);
+        assert!(is_valid_id(&id, ip));
+        assert!(is_valid_id(&node_id(Some(ip)), ip));
+        assert!(!is_valid_id(&id, "203.0.113.9".parse().unwrap()));
+    }
+
+    // Any id goes on the local network.
+    assert!(is_valid_id(&[0; 20], "192.168.1.2".parse().unwrap()));
+}
+
+
+#[test]
+fn test_routing_table() {
+    let ip: IpAddr = "124.31.75.21".parse().unwrap();
+    let addr = SocketAddr::new(ip, 6881);
+    let now = Instant::now();
+    // Every id starting with a 0 bit goes in the first bucket.
+    let mut table = RoutingTable::new([0xff; 20]);
+
+    let made_up = |i: u8| {
+        let mut id = [0; 20];
+        id[1] = i;
+        id
+    };
+    for i in 0..BUCKET_SIZE as u8 {
+        assert!(table.insert(made_up(i), addr, now));
+    }
+    assert_eq!(table.len(), BUCKET_SIZE);
+    assert!(!table.insert(made_up(100), addr, now));
+
+    // A node with an id fitting its address takes the place of one which doesn't.
+    let mut random = [0; 20];
+    random[19] = 1;
+    let id = node_id_from(ip, random);
+    assert!(table.insert(id, addr, now));
+    assert_eq!(table.len(), BUCKET_SIZE);
+
+    // It comes first even though the others are closer.
+    let closest = table.closest(&[0; 20], 2);
+    assert_eq!(closest[0].id, id);
+    assert!(!closest[1].conforming);
+
+    // Stale nodes make room for new ones.
+    assert!(table.insert(made_up(101), addr, now + STALE_AFTER));
+    assert_eq!(table.len(), BUCKET_SIZE);
+
+    table.remove(&id);
+    assert_eq!(table.len(), BUCKET_SIZE - 1);
+    assert!(!table.insert(table.id(), addr, now));
+}
+
+
+#[test]
+fn test_peer_storage() {
+    let now = Instant::now();
+    let mut storage = PeerStorage::default();
+    let addr: SocketAddr = "203.0.113.9:6881".parse().unwrap();
+
+    storage.announce([1; 20], addr, false, now);
+    storage.announce([1; 20], addr, true, now + Duration::from_secs(10));
+    assert_eq!(storage.peers(&[1; 20], now), vec![addr]);
+    assert!(storage.peers(&[2; 20], now).is_empty());
+
+    for port in 0..MAX_PEERS_PER_HASH as u16 {
+        storage.announce([1; 20], SocketAddr::new(addr.ip(), port), false, now);
+    }
+    assert_eq!(storage.peers(&[1; 20], now).len(), MAX_PEERS_PER_HASH);
+    assert!(!storage.peers(&[1; 20], now).contains(&addr));
+
+    storage.expire(now + PEER_TTL);
+    assert!(storage.is_empty());
+}
+
+
+#[test]
+fn test_tokens() {
+    let now = Instant::now();
+    let mut tokens = Tokens::new(now);
+    let ip: IpAddr = "203.0.113.9".parse().unwrap();
+
+    let token = tokens.token(ip);
+    assert!(tokens.is_valid(&token, ip));
+    assert!(!tokens.is_valid(&token, "198.51.100.7".parse().unwrap()));
+
+    // A token stays valid for one change of the secret.
+    tokens.rotate(now + TOKEN_ROTATION);
+    assert!(tokens.is_valid(&token, ip));
+    tokens.rotate(now + TOKEN_ROTATION * 2);
+    assert!(!tokens.is_valid(&token, ip));
+}
+
+
+#[test]
+fn test_lookup() {
+    let target = [0; 20];
+    let node = |first: u8| {
+        let mut id = [0; 20];
+        id[0] = first;
+        (id, SocketAddr::new(IpAddr::from([203, 0, 113, first]), 6881))
+    };
+    let response = |first: u8, nodes: Vec<u8>| Response { id: node(first).0, nodes: nodes.into_iter().map(node).collect(), ..Default::default() };
+
+    let bootstrap: SocketAddr = "198.51.100.7:6881".parse().unwrap();
+    let mut lookup = Lookup::new(target);
+    lookup.add(None, bootstrap);
+    assert_eq!(lookup.next(), vec![bootstrap]);
+    assert!(lookup.next().is_empty());
+    assert!(!lookup.is_done());
+
+    // The closest nodes are queried first, a few at a time.
+    lookup.responded(bootstrap, response(200, vec![100, 50, 10, 5]));
+    assert_eq!(lookup.next(), vec![node(5).1, node(10).1, node(50).1]);
+    lookup.failed(node(5).1);
+    lookup.responded(node(10).1, response(10, vec![1]));
+    assert_eq!(lookup.next(), vec![node(1).1, node(100).1]);
+    lookup.responded(node(50).1, response(50, vec![]));
+    lookup.responded(node(1).1, response(1, vec![]));
+    lookup.responded(node(100).1, response(100, vec![]));
+
+    assert!(lookup.is_done());
+    let closest: Vec<NodeId> = lookup.closest().into_iter().map(|(id, _, _)| id).collect();
+    assert_eq!(closest, vec![node(1).0, node(10).0, node(50).0, node(100).0, node(200).0]);
+
+    assert_eq!(parse_host_port("router.bittorrent.com:6881").unwrap(), (String::from("router.bittorrent.com"), 6881));
+    assert_eq!(parse_host_port("[2001:db8::1]:6881").unwrap(), (String::from("2001:db8::1"), 6881));
+    assert!(parse_host_port("router.bittorrent.com").is_err());
+}
+
+
+#[test]
+fn test_bloom_filter() {
+    // The example of BEP 33.
+    let mut filter = BloomFilter::default();
+    for i in 0..=255 {
+        filter.insert(IpAddr::from([192, 0, 2, i]));
+    }
+    for i in 0..=0x3e7 {
+        filter.insert(IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, i]));
+    }
+    assert_eq!(filter.estimate(), 1225);
+
+    let mut other = BloomFilter::default();
+    assert_eq!(other.estimate(), 0);
+    other.insert("203.0.113.9".parse().unwrap());
+    assert_eq!(other.estimate(), 1);
+    other.union(&filter);
+    assert_eq!(BloomFilter::from_bytes(other.as_bytes()), Some(other));
+    assert!(BloomFilter::from_bytes(&[0; 10]).is_none());
+}
+
+
+#[tokio::test]
+async fn test_read_only() {
+    let external_ip = Arc::new(ExternalIp::new(None));
+    let config = DhtConfig { bootstrap_nodes: Vec::new(), ..Default::default() };
+    let node = Arc::new(Dht::bind(&config, &Interface::Any, 0, external_ip.clone(), false).unwrap());
+    let read_only = Arc::new(Dht::bind(&DhtConfig { read_only: true, ..config }, &Interface::Any, 0, external_ip, false).unwrap());
+    let node_addr: SocketAddr = format!("127.0.0.1:{}", node.socket.local_addr().unwrap().port()).parse().unwrap();
+    let read_only_addr: SocketAddr = format!("127.0.0.1:{}", read_only.socket.local_addr().unwrap().port()).parse().unwrap();
+    tokio::spawn(node.clone().run());
+    tokio::spawn(read_only.clone().run());
+
+    // The read-only node's queries are answered, but it isn't added to the routing table.
+    let response = read_only.query(node_addr, Query::GetPeers { info_hash: [1; 20], scrape: false }).await.unwrap();
+    assert_eq!(response.id, node.id());
+    assert!(node.state.lock().unwrap().table.is_empty());
+    assert!(read_only.state.lock().unwrap().table.len() == 1);
+
+    let announce = Query::AnnouncePeer { info_hash: [1; 20], port: 6881, implied_port: false, token: response.token.unwrap(), seed: true };
+    read_only.query(node_addr, announce).await.unwrap();
+    assert_eq!(node.state.lock().unwrap().storage.peers(&[1; 20], Instant::now()), vec!["127.0.0.1:6881".parse().unwrap()]);
+    let announce = Query::AnnouncePeer { info_hash: [1; 20], port: 6881, implied_port: false, token: b"made up".to_vec(), seed: false };
+    assert!(read_only.query(node_addr, announce).await.is_err());
+
+    // Lookups find the peer.
+    assert_eq!(read_only.get_peers([1; 20]).await, vec!["127.0.0.1:6881".parse().unwrap()]);
+
+    // It's counted as a seed by a scrape.
+    let scrape = read_only.scrape([1; 20]).await.unwrap();
+    assert_eq!((scrape.seeders, scrape.leechers), (1, 0));
+
+    // The info hash it announced is in the samples.
+    let response = read_only.query(node_addr, Query::SampleInfohashes { target: [1; 20] }).await.unwrap();
+    assert_eq!((response.samples, response.num), (vec![[1; 20]], Some(1)));
+    assert!(response.interval.unwrap() > 0);
+
+    // It doesn't answer queries itself.
+    let message = Message::query(b"aa".to_vec(), [2; 20], Query::Ping, false);
+    assert!(read_only.handle(message, node_addr, Instant::now()).is_none());
+    assert!(node.handle(Message::query(b"aa".to_vec(), [2; 20], Query::Ping, false), read_only_addr, Instant::now()).is_some());
+}
+
+
+#[tokio::test]
+async fn test_ipv6() {
+    let external_ip = Arc::new(ExternalIp::new(None));
+    let config = DhtConfig { bootstrap_nodes: Vec::new(), ..Default::default() };
+    let node = Arc::new(Dht::bind(&config, &Interface::Any, 0, external_ip.clone(), true).unwrap());
+    let client = Arc::new(Dht::bind(&DhtConfig { read_only: true, ..config }, &Interface::Any, 0, external_ip, true).unwrap());
+    let node_addr: SocketAddr = format!("[::1]:{}", node.socket.local_addr().unwrap().port()).parse().unwrap();
+    tokio::spawn(node.clone().run());
+    tokio::spawn(client.clone().run());
+
+    let response = client.query(node_addr, Query::GetPeers { info_hash: [1; 20], scrape: false }).await.unwrap();
+    let announce = Query::AnnouncePeer { info_hash: [1; 20], port: 6881, implied_port: false, token: response.token.unwrap(), seed: false };
+    client.query(node_addr, announce).await.unwrap();
+
+    // The peer announced over IPv6 is found, and the IPv4 node the other node knows of is never queried.
+    node.state.lock().unwrap().table.insert([9; 20], "203.0.113.1:6881".parse().unwrap(), Instant::now());
+    let lookup = client.lookup([1; 20], Query::GetPeers { info_hash: [1; 20], scrape: false }, Vec::new()).await;
+    assert!(lookup.candidates.iter().all(|candidate| candidate.addr.is_ipv6()));
+    assert_eq!(lookup.responses()[0].2.values, vec!["[::1]:6881".parse().unwrap()]);
+}
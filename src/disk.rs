@@ -0,0 +1,1505 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{IoSlice, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use serde_derive::Deserialize;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tracing::{error, warn};
+
+use crate::cache::{ReadCache, WriteCache, WriteRun};
+use crate::config::DiskConfig;
+use crate::error::TorrenterError;
+use crate::hashing::{self, PieceHashes};
+use crate::message_handlers::PieceChannelPayload;
+use crate::metrics;
+use crate::storage::FileStorage;
+use crate::utils::torrents::{DlFile, Torrent};
+
+/// Amount of jobs which can wait for the disk before senders have to wait.
+const DISK_QUEUE_LEN: usize = 256;
+
+/// Appended to the names of files while they are being downloaded, with `part_files`.
+const PART_SUFFIX: &str = ".part";
+
+/// Bytes which can be queued on top of a full write cache before the download has to wait.
+const QUEUED_BYTES: u64 = 4 * 1024 * 1024;
+
+/// How the disk thread reads and writes files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiskBackend {
+    /// Blocking reads and writes on the disk thread.
+    #[default]
+    Threaded,
+    /// io_uring on Linux when built with the `io-uring` feature, threaded anywhere else.
+    IoUring,
+    /// Files mapped into memory, blocks are copied into the maps and pieces hashed straight from them. The maps
+    /// take the place of the write and read caches.
+    Mmap,
+}
+
+/// How space for a torrent's files is taken when the download starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Allocation {
+    /// Files are created at their full length without taking any space, blocks take space as they are written.
+    #[default]
+    Sparse,
+    /// Every file takes its full space up front, so the files don't fragment and a full disk is found out before
+    /// downloading rather than halfway through.
+    Full,
+}
+
+/// When written files are fsynced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Durability {
+    /// Left to the operating system.
+    Never,
+    /// After every piece, or run of pieces, is written.
+    Piece,
+    /// At most every `fsync_interval_secs`, when more data is written.
+    #[default]
+    Interval,
+}
+
+/// Pieces which passed their hash check, to know when a file is complete and can lose its `.part` name.
+#[derive(Debug)]
+pub(crate) struct Verified {
+    pieces: Vec<bool>,
+    /// First and last piece of every file, `None` for empty, pad and skipped files.
+    file_pieces: Vec<Option<(u64, u64)>>,
+    /// Whether pieces are read back from disk after writing them instead of trusting the hash of their blocks.
+    read_back: bool,
+    /// Pieces whose blocks matched and which are being read back.
+    reading_back: BTreeSet<u64>,
+}
+
+impl Verified {
+    pub(crate) fn new(torrent: &Torrent, storage: &FileStorage, read_back: bool) -> Verified {
+        let file_pieces = (0..storage.files().len())
+            .map(|file| if storage.is_stored(file) { storage.file_pieces(file) } else { None })
+            .collect();
+
+        return Verified {
+            pieces: vec![false; torrent.info.pieces.len() / 20],
+            file_pieces,
+            read_back,
+            reading_back: BTreeSet::new(),
+        };
+    }
+
+    /// Mark a piece as verified, returning the files which are complete because of it.
+    fn add(&mut self, index: u64) -> Vec<usize> {
+        if self.pieces[index as usize] {
+            return Vec::new();
+        }
+        self.pieces[index as usize] = true;
+
+        return self.file_pieces.iter().enumerate()
+            .filter_map(|(file, pieces)| pieces.map(|(first, last)| (file, first, last)))
+            .filter(|&(_, first, last)| first <= index && index <= last)
+            .filter(|&(_, first, last)| (first..=last).all(|piece| self.pieces[piece as usize]))
+            .map(|(file, _, _)| file)
+            .collect();
+    }
+
+    /// Whether a piece whose blocks hashed to `hash` has to be read back from disk before it's verified, which is
+    /// only done for pieces that match so a bad piece from a peer isn't taken for corruption.
+    pub(crate) fn read_back(&mut self, torrent: &Torrent, index: u64, hash: [u8; 20]) -> bool {
+        if !self.read_back || !matches(torrent, index, hash) {
+            return false;
+        }
+
+        self.reading_back.insert(index);
+        return true;
+    }
+
+    /// Compare the hash of a piece with the torrent, and move the files it completes to their final name.
+    pub(crate) fn check(&mut self, torrent: &Torrent, download_folder: &Path, files: &[DlFile], index: u64, hash: [u8; 20]) -> anyhow::Result<bool> {
+        let read_back = self.reading_back.remove(&index);
+        if !matches(torrent, index, hash) {
+            if read_back {
+                error!(piece = index, "The piece matched its hash when it was downloaded but not once read back from disk, the storage may be corrupting data");
+                metrics::READ_BACK_FAILURES.inc();
+            }
+            return Ok(false);
+        }
+
+        for file in self.add(index) {
+            let path = final_path(download_folder, &files[file]);
+            let part = part_path(&path);
+            if part.exists() {
+                fs::rename(&part, &path).with_context(|| format!("Unable to rename {:?} to {:?}", part, path))?;
+            }
+        }
+
+        return Ok(true);
+    }
+}
+
+fn matches(torrent: &Torrent, index: u64, hash: [u8; 20]) -> bool {
+    let start = index as usize * 20;
+    return torrent.info.pieces.get(start..start + 20) == Some(&hash[..]);
+}
+
+/// Files written to since they were last synced, and whether it's time to sync them.
+#[derive(Debug)]
+pub(crate) struct Syncer {
+    durability: Durability,
+    interval: Duration,
+    last_sync: Instant,
+    dirty: BTreeSet<usize>,
+}
+
+impl Syncer {
+    pub(crate) fn new(config: &DiskConfig) -> Syncer {
+        return Syncer {
+            durability: config.fsync,
+            interval: Duration::from_secs(config.fsync_interval_secs),
+            last_sync: Instant::now(),
+            dirty: BTreeSet::new(),
+        };
+    }
+
+    pub(crate) fn written(&mut self, storage: &FileStorage, offset: u64, length: u64) {
+        self.dirty.extend(storage.map_range(offset, length).iter().map(|segment| segment.file).filter(|&file| storage.is_stored(file)));
+    }
+
+    /// Files to sync after a write, following the durability setting.
+    pub(crate) fn due(&mut self) -> Vec<usize> {
+        let due = match self.durability {
+            Durability::Never => false,
+            Durability::Piece => true,
+            Durability::Interval => self.last_sync.elapsed() >= self.interval,
+        };
+
+        return if due { self.take() } else { Vec::new() };
+    }
+
+    /// Every file which hasn't been synced since it was written.
+    pub(crate) fn take(&mut self) -> Vec<usize> {
+        self.last_sync = Instant::now();
+        return std::mem::take(&mut self.dirty).into_iter().collect();
+    }
+}
+
+/// Blocks of skipped files in the pieces they share with other files, which are never written but are needed
+/// to hash those pieces.
+#[derive(Debug, Default)]
+pub(crate) struct SkippedBlocks {
+    blocks: BTreeMap<u64, Vec<u8>>,
+}
+
+impl SkippedBlocks {
+    /// Keep the parts of a range which are in skipped files, `data` gives the bytes at a position in the range.
+    pub(crate) fn keep(&mut self, storage: &FileStorage, offset: u64, length: u64, data: impl Fn(usize, usize) -> Vec<u8>) {
+        for segment in storage.map_range(offset, length) {
+            if storage.is_skipped(segment.file) {
+                self.blocks.insert(offset + segment.start as u64, data(segment.start, segment.len));
+            }
+        }
+    }
+
+    /// The bytes of a range when all of them are kept.
+    pub(crate) fn read(&self, offset: u64, length: u64) -> Option<Vec<u8>> {
+        let end = offset + length;
+        let first = self.blocks.range(..=offset).next_back().map(|(&block_offset, _)| block_offset).unwrap_or(offset);
+
+        let mut data = Vec::with_capacity(length as usize);
+        let mut position = offset;
+        for (&block_offset, block) in self.blocks.range(first..end) {
+            let block_end = block_offset + block.len() as u64;
+            if block_offset > position {
+                return None;
+            }
+            if block_end <= position {
+                continue;
+            }
+
+            let until = block_end.min(end);
+            data.extend_from_slice(&block[(position - block_offset) as usize..(until - block_offset) as usize]);
+            position = until;
+        }
+
+        return if position == end { Some(data) } else { None };
+    }
+
+    /// Drop what's kept of a piece once it's hashed.
+    pub(crate) fn discard(&mut self, offset: u64, length: u64) {
+        let kept: Vec<u64> = self.blocks.range(offset..offset + length).map(|(&block_offset, _)| block_offset).collect();
+        for block_offset in kept {
+            self.blocks.remove(&block_offset);
+        }
+    }
+}
+
+/// A new name for one of the files of a torrent, or for the folder all of them are in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rename {
+    /// Path of the file in the folder of the torrent.
+    File { index: usize, path: Vec<String> },
+    Root(String),
+}
+
+/// Work for the disk thread.
+#[derive(Debug)]
+pub enum DiskJob {
+    Write(PieceChannelPayload),
+    Read {
+        offset: u64,
+        length: u64,
+        reply: oneshot::Sender<anyhow::Result<Vec<u8>>>,
+    },
+    HashPiece {
+        index: u64,
+        reply: oneshot::Sender<anyhow::Result<[u8; 20]>>,
+    },
+    /// Replies once every job sent before it is done.
+    Flush {
+        reply: oneshot::Sender<()>,
+    },
+    /// Hash a piece which has been written in full and check it against the torrent.
+    VerifyPiece {
+        index: u64,
+        reply: oneshot::Sender<anyhow::Result<bool>>,
+    },
+    /// A piece of `VerifyPiece` hashed on the hashing threads, checked on the disk thread where the verified pieces are.
+    PieceHashed {
+        index: u64,
+        hash: [u8; 20],
+        reply: oneshot::Sender<anyhow::Result<bool>>,
+    },
+    /// Rename a file or the folder of the torrent, the reply is sent once it's renamed on disk.
+    Rename {
+        rename: Rename,
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+    /// Move the folder of the torrent to another directory.
+    Move {
+        to: PathBuf,
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+    /// Like `Flush`, and fsyncs every file written to whatever the durability setting is.
+    Sync {
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+    /// Apply the attributes of the files once the torrent is downloaded.
+    ApplyAttributes {
+        symlinks: bool,
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+}
+
+/// What the disk thread keeps between jobs, the mmap backend only uses part of it.
+#[derive(Debug)]
+pub(crate) struct DiskState {
+    pub(crate) storage: FileStorage,
+    pub(crate) skipped: SkippedBlocks,
+    pub(crate) cache: WriteCache,
+    pub(crate) read_cache: ReadCache,
+    pub(crate) syncer: Syncer,
+    pub(crate) verified: Verified,
+    pub(crate) hashes: PieceHashes,
+    pub(crate) pending: Arc<Pending>,
+    /// The job queue, for the hashing threads to send hashed pieces back. It doesn't keep the disk thread running.
+    pub(crate) jobs: mpsc::WeakSender<DiskJob>,
+}
+
+/// Bytes given to the disk thread which aren't on disk yet, and pieces which aren't checked yet.
+///
+/// Writers wait once this goes over the high watermark, the write cache plus `QUEUED_BYTES`, until the disk has
+/// caught up to the low watermark, the size of the write cache. Peers aren't asked for new blocks while either
+/// the writes or the checks are behind, so a slow disk holds up the download instead of filling up memory.
+#[derive(Debug)]
+pub(crate) struct Pending {
+    bytes: AtomicU64,
+    high: u64,
+    low: u64,
+    unverified: AtomicU64,
+    max_unverified: u64,
+    drained: Notify,
+    /// Set once a write fails because the disk is full, after which writes are refused.
+    disk_full: AtomicBool,
+}
+
+impl Default for Pending {
+    fn default() -> Pending {
+        let config = DiskConfig::default();
+        return Pending::new(config.write_cache_size, config.max_unverified_pieces);
+    }
+}
+
+impl Pending {
+    fn new(write_cache_size: u64, max_unverified: u64) -> Pending {
+        return Pending {
+            bytes: AtomicU64::new(0),
+            high: write_cache_size + QUEUED_BYTES,
+            low: write_cache_size,
+            unverified: AtomicU64::new(0),
+            max_unverified: max_unverified.max(1),
+            drained: Notify::new(),
+            disk_full: AtomicBool::new(false),
+        };
+    }
+
+    /// Called by the disk thread once `len` bytes are on disk.
+    pub(crate) fn written(&self, len: u64) {
+        let bytes = self.bytes.fetch_sub(len, Ordering::SeqCst) - len;
+        if bytes <= self.low {
+            self.drained.notify_waiters();
+        }
+    }
+
+    /// Called by the disk thread when a write fails, to stop the download if the disk is full.
+    pub(crate) fn failed(&self, error: &anyhow::Error) {
+        let storage_full = error.chain()
+            .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+            .any(|e| matches!(e.kind(), std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded));
+
+        if storage_full {
+            self.disk_full.store(true, Ordering::SeqCst);
+            self.drained.notify_waiters();
+        }
+    }
+
+    async fn wait_for_room(&self) {
+        if self.bytes.load(Ordering::SeqCst) < self.high {
+            return;
+        }
+
+        loop {
+            let drained = self.drained.notified();
+            if self.bytes.load(Ordering::SeqCst) <= self.low || self.disk_full.load(Ordering::SeqCst) {
+                return;
+            }
+            drained.await;
+        }
+    }
+
+    fn verifying(&self) {
+        self.unverified.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn verified(&self) {
+        self.unverified.fetch_sub(1, Ordering::SeqCst);
+        self.drained.notify_waiters();
+    }
+
+    /// Whether new blocks would have to wait for the disk, because of the writes or the checks.
+    pub(crate) fn is_behind(&self) -> bool {
+        return self.bytes.load(Ordering::SeqCst) >= self.high || self.unverified.load(Ordering::SeqCst) >= self.max_unverified;
+    }
+
+    /// Wait until the disk has caught up enough for new blocks to be requested.
+    pub(crate) async fn wait_to_request(&self) {
+        if !self.is_behind() {
+            return;
+        }
+
+        metrics::DISK_BACKPRESSURE_WAITS.inc();
+        loop {
+            let drained = self.drained.notified();
+            let caught_up = self.bytes.load(Ordering::SeqCst) <= self.low && self.unverified.load(Ordering::SeqCst) < self.max_unverified;
+            if caught_up || self.disk_full.load(Ordering::SeqCst) {
+                return;
+            }
+            drained.await;
+        }
+    }
+}
+
+/// Handle to the disk thread of a torrent.
+///
+/// Every read and write of the torrent's files goes through the job queue, so a slow disk only holds up the
+/// queue and never the network tasks. Blocks are kept in a write cache until their piece is complete, and the
+/// download is held back when the disk falls too far behind, see `Pending`.
+#[derive(Debug, Clone)]
+pub struct DiskIo {
+    jobs: mpsc::Sender<DiskJob>,
+    pending: Arc<Pending>,
+}
+
+impl DiskIo {
+    /// Start the disk thread for a torrent whose files are in `download_folder`, laid out as in `storage`.
+    pub(crate) fn start(torrent: Arc<Torrent>, download_folder: String, storage: FileStorage, config: &DiskConfig) -> anyhow::Result<DiskIo> {
+        let (jobs, receiver) = mpsc::channel(DISK_QUEUE_LEN);
+        allocate_files(Path::new(&download_folder), &storage.wanted_files(), config.allocation, config.part_files)?;
+
+        let backend = match config.backend {
+            DiskBackend::IoUring if !cfg!(all(target_os = "linux", feature = "io-uring")) => {
+                warn!("io_uring isn't available in this build, using the threaded disk backend");
+                DiskBackend::Threaded
+            }
+            backend => backend,
+        };
+
+        let pending = Arc::new(Pending::new(config.write_cache_size, config.max_unverified_pieces));
+        let state = DiskState {
+            verified: Verified::new(&torrent, &storage, config.verify_writes),
+            hashes: PieceHashes::new(torrent.info.piece_length, torrent.size),
+            storage,
+            skipped: SkippedBlocks::default(),
+            cache: WriteCache::new(config.write_cache_size, torrent.info.piece_length, torrent.size),
+            read_cache: ReadCache::new(config.read_cache_size, torrent.info.piece_length),
+            syncer: Syncer::new(config),
+            pending: pending.clone(),
+            jobs: jobs.downgrade(),
+        };
+
+        thread::Builder::new()
+            .name(format!("disk-{}", torrent.info.name))
+            .spawn(move || match backend {
+                #[cfg(all(target_os = "linux", feature = "io-uring"))]
+                DiskBackend::IoUring => crate::uring::run(&torrent, &download_folder, receiver, state),
+                DiskBackend::Mmap => crate::mmap::run(&torrent, &download_folder, receiver, state),
+                _ => run(&torrent, &download_folder, receiver, state),
+            })?;
+
+        return Ok(DiskIo { jobs, pending });
+    }
+
+    /// Queue a block to be written, without waiting for it to hit the disk unless the disk is too far behind.
+    pub async fn write(&self, payload: PieceChannelPayload) -> anyhow::Result<()> {
+        self.pending.wait_for_room().await;
+        if self.pending.disk_full.load(Ordering::SeqCst) {
+            anyhow::bail!("Error: The disk is full, the download is stopped");
+        }
+
+        self.pending.bytes.fetch_add(payload.block.len() as u64, Ordering::SeqCst);
+        return self.send(DiskJob::Write(payload)).await;
+    }
+
+    pub async fn read(&self, offset: u64, length: u64) -> anyhow::Result<Vec<u8>> {
+        let (reply, response) = oneshot::channel();
+        self.send(DiskJob::Read { offset, length, reply }).await?;
+        return response.await?;
+    }
+
+    /// SHA-1 of a piece as it is on disk.
+    pub async fn hash_piece(&self, index: u64) -> anyhow::Result<[u8; 20]> {
+        let (reply, response) = oneshot::channel();
+        self.send(DiskJob::HashPiece { index, reply }).await?;
+        return response.await?;
+    }
+
+    /// Check a piece which has been written in full against its hash.
+    ///
+    /// With `part_files`, the files which are complete once the piece is verified are renamed into place.
+    pub async fn verify_piece(&self, index: u64) -> anyhow::Result<bool> {
+        self.pending.verifying();
+        let result = self.check_piece(index).await;
+        self.pending.verified();
+        return result;
+    }
+
+    async fn check_piece(&self, index: u64) -> anyhow::Result<bool> {
+        let (reply, response) = oneshot::channel();
+        self.send(DiskJob::VerifyPiece { index, reply }).await?;
+        return response.await?;
+    }
+
+    /// How far behind the disk is, for peers to hold off requesting blocks.
+    pub(crate) fn pending(&self) -> Arc<Pending> {
+        return self.pending.clone();
+    }
+
+    /// Rename a file or the folder of the torrent, on disk and for every job after this one.
+    pub async fn rename(&self, rename: Rename) -> anyhow::Result<()> {
+        let (reply, response) = oneshot::channel();
+        self.send(DiskJob::Rename { rename, reply }).await?;
+        return response.await?;
+    }
+
+    /// Move the folder of the torrent to `to`, which is where the files are read from and written to afterwards.
+    pub async fn move_to(&self, to: PathBuf) -> anyhow::Result<()> {
+        let (reply, response) = oneshot::channel();
+        self.send(DiskJob::Move { to, reply }).await?;
+        return response.await?;
+    }
+
+    /// Wait for every queued write to be done.
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        let (reply, response) = oneshot::channel();
+        self.send(DiskJob::Flush { reply }).await?;
+        return Ok(response.await?);
+    }
+
+    /// Wait for every queued write to be on disk and fsynced.
+    ///
+    /// Resume data must only claim pieces after this returns, so a crash can't leave it ahead of the files.
+    pub async fn sync(&self) -> anyhow::Result<()> {
+        let (reply, response) = oneshot::channel();
+        self.send(DiskJob::Sync { reply }).await?;
+        return response.await?;
+    }
+
+    /// Make executables executable and, with `symlinks`, create the symlinks of the torrent.
+    pub async fn apply_attributes(&self, symlinks: bool) -> anyhow::Result<()> {
+        let (reply, response) = oneshot::channel();
+        self.send(DiskJob::ApplyAttributes { symlinks, reply }).await?;
+        return response.await?;
+    }
+
+    async fn send(&self, job: DiskJob) -> anyhow::Result<()> {
+        return self.jobs.send(job).await.map_err(|_| anyhow::anyhow!("Error: The disk thread has stopped"));
+    }
+}
+
+#[cfg(test)]
+impl DiskIo {
+    /// A disk for the tests which never read or write, every job fails as there's no disk thread.
+    pub(crate) fn stopped() -> DiskIo {
+        return DiskIo { jobs: mpsc::channel(1).0, pending: Arc::default() };
+    }
+}
+
+/// Run jobs until every `DiskIo` handle is dropped.
+fn run(torrent: &Torrent, download_folder: &str, mut receiver: mpsc::Receiver<DiskJob>, state: DiskState) {
+    let DiskState { mut storage, mut skipped, mut cache, mut read_cache, mut syncer, mut verified, mut hashes, pending, jobs: job_sender } = state;
+    let mut download_folder = PathBuf::from(download_folder);
+
+    while let Some(job) = receiver.blocking_recv() {
+        let mut jobs = vec![job];
+        while let Ok(job) = receiver.try_recv() {
+            jobs.push(job);
+        }
+
+        for job in coalesce(jobs) {
+            if let DiskJob::Write(payload) = job {
+                read_cache.invalidate(payload.offset, payload.block.len() as u64);
+                for run in cache_block(&mut cache, &mut hashes, payload) {
+                    write_and_sync(&download_folder, &storage, &mut skipped, run, &mut syncer, &pending);
+                }
+                continue;
+            }
+
+            // Anything else needs the cached blocks to be on disk first.
+            for run in cache.drain() {
+                write_and_sync(&download_folder, &storage, &mut skipped, run, &mut syncer, &pending);
+            }
+
+            match job {
+                DiskJob::Read { offset, length, reply } => {
+                    let _ = reply.send(read_cached(&mut read_cache, torrent, offset, length, |offset, length| {
+                        return read_block(&download_folder, &storage, &skipped, offset, length);
+                    }));
+                }
+                DiskJob::HashPiece { index, reply } => {
+                    let piece = read_block(&download_folder, &storage, &skipped, index * torrent.info.piece_length, torrent.get_piece_len(index));
+                    hash_for_reply(piece, reply);
+                }
+                DiskJob::VerifyPiece { index, reply } => {
+                    if let Some(hash) = hashes.take(index) {
+                        if !verified.read_back(torrent, index, hash) {
+                            skipped.discard(index * torrent.info.piece_length, torrent.get_piece_len(index));
+                            let _ = reply.send(verified.check(torrent, &download_folder, storage.files(), index, hash));
+                            continue;
+                        }
+                    }
+
+                    let piece = read_block(&download_folder, &storage, &skipped, index * torrent.info.piece_length, torrent.get_piece_len(index));
+                    skipped.discard(index * torrent.info.piece_length, torrent.get_piece_len(index));
+                    hash_for_check(&job_sender, index, piece, reply);
+                }
+                DiskJob::PieceHashed { index, hash, reply } => {
+                    let _ = reply.send(verified.check(torrent, &download_folder, storage.files(), index, hash));
+                }
+                DiskJob::Rename { rename, reply } => {
+                    let _ = reply.send(rename_files(&mut download_folder, &mut storage, rename));
+                }
+                DiskJob::Move { to, reply } => {
+                    let result = move_data(&download_folder, &to);
+                    if result.is_ok() {
+                        download_folder = to;
+                    }
+                    let _ = reply.send(result);
+                }
+                DiskJob::Flush { reply } => {
+                    let _ = reply.send(());
+                }
+                DiskJob::Sync { reply } => {
+                    let _ = reply.send(sync_files(&download_folder, storage.files(), &syncer.take()));
+                }
+                DiskJob::ApplyAttributes { symlinks, reply } => {
+                    let _ = reply.send(apply_attributes(&download_folder, storage.files(), symlinks));
+                }
+                DiskJob::Write(_) => unreachable!(),
+            }
+        }
+    }
+}
+
+/// Add a block to the write cache, feeding the hash of its piece with what's now contiguous with the hashed part.
+pub(crate) fn cache_block(cache: &mut WriteCache, hashes: &mut PieceHashes, payload: PieceChannelPayload) -> Vec<WriteRun> {
+    let offset = payload.offset;
+    let length = payload.block.len() as u64;
+
+    // A block partly over cached ones ends up on disk differently than it was hashed.
+    if cache.overlaps(offset, length) && !cache.is_cached(offset, length) {
+        hashes.forget(offset, length);
+    }
+    let ready = cache.insert(payload);
+
+    // The block is in a run which is either written now or still cached.
+    if let Some(run) = ready.iter().find(|run| run.contains(offset)).or_else(|| cache.run_containing(offset)) {
+        hashes.feed(run);
+    }
+
+    return ready;
+}
+
+/// Hash a piece read for `HashPiece` on the hashing threads, which reply with it.
+pub(crate) fn hash_for_reply(piece: anyhow::Result<Vec<u8>>, reply: oneshot::Sender<anyhow::Result<[u8; 20]>>) {
+    match piece {
+        Ok(piece) => hashing::hash_in_pool(piece, move |hash| {
+            let _ = reply.send(Ok(hash));
+        }),
+        Err(e) => {
+            let _ = reply.send(Err(e));
+        }
+    }
+}
+
+/// Hash a piece read for `VerifyPiece` on the hashing threads, which send it back to the disk thread to be checked.
+pub(crate) fn hash_for_check(jobs: &mpsc::WeakSender<DiskJob>, index: u64, piece: anyhow::Result<Vec<u8>>, reply: oneshot::Sender<anyhow::Result<bool>>) {
+    let piece = match piece {
+        Ok(piece) => piece,
+        Err(e) => {
+            let _ = reply.send(Err(e));
+            return;
+        }
+    };
+
+    let jobs = jobs.clone();
+    hashing::hash_in_pool(piece, move |hash| {
+        // The reply is dropped along with the job once the disk thread has stopped.
+        if let Some(jobs) = jobs.upgrade() {
+            let _ = jobs.blocking_send(DiskJob::PieceHashed { index, hash, reply });
+        }
+    });
+}
+
+fn write_and_sync(download_folder: &Path, storage: &FileStorage, skipped: &mut SkippedBlocks, run: WriteRun, syncer: &mut Syncer, pending: &Pending) {
+    skipped.keep(storage, run.offset(), run.length(), |start, len| run.slices(start, len).concat());
+
+    let timer = metrics::DISK_WRITE_SECONDS.start_timer();
+    if let Err(e) = write_run(download_folder, storage, &run) {
+        error!("Unable to write {} bytes at {}: {:#}", run.length(), run.offset(), e);
+        pending.failed(&e);
+    }
+    timer.observe_duration();
+    pending.written(run.length());
+
+    syncer.written(storage, run.offset(), run.length());
+    if let Err(e) = sync_files(download_folder, storage.files(), &syncer.due()) {
+        error!("Unable to sync files: {:#}", e);
+    }
+}
+
+/// Rename a file or the folder of the torrent on disk, and in the paths the disk thread uses.
+///
+/// Names are single path components, the path of a file can have a few of them to move it into a sub folder.
+pub(crate) fn rename_files(download_folder: &mut PathBuf, storage: &mut FileStorage, rename: Rename) -> anyhow::Result<()> {
+    let components = match &rename {
+        Rename::File { path, .. } => path.clone(),
+        Rename::Root(name) => vec![name.clone()],
+    };
+    if components.is_empty() || components.iter().any(|c| c.is_empty() || c == "." || c == ".." || c.contains(['/', '\\'])) {
+        anyhow::bail!("Error: Invalid name {:?}", components.join("/"));
+    }
+
+    let (from, to) = match &rename {
+        Rename::File { index, path } => {
+            let file = storage.files().get(*index).ok_or_else(|| anyhow::anyhow!("Error: The torrent has no file {}", index))?;
+            let from = file_path(download_folder, file);
+            let to = path.iter().fold(download_folder.clone(), |to, component| to.join(component));
+
+            // A file which is still being downloaded keeps its suffix.
+            let to = if from != final_path(download_folder, file) { part_path(&to) } else { to };
+            (from, to)
+        }
+        Rename::Root(name) => (download_folder.clone(), download_folder.with_file_name(name)),
+    };
+
+    if to.exists() {
+        anyhow::bail!("Error: {:?} already exists", to);
+    }
+    if from.exists() {
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&from, &to).with_context(|| format!("Unable to rename {:?} to {:?}", from, to))?;
+    }
+
+    match rename {
+        Rename::File { index, path } => storage.set_path(index, path),
+        Rename::Root(_) => *download_folder = to,
+    }
+
+    return Ok(());
+}
+
+/// fsync some of the files of the torrent.
+fn sync_files(download_folder: &Path, files: &[DlFile], indexes: &[usize]) -> anyhow::Result<()> {
+    for &index in indexes {
+        let path = file_path(download_folder, &files[index]);
+        OpenOptions::new().write(true).open(&path)
+            .and_then(|file| file.sync_all())
+            .with_context(|| format!("Unable to sync {:?}", path))?;
+    }
+
+    return Ok(());
+}
+
+/// Read a range through the read cache, reading and keeping its whole piece when it isn't cached.
+fn read_cached(cache: &mut ReadCache, torrent: &Torrent, offset: u64, length: u64, read: impl Fn(u64, u64) -> anyhow::Result<Vec<u8>>) -> anyhow::Result<Vec<u8>> {
+    if let Some(block) = cache.get(offset, length) {
+        return Ok(block);
+    }
+
+    // A piece which isn't all on disk yet is left out of the cache.
+    if let Some(index) = cache.piece_of(offset, length) {
+        if let Ok(piece) = read(index * torrent.info.piece_length, torrent.get_piece_len(index)) {
+            cache.insert(index, piece);
+            if let Some(block) = cache.get(offset, length) {
+                return Ok(block);
+            }
+        }
+    }
+
+    return read(offset, length);
+}
+
+/// Move the data of a torrent to another directory, copying it when the directory is on another filesystem.
+pub(crate) fn move_data(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if to.exists() {
+        anyhow::bail!("Error: {:?} already exists", to);
+    }
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    match fs::rename(from, to) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {}
+        Err(e) => return Err(e).with_context(|| format!("Unable to move {:?} to {:?}", from, to)),
+    }
+
+    // Copy everything before removing anything, so a failed copy leaves the original data as it was.
+    if let Err(e) = copy_recursive(from, to) {
+        let _ = fs::remove_dir_all(to);
+        return Err(e).with_context(|| format!("Unable to copy {:?} to {:?}", from, to));
+    }
+
+    if from.is_dir() {
+        fs::remove_dir_all(from)?;
+    } else {
+        fs::remove_file(from)?;
+    }
+
+    return Ok(());
+}
+
+fn copy_recursive(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if !from.is_dir() {
+        fs::copy(from, to)?;
+        return Ok(());
+    }
+
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        copy_recursive(&entry.path(), &to.join(entry.file_name()))?;
+    }
+
+    return Ok(());
+}
+
+/// Bytes which still have to be allocated for the files, taking into account what is already on disk.
+pub(crate) fn space_needed(download_folder: &Path, files: &[DlFile]) -> u64 {
+    return files.iter().filter(|file| !file.is_pad()).map(|file| {
+        let existing = fs::metadata(file_path(download_folder, file)).map(|metadata| metadata.len()).unwrap_or(0);
+        return file.length.saturating_sub(existing);
+    }).sum();
+}
+
+/// Apply the attributes of finished files: executables get their executable bits and, with `symlinks`,
+/// symlinks are made pointing within the torrent's folder.
+///
+/// Hidden files are left as they are, outside of Windows a file is hidden by its name.
+pub(crate) fn apply_attributes(download_folder: &Path, files: &[DlFile], symlinks: bool) -> anyhow::Result<()> {
+    for file in files {
+        let path = final_path(download_folder, file);
+
+        if let (true, Some(target)) = (file.is_symlink(), &file.symlink_path) {
+            if symlinks {
+                // Relative to the folder of the link, so the torrent can still be moved.
+                let depth = file.path.len() - 1;
+                let target: PathBuf = std::iter::repeat_n("..", depth).map(String::from).chain(target.iter().cloned()).collect();
+                create_symlink(&target, &path).with_context(|| format!("Unable to create the symlink {:?}", path))?;
+            }
+        } else if file.is_executable() {
+            set_executable(&path).with_context(|| format!("Unable to make {:?} executable", path))?;
+        }
+    }
+
+    return Ok(());
+}
+
+fn create_symlink(target: &Path, path: &Path) -> anyhow::Result<()> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.len() > 0 && !metadata.file_type().is_symlink() {
+            anyhow::bail!("Error: {:?} already exists", path);
+        }
+        fs::remove_file(path)?;
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(target, path)?;
+    #[cfg(windows)]
+    std::os::windows::fs::symlink_file(target, path)?;
+
+    return Ok(());
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Executable by whoever can read it.
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_mode(permissions.mode() | (permissions.mode() & 0o444) >> 2);
+    fs::set_permissions(path, permissions)?;
+    return Ok(());
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> anyhow::Result<()> {
+    return Ok(());
+}
+
+/// Fail when the filesystem the torrent is saved to doesn't have room for it.
+pub(crate) fn check_free_space(download_folder: &Path, files: &[DlFile]) -> anyhow::Result<()> {
+    let needed = space_needed(download_folder, files);
+
+    if let Some(available) = available_space(download_folder) {
+        if needed > available {
+            anyhow::bail!("Error: Not enough disk space in {:?}, {} bytes are needed but only {} are available", download_folder, needed, available);
+        }
+    }
+
+    return Ok(());
+}
+
+/// Space available to us on the filesystem of a path, which doesn't have to exist yet.
+#[cfg(unix)]
+fn available_space(path: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let existing = path.ancestors().find(|ancestor| !ancestor.as_os_str().is_empty() && ancestor.exists()).unwrap_or(Path::new("."));
+    let path = CString::new(existing.as_os_str().as_bytes()).ok()?;
+
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+
+    return Some(stat.f_bavail as u64 * stat.f_frsize as u64);
+}
+
+#[cfg(not(unix))]
+fn available_space(_path: &Path) -> Option<u64> {
+    return None;
+}
+
+/// Create the files of the torrent which don't exist yet and extend the ones which are too short.
+///
+/// Existing data is never truncated, so a download can be resumed on top of the files.
+pub(crate) fn allocate_files(download_folder: &Path, files: &[DlFile], allocation: Allocation, part_files: bool) -> anyhow::Result<()> {
+    let mut warned = false;
+
+    // Pad files are never written, their bytes are zeros wherever they're needed, and symlinks are made once
+    // the torrent is done.
+    for file in files.iter().filter(|file| !file.is_pad() && !file.is_symlink()) {
+        let mut path = file_path(download_folder, file);
+        if part_files && file.length > 0 && !path.exists() {
+            path = part_path(&path);
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let handle = OpenOptions::new().write(true).create(true).truncate(false).open(&path)
+            .with_context(|| format!("Unable to create {:?}", path))?;
+        if handle.metadata()?.len() >= file.length {
+            continue;
+        }
+
+        match allocation {
+            Allocation::Sparse => {
+                handle.set_len(file.length).with_context(|| format!("Unable to extend {:?} to {} bytes", path, file.length))?;
+
+                // Without sparse files the length is zero-filled right away, which is still correct but takes the
+                // space up front.
+                if !warned && !is_sparse(&handle.metadata()?) {
+                    warn!("{:?} isn't on a filesystem with sparse files, the space of the torrent is taken when it starts", path);
+                    warned = true;
+                }
+            }
+            Allocation::Full => {
+                preallocate(&handle, file.length).with_context(|| format!("Unable to allocate {} bytes for {:?}", file.length, path))?;
+            }
+        }
+    }
+
+    return Ok(());
+}
+
+/// Take the space for the first `length` bytes of a file, keeping the data which is already in it.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn preallocate(file: &File, length: u64) -> anyhow::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let result = unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, length as libc::off_t) };
+    match result {
+        0 => {}
+        libc::ENOSPC => anyhow::bail!("Error: Not enough space left on the disk"),
+        // The filesystem can't allocate space, so fill the space ourselves.
+        libc::EOPNOTSUPP | libc::EINVAL => fill_zeros(file, length)?,
+        errno => return Err(std::io::Error::from_raw_os_error(errno).into()),
+    }
+
+    return Ok(());
+}
+
+/// Without a way to ask the filesystem for space, write zeros over the part of the file past its current end.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn preallocate(file: &File, length: u64) -> anyhow::Result<()> {
+    return fill_zeros(file, length);
+}
+
+fn fill_zeros(mut file: &File, length: u64) -> anyhow::Result<()> {
+    let zeros = vec![0; 1 << 20];
+    let mut position = file.metadata()?.len();
+
+    file.seek(SeekFrom::Start(position))?;
+    while position < length {
+        let len = zeros.len().min((length - position) as usize);
+        file.write_all(&zeros[..len])?;
+        position += len as u64;
+    }
+
+    return Ok(());
+}
+
+#[cfg(unix)]
+fn is_sparse(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    return metadata.blocks() * 512 < metadata.len();
+}
+
+#[cfg(not(unix))]
+fn is_sparse(_metadata: &fs::Metadata) -> bool {
+    return true;
+}
+
+/// Path of a file of the torrent on disk, which is the `.part` file while it's being downloaded with `part_files`.
+pub(crate) fn file_path(download_folder: &Path, file: &DlFile) -> PathBuf {
+    let path = final_path(download_folder, file);
+    let part = part_path(&path);
+
+    return if part.exists() { part } else { path };
+}
+
+/// Path of a file of the torrent once it's complete.
+pub(crate) fn final_path(download_folder: &Path, file: &DlFile) -> PathBuf {
+    return file.path.iter().fold(download_folder.to_path_buf(), |path, component| path.join(component));
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_os_string();
+    part.push(PART_SUFFIX);
+    return PathBuf::from(part);
+}
+
+/// Sort each stretch of writes by offset so they reach the write cache in order.
+///
+/// Writes never move past the other jobs, a read or hash check sees exactly the writes queued before it.
+pub(crate) fn coalesce(jobs: Vec<DiskJob>) -> Vec<DiskJob> {
+    let mut coalesced = Vec::with_capacity(jobs.len());
+    let mut writes: Vec<PieceChannelPayload> = Vec::new();
+
+    for job in jobs {
+        match job {
+            DiskJob::Write(payload) => writes.push(payload),
+            job => {
+                writes.sort_by_key(|payload| payload.offset);
+                coalesced.extend(writes.drain(..).map(DiskJob::Write));
+                coalesced.push(job);
+            }
+        }
+    }
+    writes.sort_by_key(|payload| payload.offset);
+    coalesced.extend(writes.into_iter().map(DiskJob::Write));
+
+    return coalesced;
+}
+
+/// Write a run of blocks with one vectored write for each file it covers.
+pub(crate) fn write_run(download_folder: &Path, storage: &FileStorage, run: &WriteRun) -> anyhow::Result<()> {
+    for segment in storage.map_range(run.offset(), run.length()) {
+        let file = storage.file(segment.file);
+        if !storage.is_stored(segment.file) {
+            continue;
+        }
+
+        let path = file_path(download_folder, file);
+        let mut handle = OpenOptions::new().write(true).create(true).truncate(false).open(&path)
+            .with_context(|| format!("Unable to open {:?}", path))?;
+        handle.seek(SeekFrom::Start(segment.file_offset))?;
+
+        let slices = run.slices(segment.start, segment.len);
+        let mut io_slices: Vec<IoSlice> = slices.iter().map(|slice| IoSlice::new(slice)).collect();
+        let mut io_slices = &mut io_slices[..];
+
+        while !io_slices.is_empty() {
+            let written = handle.write_vectored(io_slices)?;
+            if written == 0 {
+                anyhow::bail!("Error: Unable to write to {:?}", path);
+            }
+            IoSlice::advance_slices(&mut io_slices, written);
+        }
+    }
+
+    return Ok(());
+}
+
+/// Read `length` bytes starting at `offset`, as if all the files were one continuous file.
+fn read_block(download_folder: &Path, storage: &FileStorage, skipped: &SkippedBlocks, offset: u64, length: u64) -> anyhow::Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(length as usize);
+
+    for segment in storage.map_range(offset, length) {
+        let file = storage.file(segment.file);
+        if file.is_pad() {
+            buffer.resize(buffer.len() + segment.len, 0);
+            continue;
+        }
+        if storage.is_skipped(segment.file) {
+            buffer.extend(skipped.read(offset + segment.start as u64, segment.len as u64).context("Error: The range is in a skipped file")?);
+            continue;
+        }
+
+        let path = file_path(download_folder, file);
+        let mut handle = File::open(&path).with_context(|| format!("Unable to open {:?}", path))?;
+        handle.seek(SeekFrom::Start(segment.file_offset))?;
+        handle.take(segment.len as u64).read_to_end(&mut buffer)?;
+    }
+
+    if buffer.len() as u64 != length {
+        return Err(TorrenterError::Storage(format!("Only {} of the {} bytes at {} are on disk", buffer.len(), length, offset)).into());
+    }
+
+    return Ok(buffer);
+}
+
+#[tokio::test]
+async fn test_part_files() {
+    use crate::create::{create_torrent, CreateOptions};
+
+    let dir = Path::new("test-files/part-files");
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir.join("source/data")).unwrap();
+
+    let content: Vec<u8> = (0..40000).map(|i| (i % 249) as u8).collect();
+    fs::write(dir.join("source/data/a.bin"), &content[..10000]).unwrap();
+    fs::write(dir.join("source/data/b.bin"), &content[10000..]).unwrap();
+
+    let metainfo = create_torrent(&CreateOptions {
+        path: dir.join("source/data"),
+        piece_length: Some(16384),
+        ..Default::default()
+    }).unwrap();
+    let torrent = Arc::new(Torrent::from_bytes(&metainfo).unwrap());
+    let download = dir.join("download/data");
+
+    let config = DiskConfig { part_files: true, ..Default::default() };
+    let disk = DiskIo::start(torrent.clone(), download.to_string_lossy().into_owned(), FileStorage::from_torrent(&torrent), &config).unwrap();
+    assert!(download.join("a.bin.part").exists());
+
+    // a.bin is complete once the first piece is verified, b.bin needs every piece.
+    disk.write(PieceChannelPayload { offset: 0, block: content[..16384].to_vec() }).await.unwrap();
+    assert!(disk.verify_piece(0).await.unwrap());
+    assert!(download.join("a.bin").exists());
+    assert!(download.join("b.bin.part").exists());
+
+    disk.write(PieceChannelPayload { offset: 16384, block: vec![0; 16384] }).await.unwrap();
+    assert!(!disk.verify_piece(1).await.unwrap());
+
+    disk.write(PieceChannelPayload { offset: 16384, block: content[16384..].to_vec() }).await.unwrap();
+    assert!(disk.verify_piece(1).await.unwrap());
+    assert!(disk.verify_piece(2).await.unwrap());
+    assert_eq!(fs::read(download.join("b.bin")).unwrap(), content[10000..].to_vec());
+    assert!(!download.join("b.bin.part").exists());
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+
+#[tokio::test]
+async fn test_verify_writes() {
+    use std::convert::TryInto;
+    use crate::create::{create_torrent, CreateOptions};
+
+    let dir = Path::new("test-files/verify-writes");
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir.join("source")).unwrap();
+
+    let content: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+    fs::write(dir.join("source/a.bin"), &content).unwrap();
+    let metainfo = create_torrent(&CreateOptions {
+        path: dir.join("source/a.bin"),
+        piece_length: Some(16384),
+        ..Default::default()
+    }).unwrap();
+    let torrent = Arc::new(Torrent::from_bytes(&metainfo).unwrap());
+    let storage = FileStorage::from_torrent(&torrent);
+    let good: [u8; 20] = torrent.info.pieces[..20].try_into().unwrap();
+
+    // Only pieces whose blocks matched are read back, and a mismatch on disk fails them.
+    let mut verified = Verified::new(&torrent, &storage, true);
+    assert!(!verified.read_back(&torrent, 0, [0; 20]));
+    assert!(verified.read_back(&torrent, 0, good));
+    assert!(!verified.check(&torrent, dir, storage.files(), 0, [0; 20]).unwrap());
+    assert!(verified.reading_back.is_empty());
+    assert!(!Verified::new(&torrent, &storage, false).read_back(&torrent, 0, good));
+
+    let config = DiskConfig { verify_writes: true, ..Default::default() };
+    let disk = DiskIo::start(torrent.clone(), dir.join("download").to_string_lossy().into_owned(), storage, &config).unwrap();
+    disk.write(PieceChannelPayload { offset: 0, block: content[..16384].to_vec() }).await.unwrap();
+    assert!(disk.verify_piece(0).await.unwrap());
+    disk.write(PieceChannelPayload { offset: 16384, block: vec![0; 20000 - 16384] }).await.unwrap();
+    assert!(!disk.verify_piece(1).await.unwrap());
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+
+#[test]
+fn test_rename_files() {
+    let dir = Path::new("test-files/rename");
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir.join("data")).unwrap();
+    fs::write(dir.join("data/a.bin"), vec![1; 10]).unwrap();
+    fs::write(dir.join("data/b.bin.part"), vec![2; 10]).unwrap();
+
+    let file = |name: &str| DlFile { path: vec![String::from(name)], length: 10, md5sum: None, attr: None, symlink_path: None };
+    let mut storage = FileStorage::new(vec![file("a.bin"), file("b.bin")], 16384);
+    let mut folder = dir.join("data");
+
+    rename_files(&mut folder, &mut storage, Rename::File { index: 0, path: vec![String::from("sub"), String::from("c.bin")] }).unwrap();
+    rename_files(&mut folder, &mut storage, Rename::File { index: 1, path: vec![String::from("d.bin")] }).unwrap();
+    rename_files(&mut folder, &mut storage, Rename::Root(String::from("renamed"))).unwrap();
+
+    assert_eq!(folder, dir.join("renamed"));
+    assert_eq!(storage.file(0).path, vec!["sub", "c.bin"]);
+    assert_eq!(fs::read(dir.join("renamed/sub/c.bin")).unwrap(), vec![1; 10]);
+    assert_eq!(fs::read(dir.join("renamed/d.bin.part")).unwrap(), vec![2; 10]);
+
+    assert!(rename_files(&mut folder, &mut storage, Rename::Root(String::from(".."))).is_err());
+    assert!(rename_files(&mut folder, &mut storage, Rename::File { index: 0, path: vec![String::from("d.bin.part")] }).is_err());
+    assert!(rename_files(&mut folder, &mut storage, Rename::File { index: 5, path: vec![String::from("e.bin")] }).is_err());
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+
+#[test]
+fn test_move_data() {
+    let dir = Path::new("test-files/move-data");
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir.join("incomplete/data/sub")).unwrap();
+    fs::write(dir.join("incomplete/data/sub/a.bin"), vec![1; 10]).unwrap();
+
+    move_data(&dir.join("incomplete/data"), &dir.join("complete/data")).unwrap();
+    assert_eq!(fs::read(dir.join("complete/data/sub/a.bin")).unwrap(), vec![1; 10]);
+    assert!(!dir.join("incomplete/data").exists());
+
+    // Copying is what happens across filesystems.
+    copy_recursive(&dir.join("complete/data"), &dir.join("copy/data")).unwrap();
+    assert_eq!(fs::read(dir.join("copy/data/sub/a.bin")).unwrap(), vec![1; 10]);
+    assert!(move_data(&dir.join("complete/data"), &dir.join("copy/data")).is_err());
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+
+#[test]
+fn test_check_free_space() {
+    let dir = Path::new("test-files/free-space");
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir).unwrap();
+    fs::write(dir.join("a.bin"), vec![0; 10]).unwrap();
+
+    let file = |name: &str, length| DlFile { path: vec![String::from(name)], length, md5sum: None, attr: None, symlink_path: None };
+    assert_eq!(space_needed(dir, &[file("a.bin", 25), file("b.bin", 5)]), 20);
+
+    assert!(check_free_space(&dir.join("missing/folder"), &[file("a.bin", 1024)]).is_ok());
+    assert!(check_free_space(dir, &[file("a.bin", u64::MAX)]).is_err());
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+
+#[test]
+fn test_syncer() {
+    let files = vec![
+        DlFile { path: vec![String::from("a")], length: 10, md5sum: None, attr: None, symlink_path: None },
+        DlFile { path: vec![String::from("b")], length: 10, md5sum: None, attr: None, symlink_path: None },
+    ];
+    let storage = FileStorage::new(files, 16384);
+    let config = |fsync| DiskConfig { fsync, fsync_interval_secs: 3600, ..Default::default() };
+
+    let mut syncer = Syncer::new(&config(Durability::Piece));
+    syncer.written(&storage, 5, 10);
+    assert_eq!(syncer.due(), vec![0, 1]);
+    assert!(syncer.due().is_empty());
+
+    let mut syncer = Syncer::new(&config(Durability::Interval));
+    syncer.written(&storage, 12, 1);
+    assert!(syncer.due().is_empty());
+    assert_eq!(syncer.take(), vec![1]);
+
+    let mut syncer = Syncer::new(&config(Durability::Never));
+    syncer.written(&storage, 0, 1);
+    assert!(syncer.due().is_empty());
+    assert_eq!(syncer.take(), vec![0]);
+}
+
+
+#[tokio::test]
+async fn test_pending() {
+    let pending = Arc::new(Pending::new(100, 2));
+    assert!(!pending.is_behind());
+
+    // Too many pieces waiting for their check hold up the requests until one is done.
+    pending.verifying();
+    pending.verifying();
+    assert!(pending.is_behind());
+    let waiter = tokio::spawn({
+        let pending = pending.clone();
+        async move { pending.wait_to_request().await }
+    });
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert!(!waiter.is_finished());
+    pending.verified();
+    tokio::time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap();
+
+    // So do writes over the high watermark.
+    pending.bytes.fetch_add(100 + QUEUED_BYTES, Ordering::SeqCst);
+    assert!(pending.is_behind());
+    pending.written(QUEUED_BYTES);
+    assert!(!pending.is_behind());
+}
+
+
+#[test]
+fn test_coalesce() {
+    let write = |offset, block: Vec<u8>| DiskJob::Write(PieceChannelPayload { offset, block });
+    let (reply, _) = oneshot::channel();
+
+    let offsets = |jobs: &[DiskJob]| -> Vec<Option<u64>> {
+        jobs.iter().map(|job| match job {
+            DiskJob::Write(payload) => Some(payload.offset),
+            _ => None,
+        }).collect()
+    };
+
+    let jobs = coalesce(vec![write(20, vec![3; 5]), write(10, vec![2; 5]), DiskJob::Flush { reply }, write(5, vec![1; 5]), write(0, vec![1; 5])]);
+    assert_eq!(offsets(&jobs), vec![Some(10), Some(20), None, Some(0), Some(5)]);
+    assert!(matches!(jobs[2], DiskJob::Flush { .. }));
+
+    // A read between two adjacent writes only sees the first one.
+    let (reply, _) = oneshot::channel();
+    let jobs = coalesce(vec![write(0, vec![1; 10]), DiskJob::Read { offset: 0, length: 20, reply }, write(10, vec![2; 10])]);
+    assert_eq!(offsets(&jobs), vec![Some(0), None, Some(10)]);
+}
+
+
+#[test]
+fn test_allocate_files() {
+    let dir = Path::new("test-files/allocate");
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir).unwrap();
+    fs::write(dir.join("a.bin"), vec![7; 20]).unwrap();
+
+    let files = vec![
+        DlFile { path: vec![String::from("a.bin")], length: 10, md5sum: None, attr: None, symlink_path: None },
+        DlFile { path: vec![String::from("sub"), String::from("b.bin")], length: 1 << 30, md5sum: None, attr: None, symlink_path: None },
+    ];
+    allocate_files(dir, &files, Allocation::Sparse, false).unwrap();
+
+    // Longer files are left alone, missing ones are created at full length.
+    assert_eq!(fs::read(dir.join("a.bin")).unwrap(), vec![7; 20]);
+    assert_eq!(fs::metadata(dir.join("sub/b.bin")).unwrap().len(), 1 << 30);
+
+    let files = vec![DlFile { path: vec![String::from("c.bin")], length: 3 << 20, md5sum: None, attr: None, symlink_path: None }];
+    fs::write(dir.join("c.bin"), vec![7; 10]).unwrap();
+    allocate_files(dir, &files, Allocation::Full, false).unwrap();
+
+    let content = fs::read(dir.join("c.bin")).unwrap();
+    assert_eq!(content.len(), 3 << 20);
+    assert_eq!(content[..11], [7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 0]);
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+
+#[test]
+fn test_pad_files() {
+    let dir = Path::new("test-files/pad");
+    let _ = fs::remove_dir_all(dir);
+
+    let files = vec![
+        DlFile { path: vec![String::from("a.bin")], length: 10, md5sum: None, attr: None, symlink_path: None },
+        DlFile { path: vec![String::from(".pad"), String::from("6")], length: 6, md5sum: None, attr: Some(String::from("p")), symlink_path: None },
+        DlFile { path: vec![String::from("b.bin")], length: 4, md5sum: None, attr: None, symlink_path: None },
+    ];
+    allocate_files(dir, &files, Allocation::Sparse, false).unwrap();
+    let storage = FileStorage::new(files.clone(), 16384);
+    write_run(dir, &storage, &WriteRun::from(PieceChannelPayload { offset: 0, block: vec![1; 20] })).unwrap();
+
+    // Pad files are read as zeros without ever being on disk.
+    assert_eq!(read_block(dir, &storage, &SkippedBlocks::default(), 5, 15).unwrap(), [vec![1; 5], vec![0; 6], vec![1; 4]].concat());
+    assert!(!dir.join(".pad").exists());
+    assert_eq!(space_needed(dir, &files), 0);
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+
+#[tokio::test]
+async fn test_skipped_files() {
+    use crate::create::{create_torrent, CreateOptions};
+
+    let dir = Path::new("test-files/skipped");
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir.join("source/data")).unwrap();
+
+    let content: Vec<u8> = (0..60000).map(|i| (i % 251) as u8).collect();
+    fs::write(dir.join("source/data/a.bin"), &content[..10000]).unwrap();
+    fs::write(dir.join("source/data/b.bin"), &content[10000..50000]).unwrap();
+    fs::write(dir.join("source/data/c.bin"), &content[50000..]).unwrap();
+
+    let metainfo = create_torrent(&CreateOptions {
+        path: dir.join("source/data"),
+        piece_length: Some(16384),
+        ..Default::default()
+    }).unwrap();
+    let torrent = Arc::new(Torrent::from_bytes(&metainfo).unwrap());
+
+    for backend in [DiskBackend::Threaded, DiskBackend::Mmap] {
+        let download = dir.join(format!("{:?}/data", backend));
+        let mut storage = FileStorage::from_torrent(&torrent);
+        storage.skip(1).unwrap();
+        assert_eq!(storage.unwanted_pieces(), vec![1, 2]);
+
+        let disk = DiskIo::start(torrent.clone(), download.to_string_lossy().into_owned(), storage, &DiskConfig { backend, ..Default::default() }).unwrap();
+        for index in [0, 3] {
+            let start = index as usize * 16384;
+            let end = (start + 16384).min(content.len());
+            disk.write(PieceChannelPayload { offset: start as u64, block: content[start..end].to_vec() }).await.unwrap();
+        }
+
+        // The pieces shared with the skipped file pass their check without it being created.
+        assert!(disk.verify_piece(0).await.unwrap());
+        assert!(disk.verify_piece(3).await.unwrap());
+        disk.sync().await.unwrap();
+        assert!(!download.join("b.bin").exists());
+        assert_eq!(fs::read(download.join("a.bin")).unwrap(), content[..10000].to_vec());
+        assert_eq!(fs::read(download.join("c.bin")).unwrap(), content[50000..].to_vec());
+
+        // Its blocks are gone once the pieces are hashed.
+        assert!(disk.read(10000, 10).await.is_err());
+    }
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+
+#[cfg(unix)]
+#[test]
+fn test_apply_attributes() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = Path::new("test-files/attributes");
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir.join("bin")).unwrap();
+    fs::write(dir.join("bin/run.sh"), b"#!/bin/sh").unwrap();
+    fs::set_permissions(dir.join("bin/run.sh"), fs::Permissions::from_mode(0o640)).unwrap();
+
+    let files = vec![
+        DlFile { path: vec![String::from("bin"), String::from("run.sh")], length: 9, md5sum: None, attr: Some(String::from("x")), symlink_path: None },
+        DlFile { path: vec![String::from("links"), String::from("run")], length: 0, md5sum: None, attr: Some(String::from("l")), symlink_path: Some(vec![String::from("bin"), String::from("run.sh")]) },
+    ];
+
+    apply_attributes(dir, &files, false).unwrap();
+    assert!(!dir.join("links/run").exists());
+
+    apply_attributes(dir, &files, true).unwrap();
+    assert_eq!(fs::metadata(dir.join("bin/run.sh")).unwrap().permissions().mode() & 0o777, 0o750);
+    assert_eq!(fs::read_link(dir.join("links/run")).unwrap(), Path::new("../bin/run.sh"));
+    assert_eq!(fs::read(dir.join("links/run")).unwrap(), b"#!/bin/sh");
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+
+#[tokio::test]
+async fn test_disk_io() {
+    use crate::create::{create_torrent, CreateOptions};
+
+    let dir = Path::new("test-files/disk");
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir.join("source/data")).unwrap();
+    fs::create_dir_all(dir.join("download/data")).unwrap();
+
+    let content: Vec<u8> = (0..40000).map(|i| (i % 253) as u8).collect();
+    fs::write(dir.join("source/data/a.bin"), &content[..30000]).unwrap();
+    fs::write(dir.join("source/data/b.bin"), &content[30000..]).unwrap();
+
+    let metainfo = create_torrent(&CreateOptions {
+        path: dir.join("source/data"),
+        piece_length: Some(16384),
+        ..Default::default()
+    }).unwrap();
+    let torrent = Arc::new(Torrent::from_bytes(&metainfo).unwrap());
+
+    let disk = DiskIo::start(torrent.clone(), dir.join("download/data").to_string_lossy().into_owned(), FileStorage::from_torrent(&torrent), &DiskConfig::default()).unwrap();
+    for (i, block) in content.chunks(16384).enumerate().rev() {
+        disk.write(PieceChannelPayload { offset: i as u64 * 16384, block: block.to_vec() }).await.unwrap();
+    }
+    disk.sync().await.unwrap();
+
+    assert_eq!(disk.read(29990, 20).await.unwrap(), content[29990..30010].to_vec());
+    assert_eq!(disk.hash_piece(2).await.unwrap()[..], torrent.info.pieces[40..60]);
+
+    disk.rename(Rename::Root(String::from("renamed"))).await.unwrap();
+    disk.rename(Rename::File { index: 1, path: vec![String::from("c.bin")] }).await.unwrap();
+    assert_eq!(disk.read(29990, 20).await.unwrap(), content[29990..30010].to_vec());
+    assert_eq!(fs::read(dir.join("download/renamed/c.bin")).unwrap(), content[30000..].to_vec());
+
+    let _ = fs::remove_dir_all(dir);
+}
@@ -0,0 +1,123 @@
+//! Resolving tracker hostnames off the event loop, keeping their addresses for a while.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+
+use crate::interface::AddressFamily;
+
+/// How long resolved addresses are used before the hostname is resolved again.
+pub const DNS_TTL: Duration = Duration::from_secs(300);
+
+/// Addresses of the hosts resolved so far, with the one to use first in front.
+#[derive(Debug)]
+pub struct DnsCache {
+    ttl: Duration,
+    /// Addresses of the other family are left out.
+    family: AddressFamily,
+    entries: Mutex<HashMap<(String, u16), Entry>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    addrs: Vec<SocketAddr>,
+    resolved: Instant,
+    /// Addresses given up on since the host was resolved.
+    failures: usize,
+}
+
+impl DnsCache {
+    pub fn new(ttl: Duration, family: AddressFamily) -> DnsCache {
+        DnsCache {
+            ttl,
+            family,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Address to reach a host on, resolved on the blocking pool when it isn't cached or has been for too long.
+    pub async fn resolve(&self, host: &str, port: u16) -> anyhow::Result<SocketAddr> {
+        let key = (host.to_owned(), port);
+        if let Some(entry) = self.entries.lock().unwrap().get(&key) {
+            if entry.resolved.elapsed() < self.ttl {
+                return Ok(entry.addrs[0]);
+            }
+        }
+
+        let addrs = self.family.filter(tokio::net::lookup_host((host, port)).await
+            .with_context(|| format!("Unable to resolve {}", host))?);
+        let Some(&addr) = addrs.first() else {
+            anyhow::bail!("Error: {} has no {} address", host, self.family);
+        };
+
+        self.entries.lock().unwrap().insert(key, Entry { addrs, resolved: Instant::now(), failures: 0 });
+        return Ok(addr);
+    }
+
+    /// The address a host resolved to didn't answer, move on to its next one.
+    ///
+    /// Once every address has failed the host is resolved again.
+    pub fn failed(&self, host: &str, port: u16) {
+        let key = (host.to_owned(), port);
+        let mut entries = self.entries.lock().unwrap();
+
+        if let Some(entry) = entries.get_mut(&key) {
+            entry.failures += 1;
+            if entry.failures >= entry.addrs.len() {
+                entries.remove(&key);
+            } else {
+                entry.addrs.rotate_left(1);
+            }
+        }
+    }
+}
+
+/// Resolver of the HTTP client keeping to the addresses of one family.
+pub struct FamilyResolver(pub AddressFamily);
+
+impl reqwest::dns::Resolve for FamilyResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let family = self.0;
+        return Box::pin(async move {
+            let addrs = family.filter(tokio::net::lookup_host((name.as_str(), 0)).await?);
+            if addrs.is_empty() {
+                return Err(format!("{} has no {} address", name.as_str(), family).into());
+            }
+
+            let addrs: reqwest::dns::Addrs = Box::new(addrs.into_iter());
+            return Ok(addrs);
+        });
+    }
+}
+
+
+#[tokio::test]
+async fn test_dns_cache() {
+    let first: SocketAddr = "192.0.2.1:80".parse().unwrap();
+    let second: SocketAddr = "[2001:db8::1]:80".parse().unwrap();
+
+    let cache = DnsCache::new(DNS_TTL, AddressFamily::Any);
+    cache.entries.lock().unwrap().insert((String::from("tracker.example"), 80), Entry {
+        addrs: vec![first, second],
+        resolved: Instant::now(),
+        failures: 0,
+    });
+
+    // Cached addresses are used in turn as they fail, until the host has to be resolved again.
+    assert_eq!(cache.resolve("tracker.example", 80).await.unwrap(), first);
+    cache.failed("tracker.example", 80);
+    assert_eq!(cache.resolve("tracker.example", 80).await.unwrap(), second);
+    cache.failed("tracker.example", 80);
+    assert!(cache.entries.lock().unwrap().is_empty());
+
+    assert_eq!(cache.resolve("127.0.0.1", 6969).await.unwrap(), "127.0.0.1:6969".parse().unwrap());
+    assert_eq!(cache.entries.lock().unwrap().len(), 1);
+
+    // Only addresses of the allowed family are used.
+    let cache = DnsCache::new(DNS_TTL, AddressFamily::Ipv6);
+    assert!(cache.resolve("127.0.0.1", 6969).await.is_err());
+    assert_eq!(cache.resolve("::1", 6969).await.unwrap(), "[::1]:6969".parse().unwrap());
+}
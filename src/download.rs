@@ -1,150 +1,538 @@
-use std::fs::{File, OpenOptions};
-use std::fs;
+use std::fs::File;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::prelude::*;
-use std::io::SeekFrom;
-use std::net::{Ipv4Addr, TcpStream};
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 use bytebuffer::ByteBuffer;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc};
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::mpsc::Sender;
-
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::config::{Config, SocketConfig};
+use crate::dht::Dht;
+use crate::disk::DiskIo;
+use crate::dns::DnsCache;
+use crate::tracker::{get_torrent_peers, ExternalIp, TrackerAuth, Trackers};
+use crate::events::EventSender;
+use crate::encryption::{encrypted_handshake, handshake_modes, EncryptionPolicy, HandshakeMode, PeerCrypto, PeerReader, PeerStream, PeerWriter};
+use crate::interface::{AddressFamily, Interface};
+use crate::limiter::RateLimiter;
+use crate::listener::IncomingPeer;
 use crate::message_handlers::{MessageHandler, PieceChannelPayload};
 use crate::messages::build_peer_handshake;
+use crate::metrics;
 use crate::pieces::Pieces;
 use crate::queue::Queue;
-use crate::utils::Peer;
-use crate::utils::torrents::{DlFile, Torrent};
+use crate::resume::ResumeStore;
+use crate::ticks::{Tick, TickSender};
+use crate::utils::{is_local_addr, Peer, PeerSource};
+use crate::utils::torrents::{BLOCK_LEN, Torrent};
+use crate::webseed::download_from_web_seed;
 
 pub type PiecesManager = Arc<Mutex<Pieces>>;
 
-pub async fn download_torrent(peer_id: ByteBuffer, file_path: &str) -> anyhow::Result<()> {
-    let torrent = Arc::new(Torrent::new(file_path));
-    torrent.print();
+/// How the connections to peers behave, taken from the config.
+#[derive(Debug, Clone, Default)]
+pub struct PeerSettings {
+    /// Maximum amount of peers downloaded from at once.
+    pub max_peers: usize,
+    /// Leave some pieces out of the bitfield and send them in have messages afterwards.
+    pub lazy_bitfield: bool,
+    /// Don't send have messages for pieces the peer already has.
+    pub suppress_haves: bool,
+    pub encryption: EncryptionPolicy,
+    /// Don't hold peers on the local network back with the rate limits.
+    pub exempt_lan_peers: bool,
+    /// Where connections to peers are made from.
+    pub interface: Interface,
+    /// Options set on the sockets of peer connections.
+    pub socket: SocketConfig,
+    /// Peer addresses of the other family aren't connected to.
+    pub address_family: AddressFamily,
+    /// Port announced to trackers.
+    pub listen_port: u16,
+    /// Port announced to trackers reached over IPv6, which can belong to another listener.
+    pub listen_port_v6: u16,
+    /// Peers sending slower than this in bytes per second are replaced when there are others to connect to.
+    pub min_peer_rate: u64,
+    /// Peers asked for in announces.
+    pub num_want: u32,
+    /// Bytes per second a single peer can send us, within the limit of the session, 0 is unlimited.
+    pub peer_download_rate_limit: u64,
+    /// Bytes per second sent to a single peer, within the limit of the session, 0 is unlimited.
+    pub peer_upload_rate_limit: u64,
+    /// Bytes per second a peer which snubbed us can send from then on, 0 leaves it to the other limits.
+    pub snubbed_peer_rate_limit: u64,
+    /// Key sent in every announce of the session.
+    pub announce_key: u32,
+    /// How long a peer has to answer our handshake, `PEER_HANDSHAKE_TIMEOUT` when None.
+    pub handshake_timeout: Option<Duration>,
+}
 
+impl PeerSettings {
+    pub fn from_config(config: &Config) -> PeerSettings {
+        PeerSettings {
+            max_peers: config.max_peers_per_torrent,
+            lazy_bitfield: config.lazy_bitfield,
+            suppress_haves: config.suppress_haves,
+            encryption: config.encryption,
+            exempt_lan_peers: config.exempt_lan_peers,
+            interface: config.interface(),
+            socket: config.socket.clone(),
+            address_family: config.address_family,
+            listen_port: config.listen_port,
+            listen_port_v6: config.listen_port,
+            min_peer_rate: config.min_peer_rate,
+            num_want: config.num_want,
+            peer_download_rate_limit: config.peer_download_rate_limit,
+            peer_upload_rate_limit: config.peer_upload_rate_limit,
+            snubbed_peer_rate_limit: config.snubbed_peer_rate_limit,
+            announce_key: 0,
+            handshake_timeout: None,
+        }
+    }
+}
 
-    let download_folder = torrent.info.name.clone();
-    create_download_folder(&download_folder);
+/// What the connections to the peers of a torrent share.
+#[derive(Clone)]
+pub struct Swarm {
+    pub pieces: PiecesManager,
+    pub download_limiter: Arc<RateLimiter>,
+    /// Limiter of the blocks we send the peers, shared by the torrents of the session.
+    pub upload_limiter: Arc<RateLimiter>,
+    pub settings: PeerSettings,
+    pub peers: PeerList,
+    /// Addresses of the trackers.
+    pub dns: Arc<DnsCache>,
+    /// Our address, announced to the trackers and learned from the peers.
+    pub external_ip: Arc<ExternalIp>,
+    /// Events of the session, for tracker failures.
+    pub events: EventSender,
+    pub trackers: Trackers,
+    /// Client HTTP trackers are announced to with.
+    pub http: reqwest::Client,
+    pub tracker_auth: Arc<Vec<TrackerAuth>>,
+    /// The disk of the torrent, requested blocks are read from it and no blocks are requested while it catches up.
+    pub disk: DiskIo,
+    /// Peers which can be connected to, whenever there are fewer than `max_peers`.
+    pub pool: PeerPool,
+    /// Ticks of the session timer the torrent and its peers run their timers from.
+    pub ticks: TickSender,
+    /// Where the verified pieces are kept for the next session, None to download everything again.
+    pub resume: Option<ResumeStore>,
+    /// DHT of the session the torrent looks for peers on, None when it's off.
+    pub dht: Option<Arc<Dht>>,
+}
 
-    let handshake = Arc::new(build_peer_handshake(&torrent.info_hash.unwrap(), &peer_id).to_bytes());
+#[cfg(test)]
+impl Swarm {
+    /// A swarm for the tests with the default settings, which sends its events and ticks nowhere.
+    pub(crate) fn test(pieces: Pieces) -> Swarm {
+        use crate::dns::DNS_TTL;
+
+        return Swarm {
+            pieces: Arc::new(Mutex::new(pieces)),
+            download_limiter: Arc::new(RateLimiter::new(0)),
+            upload_limiter: Arc::new(RateLimiter::new(0)),
+            settings: PeerSettings::default(),
+            peers: PeerList::default(),
+            dns: Arc::new(DnsCache::new(DNS_TTL, Default::default())),
+            external_ip: Arc::new(ExternalIp::default()),
+            events: broadcast::channel(1).0,
+            trackers: Arc::default(),
+            http: reqwest::Client::new(),
+            tracker_auth: Arc::default(),
+            disk: DiskIo::stopped(),
+            pool: PeerPool::default(),
+            ticks: broadcast::channel(1).0,
+            resume: None,
+            dht: None,
+        };
+    }
+}
 
+/// A peer connected to a torrent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStatus {
+    pub addr: SocketAddr,
+    pub source: PeerSource,
+    pub encrypted: bool,
+}
 
-    // let peers = get_torrent_peers(&torrent, &hashed_info, &peer_id)?;
+/// The peers connected to a torrent, shared with the session for its peer stats.
+#[derive(Debug, Clone, Default)]
+pub struct PeerList {
+    peers: Arc<Mutex<HashMap<u64, ListedStatus>>>,
+    next_id: Arc<AtomicU64>,
+}
 
+/// A peer in a `PeerList`, with the limiter of what it sends us.
+#[derive(Debug)]
+struct ListedStatus {
+    status: PeerStatus,
+    limiter: Arc<RateLimiter>,
+}
 
-    // println!("{:?}", peers);
-    // [Peer { ip_addr: 1410415827, port: 6682 }]
-    let peer = Peer {
-        ip_addr: 0,
-        port: 0,
-    };
+impl PeerList {
+    /// Add a peer to the list until the returned guard is dropped.
+    pub fn add(&self, status: PeerStatus, limiter: Arc<RateLimiter>) -> ListedPeer {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.peers.lock().unwrap().insert(id, ListedStatus { status, limiter });
+        return ListedPeer { peers: self.peers.clone(), id };
+    }
 
+    pub fn list(&self) -> Vec<PeerStatus> {
+        return self.peers.lock().unwrap().values().map(|listed| listed.status.clone()).collect();
+    }
 
-    let (tx, mut rx) = mpsc::channel::<PieceChannelPayload>(32);
+    /// Change how many bytes per second a connected peer can send, returning false when it isn't connected.
+    pub fn set_rate_limit(&self, addr: SocketAddr, rate: u64) -> bool {
+        let peers = self.peers.lock().unwrap();
+        let mut found = false;
+        for listed in peers.values().filter(|listed| listed.status.addr == addr) {
+            listed.limiter.set_rate(rate);
+            found = true;
+        }
+        return found;
+    }
+}
+
+/// Peers of a torrent which can be connected to, fed by the trackers, DHT and PEX.
+///
+/// Every address is only taken once, peers on the local network are handed out first and private torrents leave out
+/// the sources they can't use.
+#[derive(Debug, Clone, Default)]
+pub struct PeerPool {
+    inner: Arc<Mutex<PoolState>>,
+}
 
-    let pieces_manager = Arc::new(Mutex::new(Pieces::new(&torrent)));
+#[derive(Debug, Default)]
+struct PoolState {
+    peers: VecDeque<Peer>,
+    seen: HashSet<SocketAddr>,
+}
 
-    for i in 0..1 {
-        let file_sender = tx.clone();
-        let pm = pieces_manager.clone();
-        let torrent = torrent.clone();
-        let peer = peer.clone();
-        let hs = handshake.clone();
+impl PeerPool {
+    /// Add the peers which haven't been seen before, returning how many there were.
+    pub fn add(&self, peers: impl IntoIterator<Item = Peer>, private: bool) -> usize {
+        let mut state = self.inner.lock().unwrap();
+        let mut added = 0;
+        for peer in peers {
+            if !peer.source.is_allowed(private) {
+                continue;
+            }
+            // A peer is new when any of its addresses is, all of them are seen from then on.
+            let mut new = false;
+            for &addr in &peer.addrs {
+                new |= state.seen.insert(addr);
+            }
+            if !new {
+                continue;
+            }
+
+            if peer.addrs.iter().any(|addr| is_local_addr(addr.ip())) {
+                state.peers.push_front(peer);
+            } else {
+                state.peers.push_back(peer);
+            }
+            added += 1;
+        }
+        return added;
+    }
 
-        tokio::spawn(async move {
-            download_from_peer(torrent, file_sender, peer, hs, pm).await;
-        });
+    /// The next peer to connect to.
+    pub fn pop(&self) -> Option<Peer> {
+        return self.inner.lock().unwrap().peers.pop_front();
     }
 
-    while let Some(payload) = rx.recv().await {
-        write_block_to_file(&download_folder, &torrent.info.files.as_ref().unwrap(), payload)
+    /// Amount of peers which haven't been connected to yet.
+    pub fn len(&self) -> usize {
+        return self.inner.lock().unwrap().peers.len();
     }
 
-    Ok(())
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
 }
 
-fn create_download_folder(name: &String) {
-    match fs::create_dir_all(name) {
-        Ok(_) => {}
-        Err(_) => {}
-    };
+/// Keeps a peer in its `PeerList` for as long as it's alive.
+pub struct ListedPeer {
+    peers: Arc<Mutex<HashMap<u64, ListedStatus>>>,
+    id: u64,
 }
 
-fn write_block_to_file(download_folder: &String, files: &Vec<DlFile>, payload: PieceChannelPayload) {
-    let mut file_offset = 0;
-    let mut write_pos = payload.offset;
-    let mut bytes_to_write = payload.block.clone();
+impl Drop for ListedPeer {
+    fn drop(&mut self) {
+        self.peers.lock().unwrap().remove(&self.id);
+    }
+}
 
 
-    for file in files {
-        let file_end = file.length + file_offset;
+#[test]
+fn test_peer_list() {
+    let peers = PeerList::default();
+    let status = |port, source| PeerStatus { addr: SocketAddr::from(([10, 0, 0, 1], port)), source, encrypted: false };
+
+    let limiter = Arc::new(RateLimiter::new(0));
+    let tracker = peers.add(status(6881, PeerSource::Tracker), Arc::new(RateLimiter::new(0)));
+    let incoming = peers.add(status(6882, PeerSource::Incoming), limiter.clone());
+    assert_eq!(peers.list().len(), 2);
+
+    // Peers are limited by their address.
+    assert!(peers.set_rate_limit(SocketAddr::from(([10, 0, 0, 1], 6882)), 1000));
+    assert_eq!(limiter.rate(), 1000);
+    assert!(!peers.set_rate_limit(SocketAddr::from(([10, 0, 0, 1], 6883)), 1000));
+
+    // Peers leave the list once they disconnect.
+    drop(tracker);
+    assert_eq!(peers.list(), vec![status(6882, PeerSource::Incoming)]);
+    drop(incoming);
+    assert!(peers.list().is_empty());
+}
 
-        // Check if the block is for this file
-        let for_this_file = write_pos < file_end;
 
-        if for_this_file {
+#[test]
+fn test_peer_pool() {
+    let peer = |ip: [u8; 4], source| Peer::from_addr(SocketAddr::from((ip, 6881)), source);
+    let pool = PeerPool::default();
 
-            // Write either the full amount of bytes in the block
-            // or until the end of the file
-            let write_len = if file_end - write_pos < bytes_to_write.len() as u64 {
-                file_end - write_pos
-            } else {
-                bytes_to_write.len() as u64
-            } as usize;
+    assert_eq!(pool.add(vec![peer([8, 8, 8, 8], PeerSource::Tracker), peer([192, 168, 1, 2], PeerSource::Tracker)], false), 2);
 
-            // Calculate the starting position to write the bytes
-            let file_write_pos = write_pos - file_offset;
+    // Peers seen before aren't added twice, and private torrents don't take peers from the DHT.
+    assert_eq!(pool.add(vec![peer([8, 8, 8, 8], PeerSource::Pex), peer([1, 1, 1, 1], PeerSource::Dht)], true), 0);
+    assert_eq!(pool.len(), 2);
 
-            let file_path = download_folder.clone() + "/" + &file.path.clone().join("/");
+    // Local peers come first.
+    assert_eq!(pool.pop().map(|peer| peer.addrs), Some(vec![SocketAddr::from(([192, 168, 1, 2], 6881))]));
+    assert_eq!(pool.pop().map(|peer| peer.addrs), Some(vec![SocketAddr::from(([8, 8, 8, 8], 6881))]));
+    assert!(pool.pop().is_none());
 
-            let mut dl_file = OpenOptions::new().write(true).create(true).open(&file_path).expect("Unable to open file");
-            dl_file.seek(SeekFrom::Start(file_write_pos)).expect("Unable to set offset on file");
-            dl_file.write(&bytes_to_write[0..write_len]).expect("Unable to write to file");
+    // IPv6 peers are taken like any other.
+    assert_eq!(pool.add(vec![Peer::from_addr("[2001:db8::1]:6881".parse().unwrap(), PeerSource::Dht)], false), 1);
+    assert_eq!(pool.pop().map(|peer| peer.addrs), Some(vec!["[2001:db8::1]:6881".parse().unwrap()]));
+}
 
-            // Remove the bytes from the start of the slice
-            bytes_to_write.drain(0..write_len);
+/// A change to the pieces we have, sent to every peer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PieceUpdate {
+    /// A piece passed its hash check.
+    Have(u64),
+    /// A piece we had doesn't match its hash anymore, peers supporting `lt_donthave` are told to stop requesting it.
+    DontHave(u64),
+    /// A piece failed its hash check and is downloaded again from the peers which have it.
+    Retry(u64),
+}
+
+/// How long a peer which took the connection has to answer the handshake.
+pub const PEER_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// How often the DHT is asked for more peers of a torrent.
+const DHT_LOOKUP_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Blocks requested longer ago than this are requested again from a faster, idle peer.
+const STALL_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Peers we upload to for being the fastest to send to us, on top of the one picked at random.
+const UPLOAD_SLOTS: usize = 3;
 
-            // Calculate the write position for the next file
-            write_pos += write_len as u64;
+/// Piece updates waiting to be sent to a peer, a peer which falls further behind misses the oldest ones.
+const HAVE_CHANNEL_SIZE: usize = 256;
+
+pub async fn download_torrent(peer_id: ByteBuffer, torrent: Arc<Torrent>, swarm: Swarm, mut incoming: mpsc::Receiver<IncomingPeer>) -> anyhow::Result<()> {
+    info!(size = torrent.size, "Starting download");
+    let disk = swarm.disk.clone();
+
+    let handshake = Arc::new(build_peer_handshake(&torrent.info_hash, &peer_id).to_bytes());
+
+    // The trackers and the DHT keep feeding the pool for as long as the download runs, private torrents stay off
+    // the DHT (BEP 27).
+    let _announcer = TaskGuard(tokio::spawn(announce_peers(peer_id, torrent.clone(), swarm.clone())));
+    let _lookup = swarm.dht.clone().filter(|_| torrent.info.private != Some(1))
+        .map(|dht| TaskGuard(tokio::spawn(lookup_peers(torrent.info_hash, dht, swarm.clone()))));
+
+    let (tx, mut rx) = mpsc::channel::<PieceChannelPayload>(32);
+    let (have_sender, _) = broadcast::channel::<PieceUpdate>(HAVE_CHANNEL_SIZE);
+
+    // Web seeds (BEP 19) are downloaded from alongside the peers.
+    let _web_seeds: Vec<TaskGuard> = torrent.web_seeds().into_iter().map(|web_seed| {
+        let span = info_span!("web_seed", url = %web_seed);
+        let download = download_from_web_seed(web_seed, torrent.clone(), tx.clone(), swarm.clone());
+        return TaskGuard(tokio::spawn(async move {
+            if let Err(e) = download.await {
+                warn!("Gave up on the web seed: {:#}", e);
+            }
+        }.instrument(span)));
+    }).collect();
+    let pieces_manager = swarm.pieces.clone();
+
+    let dialer = Dialer {
+        torrent: torrent.clone(),
+        file_sender: tx.clone(),
+        handshake: handshake.clone(),
+        swarm: swarm.clone(),
+        have_sender: have_sender.clone(),
+        dialing: Arc::default(),
+    };
+    let mut ticks = swarm.ticks.subscribe();
+
+    // Offsets of the blocks written for each piece, to verify pieces once they're written in full.
+    let mut written: HashMap<u64, HashSet<u64>> = HashMap::new();
+
+    // Pieces are verified in the background so the blocks which keep coming in aren't held up by hashing.
+    let (verified_sender, mut verified) = mpsc::unbounded_channel::<(u64, anyhow::Result<bool>)>();
+    let mut verifying = 0;
+
+    // Whether pieces were verified or failed since the resume file was written, it's rewritten on announce ticks.
+    let mut unsaved = false;
+    let mut save = false;
+
+    loop {
+        let payload = tokio::select! {
+            payload = rx.recv() => match payload {
+                Some(payload) => Some(payload),
+                None => break,
+            },
+            Some(peer) = incoming.recv() => {
+                let file_sender = tx.clone();
+                let torrent = torrent.clone();
+                let hs = handshake.clone();
+                let swarm = swarm.clone();
+                let haves = have_sender.subscribe();
+
+                let span = info_span!("peer", addr = %peer.addr, incoming = true);
+
+                tokio::spawn(async move {
+                    if let Err(e) = answer_peer(torrent, file_sender, peer, hs, swarm, haves).await {
+                        debug!("Disconnected from peer: {:#}", e);
+                    }
+                }.instrument(span));
+                continue;
+            }
+            Some((index, result)) = verified.recv() => {
+                verifying -= 1;
+                unsaved = true;
+                match result {
+                    Ok(true) => {
+                        // Let every peer know there's a new piece they can request from us.
+                        pieces_manager.lock().unwrap().add_verified(index);
+                        let _ = have_sender.send(PieceUpdate::Have(index));
+                    }
+                    Ok(false) => {
+                        warn!(piece = index, "Piece doesn't match its hash, downloading it again");
+                        metrics::PIECE_VERIFICATION_FAILURES.inc();
+                        retry_piece(index, &pieces_manager, &have_sender);
+                    }
+                    Err(e) => {
+                        warn!(piece = index, "Unable to check the piece, downloading it again: {:#}", e);
+                        retry_piece(index, &pieces_manager, &have_sender);
+                    }
+                }
+                None
+            }
+            tick = ticks.recv() => {
+                match tick {
+                    Ok(Tick::Second) => dialer.fill(),
+                    Ok(Tick::Requests) => {
+                        // Slow peers give their slots to peers we haven't tried yet once they're gone.
+                        let full = swarm.peers.list().len() >= swarm.settings.max_peers;
+                        let mut pieces = pieces_manager.lock().unwrap();
+                        pieces.find_stalled(STALL_TIMEOUT);
+                        if full && !swarm.pool.is_empty() && swarm.settings.min_peer_rate > 0 {
+                            pieces.drop_slow_peers(swarm.settings.min_peer_rate);
+                        }
+                    }
+                    Ok(Tick::Choke) => pieces_manager.lock().unwrap().choke_round(UPLOAD_SLOTS),
+                    Ok(Tick::Announce) => save = unsaved,
+                    Ok(_) => {}
+                    Err(e) => debug!("Missed ticks: {}", e),
+                }
+                None
+            }
+        };
+
+        if let Some(payload) = payload {
+            let index = payload.offset / torrent.info.piece_length;
+            let blocks = written.entry(index).or_default();
+            blocks.insert(payload.offset);
+            let piece_written = blocks.len() as u64 == torrent.get_piece_len(index).div_ceil(BLOCK_LEN);
+
+            disk.write(payload).await?;
+
+            if piece_written {
+                written.remove(&index);
+                verifying += 1;
+
+                let disk = disk.clone();
+                let verified_sender = verified_sender.clone();
+                tokio::spawn(async move {
+                    let _ = verified_sender.send((index, disk.verify_piece(index).await));
+                });
+            }
         }
 
-        file_offset += file.length;
+        if save {
+            save_resume(&torrent, &swarm, &disk).await;
+            unsaved = false;
+            save = false;
+        }
 
-        // Stop if there is nothing left to write
-        if bytes_to_write.len() == 0 {
+        // Stop once the last block has been received and every piece is checked, and wait for it to be written.
+        if verifying == 0 && pieces_manager.lock().unwrap().is_done() {
+            disk.flush().await?;
+            if unsaved {
+                save_resume(&torrent, &swarm, &disk).await;
+            }
             break;
         }
     }
-}
 
+    Ok(())
+}
 
 #[test]
 fn test_write_block_to_file_1() {
+    use std::fs;
+    use std::path::Path;
+    use crate::cache::WriteRun;
+    use crate::disk::write_run;
+    use crate::storage::FileStorage;
+    use crate::utils::torrents::DlFile;
+
     let download_folder: String = String::from("test-files/test1/");
     match fs::remove_dir_all(&download_folder) {
         Ok(_) => {}
         Err(_) => {}
     };
 
-    create_download_folder(&download_folder);
+    fs::create_dir_all(&download_folder).unwrap();
 
     // Setup
     let f1 = DlFile {
         path: vec!["file1.txt".to_owned()],
         length: 5,
         md5sum: None,
+        attr: None,
+        symlink_path: None,
     };
 
     let f2 = DlFile {
         path: vec!["file2.txt".to_owned()],
         length: 5,
         md5sum: None,
+        attr: None,
+        symlink_path: None,
     };
 
     let f3 = DlFile {
         path: vec!["file3.txt".to_owned()],
         length: 5,
         md5sum: None,
+        attr: None,
+        symlink_path: None,
     };
 
     let mut files: Vec<DlFile> = Vec::new();
@@ -158,7 +546,7 @@ fn test_write_block_to_file_1() {
     };
 
     // Logic
-    write_block_to_file(&download_folder, &files, payload);
+    write_run(Path::new(&download_folder), &FileStorage::new(files, 16384), &WriteRun::from(payload)).unwrap();
 
     // Test
     let mut f = File::open(download_folder.clone() + "/file1.txt").expect("Couldn't open file");
@@ -188,30 +576,43 @@ fn test_write_block_to_file_1() {
 
 #[test]
 fn test_write_block_to_file_2() {
+    use std::fs;
+    use std::path::Path;
+    use crate::cache::WriteRun;
+    use crate::disk::write_run;
+    use crate::storage::FileStorage;
+    use crate::utils::torrents::DlFile;
+
     let download_folder: String = String::from("test-files/test2/");
     match fs::remove_dir_all(&download_folder) {
         Ok(_) => {}
         Err(_) => {}
     };
-    create_download_folder(&download_folder);
+    fs::create_dir_all(&download_folder).unwrap();
 
     // Setup
     let f1 = DlFile {
         path: vec!["file1.txt".to_owned()],
         length: 5,
         md5sum: None,
+        attr: None,
+        symlink_path: None,
     };
 
     let f2 = DlFile {
         path: vec!["file2.txt".to_owned()],
         length: 5,
         md5sum: None,
+        attr: None,
+        symlink_path: None,
     };
 
     let f3 = DlFile {
         path: vec!["file3.txt".to_owned()],
         length: 5,
         md5sum: None,
+        attr: None,
+        symlink_path: None,
     };
 
     let mut files: Vec<DlFile> = Vec::new();
@@ -225,7 +626,7 @@ fn test_write_block_to_file_2() {
     };
 
     // Logic
-    write_block_to_file(&download_folder, &files, payload);
+    write_run(Path::new(&download_folder), &FileStorage::new(files, 16384), &WriteRun::from(payload)).unwrap();
 
 
     let mut f = File::open(download_folder.clone() + "/file2.txt").expect("Couldn't open file");
@@ -247,32 +648,379 @@ fn test_write_block_to_file_2() {
     };
 }
 
-async fn download_from_peer(torrent: Arc<Torrent>, file_sender: Sender<PieceChannelPayload>, peer: Peer, handshake: Arc<Vec<u8>>, pieces: PiecesManager) -> anyhow::Result<()> {
-    let peer_addr = (Ipv4Addr::from(peer.ip_addr), peer.port);
 
-    let mut queue: Queue = Queue::new(&torrent);
+#[test]
+fn test_connect_peer_timeout() {
+    use std::net::TcpListener;
+
+    // The listener takes the connection but nobody ever answers it.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let settings = PeerSettings { handshake_timeout: Some(Duration::from_millis(200)), ..Default::default() };
+    let mut peer = Peer::from_addr(listener.local_addr().unwrap(), PeerSource::Manual);
+
+    let start = Instant::now();
+    assert!(connect_peer(&mut peer, &[0; 20], &[0; 68], &settings).is_err());
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+
+/// Download a piece again, taking it back from the peers we told we have it.
+fn retry_piece(index: u64, pieces: &PiecesManager, have_sender: &broadcast::Sender<PieceUpdate>) {
+    if pieces.lock().unwrap().reset_piece(index) {
+        let _ = have_sender.send(PieceUpdate::DontHave(index));
+    }
+    let _ = have_sender.send(PieceUpdate::Retry(index));
+}
+
+
+/// Stops a background task of a torrent when its download ends, however it ends.
+struct TaskGuard(tokio::task::JoinHandle<()>);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Write the verified pieces to the resume file of the torrent, once everything written so far is synced to disk.
+async fn save_resume(torrent: &Torrent, swarm: &Swarm, disk: &DiskIo) {
+    let Some(resume) = swarm.resume.clone() else {
+        return;
+    };
+
+    // Pieces verified before the sync are on disk after it, so a crash can't leave the resume file ahead of the files.
+    let verified = swarm.pieces.lock().unwrap().verified().to_vec();
+    let info_hash = torrent.info_hash;
+    let result = match disk.sync().await {
+        Ok(()) => tokio::task::spawn_blocking(move || resume.store(&info_hash, &verified)).await
+            .unwrap_or_else(|e| Err(e.into())),
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = result {
+        warn!("Unable to write the resume file: {:#}", e);
+    }
+}
 
-    // let mut stream = TcpStream::connect(peer_addr)?;
-    let mut stream = TcpStream::connect("127.0.0.1:14082").expect("Unable to connect to peer");
 
-    println!("Connected to Peer!");
+/// Announce to the trackers right away and then on every announce tick they're due, adding the peers they give to
+/// the pool.
+async fn announce_peers(peer_id: ByteBuffer, torrent: Arc<Torrent>, swarm: Swarm) {
+    let private = torrent.info.private == Some(1);
+    let mut ticks = swarm.ticks.subscribe();
 
-    stream.write(&handshake).expect("Unable to write to peer");
+    loop {
+        match get_torrent_peers(&torrent, &peer_id, &swarm).await {
+            Ok(peers) => {
+                let added = swarm.pool.add(peers, private);
+                debug!(added, "Got peers from the trackers");
+            }
+            Err(e) => debug!("Unable to announce: {:#}", e),
+        }
 
-    let mut message_handler = MessageHandler::new(&torrent, &mut stream, file_sender, pieces, &mut queue);
+        loop {
+            match ticks.recv().await {
+                Ok(Tick::Announce) => break,
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+/// Look the torrent up on the DHT right away and then every `DHT_LOOKUP_INTERVAL`, adding the peers it finds to the
+/// pool.
+async fn lookup_peers(info_hash: [u8; 20], dht: Arc<Dht>, swarm: Swarm) {
+    let mut ticks = swarm.ticks.subscribe();
 
-    let mut is_handshake = true;
     loop {
-        if is_handshake {
-            message_handler.handshake();
-            is_handshake = false;
-        } else {
-            let recv_msg = message_handler.get_whole_msg();
-            message_handler.router(recv_msg).await;
+        let found = dht.get_peers(info_hash).await;
+        let peers: Vec<Peer> = found.into_iter().map(|addr| Peer::from_addr(addr, PeerSource::Dht)).collect();
+        let added = swarm.pool.add(peers, false);
+        debug!(added, "Got peers from the DHT");
+
+        let looked_up = Instant::now();
+        while looked_up.elapsed() < DHT_LOOKUP_INTERVAL {
+            match ticks.recv().await {
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            }
         }
     }
+}
 
-    Ok(())
+/// Connects to the peers of the pool in the background.
+struct Dialer {
+    torrent: Arc<Torrent>,
+    file_sender: Sender<PieceChannelPayload>,
+    handshake: Arc<Vec<u8>>,
+    swarm: Swarm,
+    have_sender: broadcast::Sender<PieceUpdate>,
+    /// Connections being made, they count towards `max_peers` until they fail or show up in the peer list.
+    dialing: Arc<AtomicUsize>,
+}
+
+impl Dialer {
+    /// Dial peers from the pool until there are as many as the torrent wants.
+    fn fill(&self) {
+        let connected = self.swarm.peers.list().len() + self.dialing.load(Ordering::SeqCst);
+        for _ in connected..self.swarm.settings.max_peers {
+            let Some(peer) = self.swarm.pool.pop() else {
+                break;
+            };
+            self.dial(peer);
+        }
+    }
+
+    /// Connect to a peer and download from it in the background.
+    fn dial(&self, peer: Peer) {
+        let file_sender = self.file_sender.clone();
+        let torrent = self.torrent.clone();
+        let hs = self.handshake.clone();
+        let swarm = self.swarm.clone();
+        let haves = self.have_sender.subscribe();
+        let dialing = Dialing::new(&self.dialing);
+
+        let span = info_span!("peer", addrs = ?peer.addrs);
+
+        tokio::spawn(async move {
+            if let Err(e) = download_from_peer(torrent, file_sender, peer, hs, swarm, haves, dialing).await {
+                debug!("Disconnected from peer: {:#}", e);
+            }
+        }.instrument(span));
+    }
+}
+
+/// Counts a connection in `Dialer::dialing` until it's dropped.
+struct Dialing(Arc<AtomicUsize>);
+
+impl Dialing {
+    fn new(dialing: &Arc<AtomicUsize>) -> Dialing {
+        dialing.fetch_add(1, Ordering::SeqCst);
+        return Dialing(dialing.clone());
+    }
+}
+
+impl Drop for Dialing {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+
+#[tokio::test]
+async fn test_dial_pool_peer() {
+    use crate::testing::{bitfield, MockPeer, Step};
+    use crate::utils::torrents::test_torrent;
+
+    let torrent = Arc::new(Torrent { info_hash: [3; 20], ..test_torrent(16384, &[("a", 16384 * 10)]) });
+    let mock = MockPeer::start(torrent.info_hash, vec![
+        Step::Send(bitfield(&[0xff, 0xc0])),
+        Step::Expect(2),
+        Step::Sleep(Duration::from_millis(500)),
+        Step::Close,
+    ]).unwrap();
+
+    let swarm = Swarm {
+        settings: PeerSettings { max_peers: 1, ..Default::default() },
+        ..Swarm::test(Pieces::new(&torrent))
+    };
+    let SocketAddr::V4(addr) = mock.addr() else {
+        unreachable!();
+    };
+    // A peer on the internet from a tracker, and the mock on the local network found through the DHT, like the peers
+    // of a torrent added by its info hash.
+    swarm.pool.add(vec![
+        Peer::from_addr(SocketAddr::from(([203, 0, 113, 1], 6881)), PeerSource::Tracker),
+        Peer::from_addr(mock.addr(), PeerSource::Dht),
+    ], false);
+
+    let (file_sender, _files) = mpsc::channel(1);
+    let dialer = Dialer {
+        torrent: torrent.clone(),
+        file_sender,
+        handshake: Arc::new(build_peer_handshake(&torrent.info_hash, &ByteBuffer::from_bytes(b"-TR0001-testtesttest")).to_bytes()),
+        swarm: swarm.clone(),
+        have_sender: broadcast::channel(1).0,
+        dialing: Arc::default(),
+    };
+    dialer.fill();
+    assert_eq!(swarm.pool.len(), 1);
+
+    // The local peer is the one which is connected to, at its own address, and is listed with where it came from.
+    let start = Instant::now();
+    while swarm.peers.list().is_empty() && start.elapsed() < Duration::from_secs(5) {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(swarm.peers.list(), vec![PeerStatus { addr: mock.addr(), source: PeerSource::Dht, encrypted: false }]);
+
+    let received = tokio::task::spawn_blocking(move || mock.finish()).await.unwrap().unwrap();
+    assert_eq!(received.last().unwrap(), &vec![2]);
+}
+
+#[tokio::test]
+async fn test_retry_bad_piece() {
+    use std::fs;
+    use std::path::Path;
+    use crate::config::DiskConfig;
+    use crate::create::{create_torrent, CreateOptions};
+    use crate::storage::FileStorage;
+    use crate::testing::{bitfield, piece, unchoke, MockPeer, Step};
+
+    let dir = Path::new("test-files/retry-bad-piece");
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir.join("source/data")).unwrap();
+    let block = vec![7; 16384];
+    fs::write(dir.join("source/data/a.bin"), &block).unwrap();
+    let metainfo = create_torrent(&CreateOptions { path: dir.join("source/data"), piece_length: Some(16384), ..Default::default() }).unwrap();
+    let torrent = Arc::new(Torrent::from_bytes(&metainfo).unwrap());
+
+    // The peer sends a block which doesn't match the hash of the piece first, and the right one when it's asked again.
+    let mock = MockPeer::start(torrent.info_hash, vec![
+        Step::Send(bitfield(&[0x80])),
+        Step::Send(unchoke()),
+        Step::Expect(6),
+        Step::Send(piece(0, 0, &[1; 16384])),
+        Step::Expect(6),
+        Step::Send(piece(0, 0, &block)),
+        Step::Sleep(Duration::from_millis(500)),
+        Step::Close,
+    ]).unwrap();
+
+    let swarm = Swarm {
+        settings: PeerSettings { max_peers: 1, ..Default::default() },
+        disk: DiskIo::start(torrent.clone(), dir.join("download").to_string_lossy().into_owned(), FileStorage::from_torrent(&torrent), &DiskConfig::default()).unwrap(),
+        ..Swarm::test(Pieces::new(&torrent))
+    };
+    swarm.pool.add(vec![Peer::from_addr(mock.addr(), PeerSource::Manual)], false);
+    let ticks = swarm.ticks.clone();
+    let _ticker = TaskGuard(tokio::spawn(async move {
+        loop {
+            let _ = ticks.send(Tick::Second);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }));
+
+    let download = download_torrent(ByteBuffer::from_bytes(b"-TR0001-testtesttest"), torrent.clone(), swarm.clone(), mpsc::channel(1).1);
+    tokio::time::timeout(Duration::from_secs(10), download).await.unwrap().unwrap();
+    assert!(swarm.pieces.lock().unwrap().has_verified(0));
+    assert_eq!(fs::read(dir.join("download/a.bin")).unwrap(), block);
+
+    let received = tokio::task::spawn_blocking(move || mock.finish()).await.unwrap().unwrap();
+    assert_eq!(received.iter().filter(|message| message[0] == 6).count(), 2);
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+async fn download_from_peer(torrent: Arc<Torrent>, file_sender: Sender<PieceChannelPayload>, mut peer: Peer, handshake: Arc<Vec<u8>>, swarm: Swarm, haves: broadcast::Receiver<PieceUpdate>, dialing: Dialing) -> anyhow::Result<()> {
+    // Connecting blocks for as long as the peer takes to answer, which would hold up the other peers of the runtime.
+    let info_hash = torrent.info_hash;
+    let settings = swarm.settings.clone();
+    let (connected, peer) = tokio::task::spawn_blocking(move || {
+        let connected = connect_peer(&mut peer, &info_hash, &handshake, &settings);
+        return (connected, peer);
+    }).await?;
+    drop(dialing);
+    let (stream, peer_handshake) = connected?;
+    let (reader, writer) = stream.into_split()?;
+    let addr = writer.peer_addr()?;
+
+    info!(%addr, encrypted = writer.is_encrypted(), "Connected to peer");
+
+    let limiter = peer_limiter(&swarm);
+    let _listed = swarm.peers.add(PeerStatus { addr, source: peer.source, encrypted: writer.is_encrypted() }, limiter.clone());
+
+    return run_peer(&torrent, file_sender, (reader, writer), &peer_handshake, swarm, haves, limiter).await;
+}
+
+
+/// Answer a peer which connected to us with our handshake and download from it like any other peer.
+async fn answer_peer(torrent: Arc<Torrent>, file_sender: Sender<PieceChannelPayload>, peer: IncomingPeer, handshake: Arc<Vec<u8>>, swarm: Swarm, haves: broadcast::Receiver<PieceUpdate>) -> anyhow::Result<()> {
+    let IncomingPeer { stream, handshake: peer_handshake, addr } = peer;
+    let (reader, mut writer) = stream.into_split()?;
+
+    // The stream is already encrypted if the peer started with an encrypted handshake.
+    writer.write_all(&handshake).await?;
+
+    let limiter = peer_limiter(&swarm);
+    let _listed = swarm.peers.add(PeerStatus { addr, source: PeerSource::Incoming, encrypted: writer.is_encrypted() }, limiter.clone());
+
+    return run_peer(&torrent, file_sender, (reader, writer), &peer_handshake, swarm, haves, limiter).await;
+}
+
+
+/// Limiter of what a single peer sends us, within the limit of the session.
+fn peer_limiter(swarm: &Swarm) -> Arc<RateLimiter> {
+    return Arc::new(RateLimiter::with_parent(swarm.settings.peer_download_rate_limit, swarm.download_limiter.clone()));
+}
+
+
+/// Exchange messages with a peer once both handshakes are done.
+async fn run_peer(torrent: &Torrent, file_sender: Sender<PieceChannelPayload>, (mut reader, mut writer): (PeerReader, PeerWriter), peer_handshake: &[u8], swarm: Swarm, haves: broadcast::Receiver<PieceUpdate>, limiter: Arc<RateLimiter>) -> anyhow::Result<()> {
+    let mut queue: Queue = Queue::new(torrent);
+
+    let _connected = metrics::ConnectedPeer::new();
+
+    let mut message_handler = MessageHandler::new(torrent, &mut writer, file_sender, &mut queue, swarm, haves, limiter);
+
+    message_handler.handshake(peer_handshake).await?;
+    return message_handler.run(&mut reader).await;
+}
+
+
+/// Connect to a peer and exchange handshakes, trying the handshakes the encryption policy allows one after the other.
+///
+/// A peer with both IPv4 and IPv6 addresses is connected to on whichever answers first. Returns the stream along
+/// with the handshake of the peer. A peer which only takes an encrypted handshake is remembered as requiring encryption.
+pub fn connect_peer(peer: &mut Peer, info_hash: &[u8; 20], handshake: &[u8], settings: &PeerSettings) -> anyhow::Result<(PeerStream, Vec<u8>)> {
+    let addrs = settings.address_family.filter(peer.addrs.iter().copied());
+    if addrs.is_empty() {
+        anyhow::bail!("Error: The peer has no {} address", settings.address_family);
+    }
+    let modes = handshake_modes(settings.encryption, peer.crypto);
+    if modes.is_empty() {
+        anyhow::bail!("Error: The peer requires encryption, which is disabled");
+    }
+
+    let mut last_error = None;
+    for (attempt, mode) in modes.into_iter().enumerate() {
+        let result = settings.interface.connect_first(&addrs, &settings.socket).and_then(|stream| {
+            // A peer which takes the connection but never answers is given up on.
+            stream.set_read_timeout(Some(settings.handshake_timeout.unwrap_or(PEER_HANDSHAKE_TIMEOUT)))?;
+            let mut stream = match mode {
+                HandshakeMode::Plaintext => {
+                    let mut stream = PeerStream::plaintext(stream);
+                    stream.write_all(handshake)?;
+                    stream
+                }
+                HandshakeMode::Encrypted => encrypted_handshake(stream, info_hash, handshake)?,
+            };
+
+            let mut received = vec![0; 68];
+            stream.read_exact(&mut received)?;
+            if !check_handshake_msg(&mut ByteBuffer::from_bytes(&received)) {
+                anyhow::bail!("Error: The peer didn't answer with a BitTorrent handshake");
+            }
+            stream.set_read_timeout(None)?;
+
+            return Ok((stream, received));
+        });
+
+        match result {
+            Ok(connected) => {
+                if attempt > 0 && mode == HandshakeMode::Encrypted {
+                    peer.crypto = PeerCrypto::Required;
+                }
+                return Ok(connected);
+            }
+            Err(e) => {
+                debug!(?mode, "Handshake failed: {:#}", e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    return Err(last_error.unwrap());
 }
 
 
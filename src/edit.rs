@@ -0,0 +1,98 @@
+use torrenter::bencode::{self, Encoder, Value};
+
+use crate::utils::torrents::Torrent;
+
+/// Changes to make to a torrent file, anything left as None is kept as it is.
+#[derive(Debug, Clone, Default)]
+pub struct EditOptions {
+    /// Replaces `announce` and `announce-list`, an empty list removes every tracker.
+    pub trackers: Option<Vec<String>>,
+    /// Replaces the comment, an empty comment removes it.
+    pub comment: Option<String>,
+    /// Changing the private flag changes the info dictionary, so the torrent gets a new info hash.
+    pub private: Option<bool>,
+}
+
+/// Apply the edits to the bencoded torrent file and return the new file.
+///
+/// Entries which aren't edited are copied as they were encoded, so the info dictionary and its hash
+/// only change with the private flag.
+pub fn edit_torrent(metainfo: &[u8], options: &EditOptions) -> anyhow::Result<Vec<u8>> {
+    // Loading it first makes sure it's a valid torrent.
+    Torrent::from_bytes(metainfo)?;
+
+    let mut entries: Vec<(Vec<u8>, Vec<u8>)> = bencode::raw_dict_entries(metainfo)?
+        .into_iter()
+        .map(|(key, raw)| (key.to_vec(), raw.to_vec()))
+        .collect();
+
+    if let Some(trackers) = &options.trackers {
+        entries.retain(|(key, _)| key != b"announce" && key != b"announce-list");
+
+        if let Some(first) = trackers.first() {
+            entries.push((b"announce".to_vec(), Value::Bytes(first.as_bytes().to_vec()).encode()));
+        }
+        if trackers.len() > 1 {
+            let tiers = trackers.iter().map(|tracker| Value::List(vec![Value::Bytes(tracker.as_bytes().to_vec())])).collect();
+            entries.push((b"announce-list".to_vec(), Value::List(tiers).encode()));
+        }
+    }
+
+    if let Some(comment) = &options.comment {
+        entries.retain(|(key, _)| key != b"comment");
+
+        if !comment.is_empty() {
+            entries.push((b"comment".to_vec(), Value::Bytes(comment.as_bytes().to_vec()).encode()));
+        }
+    }
+
+    if let Some(private) = options.private {
+        let (_, raw_info) = entries.iter_mut().find(|(key, _)| key == b"info")
+            .ok_or_else(|| anyhow::anyhow!("Error: The torrent has no info dictionary"))?;
+
+        let mut info = Value::decode(raw_info)?;
+        let dict = info.as_dict_mut().ok_or_else(|| anyhow::anyhow!("Error: The info isn't a dictionary"))?;
+
+        if private {
+            dict.insert(b"private".to_vec(), Value::Int(1));
+        } else {
+            dict.remove(&b"private".to_vec());
+        }
+        *raw_info = info.encode();
+    }
+
+    // Dictionary keys have to be sorted.
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut encoder = Encoder::new();
+    encoder.begin_dict();
+    for (key, raw) in &entries {
+        encoder.bytes(key).raw(raw);
+    }
+    encoder.end();
+
+    return Ok(encoder.finish());
+}
+
+
+#[test]
+fn test_edit_torrent() {
+    let metainfo = std::fs::read("test-tor.torrent").unwrap();
+    let original = Torrent::from_bytes(&metainfo).unwrap();
+
+    let edited = edit_torrent(&metainfo, &EditOptions {
+        trackers: Some(vec![String::from("udp://a.example.com:80"), String::from("udp://b.example.com:80")]),
+        comment: Some(String::from("Edited")),
+        ..Default::default()
+    }).unwrap();
+
+    let torrent = Torrent::from_bytes(&edited).unwrap();
+    assert_eq!(torrent.info_hash, original.info_hash);
+    assert_eq!(torrent.trackers(), vec!["udp://a.example.com:80", "udp://b.example.com:80"]);
+
+    // Making it private gives a new info hash.
+    let private = edit_torrent(&edited, &EditOptions { private: Some(true), ..Default::default() }).unwrap();
+    let torrent = Torrent::from_bytes(&private).unwrap();
+    assert_eq!(torrent.info.private, Some(1));
+    assert_ne!(torrent.info_hash, original.info_hash);
+}
@@ -0,0 +1,550 @@
+//! Message stream encryption (MSE), the obfuscated handshake most clients support so connections to peers can't be
+//! told apart from other traffic by their first bytes.
+//!
+//! Both sides agree on a secret with Diffie-Hellman, the rest of the connection is RC4 encrypted with keys derived
+//! from the secret and the info hash.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use crypto::digest::Digest;
+use crypto::rc4::Rc4;
+use crypto::sha1::Sha1;
+use crypto::symmetriccipher::SynchronousStreamCipher;
+use num_bigint::BigUint;
+use rand::Rng;
+use serde_derive::Deserialize;
+use socket2::SockRef;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+/// Prime of the Diffie-Hellman exchange, the generator is 2.
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+
+/// Length of the public keys, padded with leading zeros.
+const KEY_LEN: usize = 96;
+
+/// Most random bytes sent after a public key or within the encrypted handshake.
+const MAX_PAD: usize = 512;
+
+/// Verification constant, encrypted so each side can check the other derived the same keys.
+const VC: [u8; 8] = [0; 8];
+
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+/// Whether connections to peers are encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncryptionPolicy {
+    /// Plaintext only, peers requiring encryption are left out.
+    Disabled,
+    /// Plaintext unless the peer is known to support encryption, retrying with the other mode when a handshake fails.
+    #[default]
+    Enabled,
+    /// Encrypted first, plaintext when that fails.
+    Preferred,
+    /// Encrypted only.
+    Required,
+}
+
+/// What we know of a peer's support for encryption.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PeerCrypto {
+    #[default]
+    Unknown,
+    Supported,
+    /// The peer only accepts encrypted connections, found out when a plaintext handshake fails but an encrypted one
+    /// works.
+    Required,
+}
+
+impl PeerCrypto {
+    /// From the byte a tracker gives for the peer in `crypto_flags`, 1 when it supports encryption.
+    pub fn from_tracker_flag(flag: u8) -> PeerCrypto {
+        return if flag == 1 { PeerCrypto::Supported } else { PeerCrypto::Unknown };
+    }
+
+    /// From the flags of a peer added through peer exchange, 0x01 when it prefers encryption.
+    pub fn from_pex_flags(flags: u8) -> PeerCrypto {
+        return if flags & 0x01 != 0 { PeerCrypto::Supported } else { PeerCrypto::Unknown };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeMode {
+    Plaintext,
+    Encrypted,
+}
+
+/// Handshakes to try with a peer, each one after the one before fails. Empty when the policy and the peer can't
+/// agree on any.
+pub fn handshake_modes(policy: EncryptionPolicy, crypto: PeerCrypto) -> Vec<HandshakeMode> {
+    use HandshakeMode::{Encrypted, Plaintext};
+
+    return match (policy, crypto) {
+        (EncryptionPolicy::Disabled, PeerCrypto::Required) => vec![],
+        (EncryptionPolicy::Disabled, _) => vec![Plaintext],
+        (EncryptionPolicy::Required, _) | (_, PeerCrypto::Required) => vec![Encrypted],
+        (EncryptionPolicy::Enabled, PeerCrypto::Unknown) => vec![Plaintext, Encrypted],
+        (EncryptionPolicy::Enabled, PeerCrypto::Supported) | (EncryptionPolicy::Preferred, _) => vec![Encrypted, Plaintext],
+    };
+}
+
+
+#[test]
+fn test_handshake_modes() {
+    use HandshakeMode::{Encrypted, Plaintext};
+
+    assert_eq!(handshake_modes(EncryptionPolicy::Enabled, PeerCrypto::Unknown), vec![Plaintext, Encrypted]);
+    assert_eq!(handshake_modes(EncryptionPolicy::Enabled, PeerCrypto::from_tracker_flag(1)), vec![Encrypted, Plaintext]);
+    assert_eq!(handshake_modes(EncryptionPolicy::Enabled, PeerCrypto::Required), vec![Encrypted]);
+    assert_eq!(handshake_modes(EncryptionPolicy::Preferred, PeerCrypto::Unknown), vec![Encrypted, Plaintext]);
+    assert_eq!(handshake_modes(EncryptionPolicy::Required, PeerCrypto::Unknown), vec![Encrypted]);
+    assert_eq!(handshake_modes(EncryptionPolicy::Disabled, PeerCrypto::from_pex_flags(0x01)), vec![Plaintext]);
+    assert!(handshake_modes(EncryptionPolicy::Disabled, PeerCrypto::Required).is_empty());
+}
+
+
+/// Connection to a peer, RC4 encrypted after an encrypted handshake.
+pub struct PeerStream {
+    stream: TcpStream,
+    ciphers: Option<(Rc4, Rc4)>,
+}
+
+impl PeerStream {
+    pub fn plaintext(stream: TcpStream) -> PeerStream {
+        return PeerStream { stream, ciphers: None };
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        return self.ciphers.is_some();
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        return self.stream.shutdown(how);
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        return self.stream.peer_addr();
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        return self.stream.set_read_timeout(timeout);
+    }
+
+    /// Move the stream onto the runtime once the handshakes are done, split so messages can be read while others
+    /// are sent.
+    pub fn into_split(self) -> io::Result<(PeerReader, PeerWriter)> {
+        self.stream.set_read_timeout(None)?;
+        self.stream.set_nonblocking(true)?;
+        let (reader, writer) = tokio::net::TcpStream::from_std(self.stream)?.into_split();
+
+        let (encrypt, decrypt) = match self.ciphers {
+            Some((encrypt, decrypt)) => (Some(encrypt), Some(decrypt)),
+            None => (None, None),
+        };
+        return Ok((PeerReader { stream: reader, decrypt, buffer: Vec::new() }, PeerWriter { stream: writer, encrypt }));
+    }
+}
+
+/// Reading half of a `PeerStream` on the runtime.
+pub struct PeerReader {
+    stream: OwnedReadHalf,
+    decrypt: Option<Rc4>,
+    /// Decrypted bytes of the messages which haven't fully arrived yet.
+    buffer: Vec<u8>,
+}
+
+impl PeerReader {
+    /// Read the next length prefixed message along with its length, skipping keep-alives.
+    ///
+    /// A message longer than `max_len` is an `InvalidData` error. Nothing is lost when the read is cancelled, so it
+    /// can be waited on along with other things.
+    pub async fn read_message(&mut self, max_len: usize) -> io::Result<Vec<u8>> {
+        loop {
+            while self.buffer.len() >= 4 {
+                let len = u32::from_be_bytes([self.buffer[0], self.buffer[1], self.buffer[2], self.buffer[3]]) as usize;
+                if len == 0 {
+                    self.buffer.drain(..4);
+                    continue;
+                }
+                if len > max_len {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("The peer sent a message of {} bytes", len)));
+                }
+                if self.buffer.len() < 4 + len {
+                    break;
+                }
+                return Ok(self.buffer.drain(..4 + len).collect());
+            }
+
+            let mut buf = [0; 16 * 1024];
+            let len = self.stream.read(&mut buf).await?;
+            if len == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            match &mut self.decrypt {
+                Some(decrypt) => self.buffer.extend(apply(decrypt, &buf[..len])),
+                None => self.buffer.extend_from_slice(&buf[..len]),
+            }
+        }
+    }
+}
+
+/// Writing half of a `PeerStream` on the runtime.
+pub struct PeerWriter {
+    stream: OwnedWriteHalf,
+    encrypt: Option<Rc4>,
+}
+
+impl PeerWriter {
+    pub fn is_encrypted(&self) -> bool {
+        return self.encrypt.is_some();
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        return self.stream.peer_addr();
+    }
+
+    /// Shut down the connection, the reading half sees the end of the stream.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        return SockRef::from(self.stream.as_ref()).shutdown(how);
+    }
+
+    /// Send the whole buffer, it must not be cancelled as the cipher can't take back what it encrypted.
+    pub async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        return match &mut self.encrypt {
+            Some(encrypt) => self.stream.write_all(&apply(encrypt, buf)).await,
+            None => self.stream.write_all(buf).await,
+        };
+    }
+}
+
+impl Read for PeerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.stream.read(buf)?;
+        if let Some((_, decrypt)) = &mut self.ciphers {
+            let encrypted = buf[..len].to_vec();
+            decrypt.process(&encrypted, &mut buf[..len]);
+        }
+        return Ok(len);
+    }
+}
+
+impl Write for PeerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.ciphers {
+            Some((encrypt, _)) => {
+                // The whole buffer has to go out, the cipher can't take back what it encrypted.
+                let mut encrypted = vec![0; buf.len()];
+                encrypt.process(buf, &mut encrypted);
+                self.stream.write_all(&encrypted)?;
+                return Ok(buf.len());
+            }
+            None => return self.stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.stream.flush();
+    }
+}
+
+/// Run the encrypted handshake as the side opening the connection, sending `initial` (our BitTorrent handshake)
+/// along with it.
+pub fn encrypted_handshake(mut stream: TcpStream, info_hash: &[u8; 20], initial: &[u8]) -> anyhow::Result<PeerStream> {
+    let (private_key, public_key) = generate_keys();
+    stream.write_all(&[public_key, random_pad()].concat())?;
+
+    let mut their_key = [0; KEY_LEN];
+    stream.read_exact(&mut their_key)?;
+    let secret = shared_secret(&private_key, &their_key);
+
+    let mut encrypt = cipher(hash(&[b"keyA", &secret, info_hash]));
+    let mut decrypt = cipher(hash(&[b"keyB", &secret, info_hash]));
+
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    message.extend(xor(&hash(&[b"req2", info_hash]), &hash(&[b"req3", &secret])));
+
+    let mut handshake = VC.to_vec();
+    handshake.extend(CRYPTO_RC4.to_be_bytes());
+    handshake.extend(0u16.to_be_bytes());
+    handshake.extend((initial.len() as u16).to_be_bytes());
+    handshake.extend(initial);
+    message.extend(apply(&mut encrypt, &handshake));
+    stream.write_all(&message)?;
+
+    // Their padding comes before the encrypted verification constant, which tells where it ends.
+    let expected = apply(&mut decrypt, &VC);
+    let mut window = Vec::new();
+    while !window.ends_with(&expected) {
+        if window.len() >= MAX_PAD + VC.len() {
+            anyhow::bail!("Error: The peer didn't answer the encrypted handshake");
+        }
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte)?;
+        window.push(byte[0]);
+    }
+
+    let mut select = [0; 6];
+    stream.read_exact(&mut select)?;
+    let select = apply(&mut decrypt, &select);
+    let crypto_select = u32::from_be_bytes([select[0], select[1], select[2], select[3]]);
+
+    let mut pad = vec![0; u16::from_be_bytes([select[4], select[5]]) as usize];
+    if pad.len() > MAX_PAD {
+        anyhow::bail!("Error: The peer sent {} bytes of padding", pad.len());
+    }
+    stream.read_exact(&mut pad)?;
+    apply(&mut decrypt, &pad);
+
+    return match crypto_select {
+        CRYPTO_RC4 => Ok(PeerStream { stream, ciphers: Some((encrypt, decrypt)) }),
+        CRYPTO_PLAINTEXT => anyhow::bail!("Error: The peer picked plaintext after an encrypted handshake"),
+        other => anyhow::bail!("Error: The peer picked an unknown encryption {:#x}", other),
+    };
+}
+
+/// Read the handshake of a peer which connected to us, telling a plaintext BitTorrent handshake from the start of
+/// an encrypted one by its first bytes.
+///
+/// `info_hashes` are the torrents we can take peers for, an encrypted handshake only gives a hash of the info hash.
+/// Returns the stream along with the BitTorrent handshake of the peer, which we still have to answer.
+pub fn accept_handshake(mut stream: TcpStream, info_hashes: &[[u8; 20]], policy: EncryptionPolicy) -> anyhow::Result<(PeerStream, Vec<u8>)> {
+    let mut start = [0; 20];
+    stream.read_exact(&mut start)?;
+
+    if start[0] == 19 && &start[1..20] == b"BitTorrent protocol" {
+        if policy == EncryptionPolicy::Required {
+            anyhow::bail!("Error: Plaintext handshakes aren't accepted");
+        }
+
+        let mut rest = [0; 48];
+        stream.read_exact(&mut rest)?;
+        return Ok((PeerStream::plaintext(stream), [&start[..], &rest[..]].concat()));
+    }
+
+    if policy == EncryptionPolicy::Disabled {
+        anyhow::bail!("Error: Encrypted handshakes aren't accepted");
+    }
+
+    // Without the protocol name this is the public key of an encrypted handshake.
+    let mut their_key = [0; KEY_LEN];
+    their_key[..20].copy_from_slice(&start);
+    stream.read_exact(&mut their_key[20..])?;
+
+    let (private_key, public_key) = generate_keys();
+    stream.write_all(&[public_key, random_pad()].concat())?;
+    let secret = shared_secret(&private_key, &their_key);
+
+    // Their padding ends with the first hash.
+    let req1 = hash(&[b"req1", &secret]);
+    let mut window = Vec::new();
+    while !window.ends_with(&req1) {
+        if window.len() >= MAX_PAD + req1.len() {
+            anyhow::bail!("Error: The encrypted handshake has no valid request");
+        }
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte)?;
+        window.push(byte[0]);
+    }
+
+    let mut skey_hash = [0; 20];
+    stream.read_exact(&mut skey_hash)?;
+    let skey_hash = xor(&skey_hash, &hash(&[b"req3", &secret]));
+    let info_hash = info_hashes.iter().find(|info_hash| hash(&[b"req2", &info_hash[..]])[..] == skey_hash[..])
+        .ok_or_else(|| anyhow::anyhow!("Error: The encrypted handshake is for a torrent we don't have"))?;
+
+    let mut decrypt = cipher(hash(&[b"keyA", &secret, info_hash]));
+    let mut encrypt = cipher(hash(&[b"keyB", &secret, info_hash]));
+
+    let mut provide = [0; 14];
+    stream.read_exact(&mut provide)?;
+    let provide = apply(&mut decrypt, &provide);
+    if provide[..8] != VC {
+        anyhow::bail!("Error: The encrypted handshake has the wrong verification constant");
+    }
+    let crypto_provide = u32::from_be_bytes([provide[8], provide[9], provide[10], provide[11]]);
+
+    let mut pad = vec![0; u16::from_be_bytes([provide[12], provide[13]]) as usize];
+    if pad.len() > MAX_PAD {
+        anyhow::bail!("Error: The peer sent {} bytes of padding", pad.len());
+    }
+    stream.read_exact(&mut pad)?;
+    apply(&mut decrypt, &pad);
+
+    let mut initial_len = [0; 2];
+    stream.read_exact(&mut initial_len)?;
+    let initial_len = apply(&mut decrypt, &initial_len);
+    let mut initial = vec![0; u16::from_be_bytes([initial_len[0], initial_len[1]]) as usize];
+    stream.read_exact(&mut initial)?;
+    let initial = apply(&mut decrypt, &initial);
+
+    let crypto_select = if crypto_provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if crypto_provide & CRYPTO_PLAINTEXT != 0 && policy != EncryptionPolicy::Required {
+        CRYPTO_PLAINTEXT
+    } else {
+        anyhow::bail!("Error: The peer doesn't provide any encryption we support");
+    };
+
+    let mut select = VC.to_vec();
+    select.extend(crypto_select.to_be_bytes());
+    select.extend(0u16.to_be_bytes());
+    stream.write_all(&apply(&mut encrypt, &select))?;
+
+    let mut stream = match crypto_select {
+        CRYPTO_RC4 => PeerStream { stream, ciphers: Some((encrypt, decrypt)) },
+        _ => PeerStream::plaintext(stream),
+    };
+
+    // The BitTorrent handshake is usually sent along with the encrypted one, it follows it otherwise.
+    let mut handshake = initial;
+    if handshake.len() < 68 {
+        let mut rest = vec![0; 68 - handshake.len()];
+        stream.read_exact(&mut rest)?;
+        handshake.extend(rest);
+    }
+
+    return Ok((stream, handshake));
+}
+
+
+#[test]
+fn test_accept_handshake() {
+    use std::net::TcpListener;
+
+    let info_hash = [7; 20];
+    let handshake: Vec<u8> = [&[19][..], b"BitTorrent protocol", &[0; 8], &info_hash, &[1; 20]].concat();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    for mode in [HandshakeMode::Plaintext, HandshakeMode::Encrypted] {
+        let initial = handshake.clone();
+        let peer = std::thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            let mut stream = match mode {
+                HandshakeMode::Plaintext => {
+                    let mut stream = PeerStream::plaintext(stream);
+                    stream.write_all(&initial).unwrap();
+                    stream
+                }
+                HandshakeMode::Encrypted => encrypted_handshake(stream, &info_hash, &initial).unwrap(),
+            };
+
+            let mut reply = [0; 5];
+            stream.read_exact(&mut reply).unwrap();
+            stream.write_all(b"world").unwrap();
+            return (stream.is_encrypted(), reply);
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let (mut stream, received) = accept_handshake(stream, &[[1; 20], info_hash], EncryptionPolicy::Enabled).unwrap();
+        assert_eq!(received, handshake);
+
+        // Both sides keep talking in the same mode.
+        stream.write_all(b"hello").unwrap();
+        let mut reply = [0; 5];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"world");
+        assert_eq!(peer.join().unwrap(), (mode == HandshakeMode::Encrypted, *b"hello"));
+        assert_eq!(stream.is_encrypted(), mode == HandshakeMode::Encrypted);
+    }
+
+    // A plaintext handshake is turned down when encryption is required.
+    let peer = std::thread::spawn(move || TcpStream::connect(addr).unwrap().write_all(&handshake).unwrap());
+    let (stream, _) = listener.accept().unwrap();
+    assert!(accept_handshake(stream, &[info_hash], EncryptionPolicy::Required).is_err());
+    peer.join().unwrap();
+}
+
+
+#[tokio::test]
+async fn test_split_stream() {
+    use std::net::TcpListener;
+
+    let info_hash = [7; 20];
+    let handshake: Vec<u8> = [&[19][..], b"BitTorrent protocol", &[0; 8], &info_hash, &[1; 20]].concat();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let initial = handshake.clone();
+    let peer = std::thread::spawn(move || {
+        let mut stream = encrypted_handshake(TcpStream::connect(addr).unwrap(), &info_hash, &initial).unwrap();
+
+        // A keep-alive, then a message split over two writes.
+        stream.write_all(&[0, 0, 0, 0, 0, 0, 0, 3, 4]).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        stream.write_all(&[1, 2, 0, 0, 0, 9]).unwrap();
+
+        let mut reply = [0; 5];
+        stream.read_exact(&mut reply).unwrap();
+        return reply;
+    });
+
+    let (stream, _) = listener.accept().unwrap();
+    let (stream, _) = accept_handshake(stream, &[info_hash], EncryptionPolicy::Enabled).unwrap();
+    let (mut reader, mut writer) = stream.into_split().unwrap();
+    assert!(writer.is_encrypted());
+
+    assert_eq!(reader.read_message(16).await.unwrap(), vec![0, 0, 0, 3, 4, 1, 2]);
+    writer.write_all(b"hello").await.unwrap();
+    assert_eq!(&peer.join().unwrap(), b"hello");
+
+    // A message longer than allowed isn't waited on.
+    assert_eq!(reader.read_message(8).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
+
+/// A 160 bit private key and the public key sent to the peer.
+fn generate_keys() -> (BigUint, Vec<u8>) {
+    let private_key = BigUint::from_bytes_be(&rand::thread_rng().gen::<[u8; 20]>());
+    let public_key = BigUint::from(2u32).modpow(&private_key, &prime());
+    return (private_key, pad_key(public_key.to_bytes_be()));
+}
+
+fn shared_secret(private_key: &BigUint, their_key: &[u8]) -> Vec<u8> {
+    let secret = BigUint::from_bytes_be(their_key).modpow(private_key, &prime());
+    return pad_key(secret.to_bytes_be());
+}
+
+fn prime() -> BigUint {
+    return BigUint::parse_bytes(PRIME.as_bytes(), 16).unwrap();
+}
+
+fn pad_key(key: Vec<u8>) -> Vec<u8> {
+    return [vec![0; KEY_LEN - key.len()], key].concat();
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    return (0..rng.gen_range(0, MAX_PAD)).map(|_| rng.gen()).collect();
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.input(part);
+    }
+
+    let mut hash = [0; 20];
+    hasher.result(&mut hash);
+    return hash;
+}
+
+fn xor(a: &[u8; 20], b: &[u8; 20]) -> Vec<u8> {
+    return a.iter().zip(b.iter()).map(|(a, b)| a ^ b).collect();
+}
+
+/// RC4 with the first 1024 bytes of its key stream thrown away.
+fn cipher(key: [u8; 20]) -> Rc4 {
+    let mut rc4 = Rc4::new(&key);
+    apply(&mut rc4, &[0; 1024]);
+    return rc4;
+}
+
+fn apply(rc4: &mut Rc4, data: &[u8]) -> Vec<u8> {
+    let mut output = vec![0; data.len()];
+    rc4.process(data, &mut output);
+    return output;
+}
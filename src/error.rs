@@ -0,0 +1,40 @@
+//! Errors of the client which callers can tell apart, so a bad peer or a bad file fails on its own instead of
+//! taking the whole process down.
+
+use std::io;
+
+use thiserror::Error;
+
+use crate::tracker::TrackerError;
+
+#[derive(Debug, Error)]
+pub enum TorrenterError {
+    #[error("Error: {0}")]
+    Io(#[from] io::Error),
+    /// Data which isn't valid bencode, or not what it should decode to, like a broken torrent file.
+    #[error("Error: Invalid bencode: {0}")]
+    Bencode(String),
+    #[error(transparent)]
+    Tracker(#[from] TrackerError),
+    /// A message which breaks the peer wire protocol, sent or received.
+    #[error("Error: Protocol violation: {0}")]
+    Protocol(String),
+    /// Data on disk which can't be used, like a file which is shorter than it should be.
+    #[error("Error: Storage: {0}")]
+    Storage(String),
+}
+
+pub type Result<T> = std::result::Result<T, TorrenterError>;
+
+
+#[test]
+fn test_torrenter_error() {
+    let error: anyhow::Error = TorrenterError::Protocol(String::from("a request needs a length")).into();
+    assert_eq!(error.to_string(), "Error: Protocol violation: a request needs a length");
+
+    let error = TorrenterError::from(TrackerError::Failure(String::from("unregistered torrent")));
+    assert_eq!(error.to_string(), "Error: The tracker refused the announce: unregistered torrent");
+
+    let error = TorrenterError::from(io::Error::new(io::ErrorKind::NotFound, "missing.torrent"));
+    assert!(matches!(error, TorrenterError::Io(_)));
+}
@@ -0,0 +1,57 @@
+use tokio::sync::broadcast;
+
+/// Amount of events a slow subscriber can fall behind before missing some.
+pub const EVENT_CHANNEL_SIZE: usize = 64;
+
+pub type EventSender = broadcast::Sender<Event>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Added,
+    Completed,
+    Error,
+    TrackerFailure,
+}
+
+impl EventKind {
+    /// Name of the event as used in the config and in webhook payloads.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Added => "added",
+            EventKind::Completed => "completed",
+            EventKind::Error => "error",
+            EventKind::TrackerFailure => "tracker_failure",
+        }
+    }
+}
+
+/// Something that happened to a torrent in the session.
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub kind: EventKind,
+    pub info_hash: [u8; 20],
+    pub name: String,
+    /// Details of what went wrong for error events.
+    pub message: Option<String>,
+}
+
+impl Event {
+    pub fn new(kind: EventKind, info_hash: [u8; 20], name: &str) -> Event {
+        Event {
+            kind,
+            info_hash,
+            name: name.to_owned(),
+            message: None,
+        }
+    }
+
+    pub fn with_message(mut self, message: String) -> Event {
+        self.message = Some(message);
+        return self;
+    }
+}
+
+/// Send an event to every subscriber, it's fine for there to be none.
+pub fn emit(sender: &EventSender, event: Event) {
+    let _ = sender.send(event);
+}
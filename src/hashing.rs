@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use sha1::{Digest, Sha1};
+
+use crate::cache::WriteRun;
+use crate::create::hash_piece;
+
+/// Threads pieces are hashed on, one for each CPU core, so hashing big pieces doesn't hold up the disk threads.
+static POOL: OnceLock<ThreadPool> = OnceLock::new();
+
+fn pool() -> &'static ThreadPool {
+    return POOL.get_or_init(|| {
+        ThreadPoolBuilder::new()
+            .thread_name(|index| format!("hash-{}", index))
+            .build()
+            .expect("Unable to start the hashing threads")
+    });
+}
+
+/// Hash a piece on the hashing threads, `done` is called with its SHA-1 on one of them.
+pub fn hash_in_pool(piece: Vec<u8>, done: impl FnOnce([u8; 20]) + Send + 'static) {
+    pool().spawn(move || done(hash_piece(&piece)));
+}
+
+/// SHA-1 of the pieces being downloaded, fed with their blocks as they arrive so checking a piece once its last block
+/// is in doesn't have to read it back.
+///
+/// A piece is hashed from its start, blocks after a gap are fed once the gap is filled if they're still in the write
+/// cache. Otherwise the piece is hashed from the disk when it's checked.
+#[derive(Debug)]
+pub(crate) struct PieceHashes {
+    piece_length: u64,
+    torrent_size: u64,
+    /// Hash of the start of each piece, with how many of its bytes it has taken in.
+    pieces: HashMap<u64, (Sha1, u64)>,
+}
+
+impl PieceHashes {
+    pub(crate) fn new(piece_length: u64, torrent_size: u64) -> PieceHashes {
+        return PieceHashes { piece_length, torrent_size, pieces: HashMap::new() };
+    }
+
+    /// Feed the pieces of a run with its bytes which come right after what they have hashed.
+    pub(crate) fn feed(&mut self, run: &WriteRun) {
+        if run.length() == 0 {
+            return;
+        }
+
+        let end = run.offset() + run.length();
+        for index in run.offset() / self.piece_length..=(end - 1) / self.piece_length {
+            let piece_start = index * self.piece_length;
+            let piece_end = (piece_start + self.piece_length).min(self.torrent_size);
+
+            let (hasher, hashed) = self.pieces.entry(index).or_insert_with(|| (Sha1::new(), 0));
+            let position = piece_start + *hashed;
+            if position < run.offset() || position >= end.min(piece_end) {
+                continue;
+            }
+
+            let len = end.min(piece_end) - position;
+            for slice in run.slices((position - run.offset()) as usize, len as usize) {
+                hasher.update(slice);
+            }
+            *hashed += len;
+        }
+    }
+
+    /// Forget what was hashed of the pieces the `length` bytes at `offset` are in, they're hashed from the disk instead.
+    pub(crate) fn forget(&mut self, offset: u64, length: u64) {
+        if length == 0 {
+            return;
+        }
+        for index in offset / self.piece_length..=(offset + length - 1) / self.piece_length {
+            self.pieces.remove(&index);
+        }
+    }
+
+    /// The hash of a piece when all of it has been fed, forgetting the piece either way.
+    pub(crate) fn take(&mut self, index: u64) -> Option<[u8; 20]> {
+        let piece_length = self.piece_length.min(self.torrent_size - index * self.piece_length);
+        return match self.pieces.remove(&index) {
+            Some((hasher, hashed)) if hashed == piece_length => Some(hasher.finalize().into()),
+            _ => None,
+        };
+    }
+}
+
+
+#[test]
+fn test_hash_in_pool() {
+    use std::sync::mpsc;
+
+    let (sender, receiver) = mpsc::channel();
+    for index in 0..8u8 {
+        let sender = sender.clone();
+        hash_in_pool(vec![index; 16384], move |hash| sender.send((index, hash)).unwrap());
+    }
+
+    let mut hashes: Vec<(u8, [u8; 20])> = receiver.iter().take(8).collect();
+    hashes.sort();
+    assert!(hashes.iter().all(|&(index, hash)| hash == hash_piece(&vec![index; 16384])));
+}
+
+
+#[test]
+fn test_piece_hashes() {
+    use crate::message_handlers::PieceChannelPayload;
+
+    let data: Vec<u8> = (0..50).collect();
+    let run = |offset: usize, len: usize| WriteRun::from(PieceChannelPayload { offset: offset as u64, block: data[offset..offset + len].to_vec() });
+    let mut hashes = PieceHashes::new(20, 50);
+
+    // In order, the first piece is hashed as its blocks arrive.
+    hashes.feed(&run(0, 10));
+    hashes.feed(&run(10, 10));
+    assert_eq!(hashes.take(0), Some(hash_piece(&data[..20])));
+
+    // A block after a gap waits for a run covering the gap.
+    hashes.feed(&run(30, 10));
+    hashes.feed(&run(20, 20));
+    assert_eq!(hashes.take(1), Some(hash_piece(&data[20..40])));
+
+    // The last piece is shorter, and one with a gap left can't be taken.
+    hashes.feed(&run(45, 5));
+    assert_eq!(hashes.take(2), None);
+    hashes.feed(&run(40, 10));
+    assert_eq!(hashes.take(2), Some(hash_piece(&data[40..])));
+}
+
+
+/// Compare the speed of piece hashing with the SHA-1 of rust-crypto, run with
+/// `cargo test --release bench_hash_piece -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_hash_piece() {
+    use std::time::Instant;
+    use crypto::digest::Digest;
+
+    let piece = vec![0xab; 4 * 1024 * 1024];
+    let rounds = 16;
+
+    let start = Instant::now();
+    for _ in 0..rounds {
+        std::hint::black_box(hash_piece(&piece));
+    }
+    let sha1_time = start.elapsed();
+
+    let start = Instant::now();
+    for _ in 0..rounds {
+        let mut hasher = crypto::sha1::Sha1::new();
+        hasher.input(&piece);
+        let mut hash = [0; 20];
+        hasher.result(&mut hash);
+        std::hint::black_box(hash);
+    }
+    let crypto_time = start.elapsed();
+
+    let throughput = |time: std::time::Duration| (piece.len() * rounds) as f64 / time.as_secs_f64() / 1024.0 / 1024.0;
+    println!("sha1: {:.0} MiB/s, rust-crypto: {:.0} MiB/s", throughput(sha1_time), throughput(crypto_time));
+    assert!(sha1_time <= crypto_time, "Piece hashing is slower than rust-crypto");
+}
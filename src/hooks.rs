@@ -0,0 +1,62 @@
+use std::path::Path;
+
+use tokio::process::Command;
+use tracing::{error, warn};
+
+use crate::utils::to_hex;
+
+/// Run the user's on-complete command in the background once a torrent has finished downloading.
+///
+/// The command is run through the shell with the following environment variables:
+///
+///     TORRENTER_NAME: name of the torrent
+///     TORRENTER_INFO_HASH: hex encoded info hash
+///     TORRENTER_SAVE_PATH: directory the torrent was downloaded into
+///     TORRENTER_CONTENT_PATH: path of the downloaded data within the save path
+pub fn run_on_complete(command: &str, name: &str, info_hash: &[u8; 20], save_path: &Path) {
+    let mut cmd = shell_command(command);
+    cmd.envs(build_env(name, info_hash, save_path));
+
+    let command = command.to_owned();
+    tokio::spawn(async move {
+        match cmd.status().await {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!("On-complete command {:?} exited with {}", command, status),
+            Err(e) => error!("Unable to run on-complete command {:?}: {}", command, e),
+        }
+    });
+}
+
+#[cfg(unix)]
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    return cmd;
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    return cmd;
+}
+
+fn build_env(name: &str, info_hash: &[u8; 20], save_path: &Path) -> Vec<(&'static str, String)> {
+    return vec![
+        ("TORRENTER_NAME", name.to_owned()),
+        ("TORRENTER_INFO_HASH", to_hex(info_hash)),
+        ("TORRENTER_SAVE_PATH", save_path.to_string_lossy().into_owned()),
+        ("TORRENTER_CONTENT_PATH", save_path.join(name).to_string_lossy().into_owned()),
+    ];
+}
+
+
+#[test]
+fn test_build_env() {
+    let env = build_env("Test torrent", &[0xab; 20], Path::new("downloads"));
+
+    assert_eq!(env[0], ("TORRENTER_NAME", String::from("Test torrent")));
+    assert_eq!(env[1], ("TORRENTER_INFO_HASH", "ab".repeat(20)));
+    assert_eq!(env[2], ("TORRENTER_SAVE_PATH", String::from("downloads")));
+    assert_eq!(env[3], ("TORRENTER_CONTENT_PATH", String::from("downloads/Test torrent")));
+}
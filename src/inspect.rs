@@ -0,0 +1,209 @@
+use serde_derive::Serialize;
+
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::dht::Dht;
+use crate::dns::{DnsCache, DNS_TTL};
+use crate::tracker::{self, Scrape};
+use crate::utils::to_hex;
+use crate::utils::torrents::Torrent;
+
+/// Everything `torrenter inspect` shows about a torrent.
+#[derive(Debug, Serialize)]
+pub struct Inspection {
+    name: String,
+    info_hash: String,
+    info_hash_v2: Option<String>,
+    piece_length: u64,
+    pieces: u64,
+    size: u64,
+    private: bool,
+    trackers: Vec<String>,
+    web_seeds: Vec<String>,
+    creation_date: Option<i64>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    files: Vec<InspectedFile>,
+    /// What the trackers answered to a scrape, only with `--scrape`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    scrapes: Vec<TrackerScrape>,
+}
+
+#[derive(Debug, Serialize)]
+struct TrackerScrape {
+    url: String,
+    seeders: Option<u64>,
+    leechers: Option<u64>,
+    downloads: Option<u64>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct InspectedFile {
+    path: String,
+    length: u64,
+    /// Path in the torrent file when it had to be changed to be valid on disk.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_path: Option<String>,
+}
+
+pub fn inspect(torrent: &Torrent) -> Inspection {
+    let original_path = |file: Option<usize>| torrent.renamed_paths.iter()
+        .find(|renamed| renamed.file == file)
+        .map(|renamed| renamed.original.join("/"));
+
+    let files = match &torrent.info.files {
+        Some(files) => files.iter().enumerate()
+            .filter(|(_, f)| !f.is_pad())
+            .map(|(i, f)| InspectedFile { path: f.path.join("/"), length: f.length, original_path: original_path(Some(i)) })
+            .collect(),
+        None => vec![InspectedFile { path: torrent.info.name.clone(), length: torrent.size, original_path: original_path(None) }],
+    };
+
+    Inspection {
+        name: torrent.info.name.clone(),
+        info_hash: to_hex(&torrent.info_hash),
+        info_hash_v2: torrent.info_hash_v2.as_ref().map(|hash| to_hex(hash)),
+        piece_length: torrent.info.piece_length,
+        pieces: torrent.info.pieces.len() as u64 / 20,
+        size: torrent.content_size(),
+        private: torrent.info.private == Some(1),
+        trackers: torrent.trackers(),
+        web_seeds: torrent.web_seeds(),
+        creation_date: torrent.creation_date,
+        comment: torrent.comment.clone(),
+        created_by: torrent.created_by.clone(),
+        files,
+        scrapes: Vec::new(),
+    }
+}
+
+impl Inspection {
+    /// Ask every tracker of the torrent how many peers it has, one after the other.
+    pub async fn scrape_trackers(&mut self, torrent: &Torrent, config: &Config) -> anyhow::Result<()> {
+        let client = config.http_client()?;
+        let interface = config.interface();
+        let dns = DnsCache::new(DNS_TTL, config.address_family);
+
+        for url in self.trackers.clone() {
+            let scrape = tracker::scrape(&url, &torrent.info_hash, &client, &interface, &dns, &config.tracker_auth).await;
+            self.add_scrape(url, scrape, true);
+        }
+
+        return Ok(());
+    }
+
+    /// Estimate the seeders and leechers from the DHT, listed as the `dht` tracker. Private torrents aren't on the DHT.
+    pub async fn scrape_dht(&mut self, torrent: &Torrent, dht: &Arc<Dht>) {
+        if !self.private {
+            let scrape = dht.scrape(torrent.info_hash).await;
+            self.add_scrape(String::from("dht"), scrape, false);
+        }
+    }
+
+    fn add_scrape(&mut self, url: String, scrape: anyhow::Result<Scrape>, has_downloads: bool) {
+        self.scrapes.push(TrackerScrape {
+            url,
+            seeders: scrape.as_ref().ok().map(|scrape| scrape.seeders),
+            leechers: scrape.as_ref().ok().map(|scrape| scrape.leechers),
+            downloads: scrape.as_ref().ok().filter(|_| has_downloads).map(|scrape| scrape.downloads),
+            error: scrape.err().map(|e| format!("{:#}", e)),
+        });
+    }
+
+    /// Human readable summary, one field per line followed by the files.
+    pub fn to_text(&self) -> String {
+        let mut lines = vec![
+            format!("Name:          {}", self.name),
+            format!("Info hash:     {}", self.info_hash),
+        ];
+        if let Some(info_hash_v2) = &self.info_hash_v2 {
+            lines.push(format!("Info hash v2:  {}", info_hash_v2));
+        }
+        lines.push(format!("Piece length:  {}", self.piece_length));
+        lines.push(format!("Pieces:        {}", self.pieces));
+        lines.push(format!("Size:          {}", self.size));
+        lines.push(format!("Private:       {}", if self.private { "yes" } else { "no" }));
+        if let Some(date) = self.creation_date {
+            lines.push(format!("Created:       {}", format_timestamp(date)));
+        }
+        if let Some(created_by) = &self.created_by {
+            lines.push(format!("Created by:    {}", created_by));
+        }
+        if let Some(comment) = &self.comment {
+            lines.push(format!("Comment:       {}", comment));
+        }
+        for tracker in &self.trackers {
+            lines.push(format!("Tracker:       {}", tracker));
+        }
+        for web_seed in &self.web_seeds {
+            lines.push(format!("Web seed:      {}", web_seed));
+        }
+
+        if !self.scrapes.is_empty() {
+            lines.push(String::from("Scrapes:"));
+        }
+        for scrape in &self.scrapes {
+            match &scrape.error {
+                Some(error) => lines.push(format!("  {}  {}", scrape.url, error)),
+                None => lines.push(format!(
+                    "  {}  {} seeders, {} leechers{}",
+                    scrape.url, scrape.seeders.unwrap_or(0), scrape.leechers.unwrap_or(0),
+                    scrape.downloads.map(|downloads| format!(", {} downloads", downloads)).unwrap_or_default(),
+                )),
+            }
+        }
+
+        lines.push(String::from("Files:"));
+        for file in &self.files {
+            match &file.original_path {
+                Some(original) => lines.push(format!("  {:>14}  {} (renamed from {})", file.length, file.path, original)),
+                None => lines.push(format!("  {:>14}  {}", file.length, file.path)),
+            }
+        }
+
+        return lines.join("\n");
+    }
+}
+
+/// Format a unix timestamp as a UTC date and time.
+fn format_timestamp(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86400);
+    let secs = timestamp.rem_euclid(86400);
+
+    // Convert days since the epoch to a civil date, from Howard Hinnant's `civil_from_days`.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    return format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, secs / 3600, secs % 3600 / 60, secs % 60);
+}
+
+
+#[test]
+fn test_format_timestamp() {
+    assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+    assert_eq!(format_timestamp(1700000000), "2023-11-14 22:13:20 UTC");
+    assert_eq!(format_timestamp(951782400), "2000-02-29 00:00:00 UTC");
+}
+
+
+#[test]
+fn test_inspect() {
+    let torrent = Torrent::load("test-tor.torrent").unwrap();
+    let inspection = inspect(&torrent);
+
+    assert_eq!(inspection.name, "Test torrent");
+    assert_eq!(inspection.info_hash, "06cb061240b24f730fbef7ead1b348d8865244af");
+    assert_eq!(inspection.pieces, 15);
+
+    let text = inspection.to_text();
+    assert!(text.contains("Tracker:       udp://tracker.opentrackr.org:1337"));
+}
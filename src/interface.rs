@@ -0,0 +1,361 @@
+//! Binding sockets to a chosen local address or network interface, so connections can't go out through another one,
+//! and tuning the sockets of peer connections.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde_derive::Deserialize;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+
+use crate::config::SocketConfig;
+
+/// Head start an attempt gets before the next address is tried, as recommended by RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// How long a peer has to take a connection, an address which never answers isn't waited on for the OS timeout.
+pub const PEER_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where connections to peers and trackers are made from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Interface {
+    /// Whichever interface the OS routes through.
+    #[default]
+    Any,
+    Addr(IpAddr),
+    /// Name of a network interface, like `tun0`.
+    Name(String),
+}
+
+/// Address family peer, tracker and DHT traffic is kept to, for networks where the other one is broken or not allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFamily {
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    /// Whether an address can be used, IPv4-mapped IPv6 addresses count as IPv4.
+    pub fn allows(&self, addr: IpAddr) -> bool {
+        let ipv6 = match addr {
+            IpAddr::V4(_) => false,
+            IpAddr::V6(addr) => addr.to_ipv4_mapped().is_none(),
+        };
+
+        return match self {
+            AddressFamily::Any => true,
+            AddressFamily::Ipv4 => !ipv6,
+            AddressFamily::Ipv6 => ipv6,
+        };
+    }
+
+    /// The addresses which can be used, in their order.
+    pub fn filter(&self, addrs: impl IntoIterator<Item = SocketAddr>) -> Vec<SocketAddr> {
+        return addrs.into_iter().filter(|addr| self.allows(addr.ip())).collect();
+    }
+}
+
+impl fmt::Display for AddressFamily {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return f.write_str(match self {
+            AddressFamily::Any => "IP",
+            AddressFamily::Ipv4 => "IPv4",
+            AddressFamily::Ipv6 => "IPv6",
+        });
+    }
+}
+
+impl Interface {
+    /// An IP address in the config is bound to as is, anything else is taken as the name of an interface.
+    pub fn from_config(value: Option<&str>) -> Interface {
+        return match value {
+            None => Interface::Any,
+            Some(value) => match value.parse::<IpAddr>() {
+                Ok(addr) => Interface::Addr(addr),
+                Err(_) => Interface::Name(value.to_owned()),
+            },
+        };
+    }
+
+    /// Local address to bind to for reaching IPv4 or IPv6 addresses, None when any interface will do.
+    ///
+    /// Interfaces are looked up every time, once one is gone this fails rather than letting the connection go out
+    /// another way.
+    pub fn local_addr(&self, ipv6: bool) -> anyhow::Result<Option<IpAddr>> {
+        return match self {
+            Interface::Any => Ok(None),
+            Interface::Addr(addr) if addr.is_ipv6() != ipv6 => {
+                anyhow::bail!("Error: Connections from {} can't reach IPv{} addresses", addr, if ipv6 { 6 } else { 4 })
+            }
+            Interface::Addr(addr) => Ok(Some(*addr)),
+            Interface::Name(name) => interface_addrs(name)?.into_iter()
+                .find(|addr| addr.is_ipv6() == ipv6)
+                .map(Some)
+                .with_context(|| format!("Error: Interface {} has no IPv{} address", name, if ipv6 { 6 } else { 4 })),
+        };
+    }
+
+    /// Open a TCP connection from the interface, with the socket tuned before the connection is made.
+    pub fn connect(&self, addr: SocketAddr, options: &SocketConfig) -> anyhow::Result<TcpStream> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        if let Some(local) = self.local_addr(addr.is_ipv6())? {
+            socket.bind(&SocketAddr::new(local, 0).into()).with_context(|| format!("Unable to bind to {}", local))?;
+        }
+        tune_socket(SockRef::from(&socket), options, addr.is_ipv6()).context("Unable to set the socket options")?;
+        socket.connect_timeout(&addr.into(), PEER_CONNECT_TIMEOUT)?;
+
+        return Ok(socket.into());
+    }
+
+    /// Connect to whichever address answers first, racing them with a head start for each (happy eyeballs).
+    ///
+    /// IPv6 and IPv4 addresses take turns so a broken IPv6 network only costs the head start, and an attempt which
+    /// fails right away lets the next one start without waiting.
+    pub fn connect_first(&self, addrs: &[SocketAddr], options: &SocketConfig) -> anyhow::Result<TcpStream> {
+        let mut addrs = interleave(addrs).into_iter();
+        let (sender, receiver) = mpsc::channel();
+
+        let mut pending = 0;
+        let mut last_error = None;
+        loop {
+            if let Some(addr) = addrs.next() {
+                let sender = sender.clone();
+                let interface = self.clone();
+                let options = options.clone();
+                thread::spawn(move || {
+                    let _ = sender.send(interface.connect(addr, &options));
+                });
+                pending += 1;
+            } else if pending == 0 {
+                break;
+            }
+
+            // Losing attempts are dropped once they're done.
+            let result = match addrs.len() {
+                0 => receiver.recv().ok(),
+                _ => receiver.recv_timeout(CONNECTION_ATTEMPT_DELAY).ok(),
+            };
+            match result {
+                Some(Ok(stream)) => return Ok(stream),
+                Some(Err(e)) => {
+                    pending -= 1;
+                    last_error = Some(e);
+                }
+                None => {}
+            }
+        }
+
+        return Err(last_error.unwrap_or_else(|| anyhow::anyhow!("Error: No address to connect to")));
+    }
+
+    /// Bind an IPv4 UDP socket on the interface.
+    pub fn bind_udp(&self, port: u16) -> anyhow::Result<UdpSocket> {
+        let local = self.local_addr(false)?.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        return UdpSocket::bind((local, port)).with_context(|| format!("Unable to bind to {}:{}", local, port));
+    }
+
+    /// Bind a UDP socket on the interface on any free port, of the family which reaches the address.
+    pub fn bind_udp_for(&self, remote: SocketAddr) -> anyhow::Result<UdpSocket> {
+        if remote.is_ipv4() {
+            return self.bind_udp(0);
+        }
+
+        return self.bind_udp6(0);
+    }
+
+    /// Bind an IPv6 UDP socket on the interface.
+    pub fn bind_udp6(&self, port: u16) -> anyhow::Result<UdpSocket> {
+        let local = self.local_addr(true)?.unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+        return UdpSocket::bind((local, port)).with_context(|| format!("Unable to bind to [{}]:{}", local, port));
+    }
+}
+
+/// Set the options of the config on a peer socket, the OS defaults are kept for those which are off.
+///
+/// The TOS byte is the traffic class on IPv6 sockets.
+pub fn tune_socket(socket: SockRef, options: &SocketConfig, ipv6: bool) -> std::io::Result<()> {
+    if options.nodelay {
+        socket.set_nodelay(true)?;
+    }
+    if options.send_buffer_size > 0 {
+        socket.set_send_buffer_size(options.send_buffer_size)?;
+    }
+    if options.recv_buffer_size > 0 {
+        socket.set_recv_buffer_size(options.recv_buffer_size)?;
+    }
+    if options.tos > 0 {
+        if ipv6 {
+            socket.set_tclass_v6(u32::from(options.tos))?;
+        } else {
+            socket.set_tos(u32::from(options.tos))?;
+        }
+    }
+    if options.keepalive_secs > 0 {
+        socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(Duration::from_secs(options.keepalive_secs)))?;
+    }
+
+    return Ok(());
+}
+
+/// Alternate between IPv6 and IPv4 addresses, starting with IPv6 and otherwise keeping their order.
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let (mut v6, mut v4): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.iter().partition(|addr| addr.is_ipv6());
+    v6.reverse();
+    v4.reverse();
+
+    let mut ordered = Vec::with_capacity(addrs.len());
+    while !v6.is_empty() || !v4.is_empty() {
+        ordered.extend(v6.pop());
+        ordered.extend(v4.pop());
+    }
+
+    return ordered;
+}
+
+/// Addresses of a network interface.
+#[cfg(unix)]
+fn interface_addrs(name: &str) -> anyhow::Result<Vec<IpAddr>> {
+    use std::ffi::CStr;
+    use std::net::Ipv6Addr;
+
+    let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Unable to list the network interfaces");
+    }
+
+    let mut found = Vec::new();
+    let mut current = addrs;
+    while !current.is_null() {
+        let ifaddr = unsafe { &*current };
+        let ifname = unsafe { CStr::from_ptr(ifaddr.ifa_name) };
+
+        if ifname.to_bytes() == name.as_bytes() && !ifaddr.ifa_addr.is_null() {
+            match unsafe { (*ifaddr.ifa_addr).sa_family } as libc::c_int {
+                libc::AF_INET => {
+                    let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+                    found.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr))));
+                }
+                libc::AF_INET6 => {
+                    let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in6) };
+                    found.push(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)));
+                }
+                _ => {}
+            }
+        }
+
+        current = ifaddr.ifa_next;
+    }
+
+    unsafe { libc::freeifaddrs(addrs) };
+
+    return Ok(found);
+}
+
+#[cfg(not(unix))]
+fn interface_addrs(name: &str) -> anyhow::Result<Vec<IpAddr>> {
+    anyhow::bail!("Error: Binding to interface {} by name isn't supported on this platform, use its address", name);
+}
+
+
+#[test]
+fn test_interface() {
+    use std::net::TcpListener;
+
+    assert_eq!(Interface::from_config(None), Interface::Any);
+    assert_eq!(Interface::from_config(Some("10.8.0.2")), Interface::Addr("10.8.0.2".parse().unwrap()));
+    assert_eq!(Interface::from_config(Some("tun0")), Interface::Name(String::from("tun0")));
+
+    // Connections come from the chosen address.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let interface = Interface::Addr("127.0.0.1".parse().unwrap());
+    let stream = interface.connect(listener.local_addr().unwrap(), &SocketConfig::default()).unwrap();
+    assert_eq!(stream.local_addr().unwrap().ip(), interface.local_addr(false).unwrap().unwrap());
+    assert!(interface.local_addr(true).is_err());
+
+    #[cfg(target_os = "linux")]
+    assert_eq!(Interface::Name(String::from("lo")).local_addr(false).unwrap(), Some("127.0.0.1".parse().unwrap()));
+
+    // A missing interface fails instead of falling back to any interface.
+    let missing = Interface::Name(String::from("missing0"));
+    assert!(missing.local_addr(false).is_err());
+    assert!(missing.connect(listener.local_addr().unwrap(), &SocketConfig::default()).is_err());
+    assert!(missing.bind_udp(0).is_err());
+}
+
+
+#[test]
+fn test_address_family() {
+    let v4: IpAddr = "192.0.2.1".parse().unwrap();
+    let v6: IpAddr = "2001:db8::1".parse().unwrap();
+    let mapped: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+
+    assert!(AddressFamily::Any.allows(v4) && AddressFamily::Any.allows(v6));
+    assert!(AddressFamily::Ipv4.allows(v4) && AddressFamily::Ipv4.allows(mapped));
+    assert!(!AddressFamily::Ipv4.allows(v6));
+    assert!(AddressFamily::Ipv6.allows(v6));
+    assert!(!AddressFamily::Ipv6.allows(v4) && !AddressFamily::Ipv6.allows(mapped));
+
+    let addrs = vec![SocketAddr::new(v6, 80), SocketAddr::new(v4, 80)];
+    assert_eq!(AddressFamily::Ipv4.filter(addrs.clone()), vec![SocketAddr::new(v4, 80)]);
+    assert_eq!(AddressFamily::Any.filter(addrs.clone()), addrs);
+
+    let udp = Interface::Any.bind_udp_for("[::1]:6881".parse().unwrap()).unwrap();
+    assert!(udp.local_addr().unwrap().is_ipv6());
+}
+
+
+#[test]
+fn test_connect_first() {
+    use std::net::TcpListener;
+
+    let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+    assert_eq!(interleave(&[addr("1.1.1.1:1"), addr("2.2.2.2:1"), addr("[::1]:1"), addr("3.3.3.3:1")]),
+        vec![addr("[::1]:1"), addr("1.1.1.1:1"), addr("2.2.2.2:1"), addr("3.3.3.3:1")]);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let open = listener.local_addr().unwrap();
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+    // An address refusing the connection doesn't hold back the next one.
+    let options = SocketConfig::default();
+    let stream = Interface::Any.connect_first(&[closed, open], &options).unwrap();
+    assert_eq!(stream.peer_addr().unwrap(), open);
+
+    assert!(Interface::Any.connect_first(&[closed], &options).is_err());
+    assert!(Interface::Any.connect_first(&[], &options).is_err());
+}
+
+
+#[test]
+fn test_tune_socket() {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let options = SocketConfig {
+        nodelay: true,
+        send_buffer_size: 256 * 1024,
+        recv_buffer_size: 256 * 1024,
+        tos: 0x20,
+        keepalive_secs: 60,
+    };
+    let stream = Interface::Any.connect(listener.local_addr().unwrap(), &options).unwrap();
+
+    let socket = SockRef::from(&stream);
+    assert!(socket.nodelay().unwrap());
+    assert!(socket.keepalive().unwrap());
+    assert_eq!(socket.tos().unwrap(), 0x20);
+    // The kernel may double the buffer sizes for its own bookkeeping.
+    assert!(socket.send_buffer_size().unwrap() >= 256 * 1024);
+    assert!(socket.recv_buffer_size().unwrap() >= 256 * 1024);
+
+    // Nothing is changed by default.
+    let stream = Interface::Any.connect(listener.local_addr().unwrap(), &SocketConfig::default()).unwrap();
+    assert!(!SockRef::from(&stream).nodelay().unwrap());
+    assert!(!SockRef::from(&stream).keepalive().unwrap());
+}
@@ -0,0 +1,334 @@
+//! KRPC, the bencoded messages DHT nodes send each other over UDP (BEP 5).
+
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::Context;
+use torrenter::bencode::Value;
+
+use crate::dht::{BloomFilter, NodeId};
+
+/// Error codes of KRPC error messages.
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD: i64 = 204;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    FindNode { target: NodeId },
+    /// With `scrape`, the response has bloom filters of the seeds and peers the node stores (BEP 33).
+    GetPeers { info_hash: [u8; 20], scrape: bool },
+    /// The port the query comes from is announced instead of `port` when `implied_port` is set.
+    AnnouncePeer { info_hash: [u8; 20], port: u16, implied_port: bool, token: Vec<u8>, seed: bool },
+    /// Some of the info hashes the node stores peers for, along with nodes close to the target (BEP 51).
+    SampleInfohashes { target: NodeId },
+}
+
+impl Query {
+    fn method(&self) -> &'static str {
+        return match self {
+            Query::Ping => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+            Query::SampleInfohashes { .. } => "sample_infohashes",
+        };
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Response {
+    pub id: NodeId,
+    /// Nodes closer to the target of a `find_node` or `get_peers`.
+    pub nodes: Vec<(NodeId, SocketAddr)>,
+    /// Peers of the info hash of a `get_peers`.
+    pub values: Vec<SocketAddr>,
+    /// Lets the querying node announce itself with `announce_peer` afterwards.
+    pub token: Option<Vec<u8>>,
+    /// Info hashes of a `sample_infohashes`, out of the `num` the node stores, and how many seconds until it has
+    /// new ones.
+    pub samples: Vec<[u8; 20]>,
+    pub num: Option<i64>,
+    pub interval: Option<i64>,
+    /// Bloom filters of the seeds and the other peers of the info hash of a scrape.
+    pub seeds: Option<BloomFilter>,
+    pub peers: Option<BloomFilter>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    Query { id: NodeId, query: Query },
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// Picked by the querying node, the response has the same one.
+    pub transaction: Vec<u8>,
+    pub body: Body,
+    /// Set on the queries of nodes which don't answer queries, they're kept out of routing tables (BEP 43).
+    pub read_only: bool,
+    /// Address the node sending a response sees the querying node on (BEP 42).
+    pub ip: Option<SocketAddr>,
+}
+
+impl Message {
+    pub fn query(transaction: Vec<u8>, id: NodeId, query: Query, read_only: bool) -> Message {
+        return Message { transaction, body: Body::Query { id, query }, read_only, ip: None };
+    }
+
+    pub fn response(transaction: Vec<u8>, response: Response, ip: SocketAddr) -> Message {
+        return Message { transaction, body: Body::Response(response), read_only: false, ip: Some(ip) };
+    }
+
+    pub fn error(transaction: Vec<u8>, code: i64, message: &str) -> Message {
+        return Message { transaction, body: Body::Error { code, message: message.to_owned() }, read_only: false, ip: None };
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = BTreeMap::new();
+        dict.insert(b"t".to_vec(), Value::Bytes(self.transaction.clone()));
+
+        match &self.body {
+            Body::Query { id, query } => {
+                let mut args = BTreeMap::new();
+                args.insert(b"id".to_vec(), bytes(id));
+                match query {
+                    Query::Ping => {}
+                    Query::FindNode { target } | Query::SampleInfohashes { target } => {
+                        args.insert(b"target".to_vec(), bytes(target));
+                    }
+                    Query::GetPeers { info_hash, scrape } => {
+                        args.insert(b"info_hash".to_vec(), bytes(info_hash));
+                        if *scrape {
+                            args.insert(b"scrape".to_vec(), Value::Int(1));
+                        }
+                    }
+                    Query::AnnouncePeer { info_hash, port, implied_port, token, seed } => {
+                        args.insert(b"info_hash".to_vec(), bytes(info_hash));
+                        args.insert(b"port".to_vec(), Value::Int(*port as i64));
+                        args.insert(b"implied_port".to_vec(), Value::Int(*implied_port as i64));
+                        args.insert(b"token".to_vec(), bytes(token));
+                        if *seed {
+                            args.insert(b"seed".to_vec(), Value::Int(1));
+                        }
+                    }
+                }
+
+                dict.insert(b"y".to_vec(), bytes(b"q"));
+                dict.insert(b"q".to_vec(), bytes(query.method().as_bytes()));
+                dict.insert(b"a".to_vec(), Value::Dict(args));
+            }
+            Body::Response(response) => {
+                let mut values = BTreeMap::new();
+                values.insert(b"id".to_vec(), bytes(&response.id));
+
+                let (nodes, nodes6): (Vec<_>, Vec<_>) = response.nodes.iter().partition(|(_, addr)| addr.is_ipv4());
+                if !nodes.is_empty() {
+                    values.insert(b"nodes".to_vec(), Value::Bytes(compact_nodes(&nodes)));
+                }
+                if !nodes6.is_empty() {
+                    values.insert(b"nodes6".to_vec(), Value::Bytes(compact_nodes(&nodes6)));
+                }
+                if !response.values.is_empty() {
+                    values.insert(b"values".to_vec(), Value::List(response.values.iter().map(|&addr| Value::Bytes(compact_addr(addr))).collect()));
+                }
+                if let Some(token) = &response.token {
+                    values.insert(b"token".to_vec(), bytes(token));
+                }
+                if let Some(num) = response.num {
+                    values.insert(b"samples".to_vec(), Value::Bytes(response.samples.concat()));
+                    values.insert(b"num".to_vec(), Value::Int(num));
+                }
+                if let Some(interval) = response.interval {
+                    values.insert(b"interval".to_vec(), Value::Int(interval));
+                }
+                if let Some(seeds) = &response.seeds {
+                    values.insert(b"BFsd".to_vec(), bytes(seeds.as_bytes()));
+                }
+                if let Some(peers) = &response.peers {
+                    values.insert(b"BFpe".to_vec(), bytes(peers.as_bytes()));
+                }
+
+                dict.insert(b"y".to_vec(), bytes(b"r"));
+                dict.insert(b"r".to_vec(), Value::Dict(values));
+            }
+            Body::Error { code, message } => {
+                dict.insert(b"y".to_vec(), bytes(b"e"));
+                dict.insert(b"e".to_vec(), Value::List(vec![Value::Int(*code), bytes(message.as_bytes())]));
+            }
+        }
+
+        if self.read_only {
+            dict.insert(b"ro".to_vec(), Value::Int(1));
+        }
+        if let Some(ip) = self.ip {
+            dict.insert(b"ip".to_vec(), Value::Bytes(compact_addr(ip)));
+        }
+
+        return Value::Dict(dict).encode();
+    }
+
+    pub fn decode(input: &[u8]) -> anyhow::Result<Message> {
+        let value = Value::decode(input)?;
+        let dict = value.as_dict().context("Error: The KRPC message isn't a dictionary")?;
+        let transaction = dict.get(b"t".as_slice()).and_then(|t| t.as_bytes()).context("Error: The KRPC message has no transaction id")?.to_vec();
+
+        let body = match dict.get(b"y".as_slice()).and_then(|y| y.as_bytes()) {
+            Some(b"q") => {
+                let args = dict.get(b"a".as_slice()).and_then(|a| a.as_dict()).context("Error: The query has no arguments")?;
+                let query = match dict.get(b"q".as_slice()).and_then(|q| q.as_bytes()) {
+                    Some(b"ping") => Query::Ping,
+                    Some(b"find_node") => Query::FindNode { target: id(args, b"target")? },
+                    Some(b"get_peers") => Query::GetPeers { info_hash: id(args, b"info_hash")?, scrape: flag(args, b"scrape") },
+                    Some(b"announce_peer") => Query::AnnouncePeer {
+                        info_hash: id(args, b"info_hash")?,
+                        port: args.get(b"port".as_slice()).and_then(|port| port.as_int()).and_then(|port| u16::try_from(port).ok()).unwrap_or(0),
+                        implied_port: flag(args, b"implied_port"),
+                        token: args.get(b"token".as_slice()).and_then(|token| token.as_bytes()).context("Error: The announce has no token")?.to_vec(),
+                        seed: flag(args, b"seed"),
+                    },
+                    Some(b"sample_infohashes") => Query::SampleInfohashes { target: id(args, b"target")? },
+                    method => anyhow::bail!("Error: Unknown KRPC method {:?}", method.map(String::from_utf8_lossy)),
+                };
+                Body::Query { id: id(args, b"id")?, query }
+            }
+            Some(b"r") => {
+                let values = dict.get(b"r".as_slice()).and_then(|r| r.as_dict()).context("Error: The response has no values")?;
+                let mut nodes = parse_nodes(values.get(b"nodes".as_slice()), 4);
+                nodes.extend(parse_nodes(values.get(b"nodes6".as_slice()), 16));
+
+                Body::Response(Response {
+                    id: id(values, b"id")?,
+                    nodes,
+                    values: match values.get(b"values".as_slice()) {
+                        Some(Value::List(list)) => list.iter().filter_map(|peer| parse_compact_addr(peer.as_bytes()?)).collect(),
+                        _ => Vec::new(),
+                    },
+                    token: values.get(b"token".as_slice()).and_then(|token| token.as_bytes()).map(|token| token.to_vec()),
+                    samples: match values.get(b"samples".as_slice()).and_then(|samples| samples.as_bytes()) {
+                        Some(samples) => samples.chunks_exact(20).map(|sample| sample.try_into().unwrap()).collect(),
+                        None => Vec::new(),
+                    },
+                    num: values.get(b"num".as_slice()).and_then(|num| num.as_int()),
+                    interval: values.get(b"interval".as_slice()).and_then(|interval| interval.as_int()),
+                    seeds: values.get(b"BFsd".as_slice()).and_then(|seeds| seeds.as_bytes()).and_then(BloomFilter::from_bytes),
+                    peers: values.get(b"BFpe".as_slice()).and_then(|peers| peers.as_bytes()).and_then(BloomFilter::from_bytes),
+                })
+            }
+            Some(b"e") => match dict.get(b"e".as_slice()) {
+                Some(Value::List(error)) if error.len() == 2 => Body::Error {
+                    code: error[0].as_int().unwrap_or(ERROR_GENERIC),
+                    message: error[1].as_bytes().map(|message| String::from_utf8_lossy(message).into_owned()).unwrap_or_default(),
+                },
+                _ => anyhow::bail!("Error: Invalid KRPC error"),
+            },
+            _ => anyhow::bail!("Error: Unknown KRPC message type"),
+        };
+
+        return Ok(Message {
+            transaction,
+            body,
+            read_only: flag(dict, b"ro"),
+            ip: dict.get(b"ip".as_slice()).and_then(|ip| ip.as_bytes()).and_then(parse_compact_addr),
+        });
+    }
+}
+
+fn bytes(bytes: &[u8]) -> Value {
+    return Value::Bytes(bytes.to_vec());
+}
+
+/// Whether a flag like `implied_port` is set to 1.
+fn flag(dict: &BTreeMap<Vec<u8>, Value>, key: &[u8]) -> bool {
+    return dict.get(key).and_then(|value| value.as_int()) == Some(1);
+}
+
+/// A node id or info hash argument.
+fn id(dict: &BTreeMap<Vec<u8>, Value>, key: &[u8]) -> anyhow::Result<[u8; 20]> {
+    let value = dict.get(key).and_then(|value| value.as_bytes())
+        .with_context(|| format!("Error: The KRPC message has no {}", String::from_utf8_lossy(key)))?;
+
+    return value.try_into().with_context(|| format!("Error: The {} of the KRPC message isn't 20 bytes", String::from_utf8_lossy(key)));
+}
+
+/// An address and a port in 6 bytes for IPv4 or 18 for IPv6.
+pub fn compact_addr(addr: SocketAddr) -> Vec<u8> {
+    let mut compact = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    compact.extend(addr.port().to_be_bytes());
+
+    return compact;
+}
+
+pub fn parse_compact_addr(compact: &[u8]) -> Option<SocketAddr> {
+    let ip = match compact.len() {
+        6 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&compact[..4]).ok()?)),
+        18 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&compact[..16]).ok()?)),
+        _ => return None,
+    };
+
+    return Some(SocketAddr::new(ip, u16::from_be_bytes([compact[compact.len() - 2], compact[compact.len() - 1]])));
+}
+
+fn compact_nodes(nodes: &[&(NodeId, SocketAddr)]) -> Vec<u8> {
+    return nodes.iter().flat_map(|(id, addr)| [id.to_vec(), compact_addr(*addr)].concat()).collect();
+}
+
+/// Nodes of a response, each a 20 byte id followed by a compact address with `ip_len` bytes of address.
+fn parse_nodes(value: Option<&Value>, ip_len: usize) -> Vec<(NodeId, SocketAddr)> {
+    let Some(compact) = value.and_then(|value| value.as_bytes()) else {
+        return Vec::new();
+    };
+
+    return compact.chunks_exact(20 + ip_len + 2)
+        .filter_map(|node| Some((node[..20].try_into().ok()?, parse_compact_addr(&node[20..])?)))
+        .collect();
+}
+
+
+#[test]
+fn test_krpc_messages() {
+    // The ping of BEP 5.
+    let ping = Message::decode(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe").unwrap();
+    assert_eq!(ping, Message::query(b"aa".to_vec(), *b"abcdefghij0123456789", Query::Ping, false));
+    assert_eq!(ping.encode(), b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe".to_vec());
+
+    let announce = Message::query(b"ab".to_vec(), [1; 20], Query::AnnouncePeer { info_hash: [2; 20], port: 6881, implied_port: true, token: b"secret".to_vec(), seed: true }, true);
+    let decoded = Message::decode(&announce.encode()).unwrap();
+    assert_eq!(decoded, announce);
+    assert!(decoded.read_only);
+
+    let response = Message::response(b"ab".to_vec(), Response {
+        id: [3; 20],
+        nodes: vec![([4; 20], "203.0.113.9:6881".parse().unwrap()), ([5; 20], "[2001:db8::1]:6881".parse().unwrap())],
+        values: vec!["198.51.100.7:51413".parse().unwrap()],
+        token: Some(b"token".to_vec()),
+        seeds: Some(BloomFilter::default()),
+        peers: Some(BloomFilter::default()),
+        ..Default::default()
+    }, "192.0.2.1:6882".parse().unwrap());
+    assert_eq!(Message::decode(&response.encode()).unwrap(), response);
+
+    let samples = Message::response(b"ab".to_vec(), Response {
+        id: [3; 20],
+        samples: vec![[6; 20], [7; 20]],
+        num: Some(10),
+        interval: Some(3600),
+        ..Default::default()
+    }, "192.0.2.1:6882".parse().unwrap());
+    assert_eq!(Message::decode(&samples.encode()).unwrap(), samples);
+    let query = Message::query(b"ac".to_vec(), [1; 20], Query::SampleInfohashes { target: [8; 20] }, false);
+    assert_eq!(Message::decode(&query.encode()).unwrap(), query);
+
+    let error = Message::decode(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee").unwrap();
+    assert_eq!(error.body, Body::Error { code: ERROR_GENERIC, message: String::from("A Generic Error Ocurred") });
+
+    assert!(Message::decode(b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe").is_err());
+    assert!(Message::decode(b"d1:t2:aa1:y1:xe").is_err());
+}
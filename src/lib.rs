@@ -0,0 +1,3 @@
+//! Library side of torrenter, for the parts which are useful on their own.
+
+pub mod bencode;
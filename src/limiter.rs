@@ -0,0 +1,113 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Token bucket used to limit the rate of bytes going through the session.
+///
+/// The bucket can hold up to one second worth of bytes, a rate of 0 means unlimited. A limiter can have a parent it
+/// shares, like the limit of the session over the limits of single peers, bytes have to go through both.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: AtomicU64,
+    bucket: Mutex<Bucket>,
+    parent: Option<Arc<RateLimiter>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    available: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> RateLimiter {
+        RateLimiter {
+            rate: AtomicU64::new(rate),
+            bucket: Mutex::new(Bucket {
+                available: rate as f64,
+                last_refill: Instant::now(),
+            }),
+            parent: None,
+        }
+    }
+
+    /// A limiter whose bytes also count towards the limit of `parent`.
+    pub fn with_parent(rate: u64, parent: Arc<RateLimiter>) -> RateLimiter {
+        return RateLimiter { parent: Some(parent), ..RateLimiter::new(rate) };
+    }
+
+    /// Get the rate in bytes per second.
+    pub fn rate(&self) -> u64 {
+        return self.rate.load(Ordering::Relaxed);
+    }
+
+    /// Change the rate in bytes per second.
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.available = bucket.available.min(rate as f64);
+    }
+
+    /// Take bytes out of the bucket and the buckets of the parents.
+    ///
+    /// Returns how long the caller has to wait before the bytes are allowed through, the longest wait of them all.
+    pub fn consume(&self, bytes: u64) -> Duration {
+        let parent_wait = self.parent.as_ref().map_or(Duration::from_secs(0), |parent| parent.consume(bytes));
+        return self.consume_own(bytes).max(parent_wait);
+    }
+
+    fn consume_own(&self, bytes: u64) -> Duration {
+        let rate = self.rate();
+        if rate == 0 {
+            return Duration::from_secs(0);
+        }
+
+        let mut bucket = self.bucket.lock().unwrap();
+
+        // Refill the bucket with the bytes earned since the last call.
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.available = (bucket.available + elapsed * rate as f64).min(rate as f64);
+        bucket.last_refill = now;
+
+        bucket.available -= bytes as f64;
+
+        return if bucket.available < 0.0 {
+            Duration::from_secs_f64(-bucket.available / rate as f64)
+        } else {
+            Duration::from_secs(0)
+        };
+    }
+}
+
+
+#[test]
+fn test_rate_limiter_consume() {
+    // Unlimited never waits.
+    let limiter = RateLimiter::new(0);
+    assert_eq!(limiter.consume(1_000_000), Duration::from_secs(0));
+
+    // A full bucket lets one second worth of bytes through, the next second has to wait.
+    let limiter = RateLimiter::new(1000);
+    assert_eq!(limiter.consume(1000), Duration::from_secs(0));
+
+    let wait = limiter.consume(1000);
+    assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+}
+
+
+#[test]
+fn test_rate_limiter_parent() {
+    let session = Arc::new(RateLimiter::new(1000));
+    let peer = RateLimiter::with_parent(500, session.clone());
+    let other = RateLimiter::with_parent(0, session.clone());
+
+    // The peer waits for its own limit first, its bytes still count for the session.
+    assert_eq!(peer.consume(500), Duration::from_secs(0));
+    assert!(peer.consume(250) > Duration::from_millis(400));
+
+    // Which leaves the other peers of the session less to go on.
+    assert_eq!(other.consume(250), Duration::from_secs(0));
+    assert!(other.consume(250) > Duration::from_millis(200));
+}
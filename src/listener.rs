@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use rand::seq::SliceRandom;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tracing::{debug, info, info_span};
+
+use crate::config::{Config, ListenConfig, SocketConfig};
+use crate::encryption::{accept_handshake, EncryptionPolicy, PeerStream};
+use crate::interface::{tune_socket, AddressFamily, Interface};
+use crate::limiter::RateLimiter;
+use crate::session::Session;
+
+/// How long a peer has to send its handshake once it's connected.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// IPs whose failed handshakes are kept count of, the ones whose ban is over are forgotten past this.
+const MAX_FAILING_IPS: usize = 10000;
+
+/// A peer which connected to us, waiting for our handshake.
+pub struct IncomingPeer {
+    pub stream: PeerStream,
+    /// The handshake the peer sent.
+    pub handshake: Vec<u8>,
+    pub addr: SocketAddr,
+}
+
+impl IncomingPeer {
+    /// The torrent the peer wants, from its handshake.
+    pub fn info_hash(&self) -> [u8; 20] {
+        return self.handshake[28..48].try_into().unwrap();
+    }
+}
+
+/// How peers connecting to us are taken, shared by every listener of the session.
+pub struct Incoming {
+    encryption: EncryptionPolicy,
+    socket: SocketConfig,
+    family: AddressFamily,
+    /// Connections accepted per second.
+    accepts: RateLimiter,
+    /// Handshakes in progress, connections coming in while they're all taken are dropped. None is unlimited.
+    handshakes: Option<Arc<Semaphore>>,
+    failures: Mutex<HandshakeFailures>,
+}
+
+impl Incoming {
+    pub fn from_config(config: &Config) -> Incoming {
+        let incoming = &config.incoming;
+        Incoming {
+            encryption: config.encryption,
+            socket: config.socket.clone(),
+            family: config.address_family,
+            accepts: RateLimiter::new(incoming.accepts_per_sec),
+            handshakes: match incoming.max_handshakes {
+                0 => None,
+                max => Some(Arc::new(Semaphore::new(max))),
+            },
+            failures: Mutex::new(HandshakeFailures::new(incoming.max_failed_handshakes, Duration::from_secs(incoming.ban_secs))),
+        }
+    }
+}
+
+/// IPs which failed their last handshakes, turned down without a handshake once they've failed too many in a row
+/// until their ban is over.
+#[derive(Debug)]
+struct HandshakeFailures {
+    /// Failures in a row an IP is banned after, 0 never bans.
+    max_failures: u32,
+    ban: Duration,
+    /// Failures in a row of each IP, with when the last one was.
+    ips: HashMap<IpAddr, (u32, Instant)>,
+}
+
+impl HandshakeFailures {
+    fn new(max_failures: u32, ban: Duration) -> HandshakeFailures {
+        HandshakeFailures {
+            max_failures,
+            ban,
+            ips: HashMap::new(),
+        }
+    }
+
+    fn is_banned(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self.max_failures == 0 {
+            return false;
+        }
+
+        return match self.ips.get(&ip) {
+            Some(&(failures, last)) if failures >= self.max_failures => {
+                if now.duration_since(last) < self.ban {
+                    return true;
+                }
+                // The ban is over, the IP starts again from no failures.
+                self.ips.remove(&ip);
+                false
+            }
+            _ => false,
+        };
+    }
+
+    fn failed(&mut self, ip: IpAddr, now: Instant) {
+        if self.ips.len() >= MAX_FAILING_IPS {
+            let ban = self.ban;
+            self.ips.retain(|_, (_, last)| now.duration_since(*last) < ban);
+        }
+
+        let (failures, last) = self.ips.entry(ip).or_insert((0, now));
+        *failures += 1;
+        *last = now;
+    }
+
+    fn succeeded(&mut self, ip: IpAddr) {
+        self.ips.remove(&ip);
+    }
+}
+
+/// Ports tried one after the other when the ones before are taken.
+const BIND_ATTEMPTS: usize = 10;
+
+/// Ports to try for listening in order: the port and the few after it, or random ones from the range.
+fn candidate_ports(port: u16, range: Option<[u16; 2]>) -> anyhow::Result<Vec<u16>> {
+    return match range {
+        Some([start, end]) if start == 0 || start > end => anyhow::bail!("Error: The listen port range {}-{} is empty", start, end),
+        Some([start, end]) => {
+            let mut ports: Vec<u16> = (start..=end).collect();
+            ports.shuffle(&mut rand::thread_rng());
+            ports.truncate(BIND_ATTEMPTS);
+            Ok(ports)
+        }
+        // Any free port the OS picks.
+        None if port == 0 => Ok(vec![0]),
+        None => Ok((0..BIND_ATTEMPTS as u16).filter_map(|offset| port.checked_add(offset)).collect()),
+    };
+}
+
+/// Bind the listen port on the interface, moving on to the next port while they're taken.
+///
+/// The port is taken on IPv6 in IPv6-only mode and on IPv4 otherwise.
+pub async fn bind(port: u16, range: Option<[u16; 2]>, interface: &Interface, family: AddressFamily) -> anyhow::Result<TcpListener> {
+    let addr = match family {
+        AddressFamily::Ipv6 => interface.local_addr(true)?.unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        _ => interface.local_addr(false)?.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+    };
+
+    let mut last_error = None;
+    for port in candidate_ports(port, range)? {
+        match TcpListener::bind((addr, port)).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                debug!("Port {} is taken", port);
+                last_error = Some(e);
+            }
+            Err(e) => return Err(e).with_context(|| format!("Unable to listen for peers on port {}", port)),
+        }
+    }
+
+    return Err(last_error.unwrap()).context("Error: Every listen port tried is taken");
+}
+
+/// Bind one of the listen addresses of the config, returning the listener with the port announced for it.
+pub async fn bind_addr(listen: &ListenConfig, family: AddressFamily) -> anyhow::Result<(TcpListener, u16)> {
+    if !family.allows(listen.addr.ip()) {
+        anyhow::bail!("Error: {} isn't an {} address", listen.addr, family);
+    }
+
+    let listener = TcpListener::bind(listen.addr).await
+        .with_context(|| format!("Unable to listen for peers on {}", listen.addr))?;
+    let announce_port = match listen.announce_port {
+        Some(port) => port,
+        None => listener.local_addr()?.port(),
+    };
+
+    return Ok((listener, announce_port));
+}
+
+/// Accept peers on the listen port and hand each one to the torrent it's for once its handshake is in.
+///
+/// Plaintext and encrypted handshakes are both taken on the same port, as far as the encryption policy allows.
+/// Accepted sockets get the same options as the connections we make, and peers of the other address family are
+/// turned down. Connections are accepted no faster than the accept rate, the ones beyond the cap of handshakes in
+/// progress and from banned IPs are dropped right away.
+pub async fn listen(session: Arc<Session>, listener: TcpListener, incoming: Arc<Incoming>) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Listening for peers on {}", addr);
+    }
+
+    loop {
+        // Connections beyond the rate wait in the backlog of the socket.
+        let wait = incoming.accepts.consume(1);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+
+        let (stream, addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                debug!("Unable to accept a peer: {}", e);
+                continue;
+            }
+        };
+
+        if !incoming.family.allows(addr.ip()) {
+            debug!("Turned down {}, it isn't an {} address", addr, incoming.family);
+            continue;
+        }
+        if incoming.failures.lock().unwrap().is_banned(addr.ip(), Instant::now()) {
+            debug!("Turned down {}, it failed too many handshakes", addr);
+            continue;
+        }
+        let permit = match &incoming.handshakes {
+            Some(handshakes) => match handshakes.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    debug!("Turned down {}, too many peers are handshaking", addr);
+                    continue;
+                }
+            },
+            None => None,
+        };
+        if let Err(e) = tune_socket(socket2::SockRef::from(&stream), &incoming.socket, addr.is_ipv6()) {
+            debug!("Unable to set the socket options of {}: {}", addr, e);
+        }
+
+        let (session, incoming) = (session.clone(), incoming.clone());
+        let span = info_span!("incoming", %addr);
+
+        // The handshakes are blocking, the connection moves onto the runtime once they are done.
+        tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            let _permit = permit;
+            if let Err(e) = accept_peer(&session, stream, addr, &incoming) {
+                debug!("Turned down peer: {:#}", e);
+            }
+        });
+    }
+}
+
+fn accept_peer(session: &Session, stream: TcpStream, addr: SocketAddr, incoming: &Incoming) -> anyhow::Result<()> {
+    let stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+    let (stream, handshake) = match accept_handshake(stream, &session.info_hashes(), incoming.encryption) {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            incoming.failures.lock().unwrap().failed(addr.ip(), Instant::now());
+            return Err(e);
+        }
+    };
+    incoming.failures.lock().unwrap().succeeded(addr.ip());
+    stream.set_read_timeout(None)?;
+
+    let peer = IncomingPeer { stream, handshake, addr };
+    info!(encrypted = peer.stream.is_encrypted(), "Peer connected");
+
+    return session.add_peer(peer);
+}
+
+
+#[test]
+fn test_candidate_ports() {
+    assert_eq!(candidate_ports(6881, None).unwrap(), (6881..6891).collect::<Vec<_>>());
+    assert_eq!(candidate_ports(65530, None).unwrap(), (65530..=65535).collect::<Vec<_>>());
+    assert_eq!(candidate_ports(0, None).unwrap(), vec![0]);
+
+    let ports = candidate_ports(0, Some([7000, 7100])).unwrap();
+    assert_eq!(ports.len(), BIND_ATTEMPTS);
+    assert!(ports.iter().all(|port| (7000..=7100).contains(port)));
+    assert_eq!(candidate_ports(0, Some([7000, 7000])).unwrap(), vec![7000]);
+
+    assert!(candidate_ports(0, Some([7100, 7000])).is_err());
+}
+
+
+#[tokio::test]
+async fn test_bind_taken_port() {
+    let interface = Interface::Addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = taken.local_addr().unwrap().port();
+
+    // The next free port is used instead.
+    let listener = bind(port, None, &interface, AddressFamily::Any).await.unwrap();
+    assert_ne!(listener.local_addr().unwrap().port(), port);
+
+    assert!(bind(port, Some([port, port]), &interface, AddressFamily::Any).await.is_err());
+}
+
+
+#[tokio::test]
+async fn test_bind_addr() {
+    let listen = ListenConfig { addr: "127.0.0.1:0".parse().unwrap(), announce_port: None };
+    let (listener, announce_port) = bind_addr(&listen, AddressFamily::Any).await.unwrap();
+    assert_eq!(announce_port, listener.local_addr().unwrap().port());
+
+    let listen = ListenConfig { announce_port: Some(16881), ..listen };
+    assert_eq!(bind_addr(&listen, AddressFamily::Ipv4).await.unwrap().1, 16881);
+
+    // Addresses of the other family aren't listened on.
+    assert!(bind_addr(&listen, AddressFamily::Ipv6).await.is_err());
+}
+
+
+#[test]
+fn test_handshake_failures() {
+    let ip: IpAddr = "203.0.113.5".parse().unwrap();
+    let other: IpAddr = "203.0.113.6".parse().unwrap();
+    let start = Instant::now();
+    let mut failures = HandshakeFailures::new(3, Duration::from_secs(60));
+
+    failures.failed(ip, start);
+    failures.failed(ip, start);
+    assert!(!failures.is_banned(ip, start));
+
+    // A handshake which goes through starts the count over.
+    failures.succeeded(ip);
+    for _ in 0..3 {
+        failures.failed(ip, start);
+    }
+    assert!(failures.is_banned(ip, start + Duration::from_secs(59)));
+    assert!(!failures.is_banned(other, start));
+
+    // Once the ban is over the IP gets another chance.
+    assert!(!failures.is_banned(ip, start + Duration::from_secs(60)));
+    failures.failed(ip, start + Duration::from_secs(60));
+    assert!(!failures.is_banned(ip, start + Duration::from_secs(60)));
+
+    let mut never = HandshakeFailures::new(0, Duration::from_secs(60));
+    never.failed(ip, start);
+    assert!(!never.is_banned(ip, start));
+}
@@ -0,0 +1,23 @@
+use tracing_subscriber::EnvFilter;
+
+use crate::config::Config;
+
+/// Setup the global logger.
+///
+/// The filter comes from `RUST_LOG` when it's set, otherwise from the `log_level` of the config.
+pub fn init(config: &Config) -> anyhow::Result<()> {
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(filter) if !filter.is_empty() => EnvFilter::new(filter),
+        _ => EnvFilter::try_new(&config.log_level)?,
+    };
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    if config.log_json {
+        builder.json().init();
+    } else {
+        builder.init();
+    }
+
+    Ok(())
+}
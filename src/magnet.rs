@@ -0,0 +1,247 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::ops::RangeInclusive;
+
+use tracing::debug;
+use url::form_urlencoded::{self, byte_serialize};
+
+use crate::encryption::PeerCrypto;
+use crate::utils::{info_hash_from_hex, to_hex, Peer, PeerSource};
+use crate::utils::torrents::Torrent;
+
+/// What a magnet link tells about a torrent, the rest comes with its metadata.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MagnetLink {
+    pub info_hash: [u8; 20],
+    /// Display name, until the metadata gives the real one.
+    pub name: Option<String>,
+    /// Trackers given with `tr`, each one in its own tier.
+    pub trackers: Vec<String>,
+    /// Web seeds given with `ws` (BEP 19).
+    pub web_seeds: Vec<String>,
+    /// Peers given with `x.pe` as `host:port`, connected to right away.
+    pub peers: Vec<String>,
+    /// Ranges of indexes of the files to download given with `so` (BEP 53), every file when None.
+    ///
+    /// They're kept as ranges, a link can give a range far larger than any torrent has files.
+    pub select_only: Option<Vec<RangeInclusive<usize>>>,
+}
+
+impl MagnetLink {
+    /// Parse a `magnet:?xt=urn:btih:...` link, the info hash can be hex or base32.
+    pub fn parse(link: &str) -> anyhow::Result<MagnetLink> {
+        let query = match link.strip_prefix("magnet:?") {
+            Some(query) => query,
+            None => anyhow::bail!("Error: Not a magnet link"),
+        };
+
+        let mut info_hash = None;
+        let mut magnet = MagnetLink::default();
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            // Some links number their trackers, as `tr.1`, `tr.2` and so on.
+            let key = match key.split_once('.') {
+                Some((name @ ("tr" | "ws"), _)) => name,
+                _ => key.as_ref(),
+            };
+            match key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_btih(hash)?);
+                    }
+                }
+                "dn" => magnet.name = Some(value.into_owned()),
+                "tr" if !magnet.trackers.contains(&value.to_string()) => magnet.trackers.push(value.into_owned()),
+                "ws" if !magnet.web_seeds.contains(&value.to_string()) => magnet.web_seeds.push(value.into_owned()),
+                "x.pe" => magnet.peers.push(value.into_owned()),
+                "so" => magnet.select_only.get_or_insert_with(Vec::new).extend(parse_select_only(&value)?),
+                _ => {}
+            }
+        }
+
+        magnet.info_hash = match info_hash {
+            Some(info_hash) => info_hash,
+            None => anyhow::bail!("Error: The magnet link has no urn:btih info hash"),
+        };
+
+        return Ok(magnet);
+    }
+
+    /// Indexes of the files which aren't selected with `so`, out of the `files` of the torrent.
+    pub fn skip_files(&self, files: usize) -> Vec<usize> {
+        return match &self.select_only {
+            Some(selected) => (0..files).filter(|index| !selected.iter().any(|range| range.contains(index))).collect(),
+            None => Vec::new(),
+        };
+    }
+
+    /// Look up the addresses of the `x.pe` peers, leaving out the ones which can't be found.
+    pub fn resolve_peers(&self) -> Vec<Peer> {
+        let mut peers = Vec::new();
+        for peer in &self.peers {
+            let addrs = match peer.to_socket_addrs() {
+                Ok(addrs) => addrs,
+                Err(e) => {
+                    debug!(peer, "Unable to resolve peer: {}", e);
+                    continue;
+                }
+            };
+
+            // A name with both IPv4 and IPv6 addresses is one peer, connected to on whichever answers first.
+            let addrs: Vec<SocketAddr> = addrs.collect();
+            if !addrs.is_empty() {
+                peers.push(Peer { addrs, crypto: PeerCrypto::Unknown, source: PeerSource::Manual });
+            }
+        }
+
+        return peers;
+    }
+}
+
+/// Read the file indexes of `so`, single indexes and inclusive ranges separated by commas like `0,2,4-7`.
+fn parse_select_only(value: &str) -> anyhow::Result<Vec<RangeInclusive<usize>>> {
+    let mut ranges = Vec::new();
+    for part in value.split(',').filter(|part| !part.is_empty()) {
+        let (first, last) = part.split_once('-').unwrap_or((part, part));
+        let (first, last): (usize, usize) = match (first.trim().parse(), last.trim().parse()) {
+            (Ok(first), Ok(last)) if first <= last => (first, last),
+            _ => anyhow::bail!("Error: {:?} isn't a file index or range", part),
+        };
+        ranges.push(first..=last);
+    }
+
+    return Ok(ranges);
+}
+
+/// Decode the info hash of a `urn:btih`, 40 hex or 32 base32 characters.
+fn parse_btih(hash: &str) -> anyhow::Result<[u8; 20]> {
+    if hash.len() == 40 {
+        return info_hash_from_hex(hash);
+    }
+    if hash.len() != 32 {
+        anyhow::bail!("Error: An info hash must be 40 hex or 32 base32 characters");
+    }
+
+    let mut info_hash: [u8; 20] = [0; 20];
+    let mut bits: u64 = 0;
+    let mut count = 0;
+    let mut written = 0;
+    for c in hash.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => anyhow::bail!("Error: The info hash isn't valid base32"),
+        };
+
+        bits = (bits << 5) | value as u64;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            info_hash[written] = (bits >> count) as u8;
+            written += 1;
+        }
+    }
+
+    return Ok(info_hash);
+}
+
+/// Build the magnet link of a torrent with its info hash, name, trackers and web seeds.
+///
+/// Hybrid torrents get both their v1 and v2 hashes, the v2 one as a multihash.
+pub fn magnet_link(torrent: &Torrent) -> String {
+    let mut link = format!("magnet:?xt=urn:btih:{}", to_hex(&torrent.info_hash));
+    if let Some(info_hash_v2) = &torrent.info_hash_v2 {
+        link.push_str(&format!("&xt=urn:btmh:1220{}", to_hex(info_hash_v2)));
+    }
+    link.push_str(&format!("&dn={}", encode(&torrent.info.name)));
+
+    for tracker in torrent.trackers() {
+        link.push_str(&format!("&tr={}", encode(&tracker)));
+    }
+    for web_seed in torrent.web_seeds() {
+        link.push_str(&format!("&ws={}", encode(&web_seed)));
+    }
+
+    return link;
+}
+
+/// Percent-encode a parameter, spaces as `%20` since not every client reads `+` as a space.
+fn encode(value: &str) -> String {
+    return byte_serialize(value.as_bytes()).collect::<String>().replace('+', "%20");
+}
+
+
+#[test]
+fn test_magnet_link() {
+    use crate::create::{create_torrent, CreateOptions};
+    use std::fs;
+
+    let _ = fs::remove_dir_all("test-files/magnet");
+    fs::create_dir_all("test-files/magnet").unwrap();
+    fs::write("test-files/magnet/My file.txt", b"hello").unwrap();
+
+    let metainfo = create_torrent(&CreateOptions {
+        path: "test-files/magnet/My file.txt".into(),
+        trackers: vec![String::from("udp://a.example.com:80"), String::from("http://b.example.com/announce?x=1&y=2")],
+        web_seeds: vec![String::from("https://example.com/files/")],
+        ..Default::default()
+    }).unwrap();
+    let torrent = Torrent::from_bytes(&metainfo).unwrap();
+
+    assert_eq!(magnet_link(&torrent), format!(
+        "magnet:?xt=urn:btih:{}&dn=My%20file.txt&tr=udp%3A%2F%2Fa.example.com%3A80&tr=http%3A%2F%2Fb.example.com%2Fannounce%3Fx%3D1%26y%3D2&ws=https%3A%2F%2Fexample.com%2Ffiles%2F",
+        to_hex(&torrent.info_hash),
+    ));
+
+    let _ = fs::remove_dir_all("test-files/magnet");
+}
+
+
+#[test]
+fn test_parse_magnet_link() {
+    let magnet = MagnetLink::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=My+file&x.pe=10.0.0.1:6881&x.pe=%5B::1%5D:6882").unwrap();
+    assert_eq!(to_hex(&magnet.info_hash), "c12fe1c06bba254a9dc9f519b335aa7c1367a88a");
+    assert_eq!(magnet.name.as_deref(), Some("My file"));
+    assert_eq!(magnet.peers, vec![String::from("10.0.0.1:6881"), String::from("[::1]:6882")]);
+
+    // IPv6 peers are kept along with the IPv4 ones.
+    let peers = magnet.resolve_peers();
+    assert_eq!(peers.len(), 2);
+    assert_eq!((peers[0].addrs.clone(), peers[0].source), (vec!["10.0.0.1:6881".parse().unwrap()], PeerSource::Manual));
+    assert_eq!(peers[1].addrs, vec!["[::1]:6882".parse().unwrap()]);
+
+    // The same hash in base32.
+    let magnet = MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+    assert_eq!(to_hex(&magnet.info_hash), "c12fe1c06bba254a9dc9f519b335aa7c1367a88a");
+    assert!(magnet.peers.is_empty());
+
+    assert!(MagnetLink::parse("magnet:?dn=name").is_err());
+    assert!(MagnetLink::parse("magnet:?xt=urn:btih:1234").is_err());
+    assert!(MagnetLink::parse("https://example.com/file.torrent").is_err());
+}
+
+
+#[test]
+fn test_select_only() {
+    let magnet = MagnetLink::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&so=0,2,4-7").unwrap();
+    assert_eq!(magnet.select_only, Some(vec![0..=0, 2..=2, 4..=7]));
+    assert_eq!(magnet.skip_files(10), vec![1, 3, 8, 9]);
+
+    // A range past every file only selects the files there are.
+    let magnet = MagnetLink::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&so=0-18446744073709551615").unwrap();
+    assert_eq!(magnet.select_only, Some(vec![0..=usize::MAX]));
+    assert!(magnet.skip_files(10).is_empty());
+
+    // Every file is downloaded without `so`.
+    let magnet = MagnetLink::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a").unwrap();
+    assert!(magnet.skip_files(10).is_empty());
+
+    assert!(MagnetLink::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&so=3-1").is_err());
+    assert!(MagnetLink::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&so=a").is_err());
+}
+
+
+#[test]
+fn test_magnet_trackers() {
+    let magnet = MagnetLink::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&tr=udp%3A%2F%2Fa.example.com%3A80&tr.1=http%3A%2F%2Fb.example.com%2Fannounce&tr=udp%3A%2F%2Fa.example.com%3A80&ws=https%3A%2F%2Fexample.com%2Ffiles%2F").unwrap();
+    assert_eq!(magnet.trackers, vec![String::from("udp://a.example.com:80"), String::from("http://b.example.com/announce")]);
+    assert_eq!(magnet.web_seeds, vec![String::from("https://example.com/files/")]);
+}
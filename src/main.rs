@@ -1,30 +1,380 @@
-#![feature(const_int_pow)]
-
 // TODO: Remove this once finished.
 // Don't show warnings for unused code when developping.
 #![allow(dead_code)]
 #![allow(unused_variables)]
 
-use crate::download::download_torrent;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use clap::Parser;
+
+use crate::cli::{Cli, Command, CreateArgs, EditArgs};
+use crate::config::{Config, DhtConfig};
+use crate::create::CreateOptions;
+use crate::dht::Dht;
+use crate::download::PeerSettings;
+use crate::edit::EditOptions;
+use crate::magnet::MagnetLink;
+use crate::messages::build_peer_handshake;
+use crate::session::{AddTorrentOptions, Session};
+use crate::tracker::ExternalIp;
 use crate::utils::gen_peer_id;
+use crate::utils::torrents::Torrent;
 
 mod utils;
+mod error;
 mod messages;
 mod download;
 mod tracker;
+mod dns;
 mod message_handlers;
 mod pieces;
 mod queue;
-
-const PORT: i16 = 6682;
+mod encryption;
+mod interface;
+mod listener;
+mod limiter;
+mod session;
+mod rpc;
+mod api;
+mod watch;
+mod rss;
+mod config;
+mod cli;
+mod hooks;
+mod events;
+mod webhooks;
+mod notifications;
+mod logging;
+mod metrics;
+mod speed;
+mod stats;
+mod ticks;
+mod dht;
+mod krpc;
+mod create;
+mod magnet;
+mod metadata;
+mod webseed;
+mod resume;
+mod edit;
+mod inspect;
+mod check;
+mod hashing;
+mod disk;
+mod cache;
+mod storage;
+mod mmap;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+#[cfg(any(test, feature = "testing"))]
+mod testing;
 
 
 #[tokio::main]
-async fn main() {
-    let peer_id = gen_peer_id();
-    download_torrent(peer_id, "test-tor.torrent").await;
+async fn main() -> anyhow::Result<()> {
+    let mut cli = Cli::parse();
+
+    // `add` downloads its torrents like the ones given without a command, into its own save path if it has one.
+    let mut add_save_path = None;
+    if let Some(Command::Add { torrents, save_path }) = &mut cli.command {
+        let mut torrents = std::mem::take(torrents);
+        cli.torrents.append(&mut torrents);
+        add_save_path = save_path.take();
+        cli.command = None;
+    }
+
+    if let Some(command) = cli.command.take() {
+        return match command {
+            Command::Create(args) => create(args),
+            Command::Edit(args) => edit(args),
+            Command::Inspect { torrent, json, scrape } => {
+                let torrent = Torrent::load(&torrent)?;
+                let mut inspection = inspect::inspect(&torrent);
+                if scrape {
+                    let config = Config::load(cli.config.as_deref())?;
+                    inspection.scrape_trackers(&torrent, &config).await?;
+                    if config.dht_enabled() {
+                        let dht = dht_client(&config)?;
+                        dht.clone().bootstrap(Vec::new()).await;
+                        inspection.scrape_dht(&torrent, &dht).await;
+                    }
+                }
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&inspection)?);
+                } else {
+                    println!("{}", inspection.to_text());
+                }
+                Ok(())
+            }
+            Command::Verify { torrent, data } => verify(&torrent, &data),
+            Command::Checksums { torrent, data, sha1 } => {
+                let checksums = check::file_checksums(&Torrent::load(&torrent)?, &data)?;
+                print!("{}", check::format_checksums(&checksums, |c| if sha1 { &c.sha1 } else { &c.sha256 }));
+                Ok(())
+            }
+            Command::ExportMagnet { torrent } => {
+                println!("{}", magnet::magnet_link(&Torrent::load(&torrent)?));
+                Ok(())
+            }
+            Command::FetchTorrent { magnet, output } => fetch_torrent(&Config::load(cli.config.as_deref())?, &magnet, output).await,
+            Command::Add { .. } => unreachable!("add runs the session"),
+            Command::DhtSamples { node } => dht_samples(&Config::load(cli.config.as_deref())?, node.as_deref()).await,
+        };
+    }
+
+    let mut config = Config::load(cli.config.as_deref())?;
+    config.apply_cli(&cli);
+
+    logging::init(&config)?;
+
+    let peer_id = gen_peer_id(&config.client.peer_id_prefix);
+    let http_client = config.http_client()?;
+    let session = Arc::new(Session::new(peer_id, config.clone()));
+    Session::start_ticks(session.clone());
+
+    // Bound before any torrent is added, so they announce the ports which are actually used.
+    let mut listeners = Vec::new();
+    if config.listen.is_empty() {
+        let bound = listener::bind(config.listen_port, config.listen_port_range, &config.interface(), config.address_family).await;
+        listeners.push(bound.and_then(|peer_listener| {
+            let port = peer_listener.local_addr()?.port();
+            Ok((peer_listener, port))
+        }));
+    }
+    for listen in &config.listen {
+        listeners.push(listener::bind_addr(listen, config.address_family).await);
+    }
+
+    let incoming = Arc::new(listener::Incoming::from_config(&config));
+    // The DHT takes the UDP side of the first IPv4 port peers connect to.
+    let mut dht_port = None;
+    for bound in listeners {
+        match bound {
+            Ok((peer_listener, announce_port)) => {
+                let addr = peer_listener.local_addr()?;
+                if addr.is_ipv4() && dht_port.is_none() {
+                    dht_port = Some(addr.port());
+                }
+                session.add_listener(addr, announce_port);
+                // Every listener hands its peers to the same session.
+                tokio::spawn(listener::listen(session.clone(), peer_listener, incoming.clone()));
+            }
+            Err(e) => tracing::error!("Not listening for peers: {:#}", e),
+        }
+    }
+
+    if config.dht_enabled() {
+        match Dht::bind(&config.dht, &config.interface(), dht_port.unwrap_or(config.listen_port), session.external_ip(), config.dht_ipv6()) {
+            Ok(dht) => {
+                let dht = Arc::new(dht);
+                session.set_dht(dht.clone());
+                tokio::spawn(dht.clone().run());
+                tokio::spawn(dht.bootstrap(Vec::new()));
+            }
+            Err(e) => tracing::error!("Not running the DHT: {:#}", e),
+        }
+    }
+
+    for torrent in &cli.torrents {
+        let torrent = expand_info_hash(torrent);
+        let options = AddTorrentOptions { save_path: add_save_path.clone(), ..Default::default() };
+
+        if torrent.starts_with("magnet:") {
+            // Getting the metadata can take a while, the other torrents don't wait for it.
+            let (session, link) = (session.clone(), torrent.clone());
+            tokio::spawn(async move {
+                if let Err(e) = session.add_magnet(&link, options).await {
+                    tracing::error!("Unable to add {}: {:#}", link, e);
+                }
+            });
+        } else {
+            session.add_torrent(&torrent, options)?;
+        }
+    }
+
+    if let Some(dir) = &config.watch_dir {
+        tokio::spawn(watch::watch_dir(session.clone(), dir.clone()));
+    }
+
+    for feed in config.feed_configs()? {
+        tokio::spawn(rss::watch_feed(session.clone(), http_client.clone(), feed));
+    }
+
+    if config.desktop_notifications && std::io::stdout().is_terminal() {
+        tokio::spawn(notifications::run_notifications(session.subscribe()));
+    }
+
+    if !config.webhooks.is_empty() {
+        tokio::spawn(webhooks::run_webhooks(session.subscribe(), http_client.clone(), config.webhooks.clone()));
+    }
+
+    let rpc = async {
+        if config.rpc.enabled {
+            rpc::serve(session.clone(), config.rpc.addr).await?;
+        }
+        Ok::<(), anyhow::Error>(())
+    };
+
+    let api = async {
+        if config.api.enabled {
+            api::serve(session.clone(), config.api.addr, config.api.web_ui).await?;
+        }
+        Ok::<(), anyhow::Error>(())
+    };
+
+    // Keep downloading when both servers are disabled.
+    let servers = async {
+        tokio::try_join!(rpc, api)?;
+        return std::future::pending::<anyhow::Result<()>>().await;
+    };
+
+    tokio::select! {
+        result = servers => result?,
+        result = tokio::signal::ctrl_c() => result?,
+    }
+
+    // The lifetime stats are only written every announce while running, what came since would be lost.
+    session.flush_stats();
+
+    Ok(())
+}
+
+
+/// Create a torrent file and write it in the working directory unless an output is given.
+fn create(args: CreateArgs) -> anyhow::Result<()> {
+    let options = CreateOptions {
+        path: args.path,
+        piece_length: args.piece_length,
+        trackers: args.trackers,
+        web_seeds: args.web_seeds,
+        comment: args.comment,
+        private: args.private,
+        version: args.format,
+    };
+
+    let metainfo = create::create_torrent(&options)?;
+
+    let output = match args.output {
+        Some(output) => output,
+        None => {
+            let name = options.path.file_name().unwrap_or_default().to_string_lossy();
+            std::path::PathBuf::from(format!("{}.torrent", name))
+        }
+    };
+
+    std::fs::write(&output, metainfo)?;
+    println!("Created {}", output.display());
+
+    return Ok(());
+}
+
+
+/// Edit a torrent file in place unless an output is given.
+fn edit(args: EditArgs) -> anyhow::Result<()> {
+    let trackers = if args.clear_trackers {
+        Some(Vec::new())
+    } else if args.trackers.is_empty() {
+        None
+    } else {
+        Some(args.trackers)
+    };
+
+    let private = match (args.private, args.public) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    };
+
+    let options = EditOptions {
+        trackers,
+        comment: args.comment,
+        private,
+    };
+
+    let edited = edit::edit_torrent(&std::fs::read(&args.torrent)?, &options)?;
+    let output = args.output.unwrap_or(args.torrent);
+
+    std::fs::write(&output, &edited)?;
+    println!("Wrote {} with info hash {}", output.display(), utils::to_hex(&Torrent::from_bytes(&edited)?.info_hash));
+
+    return Ok(());
+}
+
+
+/// Start a DHT node for a command which only looks things up, on any free port.
+fn dht_client(config: &Config) -> anyhow::Result<Arc<Dht>> {
+    // Read-only, the node is gone again before others would get to use it.
+    let dht_config = DhtConfig { read_only: true, ..config.dht.clone() };
+    let dht = Arc::new(Dht::bind(&dht_config, &config.interface(), 0, Arc::new(ExternalIp::new(config.external_ip)), config.dht_ipv6())?);
+    tokio::spawn(dht.clone().run());
+
+    return Ok(dht);
+}
+
+
+/// A bare info hash as a magnet link with nothing but the hash, its peers can only come from the DHT.
+fn expand_info_hash(torrent: &str) -> String {
+    return match utils::info_hash_from_hex(torrent) {
+        Ok(_) => format!("magnet:?xt=urn:btih:{}", torrent),
+        Err(_) => torrent.to_owned(),
+    };
+}
+
+
+/// Get the metadata of a magnet link from its peers and write it as a torrent file, without downloading any data.
+async fn fetch_torrent(config: &Config, link: &str, output: Option<PathBuf>) -> anyhow::Result<()> {
+    let magnet = MagnetLink::parse(&expand_info_hash(link))?;
+    let dht = if config.dht_enabled() { Some(dht_client(config)?) } else { None };
+
+    let handshake = build_peer_handshake(&magnet.info_hash, &gen_peer_id(&config.client.peer_id_prefix)).to_bytes();
+    let (info, _) = metadata::fetch_magnet(&magnet, dht.as_ref(), handshake, PeerSettings::from_config(config)).await?;
+    let metainfo = metadata::build_metainfo(&info, &magnet.trackers, &magnet.web_seeds);
+    let torrent = Torrent::from_bytes(&metainfo)?;
+
+    let output = output.unwrap_or_else(|| PathBuf::from(format!("{}.torrent", torrent.info.name)));
+    std::fs::write(&output, &metainfo)?;
+    println!("Wrote {} with info hash {}", output.display(), utils::to_hex(&torrent.info_hash));
+
+    return Ok(());
 }
 
 
+/// Print info hashes stored by a DHT node, or by the nodes around a random id.
+async fn dht_samples(config: &Config, node: Option<&str>) -> anyhow::Result<()> {
+    let dht = dht_client(config)?;
+
+    let samples = match node {
+        Some(node) => dht.sample_node(node).await?,
+        None => {
+            dht.clone().bootstrap(Vec::new()).await;
+            dht.sample_infohashes(dht::node_id(None)).await
+        }
+    };
 
+    for sample in &samples {
+        println!("{}", utils::to_hex(sample));
+    }
+    eprintln!("{} info hashes", samples.len());
 
+    return Ok(());
+}
+
+
+/// Check data on disk against a torrent and print how complete each file is.
+fn verify(torrent: &str, data: &std::path::Path) -> anyhow::Result<()> {
+    let torrent = Torrent::load(torrent)?;
+    let result = check::check_data(&torrent, data);
+
+    for file in &result.files {
+        println!("{:>6.1}%  {}", file.progress(), file.path);
+    }
+    println!("{} of {} pieces are valid", result.valid_pieces(), result.pieces.len());
+
+    if !result.is_complete() {
+        anyhow::bail!("Error: The data doesn't match the torrent");
+    }
+
+    return Ok(());
+}
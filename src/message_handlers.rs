@@ -1,16 +1,43 @@
-use std::io::prelude::*;
-use std::net::{Shutdown, TcpStream};
-
-use anyhow::{anyhow, Result};
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::io;
+use std::net::Shutdown;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
 use bytebuffer::ByteBuffer;
+use rand::seq::SliceRandom;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::mpsc::Sender;
-
-use crate::download::PiecesManager;
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, trace, warn};
+
+use crate::disk::{DiskIo, Pending};
+use crate::download::{PeerSettings, PieceUpdate, PiecesManager, Swarm};
+use crate::encryption::{PeerReader, PeerWriter};
+use crate::error::TorrenterError;
+use crate::limiter::RateLimiter;
 use crate::messages;
-use crate::messages::{GenericPayload, parse};
-use crate::queue::{PieceBlock, Queue};
-use crate::utils::torrents::Torrent;
+use crate::metrics;
+use crate::messages::{Extensions, GenericPayload, parse, EXTENDED_HANDSHAKE_ID, EXTENSION_BIT, LT_DONTHAVE_ID};
+use crate::queue::{ConnectionId, PieceBlock, Queue};
+use crate::ticks::Tick;
+use crate::tracker::ExternalIp;
+use crate::utils::is_local_addr;
+use crate::utils::torrents::{BLOCK_LEN, Torrent};
+
+/// Most pieces left out of a lazy bitfield.
+const LAZY_PIECES: usize = 16;
+
+/// Peers send a keep-alive every two minutes, one which has been quiet for longer than this is gone.
+const PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(180);
 
+/// Longest message taken from a peer, well above a block or the bitfield of any torrent.
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+#[derive(Debug, Clone)]
 pub struct PieceChannelPayload {
     pub offset: u64,
     pub block: Vec<u8>,
@@ -18,110 +45,316 @@ pub struct PieceChannelPayload {
 
 pub struct MessageHandler<'a> {
     torrent: &'a Torrent,
-    stream: &'a mut TcpStream,
+    stream: &'a mut PeerWriter,
     file_sender: Sender<PieceChannelPayload>,
     pieces: PiecesManager,
     queue: &'a mut Queue<'a>,
+    /// Limiter of the blocks of this peer, within the limit of the session.
+    download_limiter: Arc<RateLimiter>,
+    /// Limiter of the blocks we send the peer, within the limit of the session.
+    upload_limiter: RateLimiter,
+    /// Changes to our pieces since the connection started, to be sent to the peer in have and lt_donthave messages.
+    haves: broadcast::Receiver<PieceUpdate>,
+    settings: PeerSettings,
+    /// Pieces the peer told us it has.
+    peer_pieces: HashSet<u64>,
+    /// Pieces left out of a lazy bitfield which still have to be sent.
+    lazy_haves: Vec<u64>,
+    /// Ids of the extended messages the peer supports.
+    extensions: Extensions,
+    /// The peer is on the local network and isn't held back by the rate limits.
+    unlimited: bool,
+    external_ip: Arc<ExternalIp>,
+    /// The disk of the torrent, which the blocks the peer requests are read from.
+    disk: DiskIo,
+    /// How far behind the disk is.
+    pending: Arc<Pending>,
+    /// Which of the blocks in flight are requested from this peer.
+    connection: ConnectionId,
+    ticks: broadcast::Receiver<Tick>,
+    /// Whether the peer can't request blocks from us, until a choking round gives it a slot.
+    am_choking: bool,
+    /// The peer snubbed us once, and is held to `snubbed_peer_rate_limit` since.
+    snubbed: bool,
 }
 
 impl MessageHandler<'_> {
-    pub fn new<'a>(torrent: &'a Torrent, stream: &'a mut TcpStream, file_sender: Sender<PieceChannelPayload>, pieces: PiecesManager, queue: &'a mut Queue<'a>) -> MessageHandler<'a> {
+    pub fn new<'a>(torrent: &'a Torrent, stream: &'a mut PeerWriter, file_sender: Sender<PieceChannelPayload>, queue: &'a mut Queue<'a>, swarm: Swarm, haves: broadcast::Receiver<PieceUpdate>, download_limiter: Arc<RateLimiter>) -> MessageHandler<'a> {
+        let unlimited = swarm.settings.exempt_lan_peers && stream.peer_addr().is_ok_and(|addr| is_local_addr(addr.ip()));
+        let connection = swarm.pieces.lock().unwrap().connect();
+
         MessageHandler {
             torrent,
             stream,
             file_sender,
-            pieces,
+            pieces: swarm.pieces,
             queue,
+            download_limiter,
+            upload_limiter: RateLimiter::with_parent(swarm.settings.peer_upload_rate_limit, swarm.upload_limiter.clone()),
+            haves,
+            settings: swarm.settings,
+            peer_pieces: HashSet::new(),
+            lazy_haves: Vec::new(),
+            extensions: Extensions::default(),
+            unlimited,
+            external_ip: swarm.external_ip,
+            pending: swarm.disk.pending(),
+            disk: swarm.disk,
+            connection,
+            ticks: swarm.ticks.subscribe(),
+            am_choking: true,
+            snubbed: false,
         }
     }
 
-    /// Route and parse all the messages.
+    /// Route and parse all the messages, each one a whole message along with its length.
     /// Each message will be routed to their corresponding handler.
     ///
     ///     0 : choke
     ///     1 : unchoke
     ///     4 : have
     ///     5 : bitfield
+    ///     6 : request
     ///     7 : piece
+    ///     20: extended
     ///
     pub async fn router(&mut self, msg: ByteBuffer) -> Result<()> {
         if msg.len() == 0 {
             return Err(anyhow!("Peer connection closed"));
         }
+        if self.pieces.lock().unwrap().is_dropped(self.connection) {
+            self.stream.shutdown(Shutdown::Both)?;
+            return Err(anyhow!("Disconnected from a peer which is too slow"));
+        }
 
         let parsed_msg = parse(msg);
 
         match parsed_msg.id {
             0 => self.choke(),
-            1 => self.unchoke(),
-            4 => self.have(parsed_msg.payload),
+            1 => self.unchoke().await?,
+            4 => self.have(parsed_msg.payload).await?,
             5 => self.bitfield(parsed_msg.payload),
-            7 => {
-                self.piece(parsed_msg.payload).await;
-            }
+            6 => self.request(parsed_msg.payload).await?,
+            7 => self.piece(parsed_msg.payload).await?,
+            20 => self.extended(parsed_msg.payload),
             _ => {
-                println!("Unknown message ID: {:?}", parsed_msg.id);
+                debug!("Unknown message ID: {:?}", parsed_msg.id);
             }
         }
 
+        // A peer with nothing to send may have been picked to take over the blocks of a stalled peer.
+        let idle = !self.queue.choked && self.pieces.lock().unwrap().is_idle(self.connection);
+        if idle {
+            self.request_piece().await?;
+        }
+
         return Ok(());
     }
 
 
-    /// Get an entire message from a peer.
+    /// Establish the initial contact with a peer once we have its handshake, immediately afterwards we send an
+    /// intersted message.
+    pub async fn handshake(&mut self, peer_handshake: &[u8]) -> io::Result<()> {
+        self.send_bitfield().await?;
+
+        // The reserved bytes are after the protocol name.
+        let reserved = u64::from_be_bytes(peer_handshake[20..28].try_into().unwrap());
+        if reserved & EXTENSION_BIT != 0 {
+            let upload_only = self.pieces.lock().unwrap().is_upload_only();
+            let send_msg = messages::build_extended_handshake(upload_only);
+            self.stream.write_all(&send_msg.to_bytes()).await?;
+            debug!("Sent extended handshake");
+        }
+
+        return self.interested().await;
+    }
+
+    /// Tell the peer which pieces we already have, only right after the handshake as a bitfield can't be sent later.
     ///
-    /// Even though pieces should be around 16kB, it's possible that they can be larger.
-    /// If this is the case, this function will fail to receive a piece.
-    pub fn get_whole_msg(&mut self) -> ByteBuffer {
-        let mut whole_msg: ByteBuffer = ByteBuffer::new();
+    /// Nothing is sent when we don't have any piece yet.
+    async fn send_bitfield(&mut self) -> io::Result<()> {
+        let mut bitfield = self.pieces.lock().unwrap().bitfield();
+        if self.settings.lazy_bitfield {
+            self.lazy_haves = withhold_pieces(&mut bitfield, LAZY_PIECES);
+        }
+        if bitfield.iter().all(|&byte| byte == 0) {
+            return Ok(());
+        }
+
+        let send_msg = messages::build_bitfield(&ByteBuffer::from_bytes(&bitfield));
+        self.stream.write_all(&send_msg.to_bytes()).await?;
+        debug!("Sent bitfield");
+        return Ok(());
+    }
 
-        // Get the length from the first message.
-        let buf: &mut [u8; 1028 * 36] = &mut [0; 1028 * 36];
-        let len = self.stream.read(buf).expect("Unable to receive from peer");
-        whole_msg.write_bytes(&buf[0..len]);
+    /// Exchange messages with the peer until the connection ends, sending the piece updates and what the ticks of
+    /// the session timer call for as they come rather than in between the messages of the peer.
+    ///
+    /// A peer which sends nothing for `PEER_IDLE_TIMEOUT` is disconnected from.
+    pub async fn run(&mut self, reader: &mut PeerReader) -> Result<()> {
+        let mut deadline = Instant::now() + PEER_IDLE_TIMEOUT;
+        loop {
+            tokio::select! {
+                msg = reader.read_message(MAX_MESSAGE_LEN) => {
+                    deadline = Instant::now() + PEER_IDLE_TIMEOUT;
+                    self.router(ByteBuffer::from_bytes(&msg?)).await?;
+                }
+                update = self.haves.recv() => match update {
+                    Ok(update) => self.handle_update(update).await?,
+                    Err(RecvError::Lagged(missed)) => debug!(missed, "Missed have messages"),
+                    Err(RecvError::Closed) => bail!("Error: The download of the torrent stopped"),
+                },
+                tick = self.ticks.recv() => match tick {
+                    Ok(tick) => self.handle_tick(tick).await?,
+                    Err(RecvError::Lagged(missed)) => debug!(missed, "Missed ticks"),
+                    Err(RecvError::Closed) => bail!("Error: The session stopped"),
+                },
+                _ = sleep_until(deadline) => bail!("Error: The peer sent nothing for {:?}", PEER_IDLE_TIMEOUT),
+            }
 
-        return whole_msg;
+            self.send_cancels().await?;
+        }
     }
 
+    /// Tell the peer about a change to our pieces, or request a piece which failed its hash check again when the
+    /// peer has it.
+    async fn handle_update(&mut self, update: PieceUpdate) -> io::Result<()> {
+        return match update {
+            PieceUpdate::Have(index) => self.send_have(index).await,
+            PieceUpdate::DontHave(index) => self.send_donthave(index).await,
+            PieceUpdate::Retry(index) if self.peer_pieces.contains(&index) => {
+                self.queue.queue(index);
+                let idle = !self.queue.choked && self.pieces.lock().unwrap().is_idle(self.connection);
+                if idle {
+                    self.request_piece().await?;
+                }
+                Ok(())
+            }
+            PieceUpdate::Retry(_) => Ok(()),
+        };
+    }
 
-    /// Establish the initial contact with a peer, immediately afterwards we send an intersted message.
-    pub fn handshake(&mut self) {
-        let buf: &mut [u8; 1028] = &mut [0; 1028];
-        self.stream.read(buf).expect("Handshake has failed");
-        self.interested();
+    /// Cancel the requests for blocks which came from other peers first.
+    async fn send_cancels(&mut self) -> io::Result<()> {
+        let cancels = self.pieces.lock().unwrap().take_cancels(self.connection);
+        for piece_block in cancels {
+            self.stream.write_all(&messages::build_cancel(piece_block).to_bytes()).await?;
+            trace!(piece = piece_block.index, begin = piece_block.begin, "Sent cancel");
+        }
+        return Ok(());
+    }
+
+    /// Send the keep-alives and the choke and unchoke messages a tick of the session timer calls for, and one of the
+    /// pieces left out of a lazy bitfield every second so they're spread out over the connection.
+    async fn handle_tick(&mut self, tick: Tick) -> io::Result<()> {
+        match tick {
+            Tick::Second => {
+                if let Some(index) = self.lazy_haves.pop() {
+                    self.send_have(index).await?;
+                }
+            }
+            Tick::KeepAlive => {
+                self.stream.write_all(&messages::build_keep_alive().to_bytes()).await?;
+                trace!("Sent keep-alive");
+            }
+            Tick::Choke => self.update_choke().await?,
+            Tick::Requests => self.limit_snubbing(),
+            Tick::Announce => {}
+        }
+        return Ok(());
+    }
+
+    /// Hold a peer which snubs us to `snubbed_peer_rate_limit`, unless its own limit is lower already.
+    fn limit_snubbing(&mut self) {
+        let limit = self.settings.snubbed_peer_rate_limit;
+        if self.snubbed || limit == 0 || !self.pieces.lock().unwrap().is_snubbing(self.connection) {
+            return;
+        }
+
+        self.snubbed = true;
+        let rate = self.download_limiter.rate();
+        if rate == 0 || rate > limit {
+            self.download_limiter.set_rate(limit);
+        }
+        debug!(rate = limit, "Limiting a peer which snubbed us");
+    }
+
+    /// Choke or unchoke the peer when the last choking round changed whether it has an upload slot.
+    async fn update_choke(&mut self) -> io::Result<()> {
+        let upload_slot = self.pieces.lock().unwrap().is_upload_slot(self.connection);
+        if upload_slot != self.am_choking {
+            return Ok(());
+        }
+
+        let send_msg = if upload_slot { messages::build_unchoke() } else { messages::build_choke() };
+        self.stream.write_all(&send_msg.to_bytes()).await?;
+        self.am_choking = !upload_slot;
+        debug!(choked = self.am_choking, "Choking round");
+        return Ok(());
+    }
+
+    async fn send_have(&mut self, index: u64) -> io::Result<()> {
+        if self.settings.suppress_haves && self.peer_pieces.contains(&index) {
+            return Ok(());
+        }
+
+        let send_msg = messages::build_have(index as u32);
+        self.stream.write_all(&send_msg.to_bytes()).await?;
+        trace!(piece = index, "Sent have");
+        return Ok(());
+    }
+
+    /// Take back a piece we told the peer we have, when it supports lt_donthave.
+    async fn send_donthave(&mut self, index: u64) -> io::Result<()> {
+        self.lazy_haves.retain(|&lazy| lazy != index);
+        let Some(extended_id) = self.extensions.lt_donthave else {
+            return Ok(());
+        };
+
+        let send_msg = messages::build_donthave(extended_id, index as u32);
+        self.stream.write_all(&send_msg.to_bytes()).await?;
+        trace!(piece = index, "Sent lt_donthave");
+        return Ok(());
     }
 
     /// Let the peer know we're interesting in communicating.
-    pub fn interested(&mut self) {
+    pub async fn interested(&mut self) -> io::Result<()> {
         let send_msg = messages::build_interested();
-        self.stream.write(&send_msg.to_bytes()).expect("Unable to send interested");
-        println!("SENT INTERESTED!");
+        self.stream.write_all(&send_msg.to_bytes()).await?;
+        debug!("Sent interested");
+        return Ok(());
     }
 
     /// The peer has stopped communication with us
     fn choke(&mut self) {
-        println!("CHOKED");
+        debug!("Choked");
         self.stream.shutdown(Shutdown::Both).expect("The peer has choked us");
     }
 
     /// Start to requst pieces from a peer
-    fn unchoke(&mut self) {
-        println!("UNCHOKING");
+    async fn unchoke(&mut self) -> io::Result<()> {
+        debug!("Unchoked");
         self.queue.choked = false;
-        self.request_piece();
+        self.pieces.lock().unwrap().set_unchoked(self.connection, true);
+        return self.request_piece().await;
     }
 
 
     /// A peer has indicted that they have a certain piece.
-    fn have(&mut self, payload: GenericPayload) {
-        println!("HAVE");
-        let piece_index = payload.index;
+    async fn have(&mut self, payload: GenericPayload) -> io::Result<()> {
+        trace!("Have");
+        let Some(piece_index) = payload.piece_index else {
+            debug!("Have without a piece index");
+            return Ok(());
+        };
         let queue_empty = self.queue.len() == 0;
 
+        self.peer_pieces.insert(piece_index as u64);
         self.queue.queue(piece_index as u64);
         if queue_empty {
-            self.request_piece()
+            self.request_piece().await?;
         }
+        return Ok(());
     }
 
     /// Handle bitfield messages which indicate which are the pieces that the peer has.
@@ -129,28 +362,70 @@ impl MessageHandler<'_> {
     /// For example, the a bitfield of 01111 indicates that the peer is missing the first piece but has all the others.
     ///
     fn bitfield(&mut self, payload: GenericPayload) {
-        println!("BITFIELD");
+        trace!("Bitfield");
 
         let bf = payload.bitfield.as_ref().unwrap().to_bytes();
         let available_pieces = parse_bitfield(bf);
 
         // Add piece indexes to the download queue
         for piece_index in available_pieces {
+            self.peer_pieces.insert(piece_index);
             self.queue.queue(piece_index);
         }
     }
 
 
+    /// Handle extended messages, the extended handshake and lt_donthave.
+    fn extended(&mut self, payload: GenericPayload) {
+        let Some((extended_id, payload)) = payload.extended else {
+            return;
+        };
+
+        match extended_id {
+            EXTENDED_HANDSHAKE_ID => match messages::parse_extended_handshake(&payload) {
+                Ok(extensions) => {
+                    trace!(?extensions, "Extended handshake");
+                    if let Some(ip) = extensions.yourip {
+                        self.external_ip.vote(ip);
+                    }
+                    self.extensions = extensions;
+
+                    // Two seeds have nothing to exchange, same for a seed and a partial seed.
+                    if self.extensions.upload_only && self.pieces.lock().unwrap().is_upload_only() {
+                        debug!("Disconnecting from a seed while seeding");
+                        self.stream.shutdown(Shutdown::Both).expect("Unable to shutdown stream");
+                    }
+                }
+                Err(e) => debug!("Invalid extended handshake: {:#}", e),
+            },
+            LT_DONTHAVE_ID if payload.len() == 4 => {
+                let piece_index = u32::from_be_bytes(payload[..4].try_into().unwrap()) as u64;
+                trace!(piece = piece_index, "Don't have");
+
+                // Stop requesting the piece from this peer.
+                self.peer_pieces.remove(&piece_index);
+                self.queue.remove(piece_index);
+            }
+            _ => debug!("Unknown extended message ID: {}", extended_id),
+        }
+    }
+
+
     /// Handle piece message
     ///
     /// - Add piece to the recieved vec
     /// - Write to file
     /// - Request new pieces if not finished
-    async fn piece(&mut self, payload: GenericPayload) {
+    async fn piece(&mut self, payload: GenericPayload) -> Result<()> {
+        let Some(block) = payload.block else {
+            return Err(TorrenterError::Protocol(String::from("A piece message needs an index, an offset and a block")).into());
+        };
+        let block_len = block.len() as u64;
+
         let piece_block = PieceBlock {
             index: payload.index as u64,
             begin: payload.begin as u64,
-            length: None,
+            length: Some(block_len),
         };
 
         // Calculate the index offset on where we have to write the received piece.
@@ -158,16 +433,19 @@ impl MessageHandler<'_> {
 
         let payload = PieceChannelPayload {
             offset,
-            block: payload.block.unwrap().to_bytes(),
+            block: block.to_bytes(),
         };
 
         let download_finished: bool;
 
         {
             let mut pieces = self.pieces.lock().unwrap();
-            pieces.add_received(piece_block.clone());
+            pieces.add_received_from(piece_block.clone(), self.connection)?;
+            pieces.add_downloaded(self.connection, block_len);
         }
 
+        metrics::DOWNLOADED_BYTES.inc_by(block_len);
+
         {
             // Send message to the channel
             self.file_sender.send(payload).await;
@@ -178,43 +456,117 @@ impl MessageHandler<'_> {
             download_finished = pieces.is_done();
         }
 
-        // Shutdown if finished
+        // Nothing is left to request once every block is in, the connection stays up until the download ends in case
+        // a piece fails its hash check and is downloaded again.
         if download_finished {
-            println!("Torrent downloaded!");
-            self.stream.shutdown(Shutdown::Both).expect("Unable to shutdown stream");
+            debug!("Received every block");
 
-            // Otherwise, request new pieces
+            // Otherwise, request new pieces once the download limit allows it
         } else {
-            self.request_piece();
+            let wait = if self.unlimited { Duration::from_secs(0) } else { self.download_limiter.consume(block_len) };
+            if wait.as_nanos() > 0 {
+                tokio::time::sleep(wait).await;
+            }
+
+            // Hold off while the disk is behind, blocks keep arriving for the requests already sent.
+            if self.pending.is_behind() {
+                debug!("Not requesting pieces until the disk catches up");
+                self.pending.wait_to_request().await;
+            }
+
+            self.request_piece().await?;
         }
+
+        return Ok(());
+    }
+
+
+    /// Send the peer a block it asked for, read through the read cache of the disk.
+    ///
+    /// Requests are only served while the peer has an upload slot, only for blocks of verified pieces, and as fast as
+    /// the upload limits allow.
+    async fn request(&mut self, payload: GenericPayload) -> Result<()> {
+        let Some(length) = payload.length else {
+            return Err(TorrenterError::Protocol(String::from("A request message needs an index, an offset and a length")).into());
+        };
+        let (index, begin, length) = (payload.index as u64, payload.begin as u64, length as u64);
+
+        if self.am_choking {
+            trace!(piece = index, "Not serving a request while choking the peer");
+            return Ok(());
+        }
+        let verified = self.pieces.lock().unwrap().verified().get(index as usize).copied().unwrap_or(false);
+        if !verified || length == 0 || length > BLOCK_LEN || begin + length > self.torrent.get_piece_len(index) {
+            debug!(piece = index, begin, length, "Not serving a request for a block we don't have");
+            return Ok(());
+        }
+
+        let block = match self.disk.read(index * self.torrent.info.piece_length + begin, length).await {
+            Ok(block) => block,
+            Err(e) => {
+                warn!(piece = index, "Unable to read a requested block: {:#}", e);
+                return Ok(());
+            }
+        };
+        let wait = if self.unlimited { Duration::from_secs(0) } else { self.upload_limiter.consume(length) };
+        if wait.as_nanos() > 0 {
+            tokio::time::sleep(wait).await;
+        }
+
+        let send_msg = messages::build_piece(&GenericPayload {
+            index: payload.index,
+            begin: payload.begin,
+            length: None,
+            block: Some(ByteBuffer::from_bytes(&block)),
+            bitfield: None,
+            piece_index: None,
+            extended: None,
+        })?;
+        self.stream.write_all(&send_msg.to_bytes()).await?;
+        self.pieces.lock().unwrap().add_uploaded(length);
+        metrics::UPLOADED_BYTES.inc_by(length);
+        trace!(piece = index, begin, "Sent block");
+        return Ok(());
     }
 
 
     /// Request the first block in the job queue.
-    fn request_piece(&mut self) {
+    async fn request_piece(&mut self) -> io::Result<()> {
 
         // Don't request anything if we're choked.
         // TODO: Add error handling to retry if we're choked.
         if self.queue.choked {
-            println!("We're choked!");
-            return;
+            debug!("Not requesting pieces while choked");
+            return Ok(());
         }
 
-        let mut pieces = self.pieces.lock().unwrap();
-
-        while self.queue.len() > 0 {
-
-            // Grab the first piece in the queue
-            let piece_block = self.queue.deque().unwrap();
+        // Grab the first block of the queue nobody else is downloading, the lock is let go of before it's sent.
+        let request = {
+            let mut pieces = self.pieces.lock().unwrap();
+            let Some(piece_block) = self.queue.next(&pieces, self.connection) else {
+                return Ok(());
+            };
+            match messages::build_request(piece_block) {
+                Ok(request) => {
+                    pieces.add_requested(piece_block, self.connection);
+                    request
+                }
+                Err(e) => {
+                    warn!("Not requesting {:?}: {}", piece_block, e);
+                    return Ok(());
+                }
+            }
+        };
 
-            // Check if that piece is still needed and request if so
-            if pieces.needed(piece_block) {
-                let request = messages::build_request(piece_block);
-                self.stream.write(&*request.to_bytes());
-                pieces.add_requested(piece_block);
+        return self.stream.write_all(&request.to_bytes()).await;
+    }
+}
 
-                break;
-            }
+impl Drop for MessageHandler<'_> {
+    /// Give back the blocks still requested from the peer once the connection is gone.
+    fn drop(&mut self) {
+        if let Ok(mut pieces) = self.pieces.lock() {
+            pieces.disconnect(self.connection);
         }
     }
 }
@@ -247,6 +599,34 @@ fn parse_bitfield(bitfield: Vec<u8>) -> Vec<u64> {
 }
 
 
+/// Clear up to `count` random pieces of a bitfield, returning the pieces which were cleared.
+fn withhold_pieces(bitfield: &mut [u8], count: usize) -> Vec<u64> {
+    let pieces: Vec<u64> = parse_bitfield(bitfield.to_vec());
+    let withheld: Vec<u64> = pieces.choose_multiple(&mut rand::thread_rng(), count).copied().collect();
+
+    for &index in &withheld {
+        bitfield[index as usize / 8] &= !(0x80 >> (index % 8));
+    }
+
+    return withheld;
+}
+
+
+#[test]
+fn test_withhold_pieces() {
+    let mut bitfield = vec![0b1111_0000, 0b0000_0001];
+    let withheld = withhold_pieces(&mut bitfield, 3);
+
+    assert_eq!(withheld.len(), 3);
+    assert!(withheld.iter().all(|index| [0, 1, 2, 3, 15].contains(index)));
+    assert_eq!(parse_bitfield(bitfield.clone()).len(), 2);
+
+    // Everything is withheld when there are fewer pieces than asked for.
+    assert_eq!(withhold_pieces(&mut bitfield, 3).len(), 2);
+    assert_eq!(bitfield, vec![0, 0]);
+}
+
+
 #[test]
 fn test_parse_bitfield() {
     let bitfield: Vec<u8> = vec![127];
@@ -259,3 +639,120 @@ fn test_parse_bitfield() {
     assert_eq!(piece_indexes, vec![7, 6, 5, 4, 3, 2, 1, 0]);
 }
 
+
+
+#[tokio::test]
+async fn test_send_bitfield() {
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use crate::encryption::PeerStream;
+    use crate::pieces::Pieces;
+    use crate::utils::torrents::test_torrent;
+
+    let torrent = test_torrent(16384, &[("a", 16384 * 10)]);
+    let mut pieces = Pieces::new(&torrent);
+    pieces.add_verified(1);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = PeerStream::plaintext(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+    let (_reader, mut writer) = stream.into_split().unwrap();
+    let (mut peer, _) = listener.accept().unwrap();
+
+    let mut queue = Queue::new(&torrent);
+    let swarm = Swarm::test(pieces);
+    let mut handler = MessageHandler::new(&torrent, &mut writer, mpsc::channel(1).0, &mut queue, swarm, broadcast::channel(1).1, Arc::new(RateLimiter::new(0)));
+    handler.handshake(&[0; 68]).await.unwrap();
+
+    // The bitfield comes right after the handshake, before interested.
+    let mut received = [0; 12];
+    peer.read_exact(&mut received).unwrap();
+    assert_eq!(received, [0, 0, 0, 3, 5, 0b0100_0000, 0, 0, 0, 0, 1, 2]);
+}
+
+
+#[tokio::test]
+async fn test_send_have_to_quiet_peer() {
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use crate::encryption::PeerStream;
+    use crate::pieces::Pieces;
+    use crate::utils::torrents::test_torrent;
+
+    let torrent = test_torrent(16384, &[("a", 16384 * 10)]);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = PeerStream::plaintext(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+    let (mut reader, mut writer) = stream.into_split().unwrap();
+    let (mut peer, _) = listener.accept().unwrap();
+    let peer = std::thread::spawn(move || {
+        let mut received = [0; 9];
+        peer.read_exact(&mut received).unwrap();
+        return received;
+    });
+
+    let mut queue = Queue::new(&torrent);
+    let swarm = Swarm::test(Pieces::new(&torrent));
+    let _ticks = swarm.ticks.clone();
+    let (have_sender, haves) = broadcast::channel(1);
+    let mut handler = MessageHandler::new(&torrent, &mut writer, mpsc::channel(1).0, &mut queue, swarm, haves, Arc::new(RateLimiter::new(0)));
+    have_sender.send(PieceUpdate::Have(3)).unwrap();
+
+    // The have goes out although the peer never sends anything, the connection ends once the peer closes it.
+    assert!(handler.run(&mut reader).await.is_err());
+    assert_eq!(peer.join().unwrap(), [0, 0, 0, 5, 4, 0, 0, 0, 3]);
+}
+
+
+#[tokio::test]
+async fn test_serve_request() {
+    use std::fs;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::path::Path;
+    use tokio::sync::mpsc;
+    use crate::config::DiskConfig;
+    use crate::create::{create_torrent, CreateOptions};
+    use crate::encryption::PeerStream;
+    use crate::pieces::Pieces;
+    use crate::storage::FileStorage;
+
+    let dir = Path::new("test-files/serve-request");
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir.join("data")).unwrap();
+    let content: Vec<u8> = (0..BLOCK_LEN * 2).map(|i| i as u8).collect();
+    fs::write(dir.join("data/a.bin"), &content).unwrap();
+    let metainfo = create_torrent(&CreateOptions { path: dir.join("data"), piece_length: Some(BLOCK_LEN), ..Default::default() }).unwrap();
+    let torrent = Arc::new(Torrent::from_bytes(&metainfo).unwrap());
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = PeerStream::plaintext(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+    let (_reader, mut writer) = stream.into_split().unwrap();
+    let (mut peer, _) = listener.accept().unwrap();
+
+    // Only the first piece is verified.
+    let mut pieces = Pieces::new(&torrent);
+    pieces.add_verified(0);
+    let swarm = Swarm {
+        disk: DiskIo::start(torrent.clone(), dir.join("data").to_string_lossy().into_owned(), FileStorage::from_torrent(&torrent), &DiskConfig::default()).unwrap(),
+        ..Swarm::test(pieces)
+    };
+    let mut queue = Queue::new(&torrent);
+    let mut handler = MessageHandler::new(&torrent, &mut writer, mpsc::channel(1).0, &mut queue, swarm, broadcast::channel(1).1, Arc::new(RateLimiter::new(0)));
+    let request = |index, begin, length| messages::build_request(PieceBlock { index, begin, length: Some(length) }).unwrap();
+
+    // Nothing is sent while the peer is choked, or for a piece we don't have.
+    handler.router(request(0, 0, BLOCK_LEN)).await.unwrap();
+    handler.am_choking = false;
+    handler.router(request(1, 0, BLOCK_LEN)).await.unwrap();
+    handler.router(request(0, 1024, 2048)).await.unwrap();
+
+    let mut received = vec![0; 13 + 2048];
+    peer.read_exact(&mut received).unwrap();
+    assert_eq!(received[..13], [0, 0, 8, 9, 7, 0, 0, 0, 0, 0, 0, 4, 0]);
+    assert_eq!(received[13..], content[1024..3072]);
+    assert_eq!(handler.pieces.lock().unwrap().uploaded(), 2048);
+
+    drop(handler);
+    fs::remove_dir_all(dir).unwrap();
+}
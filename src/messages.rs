@@ -1,8 +1,22 @@
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr};
+
 use bytebuffer::ByteBuffer;
 use rand::Rng;
 
+use torrenter::bencode::{Encoder, Value};
+use crate::error::{self, TorrenterError};
 use crate::queue::PieceBlock;
-use crate::utils::torrents;
+use crate::utils::{torrents, PEER_ID_LEN};
+
+/// Bit of the reserved bytes of the handshake for the extension protocol (BEP 10), the 0x10 of the sixth byte.
+pub const EXTENSION_BIT: u64 = 0x10 << 16;
+
+/// Extended message id of the extended handshake.
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+/// Id peers send our `lt_donthave` messages with, given to them in the extended handshake.
+pub const LT_DONTHAVE_ID: u8 = 1;
 
 #[derive(Debug)]
 pub struct GenericPayload {
@@ -12,6 +26,19 @@ pub struct GenericPayload {
     pub(crate) piece_index: Option<u32>,
     pub(crate) block: Option<ByteBuffer>,
     pub(crate) bitfield: Option<ByteBuffer>,
+    /// Extended message id and payload of an extended message.
+    pub(crate) extended: Option<(u8, Vec<u8>)>,
+}
+
+/// What a peer told us in its extended handshake.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Extensions {
+    /// Id of the peer's `lt_donthave` messages, None when it doesn't support them.
+    pub lt_donthave: Option<u8>,
+    /// The peer is a seed or only uploads, it won't request anything from us (BEP 21).
+    pub upload_only: bool,
+    /// Our address as the peer sees it.
+    pub yourip: Option<IpAddr>,
 }
 
 #[derive(Debug)]
@@ -46,8 +73,8 @@ pub fn parse(mut msg: ByteBuffer) -> Msg {
     };
 
     match id {
-        // if message request, piece or cancel
-        6 | 7 | 8 | 9 => {
+        // if message request, piece or cancel, leaving out the ones too short to have an index and offset
+        6 | 7 | 8 | 9 if payload_bytes.len() >= 8 => {
             rest.write_bytes(&payload_bytes.to_bytes()[8..payload_bytes.len()]);
             index = payload_bytes.read_u32();
             begin = payload_bytes.read_u32();
@@ -62,6 +89,7 @@ pub fn parse(mut msg: ByteBuffer) -> Msg {
         block: None,
         bitfield: None,
         piece_index: None,
+        extended: None,
     };
 
     // Fill payload with different data depending on the message type.
@@ -69,13 +97,18 @@ pub fn parse(mut msg: ByteBuffer) -> Msg {
         // Choke, unchoke, interested, uninterested.
         0 | 1 | 2 | 3 => payload.length = Some(rest.len() as u32),
         // Have
-        4 => payload.piece_index = Some(rest.read_u32()),
+        4 if payload_bytes.len() >= 4 => payload.piece_index = Some(payload_bytes.read_u32()),
         // Bitfield
         5 => payload.bitfield = Some(payload_bytes),
         // Request, cancel
-        6 | 8 => payload.length = Some(rest.read_u32()),
+        6 | 8 if rest.len() >= 4 => payload.length = Some(rest.read_u32()),
         // Piece
-        7 => payload.block = Some(rest),
+        7 if payload_bytes.len() >= 8 => payload.block = Some(rest),
+        // Extended
+        20 if msg.len() > 5 => {
+            let bytes = payload_bytes.to_bytes();
+            payload.extended = Some((bytes[0], bytes[1..].to_vec()));
+        }
         _ => {}
     };
 
@@ -110,7 +143,7 @@ pub fn build_peer_handshake(info_hash: &[u8; 20], peer_id: &ByteBuffer) -> ByteB
     let mut handshake: ByteBuffer = ByteBuffer::new();
     handshake.write_u8(19);
     handshake.write_bytes("BitTorrent protocol".as_bytes());
-    handshake.write_u64(0);
+    handshake.write_u64(EXTENSION_BIT);
     handshake.write_bytes(info_hash);
     handshake.write_bytes(&peer_id.to_bytes());
 
@@ -215,17 +248,21 @@ pub fn build_bitfield(bitfield: &ByteBuffer) -> ByteBuffer {
 ///   length: integer specifying the requested length.
 ///
 ///   request: <len=0013><id=6><index><begin><length>
-pub fn build_request(payload: PieceBlock) -> ByteBuffer {
+pub fn build_request(payload: PieceBlock) -> error::Result<ByteBuffer> {
+    let length = payload.length.ok_or_else(|| TorrenterError::Protocol(String::from("A request needs a length")))?;
+    let field = |value: u64| u32::try_from(value)
+        .map_err(|_| TorrenterError::Protocol(format!("{} doesn't fit in a request", value)));
+
     let mut buf: ByteBuffer = ByteBuffer::new();
 
     buf.write_u32(13);
     buf.write_u8(6);
 
-    buf.write_u32(payload.index as u32);
-    buf.write_u32(payload.begin as u32);
-    buf.write_u32(payload.length.unwrap() as u32);
+    buf.write_u32(field(payload.index)?);
+    buf.write_u32(field(payload.begin)?);
+    buf.write_u32(field(length)?);
 
-    return buf;
+    return Ok(buf);
 }
 
 
@@ -236,17 +273,21 @@ pub fn build_request(payload: PieceBlock) -> ByteBuffer {
 ///     block: block of data, which is a subset of the piece specified by index.
 ///
 ///     piece: <len=0009+X><id=7><index><begin><block>
-pub fn build_piece(payload: &GenericPayload) -> ByteBuffer {
+pub fn build_piece(payload: &GenericPayload) -> error::Result<ByteBuffer> {
+    let block = payload.block.as_ref().ok_or_else(|| TorrenterError::Protocol(String::from("A piece message needs a block")))?;
+    let len = u32::try_from(9 + block.len())
+        .map_err(|_| TorrenterError::Protocol(format!("A block of {} bytes doesn't fit in a message", block.len())))?;
+
     let mut buf: ByteBuffer = ByteBuffer::new();
 
-    buf.write_u32(9 + payload.block.as_ref().unwrap().len() as u32);
+    buf.write_u32(len);
     buf.write_u8(7);
 
     buf.write_u32(payload.index);
     buf.write_u32(payload.begin);
-    buf.write_bytes(&payload.block.as_ref().unwrap().to_bytes());
+    buf.write_bytes(&block.to_bytes());
 
-    return buf;
+    return Ok(buf);
 }
 
 
@@ -260,15 +301,15 @@ pub fn build_piece(payload: &GenericPayload) -> ByteBuffer {
 ///
 ///  cancel: <len=0013><id=8><index><begin><length>
 ///
-pub fn build_cancel(payload: GenericPayload) -> ByteBuffer {
+pub fn build_cancel(payload: PieceBlock) -> ByteBuffer {
     let mut buf: ByteBuffer = ByteBuffer::new();
 
     buf.write_u32(13);
     buf.write_u8(8);
 
-    buf.write_u32(payload.index);
-    buf.write_u32(payload.begin);
-    buf.write_u32(payload.length.unwrap_or(0));
+    buf.write_u32(payload.index as u32);
+    buf.write_u32(payload.begin as u32);
+    buf.write_u32(payload.length.unwrap_or(0) as u32);
 
     return buf;
 }
@@ -293,6 +334,87 @@ pub fn build_port(port: u16) -> ByteBuffer {
 }
 
 
+/// extended: <len=0002+X><id=20><extended id><payload>
+pub fn build_extended(extended_id: u8, payload: &[u8]) -> ByteBuffer {
+    let mut buf: ByteBuffer = ByteBuffer::new();
+
+    buf.write_u32((payload.len() + 2) as u32);
+    buf.write_u8(20);
+    buf.write_u8(extended_id);
+    buf.write_bytes(payload);
+
+    return buf;
+}
+
+
+/// The extended handshake gives the ids the peer has to send our extended messages with, and whether we're only
+/// uploading because we have every piece.
+///
+///     {"m": {"lt_donthave": 1}, "upload_only": 1}
+pub fn build_extended_handshake(upload_only: bool) -> ByteBuffer {
+    let mut encoder = Encoder::new();
+    encoder.begin_dict().bytes(b"m").begin_dict().bytes(b"lt_donthave").int(LT_DONTHAVE_ID as i64).end();
+    if upload_only {
+        encoder.bytes(b"upload_only").int(1);
+    }
+    encoder.end();
+
+    return build_extended(EXTENDED_HANDSHAKE_ID, &encoder.finish());
+}
+
+
+/// Read the ids of the extended messages the peer supports from its extended handshake.
+pub fn parse_extended_handshake(payload: &[u8]) -> anyhow::Result<Extensions> {
+    let handshake = Value::decode(payload)?;
+    let dict = handshake.as_dict().ok_or_else(|| anyhow::anyhow!("Error: The extended handshake isn't a dictionary"))?;
+    let ids = dict.get(b"m".as_slice())
+        .and_then(|m| m.as_dict())
+        .ok_or_else(|| anyhow::anyhow!("Error: The extended handshake has no m dictionary"))?;
+
+    // An id of 0 means the extension is turned off.
+    let id = |name: &[u8]| ids.get(name).and_then(|id| id.as_int()).filter(|&id| id > 0 && id <= 255).map(|id| id as u8);
+
+    return Ok(Extensions {
+        lt_donthave: id(b"lt_donthave"),
+        upload_only: dict.get(b"upload_only".as_slice()).and_then(|upload_only| upload_only.as_int()).unwrap_or(0) != 0,
+        yourip: dict.get(b"yourip".as_slice()).and_then(|yourip| yourip.as_bytes()).and_then(|yourip| match yourip.len() {
+            4 => Some(IpAddr::from(<[u8; 4]>::try_from(yourip).unwrap())),
+            16 => Some(IpAddr::from(<[u8; 16]>::try_from(yourip).unwrap())),
+            _ => None,
+        }),
+    });
+}
+
+
+/// lt_donthave: <len=0006><id=20><extended id><piece index>
+///
+/// Takes back a have, the piece can't be requested from us anymore.
+pub fn build_donthave(extended_id: u8, piece_index: u32) -> ByteBuffer {
+    return build_extended(extended_id, &piece_index.to_be_bytes());
+}
+
+
+#[test]
+fn test_extended_handshake() {
+    let mut handshake = build_extended_handshake(false);
+    let msg = parse(ByteBuffer::from_bytes(&handshake.to_bytes()));
+    assert_eq!(msg.id, 20);
+
+    let (extended_id, payload) = msg.payload.extended.unwrap();
+    assert_eq!(extended_id, EXTENDED_HANDSHAKE_ID);
+    assert_eq!(parse_extended_handshake(&payload).unwrap(), Extensions { lt_donthave: Some(LT_DONTHAVE_ID), upload_only: false, yourip: None });
+    assert_eq!(handshake.read_u32() as usize, handshake.len() - 4);
+
+    let (_, payload) = parse(build_extended_handshake(true)).payload.extended.unwrap();
+    assert!(parse_extended_handshake(&payload).unwrap().upload_only);
+
+    assert_eq!(parse_extended_handshake(b"d1:md11:lt_donthavei0eee").unwrap(), Extensions { lt_donthave: None, upload_only: false, yourip: None });
+    assert_eq!(parse_extended_handshake(b"d1:mde6:yourip4:\xc6\x33\x64\x07e").unwrap().yourip, Some("198.51.100.7".parse().unwrap()));
+    assert!(parse_extended_handshake(b"d1:v3:abce").is_err());
+    assert_eq!(build_donthave(3, 7).to_bytes(), vec![0, 0, 0, 6, 20, 3, 0, 0, 0, 7]);
+}
+
+
 pub fn build_conn_req() -> ByteBuffer {
     let mut rng = rand::thread_rng();
     let mut buffer = ByteBuffer::new();
@@ -312,12 +434,104 @@ pub fn build_conn_req() -> ByteBuffer {
     return buffer;
 }
 
+/// Ask a UDP tracker for the size of the swarm of a torrent.
+pub fn build_scrape_req(connection_id: i64, info_hash: &[u8; 20]) -> ByteBuffer {
+    let mut scrape_req = ByteBuffer::new();
+
+    // 0       64-bit integer  connection_id
+    scrape_req.write_i64(connection_id);
+    // 8       32-bit integer  action          2 // scrape
+    scrape_req.write_i32(2);
+    // 12      32-bit integer  transaction_id
+    scrape_req.write_i32(rand::thread_rng().gen::<i32>());
+    // 16      20-byte string  info_hash
+    scrape_req.write_bytes(info_hash);
+
+    return scrape_req;
+}
+
+/// Why we're announcing, none for the regular announces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AnnounceEvent {
+    #[default]
+    None,
+    Completed,
+    Started,
+    Stopped,
+    /// Sent with every announce while we're a partial seed, with all the wanted files but not the skipped ones (BEP 21).
+    Paused,
+}
+
+impl AnnounceEvent {
+    /// Value of the event in a UDP announce.
+    pub fn udp_id(&self) -> i32 {
+        return match self {
+            AnnounceEvent::None => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+            AnnounceEvent::Paused => 4,
+        };
+    }
+
+    /// Value of the `event` parameter of an HTTP announce, which is left out for none.
+    pub fn http_name(&self) -> Option<&'static str> {
+        return match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Stopped => Some("stopped"),
+            AnnounceEvent::Paused => Some("paused"),
+        };
+    }
+}
+
+/// What we tell a tracker about ourselves when announcing.
+#[derive(Debug, Clone, Default)]
+pub struct AnnounceParams {
+    pub port: u16,
+    pub event: AnnounceEvent,
+    /// Our external address, trackers use the one the announce comes from without it.
+    pub ip: Option<Ipv4Addr>,
+    /// How many peers we want back, -1 leaves it to the tracker.
+    pub num_want: i32,
+    /// Random key kept for the whole session, so trackers can tell it's still us when our address changes.
+    pub key: u32,
+    /// Path and query of the tracker URL, sent as URLData options since UDP trackers can't see the URL (BEP 41).
+    pub url_data: String,
+}
+
+/// Option types of the extensions at the end of UDP announce requests (BEP 41).
+const OPTION_END: u8 = 0x0;
+const OPTION_URL_DATA: u8 = 0x2;
+
+/// The URLData options carrying a path and query, split into as many options as its length needs.
+pub fn url_data_options(url_data: &str) -> Vec<u8> {
+    let mut options = Vec::new();
+    if url_data.is_empty() {
+        return options;
+    }
+
+    for chunk in url_data.as_bytes().chunks(u8::MAX as usize) {
+        options.push(OPTION_URL_DATA);
+        options.push(chunk.len() as u8);
+        options.extend_from_slice(chunk);
+    }
+    options.push(OPTION_END);
+
+    return options;
+}
+
 pub fn build_announce_req(
     torrent: &torrents::Torrent,
     connection_id: i64,
     peer_id: &ByteBuffer,
-    port: i16,
-) -> ByteBuffer {
+    params: &AnnounceParams,
+) -> error::Result<ByteBuffer> {
+    if peer_id.len() != PEER_ID_LEN {
+        return Err(TorrenterError::Protocol(format!("A peer id has {} bytes, not {}", peer_id.len(), PEER_ID_LEN)));
+    }
+
     // Offset  Size    Name    Value
 
     let mut announce_req = ByteBuffer::new();
@@ -330,25 +544,86 @@ pub fn build_announce_req(
     // 12      32-bit integer  transaction_id
     announce_req.write_i32(rng.gen::<i32>());
     // 16      20-byte string  info_hash
-    announce_req.write_bytes(&*torrent.info_hash.as_ref().unwrap());
+    announce_req.write_bytes(&torrent.info_hash);
     // 36      20-byte string  peer_id
     announce_req.write_bytes(&peer_id.to_bytes());
     // 56      64-bit integer  downloaded
     announce_req.write_i64(0);
     // 64      64-bit integer  left
-    announce_req.write_u64(torrent.size.unwrap());
+    announce_req.write_u64(torrent.size);
     // 72      64-bit integer  uploaded
     announce_req.write_i64(0);
-    // 80      32-bit integer  event           0 // 0: none; 1: completed; 2: started; 3: stopped
-    announce_req.write_i32(0);
+    // 80      32-bit integer  event           0 // 0: none; 1: completed; 2: started; 3: stopped; 4: paused
+    announce_req.write_i32(params.event.udp_id());
     // 84      32-bit integer  IP address      0 // default
-    announce_req.write_i32(0);
+    announce_req.write_u32(params.ip.map_or(0, u32::from));
     // 88      32-bit integer  key
-    announce_req.write_i32(0);
+    announce_req.write_u32(params.key);
     // 92      32-bit integer  num_want        -1 // default
-    announce_req.write_i32(-1);
+    announce_req.write_i32(params.num_want);
     // 96      16-bit integer  port
-    announce_req.write_i16(port);
+    announce_req.write_u16(params.port);
+    // 98      options
+    announce_req.write_bytes(&url_data_options(&params.url_data));
+
+    return Ok(announce_req);
+}
+
+
+#[test]
+fn test_build_announce_req() {
+    let torrent = torrents::Torrent { info_hash: [7; 20], size: 1000, ..Default::default() };
+    let params = AnnounceParams {
+        port: 6881,
+        event: AnnounceEvent::Paused,
+        ip: Some(Ipv4Addr::new(203, 0, 113, 9)),
+        num_want: 20,
+        key: 0xdeadbeef,
+        url_data: String::new(),
+    };
+    let req = build_announce_req(&torrent, 42, &ByteBuffer::from_bytes(&[1; 20]), &params).unwrap().to_bytes();
+
+    assert_eq!(req.len(), 98);
+    assert_eq!(req[..8], 42i64.to_be_bytes());
+    assert_eq!(req[16..36], [7; 20]);
+    assert_eq!(req[64..72], 1000u64.to_be_bytes());
+    assert_eq!(req[80..84], 4i32.to_be_bytes());
+    assert_eq!(req[84..88], [203, 0, 113, 9]);
+    assert_eq!(req[88..92], 0xdeadbeefu32.to_be_bytes());
+    assert_eq!(req[92..96], 20i32.to_be_bytes());
+    assert_eq!(req[96..], 6881u16.to_be_bytes());
+}
+
+
+#[test]
+fn test_url_data_options() {
+    assert!(url_data_options("").is_empty());
+    assert_eq!(url_data_options("/an?x=1"), [&[2, 7][..], b"/an?x=1", &[0]].concat());
+
+    // Anything longer than 255 bytes takes several options.
+    let long = "a".repeat(300);
+    let options = url_data_options(&long);
+    assert_eq!(options.len(), 2 + 255 + 2 + 45 + 1);
+    assert_eq!(options[..2], [2, 255]);
+    assert_eq!(options[257..259], [2, 45]);
+    assert_eq!(options[options.len() - 1], 0);
+}
+
+
+#[test]
+fn test_build_request() {
+    let request = build_request(PieceBlock { index: 3, begin: 16384, length: Some(16384) }).unwrap().to_bytes();
+    assert_eq!(request, [0, 0, 0, 13, 6, 0, 0, 0, 3, 0, 0, 64, 0, 0, 0, 64, 0]);
+
+    // Broken payloads are errors instead of panics.
+    assert!(matches!(build_request(PieceBlock { index: 3, begin: 0, length: None }), Err(TorrenterError::Protocol(_))));
+    assert!(build_request(PieceBlock { index: 1 << 32, begin: 0, length: Some(16384) }).is_err());
+    assert!(build_piece(&GenericPayload { index: 0, begin: 0, length: None, piece_index: None, block: None, bitfield: None, extended: None }).is_err());
+
+    let torrent = torrents::Torrent::default();
+    assert!(build_announce_req(&torrent, 42, &ByteBuffer::from_bytes(&[1; 8]), &AnnounceParams::default()).is_err());
 
-    return announce_req;
+    // So are messages of a peer which are too short for what they should have.
+    assert!(parse(ByteBuffer::from_bytes(&[0, 0, 0, 4, 7, 0, 0, 1])).payload.block.is_none());
+    assert!(parse(ByteBuffer::from_bytes(&[0, 0, 0, 9, 6, 0, 0, 0, 1, 0, 0, 0, 0])).payload.length.is_none());
 }
@@ -0,0 +1,391 @@
+use std::convert::TryInto;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytebuffer::ByteBuffer;
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use tracing::{debug, info};
+
+use torrenter::bencode::{Decoder, Encoder, Value};
+use crate::dht::Dht;
+use crate::download::{connect_peer, PeerSettings};
+use crate::encryption::PeerStream;
+use crate::magnet::MagnetLink;
+use crate::messages::{build_extended, EXTENDED_HANDSHAKE_ID, EXTENSION_BIT};
+use crate::resume;
+use crate::utils::{to_hex, Peer, PeerSource};
+use crate::utils::torrents::Torrent;
+
+/// The metadata is sent in pieces of 16 KiB, only the last one can be shorter (BEP 9).
+pub const METADATA_PIECE_LEN: u64 = 16 * 1024;
+
+/// Metadata larger than this is taken as the peer lying about its size.
+const MAX_METADATA_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Id peers send our `ut_metadata` messages with, given to them in the extended handshake.
+pub const UT_METADATA_ID: u8 = 2;
+
+/// How long a peer has to send the next message before the next peer is tried.
+const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Torrent files by info hash, of the magnet links whose metadata was fetched and the torrent files added, so adding
+/// a magnet link again doesn't need the metadata from peers.
+#[derive(Debug, Clone)]
+pub struct MetadataCache {
+    dir: PathBuf,
+}
+
+/// A `ut_metadata` message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataMessage {
+    Request(u64),
+    Data { piece: u64, total_size: u64, data: Vec<u8> },
+    /// The peer doesn't have the metadata or won't send it.
+    Reject(u64),
+}
+
+
+impl MetadataCache {
+    pub fn new(dir: PathBuf) -> MetadataCache {
+        return MetadataCache { dir };
+    }
+
+    fn path(&self, info_hash: &[u8; 20]) -> PathBuf {
+        return self.dir.join(format!("{}.torrent", to_hex(info_hash)));
+    }
+
+    /// The torrent file of an info hash, None when it isn't cached or the file is for another torrent.
+    pub fn load(&self, info_hash: &[u8; 20]) -> Option<Vec<u8>> {
+        let metainfo = fs::read(self.path(info_hash)).ok()?;
+        return match Torrent::from_bytes(&metainfo) {
+            Ok(torrent) if torrent.info_hash == *info_hash => Some(metainfo),
+            _ => None,
+        };
+    }
+
+    pub fn store(&self, info_hash: &[u8; 20], metainfo: &[u8]) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        return resume::write_atomic(&self.path(info_hash), metainfo);
+    }
+}
+
+
+/// The extended handshake asking peers for the metadata of a torrent.
+///
+///     {"m": {"ut_metadata": 2}}
+pub fn build_metadata_handshake() -> ByteBuffer {
+    let mut encoder = Encoder::new();
+    encoder.begin_dict().bytes(b"m").begin_dict().bytes(b"ut_metadata").int(UT_METADATA_ID as i64).end().end();
+
+    return build_extended(EXTENDED_HANDSHAKE_ID, &encoder.finish());
+}
+
+
+/// Ask for a piece of the metadata, with the id of the peer's `ut_metadata` messages.
+///
+///     {"msg_type": 0, "piece": 0}
+pub fn build_metadata_request(extended_id: u8, piece: u64) -> ByteBuffer {
+    let mut encoder = Encoder::new();
+    encoder.begin_dict().bytes(b"msg_type").int(0).bytes(b"piece").int(piece as i64).end();
+
+    return build_extended(extended_id, &encoder.finish());
+}
+
+
+/// Read a `ut_metadata` message, the data of a piece follows its dictionary.
+pub fn parse_metadata_message(payload: &[u8]) -> anyhow::Result<MetadataMessage> {
+    let mut decoder = Decoder::new(payload)?;
+    let message = decoder.value()?;
+    let data = payload[decoder.position()..].to_vec();
+
+    let dict = message.as_dict().ok_or_else(|| anyhow::anyhow!("Error: The metadata message isn't a dictionary"))?;
+    let int = |key: &[u8]| dict.get(key).and_then(|value| value.as_int()).filter(|&value| value >= 0).map(|value| value as u64);
+    let piece = int(b"piece").ok_or_else(|| anyhow::anyhow!("Error: The metadata message has no piece"))?;
+
+    return match int(b"msg_type") {
+        Some(0) => Ok(MetadataMessage::Request(piece)),
+        Some(1) => {
+            let total_size = int(b"total_size").ok_or_else(|| anyhow::anyhow!("Error: The metadata piece has no total size"))?;
+            Ok(MetadataMessage::Data { piece, total_size, data })
+        }
+        Some(2) => Ok(MetadataMessage::Reject(piece)),
+        _ => anyhow::bail!("Error: Unknown metadata message type"),
+    };
+}
+
+
+/// Get the id of the peer's `ut_metadata` messages and the size of the metadata from its extended handshake.
+fn parse_metadata_handshake(payload: &[u8]) -> anyhow::Result<(u8, u64)> {
+    let handshake = Value::decode(payload)?;
+    let dict = handshake.as_dict().ok_or_else(|| anyhow::anyhow!("Error: The extended handshake isn't a dictionary"))?;
+
+    let id = dict.get(b"m".as_slice())
+        .and_then(|m| m.as_dict())
+        .and_then(|m| m.get(b"ut_metadata".as_slice()))
+        .and_then(|id| id.as_int())
+        .filter(|&id| id > 0 && id <= 255);
+    let size = dict.get(b"metadata_size".as_slice()).and_then(|size| size.as_int());
+
+    return match (id, size) {
+        (Some(id), Some(size)) if size > 0 && size as u64 <= MAX_METADATA_SIZE => Ok((id as u8, size as u64)),
+        (Some(_), Some(_)) => anyhow::bail!("Error: The metadata size the peer gave isn't valid"),
+        _ => anyhow::bail!("Error: The peer doesn't send metadata"),
+    };
+}
+
+
+/// Get the info dictionary of a torrent from the first of the peers which has it.
+///
+/// The metadata is checked against the info hash, so a peer can't send something else.
+pub fn fetch_metadata(info_hash: &[u8; 20], peers: &mut [Peer], handshake: &[u8], settings: &PeerSettings) -> anyhow::Result<Vec<u8>> {
+    for peer in peers.iter_mut() {
+        match fetch_from_peer(peer, info_hash, handshake, settings) {
+            Ok(metadata) => {
+                info!(addrs = ?peer.addrs, size = metadata.len(), "Got the metadata");
+                return Ok(metadata);
+            }
+            Err(e) => debug!(addrs = ?peer.addrs, "Unable to get the metadata: {:#}", e),
+        }
+    }
+
+    anyhow::bail!("Error: None of the peers sent the metadata");
+}
+
+
+fn fetch_from_peer(peer: &mut Peer, info_hash: &[u8; 20], handshake: &[u8], settings: &PeerSettings) -> anyhow::Result<Vec<u8>> {
+    // A peer which never answers the handshake is given up on as soon as one which goes quiet later.
+    let settings = PeerSettings { handshake_timeout: settings.handshake_timeout.or(Some(METADATA_TIMEOUT)), ..settings.clone() };
+    let (mut stream, peer_handshake) = connect_peer(peer, info_hash, handshake, &settings)?;
+    let reserved = u64::from_be_bytes(peer_handshake[20..28].try_into().unwrap());
+    if reserved & EXTENSION_BIT == 0 {
+        anyhow::bail!("Error: The peer doesn't support the extension protocol");
+    }
+
+    stream.set_read_timeout(Some(METADATA_TIMEOUT))?;
+    stream.write_all(&build_metadata_handshake().to_bytes())?;
+
+    let mut pieces: Vec<Option<Vec<u8>>> = Vec::new();
+    let mut size = 0;
+    loop {
+        let message = read_message(&mut stream)?;
+        // Everything but extended messages is left for once the torrent is downloading.
+        if message.len() < 2 || message[0] != 20 {
+            continue;
+        }
+
+        match message[1] {
+            EXTENDED_HANDSHAKE_ID => {
+                let (id, metadata_size) = parse_metadata_handshake(&message[2..])?;
+                size = metadata_size;
+                pieces = vec![None; size.div_ceil(METADATA_PIECE_LEN) as usize];
+                for piece in 0..pieces.len() {
+                    stream.write_all(&build_metadata_request(id, piece as u64).to_bytes())?;
+                }
+            }
+            UT_METADATA_ID => match parse_metadata_message(&message[2..])? {
+                MetadataMessage::Data { piece, data, .. } => {
+                    let expected = size.saturating_sub(piece * METADATA_PIECE_LEN).min(METADATA_PIECE_LEN);
+                    match pieces.get_mut(piece as usize) {
+                        Some(slot) if data.len() as u64 == expected => *slot = Some(data),
+                        _ => anyhow::bail!("Error: The peer sent a piece of the metadata which isn't valid"),
+                    }
+                }
+                MetadataMessage::Reject(_) => anyhow::bail!("Error: The peer rejected the metadata request"),
+                MetadataMessage::Request(_) => {}
+            },
+            _ => {}
+        }
+
+        if !pieces.is_empty() && pieces.iter().all(|piece| piece.is_some()) {
+            break;
+        }
+    }
+
+    let metadata: Vec<u8> = pieces.into_iter().flatten().flatten().collect();
+    let mut hasher = Sha1::new();
+    hasher.input(&metadata);
+    let mut hash: [u8; 20] = [0; 20];
+    hasher.result(&mut hash);
+    if hash != *info_hash {
+        anyhow::bail!("Error: The metadata doesn't match the info hash");
+    }
+
+    return Ok(metadata);
+}
+
+
+/// Read the next length prefixed message, skipping keep-alives.
+fn read_message(stream: &mut PeerStream) -> anyhow::Result<Vec<u8>> {
+    loop {
+        let mut len = [0; 4];
+        stream.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as u64;
+        if len == 0 {
+            continue;
+        }
+        if len > METADATA_PIECE_LEN * 2 {
+            anyhow::bail!("Error: The peer sent a message which is too long");
+        }
+
+        let mut message = vec![0; len as usize];
+        stream.read_exact(&mut message)?;
+        return Ok(message);
+    }
+}
+
+
+/// Get the metadata of a magnet link from its `x.pe` peers and the peers the DHT has for it.
+///
+/// Returns the info dictionary along with the peers, to connect to them again for the download.
+pub async fn fetch_magnet(magnet: &MagnetLink, dht: Option<&Arc<Dht>>, handshake: Vec<u8>, settings: PeerSettings) -> anyhow::Result<(Vec<u8>, Vec<Peer>)> {
+    let info_hash = magnet.info_hash;
+    let resolving = magnet.clone();
+    let mut peers = tokio::task::spawn_blocking(move || resolving.resolve_peers()).await?;
+    if let Some(dht) = dht {
+        let found = dht.get_peers(info_hash).await;
+        info!(peers = found.len(), "Got peers for the metadata from the DHT");
+        peers.extend(found.into_iter().map(|addr| Peer::from_addr(addr, PeerSource::Dht)));
+    }
+    if peers.is_empty() {
+        anyhow::bail!("Error: No peers to get the metadata of the magnet link from");
+    }
+
+    return tokio::task::spawn_blocking(move || {
+        let info = fetch_metadata(&info_hash, &mut peers, &handshake, &settings)?;
+        return Ok((info, peers));
+    }).await?;
+}
+
+
+/// Build a torrent file around an info dictionary received from peers, with the trackers and web seeds of the magnet
+/// link.
+///
+/// Every tracker gets its own tier, in the order of the link.
+pub fn build_metainfo(info: &[u8], trackers: &[String], web_seeds: &[String]) -> Vec<u8> {
+    let mut encoder = Encoder::new();
+    encoder.begin_dict();
+    if let Some(tracker) = trackers.first() {
+        encoder.bytes(b"announce").bytes(tracker.as_bytes());
+        encoder.bytes(b"announce-list").begin_list();
+        for tracker in trackers {
+            encoder.begin_list().bytes(tracker.as_bytes()).end();
+        }
+        encoder.end();
+    }
+    encoder.bytes(b"info").raw(info);
+    if !web_seeds.is_empty() {
+        encoder.bytes(b"url-list").begin_list();
+        for web_seed in web_seeds {
+            encoder.bytes(web_seed.as_bytes());
+        }
+        encoder.end();
+    }
+    encoder.end();
+
+    return encoder.finish();
+}
+
+
+#[test]
+fn test_fetch_metadata() {
+    use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+    use crate::create::{create_torrent, CreateOptions};
+    use crate::messages::build_peer_handshake;
+    use std::fs;
+
+    let _ = fs::remove_dir_all("test-files/metadata");
+    fs::create_dir_all("test-files/metadata").unwrap();
+    fs::write("test-files/metadata/file.txt", b"hello").unwrap();
+
+    let metainfo = create_torrent(&CreateOptions { path: "test-files/metadata/file.txt".into(), ..Default::default() }).unwrap();
+    let torrent = Torrent::from_bytes(&metainfo).unwrap();
+    let info = torrenter::bencode::raw_dict_entries(&metainfo).unwrap().into_iter().find(|(key, _)| *key == b"info").unwrap().1.to_vec();
+    let info_hash = torrent.info_hash;
+
+    // A peer which sends a bitfield before its extended handshake, and the metadata for every request.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let served = info.clone();
+    let seed = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = vec![0; 68];
+        stream.read_exact(&mut received).unwrap();
+        stream.write_all(&build_peer_handshake(&info_hash, &ByteBuffer::from_bytes(&[1; 20])).to_bytes()).unwrap();
+        stream.write_all(&[0, 0, 0, 2, 5, 0x80]).unwrap();
+
+        let mut encoder = Encoder::new();
+        encoder.begin_dict().bytes(b"m").begin_dict().bytes(b"ut_metadata").int(3).end().bytes(b"metadata_size").int(served.len() as i64).end();
+        stream.write_all(&build_extended(EXTENDED_HANDSHAKE_ID, &encoder.finish()).to_bytes()).unwrap();
+
+        loop {
+            let mut len = [0; 4];
+            if stream.read_exact(&mut len).is_err() {
+                return;
+            }
+            let mut message = vec![0; u32::from_be_bytes(len) as usize];
+            stream.read_exact(&mut message).unwrap();
+            if message[..2] != [20, 3] {
+                continue;
+            }
+
+            assert_eq!(parse_metadata_message(&message[2..]).unwrap(), MetadataMessage::Request(0));
+            let mut encoder = Encoder::new();
+            encoder.begin_dict().bytes(b"msg_type").int(1).bytes(b"piece").int(0).bytes(b"total_size").int(served.len() as i64).end();
+            let mut payload = encoder.finish();
+            payload.extend_from_slice(&served);
+            stream.write_all(&build_extended(UT_METADATA_ID, &payload).to_bytes()).unwrap();
+        }
+    });
+
+    let handshake = build_peer_handshake(&info_hash, &ByteBuffer::from_bytes(&[2; 20])).to_bytes();
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut peers = vec![
+        // Nothing listens on the first one, and the second never answers the handshake.
+        Peer::from_addr(SocketAddr::from((Ipv4Addr::LOCALHOST, 1)), PeerSource::Manual),
+        Peer::from_addr(silent.local_addr().unwrap(), PeerSource::Manual),
+        Peer::from_addr(SocketAddr::from((Ipv4Addr::LOCALHOST, port)), PeerSource::Manual),
+    ];
+    let settings = PeerSettings { handshake_timeout: Some(Duration::from_millis(200)), ..Default::default() };
+    let metadata = fetch_metadata(&info_hash, &mut peers, &handshake, &settings).unwrap();
+    assert_eq!(metadata, info);
+    seed.join().unwrap();
+
+    let trackers = vec![String::from("udp://a.example.com:80"), String::from("udp://b.example.com:80")];
+    let fetched = Torrent::from_bytes(&build_metainfo(&metadata, &trackers, &[String::from("https://example.com/files/")])).unwrap();
+    assert_eq!(fetched.info_hash, info_hash);
+    assert_eq!(fetched.trackers(), trackers);
+    assert_eq!(fetched.announce_list.len(), 2);
+    assert_eq!(fetched.web_seeds(), vec![String::from("https://example.com/files/")]);
+
+    assert!(matches!(parse_metadata_message(b"d8:msg_typei2e5:piecei1ee"), Ok(MetadataMessage::Reject(1))));
+    assert!(parse_metadata_handshake(b"d1:md11:ut_metadatai3ee13:metadata_sizei999999999ee").is_err());
+
+    let _ = fs::remove_dir_all("test-files/metadata");
+}
+
+
+#[test]
+fn test_metadata_cache() {
+    use crate::create::{create_torrent, CreateOptions};
+
+    let _ = fs::remove_dir_all("test-files/metadata-cache");
+    fs::create_dir_all("test-files/metadata-cache").unwrap();
+    fs::write("test-files/metadata-cache/file.txt", b"hello").unwrap();
+    let metainfo = create_torrent(&CreateOptions { path: "test-files/metadata-cache/file.txt".into(), ..Default::default() }).unwrap();
+    let info_hash = Torrent::from_bytes(&metainfo).unwrap().info_hash;
+
+    let cache = MetadataCache::new(PathBuf::from("test-files/metadata-cache/cache"));
+    assert!(cache.load(&info_hash).is_none());
+    cache.store(&info_hash, &metainfo).unwrap();
+    assert_eq!(cache.load(&info_hash), Some(metainfo.clone()));
+
+    // A file which doesn't match the info hash it's cached under is left out.
+    cache.store(&[0; 20], &metainfo).unwrap();
+    assert!(cache.load(&[0; 20]).is_none());
+
+    let _ = fs::remove_dir_all("test-files/metadata-cache");
+}
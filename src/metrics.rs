@@ -0,0 +1,106 @@
+use std::sync::LazyLock;
+
+use prometheus::{Encoder, GaugeVec, Histogram, HistogramOpts, IntCounter, IntGauge, Opts, Registry, TextEncoder};
+
+use crate::session::Session;
+use crate::utils::to_hex;
+
+/// Registry holding every metric of the session, exposed on `/metrics`.
+pub static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+pub static DOWNLOADED_BYTES: LazyLock<IntCounter> = LazyLock::new(|| register(
+    IntCounter::new("torrenter_downloaded_bytes_total", "Bytes received from peers").unwrap()
+));
+
+pub static UPLOADED_BYTES: LazyLock<IntCounter> = LazyLock::new(|| register(
+    IntCounter::new("torrenter_uploaded_bytes_total", "Bytes sent to peers").unwrap()
+));
+
+pub static CONNECTED_PEERS: LazyLock<IntGauge> = LazyLock::new(|| register(
+    IntGauge::new("torrenter_connected_peers", "Peers currently connected").unwrap()
+));
+
+pub static PIECE_VERIFICATION_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| register(
+    IntCounter::new("torrenter_piece_verification_failures_total", "Pieces which didn't match their hash").unwrap()
+));
+
+pub static READ_BACK_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| register(
+    IntCounter::new("torrenter_read_back_failures_total", "Pieces which matched their hash but not once read back from disk").unwrap()
+));
+
+pub static TRACKER_ERRORS: LazyLock<IntCounter> = LazyLock::new(|| register(
+    IntCounter::new("torrenter_tracker_errors_total", "Failed tracker requests").unwrap()
+));
+
+pub static DISK_WRITE_SECONDS: LazyLock<Histogram> = LazyLock::new(|| register(
+    Histogram::with_opts(HistogramOpts::new("torrenter_disk_write_seconds", "Time taken to write a block to disk")
+        .buckets(vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0])).unwrap()
+));
+
+pub static DISK_BACKPRESSURE_WAITS: LazyLock<IntCounter> = LazyLock::new(|| register(
+    IntCounter::new("torrenter_disk_backpressure_waits_total", "Times peers stopped requesting blocks until the disk caught up").unwrap()
+));
+
+static TORRENT_PROGRESS: LazyLock<GaugeVec> = LazyLock::new(|| register(
+    GaugeVec::new(Opts::new("torrenter_torrent_progress_percent", "Percentage of blocks received per torrent"), &["info_hash", "name"]).unwrap()
+));
+
+/// Counts a peer in `torrenter_connected_peers` for as long as it's alive.
+pub struct ConnectedPeer;
+
+impl ConnectedPeer {
+    pub fn new() -> ConnectedPeer {
+        CONNECTED_PEERS.inc();
+        ConnectedPeer
+    }
+}
+
+impl Drop for ConnectedPeer {
+    fn drop(&mut self) {
+        CONNECTED_PEERS.dec();
+    }
+}
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: M) -> M {
+    REGISTRY.register(Box::new(metric.clone())).unwrap();
+    return metric;
+}
+
+/// Encode every metric in the Prometheus text format.
+pub fn render(session: &Session) -> String {
+    // Make sure the metrics are registered even if nothing has used them yet.
+    LazyLock::force(&DOWNLOADED_BYTES);
+    LazyLock::force(&UPLOADED_BYTES);
+    LazyLock::force(&CONNECTED_PEERS);
+    LazyLock::force(&PIECE_VERIFICATION_FAILURES);
+    LazyLock::force(&READ_BACK_FAILURES);
+    LazyLock::force(&TRACKER_ERRORS);
+    LazyLock::force(&DISK_WRITE_SECONDS);
+    LazyLock::force(&DISK_BACKPRESSURE_WAITS);
+
+    // The progress is read from the session on every scrape rather than updated on every block.
+    TORRENT_PROGRESS.reset();
+    for status in session.list() {
+        TORRENT_PROGRESS.with_label_values(&[&to_hex(&status.info_hash), &status.name]).set(status.progress as f64);
+    }
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer).unwrap();
+
+    return String::from_utf8(buffer).unwrap();
+}
+
+
+#[test]
+fn test_render() {
+    use crate::config::Config;
+    use crate::utils::gen_peer_id;
+
+    let session = Session::new(gen_peer_id(&Config::default().client.peer_id_prefix), Config::default());
+    DOWNLOADED_BYTES.inc_by(16384);
+
+    let rendered = render(&session);
+    assert!(rendered.contains("# TYPE torrenter_downloaded_bytes_total counter"));
+    assert!(rendered.contains("# TYPE torrenter_disk_write_seconds histogram"));
+    assert!(rendered.contains("torrenter_connected_peers 0"));
+}
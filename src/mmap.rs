@@ -0,0 +1,235 @@
+use std::fs::{self, OpenOptions};
+use std::path::PathBuf;
+
+use anyhow::Context;
+use memmap2::MmapMut;
+use tokio::sync::mpsc;
+use tracing::error;
+
+use crate::cache::WriteRun;
+use crate::disk::{apply_attributes, coalesce, file_path, hash_for_check, hash_for_reply, move_data, rename_files, DiskJob, DiskState, SkippedBlocks};
+use crate::metrics;
+use crate::storage::FileStorage;
+use crate::utils::torrents::Torrent;
+
+/// Files of a torrent mapped into memory, each one mapped the first time it is used.
+struct MappedFiles {
+    download_folder: PathBuf,
+    storage: FileStorage,
+    skipped: SkippedBlocks,
+    maps: Vec<Option<MmapMut>>,
+}
+
+impl MappedFiles {
+    fn new(download_folder: PathBuf, storage: FileStorage, skipped: SkippedBlocks) -> MappedFiles {
+        let maps = storage.files().iter().map(|_| None).collect();
+        return MappedFiles { download_folder, storage, skipped, maps };
+    }
+
+    /// The map of a file, creating the file at its full length if it doesn't exist yet.
+    fn map(&mut self, index: usize) -> anyhow::Result<&mut MmapMut> {
+        if self.maps[index].is_none() {
+            let path = file_path(&self.download_folder, self.storage.file(index));
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)
+                .with_context(|| format!("Unable to open {:?}", path))?;
+            file.set_len(self.storage.file(index).length)?;
+
+            // Safety: the files of a torrent are only changed through its disk thread while it runs.
+            let map = unsafe { MmapMut::map_mut(&file) }.with_context(|| format!("Unable to map {:?}", path))?;
+            self.maps[index] = Some(map);
+        }
+
+        return Ok(self.maps[index].as_mut().unwrap());
+    }
+
+    fn write(&mut self, offset: u64, block: &[u8]) -> anyhow::Result<()> {
+        self.skipped.keep(&self.storage, offset, block.len() as u64, |start, len| block[start..start + len].to_vec());
+
+        for segment in self.storage.map_range(offset, block.len() as u64) {
+            if !self.storage.is_stored(segment.file) {
+                continue;
+            }
+
+            let start = segment.file_offset as usize;
+            self.map(segment.file)?[start..start + segment.len].copy_from_slice(&block[segment.start..segment.start + segment.len]);
+        }
+
+        return Ok(());
+    }
+
+    /// Call `f` with each part of the range, in order, straight from the maps.
+    fn for_each_slice(&mut self, offset: u64, length: u64, mut f: impl FnMut(&[u8])) -> anyhow::Result<()> {
+        let segments = self.storage.map_range(offset, length);
+        if segments.iter().map(|segment| segment.len as u64).sum::<u64>() != length {
+            anyhow::bail!("Error: {} bytes at {} are past the end of the torrent", length, offset);
+        }
+
+        for segment in segments {
+            if self.storage.file(segment.file).is_pad() {
+                f(&vec![0; segment.len]);
+                continue;
+            }
+            if self.storage.is_skipped(segment.file) {
+                let kept = self.skipped.read(offset + segment.start as u64, segment.len as u64);
+                f(&kept.context("Error: The range is in a skipped file")?);
+                continue;
+            }
+
+            let start = segment.file_offset as usize;
+            f(&self.map(segment.file)?[start..start + segment.len]);
+        }
+
+        return Ok(());
+    }
+
+    /// Copy a range out of the maps.
+    fn read(&mut self, offset: u64, length: u64) -> anyhow::Result<Vec<u8>> {
+        let mut block = Vec::with_capacity(length as usize);
+        self.for_each_slice(offset, length, |slice| block.extend_from_slice(slice))?;
+        return Ok(block);
+    }
+
+    /// msync some of the files.
+    fn flush(&self, indexes: &[usize]) -> anyhow::Result<()> {
+        for &index in indexes {
+            if let Some(map) = &self.maps[index] {
+                map.flush()?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Flush and drop every map, for files which are going to be renamed or moved.
+    fn unmap(&mut self) -> anyhow::Result<()> {
+        for map in self.maps.iter_mut() {
+            if let Some(map) = map.take() {
+                map.flush()?;
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+/// Run jobs with the files mapped into memory until every `DiskIo` handle is dropped.
+pub fn run(torrent: &Torrent, download_folder: &str, mut receiver: mpsc::Receiver<DiskJob>, state: DiskState) {
+    let DiskState { storage, skipped, mut syncer, mut verified, mut hashes, pending, jobs: job_sender, .. } = state;
+    let mut files = MappedFiles::new(PathBuf::from(download_folder), storage, skipped);
+
+    while let Some(job) = receiver.blocking_recv() {
+        let mut jobs = vec![job];
+        while let Ok(job) = receiver.try_recv() {
+            jobs.push(job);
+        }
+
+        for job in coalesce(jobs) {
+            match job {
+                DiskJob::Write(payload) => {
+                    // Blocks are written straight away, so only the ones right after the hashed part of their piece are hashed.
+                    hashes.feed(&WriteRun::from(payload.clone()));
+
+                    let timer = metrics::DISK_WRITE_SECONDS.start_timer();
+                    if let Err(e) = files.write(payload.offset, &payload.block) {
+                        error!("Unable to write block at {}: {:#}", payload.offset, e);
+                        pending.failed(&e);
+                    }
+                    timer.observe_duration();
+                    pending.written(payload.block.len() as u64);
+
+                    syncer.written(&files.storage, payload.offset, payload.block.len() as u64);
+                    if let Err(e) = files.flush(&syncer.due()) {
+                        error!("Unable to flush mapped files: {:#}", e);
+                    }
+                }
+                DiskJob::Read { offset, length, reply } => {
+                    let _ = reply.send(files.read(offset, length));
+                }
+                DiskJob::HashPiece { index, reply } => {
+                    hash_for_reply(files.read(index * torrent.info.piece_length, torrent.get_piece_len(index)), reply);
+                }
+                DiskJob::VerifyPiece { index, reply } => {
+                    if let Some(hash) = hashes.take(index) {
+                        if !verified.read_back(torrent, index, hash) {
+                            files.skipped.discard(index * torrent.info.piece_length, torrent.get_piece_len(index));
+                            let _ = reply.send(verified.check(torrent, &files.download_folder, files.storage.files(), index, hash));
+                            continue;
+                        }
+                    }
+
+                    let piece = files.read(index * torrent.info.piece_length, torrent.get_piece_len(index));
+                    files.skipped.discard(index * torrent.info.piece_length, torrent.get_piece_len(index));
+                    hash_for_check(&job_sender, index, piece, reply);
+                }
+                DiskJob::PieceHashed { index, hash, reply } => {
+                    let _ = reply.send(verified.check(torrent, &files.download_folder, files.storage.files(), index, hash));
+                }
+                DiskJob::Rename { rename, reply } => {
+                    let result = files.unmap().and_then(|_| rename_files(&mut files.download_folder, &mut files.storage, rename));
+                    let _ = reply.send(result);
+                }
+                DiskJob::Move { to, reply } => {
+                    let result = files.unmap().and_then(|_| move_data(&files.download_folder, &to));
+                    if result.is_ok() {
+                        files.download_folder = to;
+                    }
+                    let _ = reply.send(result);
+                }
+                DiskJob::Flush { reply } => {
+                    let _ = reply.send(());
+                }
+                DiskJob::Sync { reply } => {
+                    let _ = reply.send(files.flush(&syncer.take()));
+                }
+                DiskJob::ApplyAttributes { symlinks, reply } => {
+                    let _ = reply.send(apply_attributes(&files.download_folder, files.storage.files(), symlinks));
+                }
+            }
+        }
+    }
+}
+
+
+#[tokio::test]
+async fn test_mmap_disk_io() {
+    use std::path::Path;
+    use std::sync::Arc;
+    use crate::config::DiskConfig;
+    use crate::create::{create_torrent, CreateOptions};
+    use crate::disk::{DiskBackend, DiskIo};
+    use crate::message_handlers::PieceChannelPayload;
+    use crate::storage::FileStorage;
+
+    let dir = Path::new("test-files/mmap");
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir.join("source/data")).unwrap();
+
+    let content: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+    fs::write(dir.join("source/data/a.bin"), &content[..30000]).unwrap();
+    fs::write(dir.join("source/data/b.bin"), &content[30000..]).unwrap();
+
+    let metainfo = create_torrent(&CreateOptions {
+        path: dir.join("source/data"),
+        piece_length: Some(16384),
+        ..Default::default()
+    }).unwrap();
+    let torrent = Arc::new(Torrent::from_bytes(&metainfo).unwrap());
+
+    let config = DiskConfig { backend: DiskBackend::Mmap, ..Default::default() };
+    let disk = DiskIo::start(torrent.clone(), dir.join("download/data").to_string_lossy().into_owned(), FileStorage::from_torrent(&torrent), &config).unwrap();
+    for (i, block) in content.chunks(16384).enumerate() {
+        disk.write(PieceChannelPayload { offset: i as u64 * 16384, block: block.to_vec() }).await.unwrap();
+    }
+    disk.sync().await.unwrap();
+
+    assert_eq!(disk.read(29990, 20).await.unwrap(), content[29990..30010].to_vec());
+    assert_eq!(disk.hash_piece(1).await.unwrap()[..], torrent.info.pieces[20..40]);
+    assert!(disk.read(39990, 20).await.is_err());
+    assert_eq!(fs::read(dir.join("download/data/b.bin")).unwrap(), content[30000..].to_vec());
+
+    let _ = fs::remove_dir_all(dir);
+}
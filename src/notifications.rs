@@ -0,0 +1,54 @@
+use notify_rust::Notification;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+use crate::events::{Event, EventKind};
+
+/// Show a desktop notification when a torrent finishes or fails.
+pub async fn run_notifications(mut events: broadcast::Receiver<Event>) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        };
+
+        let (summary, body) = match build_notification(&event) {
+            Some(notification) => notification,
+            None => continue,
+        };
+
+        // Showing a notification blocks until the notification server answers.
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = Notification::new().appname("torrenter").summary(&summary).body(&body).show() {
+                warn!("Unable to show desktop notification: {}", e);
+            }
+        });
+    }
+}
+
+/// Get the summary and body of the notification for an event, if there is one for it.
+fn build_notification(event: &Event) -> Option<(String, String)> {
+    return match event.kind {
+        EventKind::Completed => Some((String::from("Download finished"), event.name.clone())),
+        EventKind::Error => {
+            let message = event.message.as_deref().unwrap_or("Unknown error");
+            Some((String::from("Download failed"), format!("{}: {}", event.name, message)))
+        }
+        EventKind::Added | EventKind::TrackerFailure => None,
+    };
+}
+
+
+#[test]
+fn test_build_notification() {
+    let event = Event::new(EventKind::Completed, [0; 20], "Test torrent");
+    assert_eq!(build_notification(&event), Some((String::from("Download finished"), String::from("Test torrent"))));
+
+    let event = Event::new(EventKind::Error, [0; 20], "Test torrent").with_message(String::from("Disk full"));
+    assert_eq!(build_notification(&event).unwrap().1, "Test torrent: Disk full");
+
+    let event = Event::new(EventKind::Added, [0; 20], "Test torrent");
+    assert_eq!(build_notification(&event), None);
+}
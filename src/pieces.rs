@@ -1,90 +1,355 @@
-use crate::queue::PieceBlock;
+use std::time::{Duration, Instant};
+
+use tracing::trace;
+
+use crate::error::{self, TorrenterError};
+use crate::queue::{ConnectionId, InFlight, PieceBlock};
+use crate::storage::FileStorage;
 use crate::utils::torrents::{BLOCK_LEN, calculate_torrent_size, Torrent};
 
 #[derive(Debug, Clone)]
 pub struct Pieces {
-    requested: Vec<Vec<bool>>,
+    /// Blocks requested from the peers which haven't arrived yet.
+    in_flight: InFlight,
     received: Vec<Vec<bool>>,
+    /// Blocks of `received` in all, and the ones which haven't been received, kept up to date so telling how far
+    /// along the download is doesn't go through every block.
+    blocks: usize,
+    missing: usize,
     percent_received: f32,
+    /// Bytes received, without the bytes of pad files and skipped files.
+    downloaded: u64,
+    /// Bytes of the blocks sent to peers.
+    uploaded: u64,
+    piece_length: u64,
+    size: u64,
+    content_size: u64,
+    /// Start and end of each pad file and skipped file within the torrent.
+    pads: Vec<(u64, u64)>,
+    /// Pieces which passed their hash check, the ones we can share with peers.
+    verified: Vec<bool>,
+    /// Pieces of nothing but skipped files, they're never downloaded.
+    unwanted: Vec<bool>,
 }
 
 impl Pieces {
     pub fn new(torrent: &Torrent) -> Pieces {
+        let received = build_pieces_vec(torrent);
+        let blocks = received.iter().map(|piece| piece.len()).sum();
         Pieces {
-            requested: build_pieces_vec(torrent),
-            received: build_pieces_vec(torrent),
+            in_flight: InFlight::default(),
+            received,
+            blocks,
+            missing: blocks,
             percent_received: 0.0,
+            downloaded: 0,
+            uploaded: 0,
+            piece_length: torrent.info.piece_length,
+            size: torrent.size,
+            content_size: torrent.content_size(),
+            pads: build_pads(torrent),
+            verified: vec![false; torrent.info.pieces.len() / 20],
+            unwanted: vec![false; torrent.info.pieces.len() / 20],
         }
     }
 
-    /// Flag the requested block as true
-    pub fn add_requested(&mut self, piece_block: PieceBlock) {
-        let block_index = piece_block.begin / BLOCK_LEN;
-        self.requested[piece_block.index as usize][block_index as usize] = true;
+    /// Leave out the skipped files of the storage, their pieces count as received without being downloaded.
+    ///
+    /// Pieces shared with wanted files are still downloaded, as they can't be checked otherwise.
+    pub fn skip(&mut self, storage: &FileStorage) {
+        for piece in storage.unwanted_pieces() {
+            self.received[piece as usize].fill(true);
+            self.unwanted[piece as usize] = true;
+        }
+
+        for (start, end) in storage.skipped_ranges() {
+            self.content_size -= end - start;
+            self.pads.push((start, end));
+        }
+        self.missing = count_missing(&self.received);
+        self.percent_received = calculate_downloaded_percent(&self.received);
+    }
+
+    /// A new id for a peer connection, to keep track of the blocks requested from it.
+    pub fn connect(&mut self) -> ConnectionId {
+        return self.in_flight.connect();
+    }
+
+    /// Return the blocks requested from a peer which disconnected, for the other peers to request.
+    pub fn disconnect(&mut self, connection: ConnectionId) {
+        self.in_flight.disconnect(connection);
+    }
+
+    pub fn set_unchoked(&mut self, connection: ConnectionId, unchoked: bool) {
+        self.in_flight.set_unchoked(connection, unchoked);
+    }
+
+    /// A block arrived from a peer, the other peers it was requested from are told to cancel it.
+    pub fn add_received_from(&mut self, piece_block: PieceBlock, connection: ConnectionId) -> error::Result<()> {
+        self.block_index(piece_block)?;
+        self.in_flight.received(piece_block, connection);
+        return self.add_received(piece_block);
+    }
+
+    /// Requests a peer should cancel, as the blocks came from other peers.
+    pub fn take_cancels(&mut self, connection: ConnectionId) -> Vec<PieceBlock> {
+        return self.in_flight.take_cancels(connection);
+    }
+
+    /// Bytes received from a peer, for how fast it is.
+    pub fn add_downloaded(&mut self, connection: ConnectionId, len: u64) {
+        self.in_flight.add_downloaded(connection, len);
+    }
+
+    /// Whether a peer has no blocks requested from it while it would send them.
+    pub fn is_idle(&self, connection: ConnectionId) -> bool {
+        return self.in_flight.is_idle(connection);
+    }
+
+    /// Whether a peer hasn't sent any of the blocks requested from it for a while, see `InFlight::is_snubbing`.
+    pub fn is_snubbing(&self, connection: ConnectionId) -> bool {
+        return self.in_flight.is_snubbing(connection, Instant::now());
+    }
+
+    /// Let the fastest idle peers take the blocks which have been in flight for `timeout` or longer.
+    pub fn find_stalled(&mut self, timeout: Duration) {
+        self.in_flight.find_stalled(timeout);
+    }
+
+    /// Flag the peers sending less than `min_rate` bytes per second to be disconnected, returning how many there are.
+    pub fn drop_slow_peers(&mut self, min_rate: u64) -> usize {
+        return self.in_flight.drop_slow(min_rate);
+    }
+
+    /// Whether a peer was found too slow and should disconnect.
+    pub fn is_dropped(&self, connection: ConnectionId) -> bool {
+        return self.in_flight.is_dropped(connection);
+    }
+
+    /// Pick the peers we upload to until the next choking round, see `InFlight::choke_round`.
+    pub fn choke_round(&mut self, slots: usize) {
+        self.in_flight.choke_round(slots);
+    }
+
+    /// Whether we upload to a peer this choking round.
+    pub fn is_upload_slot(&self, connection: ConnectionId) -> bool {
+        return self.in_flight.is_upload_slot(connection);
+    }
+
+    /// Flag a block as requested from a peer.
+    pub fn add_requested(&mut self, piece_block: PieceBlock, connection: ConnectionId) {
+        self.in_flight.add(piece_block, connection);
     }
 
 
     /// Flag the received block as true
-    pub fn add_received(&mut self, piece_block: PieceBlock) {
-        let block_index = piece_block.begin / BLOCK_LEN;
-        self.received[piece_block.index as usize][block_index as usize] = true;
-        self.percent_received = calculate_downloaded_percent(&self.received);
-        println!("Downloaded: {}", self.percent_received);
+    pub fn add_received(&mut self, piece_block: PieceBlock) -> error::Result<()> {
+        let block_index = self.block_index(piece_block)?;
+
+        // Only count the bytes once if the same block arrives twice.
+        if !self.received[piece_block.index as usize][block_index] {
+            let start = piece_block.index * self.piece_length + piece_block.begin;
+            self.add_downloaded_bytes(start, start + piece_block.length.unwrap_or(0));
+            self.missing -= 1;
+        }
+
+        self.received[piece_block.index as usize][block_index] = true;
+        self.in_flight.remove(piece_block);
+        self.percent_received = received_percent(self.blocks, self.missing);
+        trace!(percent = self.percent_received, "Received block");
+        return Ok(());
     }
 
-    /// Find out of a piece_block as been requested.
-    ///
-    /// If the piece has been requested and we still haven't received the piece, it will return false.
-    pub fn needed(&mut self, piece_block: PieceBlock) -> bool {
-        let mut requested_all_pieces = true;
-        let block_index = piece_block.begin / BLOCK_LEN;
+    /// Index of a block within its piece, a peer can send a block which isn't one of the torrent.
+    fn block_index(&self, piece_block: PieceBlock) -> error::Result<usize> {
+        let block_index = (piece_block.begin / BLOCK_LEN) as usize;
+        let blocks = self.received.get(piece_block.index as usize).map_or(0, |piece| piece.len());
+        if !piece_block.begin.is_multiple_of(BLOCK_LEN) || block_index >= blocks {
+            return Err(TorrenterError::Protocol(format!("There's no block at {} of piece {}", piece_block.begin, piece_block.index)));
+        }
+        return Ok(block_index);
+    }
 
-        // Check if all pieces have been requested
-        for piece in &self.requested {
-            for block in piece {
-                if !block {
-                    requested_all_pieces = false;
-                    break;
-                }
+    /// Count the bytes from `start` to `end` of the torrent as downloaded, leaving out pad files and skipped files.
+    fn add_downloaded_bytes(&mut self, start: u64, end: u64) {
+        self.downloaded += self.content_bytes(start, end);
+    }
+
+    /// Bytes from `start` to `end` of the torrent which aren't in pad files or skipped files.
+    fn content_bytes(&self, start: u64, end: u64) -> u64 {
+        let padding: u64 = self.pads.iter().map(|&(pad_start, pad_end)| end.min(pad_end).saturating_sub(start.max(pad_start))).sum();
+        return end - start - padding;
+    }
+
+    /// Download a piece which failed its hash check again, its blocks are missing and their bytes no longer count as
+    /// downloaded. Returns whether the piece was verified before.
+    pub fn reset_piece(&mut self, index: u64) -> bool {
+        let start = index * self.piece_length;
+        let end = (start + self.piece_length).min(self.size);
+        for block in 0..self.received[index as usize].len() {
+            if std::mem::replace(&mut self.received[index as usize][block], false) {
+                let begin = start + block as u64 * BLOCK_LEN;
+                self.downloaded -= self.content_bytes(begin, (begin + BLOCK_LEN).min(end));
+                self.missing += 1;
             }
-            if !requested_all_pieces {
-                break;
+        }
+
+        self.percent_received = received_percent(self.blocks, self.missing);
+        return self.remove_verified(index);
+    }
+
+    /// Flag the pieces verified in an earlier session as received and verified, returning how many there were.
+    pub fn resume(&mut self, torrent: &Torrent, verified: &[bool]) -> usize {
+        let mut resumed = 0;
+        for (index, _) in verified.iter().enumerate().filter(|(_, &verified)| verified) {
+            if !self.received[index].iter().all(|&block| block) {
+                let start = index as u64 * self.piece_length;
+                self.add_downloaded_bytes(start, start + torrent.get_piece_len(index as u64));
             }
+            self.received[index].fill(true);
+            self.verified[index] = true;
+            resumed += 1;
         }
 
-        // If all of the pieces have been requested, replace requested with a copy of received.
-        // This is used to refresh the list of requested pieces.
-        if requested_all_pieces {
-            self.requested = self.received.clone();
+        self.missing = count_missing(&self.received);
+        self.percent_received = calculate_downloaded_percent(&self.received);
+        return resumed;
+    }
+
+    /// Whether a block should be requested from a peer.
+    ///
+    /// A block which is requested from another peer is only needed in endgame, so the last blocks aren't held up by
+    /// a single slow peer, when the other peer is stalled and this one is idle, or when the other peer is snubbing us.
+    pub fn needed(&self, piece_block: PieceBlock, connection: ConnectionId) -> bool {
+        let block_index = piece_block.begin / BLOCK_LEN;
+        if self.received[piece_block.index as usize][block_index as usize] {
+            return false;
         }
 
-        return !self.requested[piece_block.index as usize][block_index as usize];
+        if !self.in_flight.is_requested(piece_block) {
+            return true;
+        }
+        if self.in_flight.is_stealable(piece_block, connection) || self.in_flight.is_snubbed(piece_block, connection) {
+            return true;
+        }
+        return self.is_endgame() && !self.in_flight.is_requested_from(piece_block, connection);
+    }
+
+    /// Whether every block which is left has been requested.
+    pub fn is_endgame(&self) -> bool {
+        return self.missing <= self.in_flight.len();
     }
 
     /// Check if every piece and block has been received
     pub fn is_done(&self) -> bool {
         return self.percent_received == 100.0;
     }
-}
 
-/// Calculate the percentage of blocks that have been received.
-fn calculate_downloaded_percent(pieces: &Vec<Vec<bool>>) -> f32 {
-    let mut total_blocks: f32 = 0.0;
-    let mut downloaded: f32 = 0.0;
+    /// Get the percentage of blocks that have been received.
+    pub fn percent_received(&self) -> f32 {
+        return self.percent_received;
+    }
 
-    for piece in pieces {
-        for block in piece {
-            total_blocks += 1.0;
+    /// Get the amount of bytes that have been received.
+    pub fn downloaded(&self) -> u64 {
+        return self.downloaded;
+    }
 
-            if *block {
-                downloaded += 1.0;
-            }
+    /// Count a block sent to a peer.
+    pub fn add_uploaded(&mut self, len: u64) {
+        self.uploaded += len;
+    }
+
+    /// Get the amount of bytes that have been sent to peers.
+    pub fn uploaded(&self) -> u64 {
+        return self.uploaded;
+    }
+
+    /// Whether every block of a piece has been received.
+    pub fn has_piece(&self, index: u64) -> bool {
+        return self.received[index as usize].iter().all(|&block| block);
+    }
+
+    /// Flag a piece as passing its hash check.
+    pub fn add_verified(&mut self, index: u64) {
+        self.verified[index as usize] = true;
+    }
+
+    /// Flag a piece as not matching its hash anymore, returning whether it was verified before.
+    pub fn remove_verified(&mut self, index: u64) -> bool {
+        return std::mem::replace(&mut self.verified[index as usize], false);
+    }
+
+    /// Whether every piece passed its hash check, so there's nothing left to download.
+    pub fn is_seed(&self) -> bool {
+        return self.verified.iter().all(|&verified| verified);
+    }
+
+    /// Whether every wanted piece passed its hash check but some pieces are skipped, we only upload (BEP 21).
+    pub fn is_partial_seed(&self) -> bool {
+        return !self.is_seed() && self.verified.iter().zip(&self.unwanted).all(|(&verified, &unwanted)| verified || unwanted);
+    }
+
+    /// Whether there's nothing we want from peers anymore.
+    pub fn is_upload_only(&self) -> bool {
+        return self.is_seed() || self.is_partial_seed();
+    }
+
+    pub fn has_verified(&self, index: u64) -> bool {
+        return self.verified[index as usize];
+    }
+
+    /// Which pieces passed their hash check, by index.
+    pub fn verified(&self) -> &[bool] {
+        return &self.verified;
+    }
+
+    /// The verified pieces as the payload of a bitfield message, the first piece in the highest bit.
+    pub fn bitfield(&self) -> Vec<u8> {
+        let mut bitfield = vec![0; self.verified.len().div_ceil(8)];
+        for (index, _) in self.verified.iter().enumerate().filter(|(_, &verified)| verified) {
+            bitfield[index / 8] |= 0x80 >> (index % 8);
+        }
+        return bitfield;
+    }
+
+    /// Bytes of a file within the pieces which have been received.
+    pub fn file_downloaded(&self, storage: &FileStorage, file: usize) -> u64 {
+        let Some((first, last)) = storage.file_pieces(file) else {
+            return 0;
+        };
+
+        return (first..=last)
+            .filter(|&piece| self.has_piece(piece))
+            .flat_map(|piece| storage.map_block(piece, 0, self.piece_length))
+            .filter(|segment| segment.file == file)
+            .map(|segment| segment.len as u64)
+            .sum();
+    }
+
+    /// Percentage of the files which has been received, leaving out pad files and skipped files.
+    pub fn progress(&self) -> f32 {
+        if self.is_done() || self.content_size == 0 {
+            return self.percent_received;
         }
+        return self.downloaded as f32 / self.content_size as f32 * 100.0;
     }
+}
 
-    let percent = downloaded / total_blocks * 100.0;
+/// Calculate the percentage of blocks that have been received.
+fn calculate_downloaded_percent(pieces: &[Vec<bool>]) -> f32 {
+    let total_blocks = pieces.iter().map(|piece| piece.len()).sum();
+    return received_percent(total_blocks, count_missing(pieces));
+}
 
-    return percent;
+/// Percentage of `blocks` which have been received when `missing` of them haven't.
+fn received_percent(blocks: usize, missing: usize) -> f32 {
+    return (blocks - missing) as f32 / blocks as f32 * 100.0;
+}
+
+/// Amount of blocks which haven't been received.
+fn count_missing(pieces: &[Vec<bool>]) -> usize {
+    return pieces.iter().flatten().filter(|&&received| !received).count();
 }
 
 #[test]
@@ -105,7 +370,175 @@ fn test_calculate_downloaded_percent() {
 }
 
 
-/// Used to init the requested and received vecs.
+/// Where the pad files are, their bytes aren't counted as downloaded.
+fn build_pads(torrent: &Torrent) -> Vec<(u64, u64)> {
+    let mut pads = Vec::new();
+    let mut offset = 0;
+
+    for file in torrent.info.files.iter().flatten() {
+        if file.is_pad() {
+            pads.push((offset, offset + file.length));
+        }
+        offset += file.length;
+    }
+
+    return pads;
+}
+
+#[test]
+fn test_received_padding() {
+    use crate::utils::torrents::test_torrent;
+
+    let torrent = test_torrent(16384, &[("a", 10000), (".pad/6384", 6384), ("b", 100)]);
+
+    let mut pieces = Pieces::new(&torrent);
+    pieces.add_received(PieceBlock { index: 0, begin: 0, length: Some(16384) }).unwrap();
+    assert_eq!(pieces.downloaded(), 10000);
+    assert_eq!(pieces.progress(), 10000.0 / 10100.0 * 100.0);
+
+    pieces.add_received(PieceBlock { index: 1, begin: 0, length: Some(100) }).unwrap();
+    assert_eq!(pieces.downloaded(), 10100);
+    assert_eq!(pieces.progress(), 100.0);
+}
+
+
+#[test]
+fn test_resume() {
+    use crate::utils::torrents::test_torrent;
+
+    let torrent = test_torrent(16384, &[("a", 10000), (".pad/6384", 6384), ("b", 20000)]);
+
+    let mut pieces = Pieces::new(&torrent);
+    pieces.add_received(PieceBlock { index: 2, begin: 0, length: Some(3616) }).unwrap();
+    assert_eq!(pieces.resume(&torrent, &[true, false, true]), 2);
+    assert_eq!(pieces.verified(), &[true, false, true]);
+    assert_eq!(pieces.downloaded(), 10000 + 3616);
+    assert!(pieces.has_piece(0) && !pieces.has_piece(1));
+    assert!(!pieces.needed(PieceBlock { index: 0, begin: 0, length: Some(16384) }, 0));
+}
+
+#[test]
+fn test_skip_files() {
+    use crate::utils::torrents::test_torrent;
+
+    let torrent = test_torrent(16384, &[("a", 100), ("b", 32668), ("c", 100)]);
+
+    let mut storage = FileStorage::from_torrent(&torrent);
+    storage.skip(1).unwrap();
+    let mut pieces = Pieces::new(&torrent);
+    pieces.skip(&storage);
+
+    // Only the pieces shared with the wanted files are left, and only their bytes count.
+    assert!(!pieces.needed(PieceBlock { index: 1, begin: 0, length: Some(16384) }, 0));
+    assert!(pieces.needed(PieceBlock { index: 0, begin: 0, length: Some(16384) }, 0));
+
+    // Endgame starts once the blocks of the wanted pieces are requested, the skipped blocks aren't missing.
+    let connection = pieces.connect();
+    pieces.add_requested(PieceBlock { index: 0, begin: 0, length: Some(16384) }, connection);
+    assert!(!pieces.is_endgame());
+    pieces.add_requested(PieceBlock { index: 2, begin: 0, length: Some(100) }, connection);
+    assert!(pieces.is_endgame());
+
+    pieces.add_received(PieceBlock { index: 0, begin: 0, length: Some(16384) }).unwrap();
+    assert_eq!(pieces.progress(), 50.0);
+
+    pieces.add_received(PieceBlock { index: 2, begin: 0, length: Some(100) }).unwrap();
+    assert!(pieces.is_done());
+
+    // Done with every wanted piece, the skipped one in the middle is never had.
+    pieces.add_verified(0);
+    assert!(!pieces.is_upload_only());
+    pieces.add_verified(2);
+    assert!(pieces.is_partial_seed());
+    assert!(pieces.is_upload_only());
+    assert!(!pieces.is_seed());
+}
+
+
+#[test]
+fn test_file_downloaded() {
+    use crate::utils::torrents::test_torrent;
+
+    let torrent = test_torrent(16384, &[("a", 20000), ("b", 100), ("c", 12668)]);
+
+    let storage = FileStorage::from_torrent(&torrent);
+    let mut pieces = Pieces::new(&torrent);
+    pieces.add_received(PieceBlock { index: 1, begin: 0, length: Some(16384) }).unwrap();
+
+    // Only whole pieces count, so the first part of a is missing until piece 0 is in.
+    assert_eq!(pieces.file_downloaded(&storage, 0), 20000 - 16384);
+    assert_eq!(pieces.file_downloaded(&storage, 1), 100);
+    assert_eq!(pieces.file_downloaded(&storage, 2), 12668);
+}
+
+
+#[test]
+fn test_received_out_of_range() {
+    use crate::utils::torrents::test_torrent;
+
+    let torrent = test_torrent(32768, &[("a", 49152)]);
+    let mut pieces = Pieces::new(&torrent);
+
+    // Blocks a peer makes up are errors, not panics which would poison the lock of every peer.
+    for (index, begin) in [(2, 0), (1, 16384), (0, 100)] {
+        let result = pieces.add_received_from(PieceBlock { index, begin, length: Some(16384) }, 0);
+        assert!(matches!(result, Err(TorrenterError::Protocol(_))));
+    }
+    assert_eq!(pieces.downloaded(), 0);
+
+    pieces.add_received_from(PieceBlock { index: 1, begin: 0, length: Some(16384) }, 0).unwrap();
+    assert_eq!(pieces.downloaded(), 16384);
+}
+
+
+#[test]
+fn test_reset_piece() {
+    use crate::utils::torrents::test_torrent;
+
+    let torrent = test_torrent(32768, &[("a", 32768), (".pad/16384", 16384), ("b", 100)]);
+    let mut pieces = Pieces::new(&torrent);
+    for (index, begin, length) in [(0, 0, 16384), (0, 16384, 16384), (1, 0, 16384), (1, 16384, 100)] {
+        pieces.add_received(PieceBlock { index, begin, length: Some(length) }).unwrap();
+    }
+    pieces.add_verified(1);
+    assert!(pieces.is_done());
+
+    // The blocks of a piece which failed its hash check are missing again and requested once more.
+    assert!(!pieces.reset_piece(0));
+    assert!(!pieces.is_done());
+    assert_eq!(pieces.downloaded(), 100);
+    assert!(pieces.needed(PieceBlock { index: 0, begin: 16384, length: Some(16384) }, 0));
+
+    // The pad file isn't taken off the downloaded bytes twice, and the piece is no longer verified.
+    assert!(pieces.reset_piece(1));
+    assert_eq!(pieces.downloaded(), 0);
+    assert_eq!(pieces.percent_received(), 0.0);
+    assert!(!pieces.has_verified(1));
+}
+
+#[test]
+fn test_bitfield() {
+    use crate::utils::torrents::test_torrent;
+
+    let torrent = test_torrent(16384, &[("a", 16384 * 10)]);
+
+    let mut pieces = Pieces::new(&torrent);
+    assert_eq!(pieces.bitfield(), vec![0, 0]);
+
+    pieces.add_verified(0);
+    pieces.add_verified(7);
+    pieces.add_verified(9);
+    assert!(pieces.has_verified(9));
+    assert_eq!(pieces.bitfield(), vec![0b1000_0001, 0b0100_0000]);
+
+    assert!(!pieces.is_seed());
+    assert!(pieces.remove_verified(7));
+    assert!(!pieces.remove_verified(7));
+    assert_eq!(pieces.bitfield(), vec![0b1000_0000, 0b0100_0000]);
+}
+
+
+/// Used to init the received vec.
 ///
 /// - The first vec will be the length of the pieces.
 /// - The nested vecs will be the length of the number of blocks per piece.
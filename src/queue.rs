@@ -1,5 +1,10 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
 
+use rand::seq::SliceRandom;
+
+use crate::pieces::Pieces;
+use crate::speed::WindowRate;
 use crate::utils::torrents::{BLOCK_LEN, Torrent};
 
 #[derive(Debug, Copy, Clone)]
@@ -9,11 +14,229 @@ pub struct PieceBlock {
     pub length: Option<u64>,
 }
 
-/// Job queue which tracks the blocks and pieces that need to be downloaded from a given peer
+/// Time over which the download rate of each peer is measured.
+const RATE_WINDOW: Duration = Duration::from_secs(30);
+
+/// A peer which hasn't sent any of the blocks requested from it for this long is snubbing us, and its blocks are
+/// requested from other peers as well.
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Choking rounds the peer unchoked at random keeps its slot for.
+const OPTIMISTIC_ROUNDS: u64 = 3;
+
+/// Tells apart the peer connections of a torrent in the `InFlight` registry.
+pub type ConnectionId = u64;
+
+/// What the registry knows of a connected peer, to pick the fastest idle peers to steal stalled blocks for.
+#[derive(Debug, Clone)]
+struct Connection {
+    since: Instant,
+    /// Blocks received from the peer over the last `RATE_WINDOW`.
+    received: WindowRate,
+    /// When the peer last sent a block, or connected.
+    last_block: Instant,
+    /// Whether the peer lets us request blocks.
+    unchoked: bool,
+}
+
+/// Blocks requested from peers which haven't arrived yet, shared by every peer of a torrent.
+///
+/// Outside of endgame a block is only requested from one peer at a time. Once every missing block is requested, the
+/// same block can be requested from several peers and the first one to arrive wins. Blocks a peer is taking too long
+/// with are requested again from a faster peer with nothing to do, see `find_stalled`.
+#[derive(Debug, Clone, Default)]
+pub struct InFlight {
+    /// Peers each block is requested from, by piece and offset, with when it was requested.
+    blocks: HashMap<(u64, u64), Vec<(ConnectionId, Instant)>>,
+    next_connection: ConnectionId,
+    peers: HashMap<ConnectionId, Connection>,
+    /// Blocks found stalled by the last `find_stalled` which no other peer has taken yet.
+    stalled: HashSet<(u64, u64)>,
+    /// The idle peers allowed to take the stalled blocks, the fastest ones.
+    thieves: HashSet<ConnectionId>,
+    /// Peers found too slow to keep, they disconnect once they see it.
+    dropped: HashSet<ConnectionId>,
+    /// Peers we upload to until the next choking round.
+    upload_slots: HashSet<ConnectionId>,
+    /// The slot given at random, kept for `OPTIMISTIC_ROUNDS` rounds.
+    optimistic: Option<ConnectionId>,
+    choke_rounds: u64,
+    /// Requests to cancel because the block arrived from another peer first, for each peer.
+    cancels: HashMap<ConnectionId, Vec<PieceBlock>>,
+}
+
+impl InFlight {
+    /// A new id for a peer which just connected.
+    pub fn connect(&mut self) -> ConnectionId {
+        self.next_connection += 1;
+        let now = Instant::now();
+        self.peers.insert(self.next_connection, Connection { since: now, received: WindowRate::new(RATE_WINDOW), last_block: now, unchoked: false });
+        return self.next_connection;
+    }
+
+    pub fn add(&mut self, piece_block: PieceBlock, connection: ConnectionId) {
+        self.blocks.entry((piece_block.index, piece_block.begin)).or_default().push((connection, Instant::now()));
+        if self.thieves.contains(&connection) {
+            self.stalled.remove(&(piece_block.index, piece_block.begin));
+        }
+    }
+
+    pub fn set_unchoked(&mut self, connection: ConnectionId, unchoked: bool) {
+        if let Some(peer) = self.peers.get_mut(&connection) {
+            peer.unchoked = unchoked;
+        }
+    }
+
+    /// Count the bytes of a block received from a peer towards its download rate.
+    pub fn add_downloaded(&mut self, connection: ConnectionId, len: u64) {
+        if let Some(peer) = self.peers.get_mut(&connection) {
+            let now = Instant::now();
+            peer.received.add(now, len);
+            peer.last_block = now;
+        }
+    }
+
+    /// Whether a peer would send us blocks but has none requested from it.
+    pub fn is_idle(&self, connection: ConnectionId) -> bool {
+        let unchoked = self.peers.get(&connection).is_some_and(|peer| peer.unchoked);
+        return unchoked && !self.blocks.values().flatten().any(|&(requested_from, _)| requested_from == connection);
+    }
+
+    /// Look for blocks every peer they're requested from has been holding for `timeout` or longer, and let the
+    /// fastest idle peers, one for each stalled piece, request them as well.
+    pub fn find_stalled(&mut self, timeout: Duration) {
+        let now = Instant::now();
+        self.stalled = self.blocks.iter()
+            .filter(|(_, requests)| requests.iter().all(|&(_, requested)| now.duration_since(requested) >= timeout))
+            .map(|(&block, _)| block)
+            .collect();
+
+        let stalled_pieces: HashSet<u64> = self.stalled.iter().map(|&(index, _)| index).collect();
+        let mut idle: Vec<ConnectionId> = self.peers.keys().copied().filter(|&connection| self.is_idle(connection)).collect();
+        idle.sort_by_key(|connection| std::cmp::Reverse(self.peers[connection].received.rate(now)));
+        self.thieves = idle.into_iter().take(stalled_pieces.len()).collect();
+    }
+
+    /// Flag the peers which have been connected for a whole rate window and sent less than `min_rate` bytes per
+    /// second over it to be disconnected, returning how many there are.
+    pub fn drop_slow(&mut self, min_rate: u64) -> usize {
+        let now = Instant::now();
+        let slow: Vec<ConnectionId> = self.peers.iter()
+            .filter(|(_, peer)| now.duration_since(peer.since) >= RATE_WINDOW && peer.received.rate(now) < min_rate)
+            .map(|(&connection, _)| connection)
+            .filter(|connection| !self.dropped.contains(connection))
+            .collect();
+
+        self.dropped.extend(&slow);
+        return slow.len();
+    }
+
+    pub fn is_dropped(&self, connection: ConnectionId) -> bool {
+        return self.dropped.contains(&connection);
+    }
+
+    /// Pick the peers we upload to until the next round, the `slots` we download from the fastest and one more picked
+    /// at random every few rounds, so peers we don't know the speed of yet get a chance.
+    pub fn choke_round(&mut self, slots: usize) {
+        let now = Instant::now();
+        let mut peers: Vec<ConnectionId> = self.peers.keys().copied().collect();
+        peers.sort_by_key(|connection| (std::cmp::Reverse(self.peers[connection].received.rate(now)), *connection));
+        self.upload_slots = peers.iter().take(slots).copied().collect();
+
+        let optimistic_gone = self.optimistic.is_some_and(|connection| !self.peers.contains_key(&connection));
+        if self.choke_rounds.is_multiple_of(OPTIMISTIC_ROUNDS) || optimistic_gone {
+            let others = &peers[slots.min(peers.len())..];
+            self.optimistic = others.choose(&mut rand::thread_rng()).copied();
+        }
+        self.upload_slots.extend(self.optimistic);
+        self.choke_rounds += 1;
+    }
+
+    pub fn is_upload_slot(&self, connection: ConnectionId) -> bool {
+        return self.upload_slots.contains(&connection);
+    }
+
+    /// Whether a peer may take a stalled block from the peer which is holding it up.
+    pub fn is_stealable(&self, piece_block: PieceBlock, connection: ConnectionId) -> bool {
+        return self.thieves.contains(&connection)
+            && self.stalled.contains(&(piece_block.index, piece_block.begin))
+            && !self.is_requested_from(piece_block, connection);
+    }
+
+    /// Whether a block is requested from any peer.
+    pub fn is_requested(&self, piece_block: PieceBlock) -> bool {
+        return self.blocks.contains_key(&(piece_block.index, piece_block.begin));
+    }
+
+    pub fn is_requested_from(&self, piece_block: PieceBlock, connection: ConnectionId) -> bool {
+        return self.blocks.get(&(piece_block.index, piece_block.begin))
+            .is_some_and(|requests| requests.iter().any(|&(requested_from, _)| requested_from == connection));
+    }
+
+    /// Forget a block once it arrives from a peer, the other peers it was requested from are told to cancel it.
+    pub fn received(&mut self, piece_block: PieceBlock, from: ConnectionId) {
+        for connection in self.remove(piece_block) {
+            if connection != from {
+                self.cancels.entry(connection).or_default().push(piece_block);
+            }
+        }
+    }
+
+    /// Requests a peer should cancel.
+    pub fn take_cancels(&mut self, connection: ConnectionId) -> Vec<PieceBlock> {
+        return self.cancels.remove(&connection).unwrap_or_default();
+    }
+
+    /// Whether a peer has blocks requested from it but hasn't sent any of them for `SNUB_TIMEOUT`.
+    pub fn is_snubbing(&self, connection: ConnectionId, now: Instant) -> bool {
+        let waiting = self.peers.get(&connection).is_some_and(|peer| now.duration_since(peer.last_block) >= SNUB_TIMEOUT);
+        return waiting && self.blocks.values().flatten().any(|&(requested_from, _)| requested_from == connection);
+    }
+
+    /// Whether every peer a block is requested from is snubbing us, so another peer with the piece should request it
+    /// too, even before endgame.
+    pub fn is_snubbed(&self, piece_block: PieceBlock, connection: ConnectionId) -> bool {
+        let now = Instant::now();
+        let Some(requests) = self.blocks.get(&(piece_block.index, piece_block.begin)) else {
+            return false;
+        };
+        return !self.is_snubbing(connection, now)
+            && requests.iter().all(|&(requested_from, _)| requested_from != connection && self.is_snubbing(requested_from, now));
+    }
+
+    /// Forget a block once it arrives, returning the peers it was requested from.
+    pub fn remove(&mut self, piece_block: PieceBlock) -> Vec<ConnectionId> {
+        let requests = self.blocks.remove(&(piece_block.index, piece_block.begin)).unwrap_or_default();
+        return requests.into_iter().map(|(connection, _)| connection).collect();
+    }
+
+    /// Forget the requests of a peer which is gone, so its blocks can be requested from the other peers right away.
+    pub fn disconnect(&mut self, connection: ConnectionId) {
+        self.blocks.retain(|_, requests| {
+            requests.retain(|&(requested_from, _)| requested_from != connection);
+            return !requests.is_empty();
+        });
+        self.peers.remove(&connection);
+        self.thieves.remove(&connection);
+        self.dropped.remove(&connection);
+        self.upload_slots.remove(&connection);
+        self.cancels.remove(&connection);
+    }
+
+    /// Number of blocks in flight.
+    pub fn len(&self) -> usize {
+        return self.blocks.len();
+    }
+}
+
+/// Job queue which tracks the pieces that can be downloaded from a given peer.
+///
+/// Which of their blocks to request is picked from the `InFlight` registry shared with the other peers, so a block
+/// given up by a peer which disconnects is picked up by the next peer asking for one.
 pub struct Queue<'a> {
     torrent: &'a Torrent,
     pub(crate) choked: bool,
-    pub(crate) pieces: VecDeque<PieceBlock>,
+    pub(crate) pieces: VecDeque<u64>,
 }
 
 impl Queue<'_> {
@@ -25,28 +248,36 @@ impl Queue<'_> {
         }
     }
 
-    /// Add the blocks from a given piece_index into the job queue
+    /// Add a piece to the job queue.
     pub fn queue(&mut self, piece_index: u64) {
-        let num_blocks = self.torrent.get_blocks_per_piece(piece_index);
-
-        for i in 0..num_blocks {
-            let piece_block = PieceBlock {
-                index: piece_index,
-                begin: i * BLOCK_LEN,
-                length: Some(self.torrent.get_block_len(piece_index, i)),
-            };
-            self.pieces.push_back(piece_block);
+        if !self.pieces.contains(&piece_index) {
+            self.pieces.push_back(piece_index);
         }
     }
 
-    /// Remove the first item from the pieces queue.
-    pub fn deque(&mut self) -> Option<PieceBlock> {
-        return self.pieces.pop_front();
+    /// Remove a piece from the job queue, once the peer doesn't have it anymore.
+    pub fn remove(&mut self, piece_index: u64) {
+        self.pieces.retain(|&index| index != piece_index);
     }
 
-    /// Get the first item in pieces queue.
-    pub fn peek(&self) -> PieceBlock {
-        return self.pieces[0];
+    /// The first block of the queued pieces which is still needed, leaving out pieces which have been received.
+    pub fn next(&mut self, pieces: &Pieces, connection: ConnectionId) -> Option<PieceBlock> {
+        self.pieces.retain(|&index| !pieces.has_piece(index));
+
+        for &piece_index in &self.pieces {
+            for i in 0..self.torrent.get_blocks_per_piece(piece_index) {
+                let piece_block = PieceBlock {
+                    index: piece_index,
+                    begin: i * BLOCK_LEN,
+                    length: Some(self.torrent.get_block_len(piece_index, i)),
+                };
+                if pieces.needed(piece_block, connection) {
+                    return Some(piece_block);
+                }
+            }
+        }
+
+        return None;
     }
 
     /// Get the length of the pieces queue.
@@ -54,3 +285,175 @@ impl Queue<'_> {
         return self.pieces.len();
     }
 }
+
+
+#[test]
+fn test_in_flight() {
+    let block = |index, begin| PieceBlock { index, begin, length: Some(BLOCK_LEN) };
+    let mut in_flight = InFlight::default();
+    let first = in_flight.connect();
+    let second = in_flight.connect();
+    assert_ne!(first, second);
+
+    in_flight.add(block(0, 0), first);
+    in_flight.add(block(0, BLOCK_LEN), first);
+    in_flight.add(block(0, 0), second);
+    assert!(in_flight.is_requested_from(block(0, 0), second));
+    assert!(!in_flight.is_requested_from(block(0, BLOCK_LEN), second));
+    assert_eq!(in_flight.len(), 2);
+
+    // The blocks of a peer which is gone are free again, unless another peer has them too.
+    in_flight.disconnect(first);
+    assert!(!in_flight.is_requested(block(0, BLOCK_LEN)));
+    assert!(in_flight.is_requested(block(0, 0)));
+    assert_eq!(in_flight.remove(block(0, 0)), vec![second]);
+    assert_eq!(in_flight.len(), 0);
+}
+
+
+#[test]
+fn test_find_stalled() {
+    let block = |index, begin| PieceBlock { index, begin, length: Some(BLOCK_LEN) };
+    let mut in_flight = InFlight::default();
+    let slow = in_flight.connect();
+    let fast = in_flight.connect();
+    let faster = in_flight.connect();
+    for connection in [slow, fast, faster] {
+        in_flight.set_unchoked(connection, true);
+    }
+    in_flight.add_downloaded(fast, BLOCK_LEN);
+    in_flight.add_downloaded(faster, 2 * BLOCK_LEN);
+
+    in_flight.add(block(0, 0), slow);
+    in_flight.add(block(0, BLOCK_LEN), slow);
+    assert!(!in_flight.is_idle(slow));
+    assert!(in_flight.is_idle(fast));
+
+    // Nothing is stalled until the requests are older than the timeout.
+    in_flight.find_stalled(Duration::from_secs(60));
+    assert!(!in_flight.is_stealable(block(0, 0), faster));
+
+    // Only the fastest idle peer takes the blocks of the one stalled piece, each block once.
+    in_flight.find_stalled(Duration::ZERO);
+    assert!(!in_flight.is_stealable(block(0, 0), fast));
+    assert!(in_flight.is_stealable(block(0, 0), faster));
+    in_flight.add(block(0, 0), faster);
+    assert!(!in_flight.is_stealable(block(0, 0), faster));
+    assert!(in_flight.is_stealable(block(0, BLOCK_LEN), faster));
+}
+
+
+#[test]
+fn test_drop_slow() {
+    let mut in_flight = InFlight::default();
+    let slow = in_flight.connect();
+    let fast = in_flight.connect();
+    let new = in_flight.connect();
+    for connection in [slow, fast] {
+        in_flight.peers.get_mut(&connection).unwrap().since -= RATE_WINDOW;
+    }
+    in_flight.add_downloaded(fast, 1024 * RATE_WINDOW.as_secs());
+
+    // Peers which just connected get a whole window before they're judged.
+    assert_eq!(in_flight.drop_slow(1024), 1);
+    assert!(in_flight.is_dropped(slow));
+    assert!(!in_flight.is_dropped(fast) && !in_flight.is_dropped(new));
+
+    // Peers are only counted once until they're gone.
+    assert_eq!(in_flight.drop_slow(1024), 0);
+    in_flight.disconnect(slow);
+    assert!(!in_flight.is_dropped(slow));
+}
+
+
+#[test]
+fn test_queue_next() {
+    use crate::utils::torrents::test_torrent;
+
+    let torrent = test_torrent(BLOCK_LEN * 2, &[("a", BLOCK_LEN * 3)]);
+    let mut pieces = Pieces::new(&torrent);
+    let first = pieces.connect();
+    let second = pieces.connect();
+
+    let mut queue = Queue::new(&torrent);
+    let mut other = Queue::new(&torrent);
+    for index in 0..2 {
+        queue.queue(index);
+        other.queue(index);
+    }
+
+    // Two peers never get the same block while there are others left.
+    let block = queue.next(&pieces, first).unwrap();
+    pieces.add_requested(block, first);
+    let other_block = other.next(&pieces, second).unwrap();
+    assert_eq!((other_block.index, other_block.begin), (0, BLOCK_LEN));
+    pieces.add_requested(other_block, second);
+
+    // A peer which disconnects gives its blocks back.
+    pieces.disconnect(first);
+    let block = other.next(&pieces, second).unwrap();
+    assert_eq!((block.index, block.begin), (0, 0));
+    pieces.add_requested(block, second);
+
+    // Received pieces are dropped from the queue.
+    pieces.add_received(block).unwrap();
+    pieces.add_received(other_block).unwrap();
+    let block = queue.next(&pieces, first).unwrap();
+    assert_eq!(queue.len(), 1);
+    assert_eq!((block.index, block.begin, block.length), (1, 0, Some(BLOCK_LEN)));
+
+    // In endgame the last block is requested from both.
+    pieces.add_requested(block, first);
+    assert!(pieces.is_endgame());
+    assert!(queue.next(&pieces, first).is_none());
+    assert_eq!(other.next(&pieces, second).map(|block| block.index), Some(1));
+}
+
+
+#[test]
+fn test_choke_round() {
+    let mut in_flight = InFlight::default();
+    let peers: Vec<ConnectionId> = (0..5).map(|_| in_flight.connect()).collect();
+    for (rate, &connection) in peers.iter().enumerate() {
+        in_flight.add_downloaded(connection, rate as u64 * BLOCK_LEN);
+    }
+
+    // The two fastest peers get a slot, and one of the others as well.
+    in_flight.choke_round(2);
+    assert!(in_flight.is_upload_slot(peers[4]) && in_flight.is_upload_slot(peers[3]));
+    assert_eq!(peers.iter().filter(|&&connection| in_flight.is_upload_slot(connection)).count(), 3);
+
+    // The random slot stays for a few rounds.
+    let optimistic = in_flight.optimistic.unwrap();
+    in_flight.choke_round(2);
+    assert!(in_flight.is_upload_slot(optimistic));
+
+    in_flight.disconnect(peers[4]);
+    in_flight.choke_round(2);
+    assert!(!in_flight.is_upload_slot(peers[4]));
+    assert!(in_flight.is_upload_slot(peers[2]));
+}
+
+
+#[test]
+fn test_snubbed() {
+    let block = PieceBlock { index: 0, begin: 0, length: Some(BLOCK_LEN) };
+    let mut in_flight = InFlight::default();
+    let snubbing = in_flight.connect();
+    let other = in_flight.connect();
+
+    in_flight.add(block, snubbing);
+    assert!(!in_flight.is_snubbed(block, other));
+
+    // Nothing from the peer holding the block for a while, the other peer requests it too.
+    in_flight.peers.get_mut(&snubbing).unwrap().last_block -= SNUB_TIMEOUT;
+    assert!(in_flight.is_snubbed(block, other));
+    assert!(!in_flight.is_snubbed(block, snubbing));
+    in_flight.add(block, other);
+    assert!(!in_flight.is_snubbed(block, other));
+
+    // The first one to arrive wins, the other request is cancelled.
+    in_flight.received(block, other);
+    assert_eq!(in_flight.take_cancels(snubbing).len(), 1);
+    assert!(in_flight.take_cancels(other).is_empty());
+}
@@ -0,0 +1,169 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+
+use torrenter::bencode::{Encoder, Value};
+use crate::utils::to_hex;
+
+/// Start of every file written with `seal`, followed by the version and the SHA-1 of the payload.
+const MAGIC: &[u8; 8] = b"TRRESUME";
+
+/// Files of another version are ignored, the torrent is downloaded again as if it had none.
+const VERSION: u32 = 1;
+
+const HEADER_LEN: usize = MAGIC.len() + 4 + 20;
+
+/// The verified pieces of the torrents in the session, by info hash, so they aren't downloaded again after a restart.
+#[derive(Debug, Clone)]
+pub struct ResumeStore {
+    dir: PathBuf,
+}
+
+impl ResumeStore {
+    pub fn new(dir: PathBuf) -> ResumeStore {
+        return ResumeStore { dir };
+    }
+
+    fn path(&self, info_hash: &[u8; 20]) -> PathBuf {
+        return self.dir.join(format!("{}.resume", to_hex(info_hash)));
+    }
+
+    /// The verified pieces of a torrent with `pieces` pieces, None when there's no resume file or it can't be trusted.
+    pub fn load(&self, info_hash: &[u8; 20], pieces: usize) -> Option<Vec<bool>> {
+        let data = fs::read(self.path(info_hash)).ok()?;
+        let value = Value::decode(unseal(&data).ok()?).ok()?;
+        let dict = value.as_dict()?;
+        if dict.get(&b"info hash"[..])?.as_bytes()? != info_hash {
+            return None;
+        }
+
+        let bitfield = dict.get(&b"pieces"[..])?.as_bytes()?;
+        if bitfield.len() != pieces.div_ceil(8) {
+            return None;
+        }
+        return Some((0..pieces).map(|index| bitfield[index / 8] & (0x80 >> (index % 8)) != 0).collect());
+    }
+
+    /// Replace the resume file of a torrent, it only claims `verified` pieces once they're synced to disk.
+    pub fn store(&self, info_hash: &[u8; 20], verified: &[bool]) -> anyhow::Result<()> {
+        let mut bitfield = vec![0; verified.len().div_ceil(8)];
+        for (index, _) in verified.iter().enumerate().filter(|(_, &verified)| verified) {
+            bitfield[index / 8] |= 0x80 >> (index % 8);
+        }
+
+        let mut encoder = Encoder::new();
+        encoder.begin_dict().bytes(b"info hash").bytes(info_hash).bytes(b"pieces").bytes(&bitfield).end();
+
+        fs::create_dir_all(&self.dir)?;
+        return write_atomic(&self.path(info_hash), &seal(&encoder.finish()));
+    }
+}
+
+
+/// Put the header in front of a payload, which `unseal` checks so a torn or foreign file is never trusted.
+///
+///     "TRRESUME" <version, u32 big endian> <SHA-1 of the payload> <payload>
+pub fn seal(payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(HEADER_LEN + payload.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&VERSION.to_be_bytes());
+    data.extend_from_slice(&sha1(payload));
+    data.extend_from_slice(payload);
+    return data;
+}
+
+
+/// The payload of a file written with `seal`.
+pub fn unseal(data: &[u8]) -> anyhow::Result<&[u8]> {
+    if data.len() < HEADER_LEN || &data[..MAGIC.len()] != MAGIC {
+        anyhow::bail!("Error: The file has no resume header");
+    }
+
+    let version = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
+    if version != VERSION {
+        anyhow::bail!("Error: The file is version {} not {}", version, VERSION);
+    }
+
+    let payload = &data[HEADER_LEN..];
+    if data[12..HEADER_LEN] != sha1(payload) {
+        anyhow::bail!("Error: The file doesn't match its checksum");
+    }
+    return Ok(payload);
+}
+
+
+/// Replace a file so it has either its old or its new contents after a crash, never part of them.
+///
+/// The data goes to `<path>.tmp` which is fsynced and renamed over the file, and the folder is fsynced for the rename.
+pub fn write_atomic(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    File::open(dir)?.sync_all()?;
+    return Ok(());
+}
+
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.input(data);
+    let mut hash: [u8; 20] = [0; 20];
+    hasher.result(&mut hash);
+    return hash;
+}
+
+
+#[test]
+fn test_seal() {
+    let data = seal(b"d3:fooi1ee");
+    assert_eq!(data.len(), HEADER_LEN + 10);
+    assert_eq!(unseal(&data).unwrap(), b"d3:fooi1ee");
+
+    // A write cut short or a flipped byte fails the checksum.
+    assert!(unseal(&data[..data.len() - 1]).is_err());
+    let mut corrupt = data.clone();
+    corrupt[HEADER_LEN] ^= 1;
+    assert!(unseal(&corrupt).is_err());
+
+    let mut newer = data.clone();
+    newer[11] = 2;
+    assert!(unseal(&newer).is_err());
+    assert!(unseal(b"d3:fooi1ee").is_err());
+}
+
+
+#[test]
+fn test_resume_store() {
+    let dir = Path::new("test-files/resume");
+    let _ = fs::remove_dir_all(dir);
+    let store = ResumeStore::new(dir.to_path_buf());
+    let info_hash = [7; 20];
+    let verified: Vec<bool> = (0..11).map(|index| index % 3 == 0).collect();
+
+    assert_eq!(store.load(&info_hash, 11), None);
+    store.store(&info_hash, &verified).unwrap();
+    assert_eq!(store.load(&info_hash, 11), Some(verified.clone()));
+    assert!(!dir.join(format!("{}.resume.tmp", to_hex(&info_hash))).exists());
+
+    // Resume data for another torrent or size isn't used.
+    assert_eq!(store.load(&[8; 20], 11), None);
+    assert_eq!(store.load(&info_hash, 20), None);
+
+    // Neither is a file which was torn while it was written.
+    let path = store.path(&info_hash);
+    let data = fs::read(&path).unwrap();
+    fs::write(&path, &data[..data.len() - 2]).unwrap();
+    assert_eq!(store.load(&info_hash, 11), None);
+
+    let _ = fs::remove_dir_all(dir);
+}
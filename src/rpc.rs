@@ -0,0 +1,186 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio_stream::Stream;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+use tonic::transport::Server;
+use tracing::info;
+
+use crate::session::{self, AddTorrentOptions, Session};
+use crate::speed::SAMPLE_INTERVAL;
+use crate::stats::LifetimeStats;
+use crate::utils::{info_hash_from_hex, to_hex};
+
+use self::proto::torrenter_server::{Torrenter, TorrenterServer};
+
+pub mod proto {
+    tonic::include_proto!("torrenter");
+}
+
+/// Default interval between two status updates on the GetStatus stream.
+const STATUS_INTERVAL_MS: u32 = 1000;
+
+/// gRPC implementation of the control service defined in `proto/torrenter.proto`.
+pub struct ControlService {
+    session: Arc<Session>,
+}
+
+impl ControlService {
+    pub fn new(session: Arc<Session>) -> ControlService {
+        ControlService { session }
+    }
+}
+
+/// Serve the control service until the server fails.
+pub async fn serve(session: Arc<Session>, addr: SocketAddr) -> anyhow::Result<()> {
+    info!("Control service listening on {}", addr);
+
+    Server::builder()
+        .add_service(TorrenterServer::new(ControlService::new(session)))
+        .serve(addr)
+        .await?;
+
+    Ok(())
+}
+
+#[tonic::async_trait]
+impl Torrenter for ControlService {
+    async fn add_torrent(&self, request: Request<proto::AddTorrentRequest>) -> Result<Response<proto::AddTorrentResponse>, Status> {
+        let request = request.into_inner();
+        let options = AddTorrentOptions {
+            save_path: Some(PathBuf::from(request.save_path)).filter(|path| !path.as_os_str().is_empty()),
+            ..Default::default()
+        };
+
+        let added = self.session.add_torrent(&request.path, options)
+            .map_err(|e| Status::invalid_argument(format!("{:#}", e)))?;
+
+        Ok(Response::new(proto::AddTorrentResponse {
+            info_hash: to_hex(&added.info_hash),
+            duplicate: added.duplicate,
+            new_trackers: added.new_trackers as u32,
+            new_web_seeds: added.new_web_seeds as u32,
+        }))
+    }
+
+    async fn list_torrents(&self, _request: Request<proto::ListTorrentsRequest>) -> Result<Response<proto::ListTorrentsResponse>, Status> {
+        let torrents = self.session.list().into_iter().map(build_status).collect();
+
+        Ok(Response::new(proto::ListTorrentsResponse { torrents }))
+    }
+
+    type GetStatusStream = Pin<Box<dyn Stream<Item=Result<proto::TorrentStatus, Status>> + Send>>;
+
+    async fn get_status(&self, request: Request<proto::GetStatusRequest>) -> Result<Response<Self::GetStatusStream>, Status> {
+        let request = request.into_inner();
+
+        let info_hash = info_hash_from_hex(&request.info_hash)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        if self.session.status(&info_hash).is_none() {
+            return Err(Status::not_found("Torrent isn't in the session"));
+        }
+
+        let interval_ms = if request.interval_ms > 0 { request.interval_ms } else { STATUS_INTERVAL_MS };
+        let session = self.session.clone();
+        let (tx, rx) = mpsc::channel(4);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(interval_ms as u64));
+
+            loop {
+                interval.tick().await;
+
+                let status = match session.status(&info_hash) {
+                    Some(status) => status,
+                    None => break,
+                };
+                let finished = status.finished;
+
+                // Stop once the client has disconnected.
+                if tx.send(Ok(build_status(status))).await.is_err() {
+                    break;
+                }
+
+                if finished {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn get_speed_history(&self, request: Request<proto::GetSpeedHistoryRequest>) -> Result<Response<proto::SpeedHistory>, Status> {
+        let request = request.into_inner();
+
+        let info_hash = if request.info_hash.is_empty() {
+            None
+        } else {
+            Some(info_hash_from_hex(&request.info_hash).map_err(|e| Status::invalid_argument(e.to_string()))?)
+        };
+
+        let history = self.session.speed_history(info_hash.as_ref())
+            .ok_or_else(|| Status::not_found("Torrent isn't in the session"))?;
+
+        Ok(Response::new(proto::SpeedHistory {
+            interval_ms: SAMPLE_INTERVAL.as_millis() as u32,
+            download: history.download(),
+            upload: history.upload(),
+        }))
+    }
+
+    async fn get_lifetime_stats(&self, request: Request<proto::GetLifetimeStatsRequest>) -> Result<Response<proto::LifetimeStats>, Status> {
+        let request = request.into_inner();
+
+        let lifetime = if request.info_hash.is_empty() {
+            self.session.stats().lifetime
+        } else {
+            let info_hash = info_hash_from_hex(&request.info_hash).map_err(|e| Status::invalid_argument(e.to_string()))?;
+            self.session.status(&info_hash)
+                .ok_or_else(|| Status::not_found("Torrent isn't in the session"))?
+                .lifetime
+        };
+
+        Ok(Response::new(build_lifetime(&lifetime)))
+    }
+
+    async fn set_limits(&self, request: Request<proto::SetLimitsRequest>) -> Result<Response<proto::SetLimitsResponse>, Status> {
+        let limits = request.into_inner();
+        self.session.set_limits(limits.download_rate, limits.upload_rate);
+
+        Ok(Response::new(proto::SetLimitsResponse {}))
+    }
+}
+
+fn build_status(status: session::TorrentStatus) -> proto::TorrentStatus {
+    proto::TorrentStatus {
+        info_hash: to_hex(&status.info_hash),
+        name: status.name,
+        size: status.size,
+        downloaded: status.downloaded,
+        progress: status.progress,
+        finished: status.finished,
+        label: status.label.unwrap_or_default(),
+        download_rate: status.download_rate,
+        upload_rate: status.upload_rate,
+        eta_secs: status.eta.map(|eta| eta.as_secs()),
+        save_path: status.save_path.to_string_lossy().into_owned(),
+        lifetime: Some(build_lifetime(&status.lifetime)),
+    }
+}
+
+fn build_lifetime(lifetime: &LifetimeStats) -> proto::LifetimeStats {
+    proto::LifetimeStats {
+        downloaded: lifetime.downloaded,
+        uploaded: lifetime.uploaded,
+        ratio: lifetime.ratio(),
+        active_secs: lifetime.active_secs,
+        completed_at: lifetime.completed_at,
+    }
+}
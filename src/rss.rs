@@ -0,0 +1,180 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use regex::Regex;
+use tracing::{info, warn};
+
+use crate::session::{AddTorrentOptions, Session};
+
+/// A RSS or Atom feed polled for new torrents.
+#[derive(Debug, Clone)]
+pub struct FeedConfig {
+    pub url: String,
+    pub interval: Duration,
+    pub filters: Vec<FeedFilter>,
+}
+
+/// Items whose title matches the pattern are added with the given save path and label.
+#[derive(Debug, Clone)]
+pub struct FeedFilter {
+    pub pattern: Regex,
+    pub save_path: Option<PathBuf>,
+    pub label: Option<String>,
+}
+
+/// An entry of a feed pointing to a torrent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedItem {
+    pub id: String,
+    pub title: String,
+    pub link: Option<String>,
+}
+
+/// Poll a feed forever and add the torrents of the items matching one of its filters.
+///
+/// Items are only considered once, even if adding their torrent failed.
+pub async fn watch_feed(session: Arc<Session>, client: reqwest::Client, feed: FeedConfig) {
+    let mut seen: HashSet<String> = HashSet::new();
+
+    let mut interval = tokio::time::interval(feed.interval);
+    loop {
+        interval.tick().await;
+
+        let items = match fetch_items(&client, &feed.url).await {
+            Ok(items) => items,
+            Err(e) => {
+                warn!("Unable to fetch feed {}: {:#}", feed.url, e);
+                continue;
+            }
+        };
+
+        for item in items {
+            if !seen.insert(item.id.clone()) {
+                continue;
+            }
+
+            let filter = match match_filter(&feed.filters, &item.title) {
+                Some(filter) => filter,
+                None => continue,
+            };
+
+            if let Err(e) = add_item(&session, &client, &item, filter).await {
+                warn!("Unable to add {:?} from feed {}: {:#}", item.title, feed.url, e);
+            }
+        }
+    }
+}
+
+async fn fetch_items(client: &reqwest::Client, url: &str) -> anyhow::Result<Vec<FeedItem>> {
+    let body = client.get(url).send().await?.error_for_status()?.bytes().await?;
+    return parse_items(&body);
+}
+
+/// Download the torrent file of an item and add it to the session.
+async fn add_item(session: &Session, client: &reqwest::Client, item: &FeedItem, filter: &FeedFilter) -> anyhow::Result<()> {
+    let link = match &item.link {
+        Some(link) => link,
+        None => anyhow::bail!("Item has no link to a torrent"),
+    };
+
+    let options = AddTorrentOptions {
+        save_path: filter.save_path.clone(),
+        label: filter.label.clone(),
+        ..Default::default()
+    };
+
+    if link.starts_with("magnet:") {
+        session.add_magnet(link, options).await?;
+        info!("Added {:?} from feed", item.title);
+        return Ok(());
+    }
+
+    let buffer = client.get(link.as_str()).send().await?.error_for_status()?.bytes().await?;
+
+    session.add_torrent_bytes(&buffer, options)?;
+
+    info!("Added {:?} from feed", item.title);
+    Ok(())
+}
+
+/// Parse a RSS or Atom document into feed items.
+///
+/// The link of an item is its enclosure when there is one, as torrent feeds usually
+/// put the .torrent file there, otherwise the first link of the item.
+fn parse_items(body: &[u8]) -> anyhow::Result<Vec<FeedItem>> {
+    let feed = feed_rs::parser::parse(body)?;
+
+    let items = feed.entries.into_iter().map(|entry| {
+        let enclosure = entry.media.iter()
+            .flat_map(|media| media.content.iter())
+            .find_map(|content| content.url.as_ref().map(|url| url.to_string()));
+
+        let link = enclosure.or_else(|| entry.links.first().map(|link| link.href.clone()));
+
+        FeedItem {
+            id: entry.id,
+            title: entry.title.map(|title| title.content).unwrap_or_default(),
+            link,
+        }
+    }).collect();
+
+    return Ok(items);
+}
+
+/// Find the first filter matching the title of an item.
+fn match_filter<'a>(filters: &'a [FeedFilter], title: &str) -> Option<&'a FeedFilter> {
+    return filters.iter().find(|filter| filter.pattern.is_match(title));
+}
+
+
+#[test]
+fn test_parse_items() {
+    let rss = r#"<?xml version="1.0"?>
+        <rss version="2.0">
+          <channel>
+            <title>Linux ISOs</title>
+            <item>
+              <title>Debian 12.1 amd64</title>
+              <guid>debian-12.1</guid>
+              <link>https://example.com/debian</link>
+              <enclosure url="https://example.com/debian.torrent" type="application/x-bittorrent" length="1000"/>
+            </item>
+            <item>
+              <title>Arch 2023.09</title>
+              <guid>arch-2023.09</guid>
+              <link>https://example.com/arch.torrent</link>
+            </item>
+          </channel>
+        </rss>"#;
+
+    let items = parse_items(rss.as_bytes()).unwrap();
+    assert_eq!(items.len(), 2);
+    assert_eq!(items[0].title, "Debian 12.1 amd64");
+    assert_eq!(items[0].link, Some(String::from("https://example.com/debian.torrent")));
+    assert_eq!(items[1].link, Some(String::from("https://example.com/arch.torrent")));
+}
+
+
+#[test]
+fn test_match_filter() {
+    let filters = vec![
+        FeedFilter {
+            pattern: Regex::new("(?i)debian .* amd64").unwrap(),
+            save_path: Some(PathBuf::from("isos/debian")),
+            label: Some(String::from("debian")),
+        },
+        FeedFilter {
+            pattern: Regex::new("^Arch").unwrap(),
+            save_path: None,
+            label: None,
+        },
+    ];
+
+    let filter = match_filter(&filters, "Debian 12.1 AMD64").unwrap();
+    assert_eq!(filter.label, Some(String::from("debian")));
+
+    assert!(match_filter(&filters, "Arch 2023.09").unwrap().label.is_none());
+    assert!(match_filter(&filters, "Fedora 38").is_none());
+}
@@ -0,0 +1,714 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use bytebuffer::ByteBuffer;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::check;
+use crate::config::Config;
+use crate::dht::Dht;
+use crate::disk::{self, DiskIo, Rename};
+use crate::dns::{DnsCache, DNS_TTL};
+use crate::download::{download_torrent, PeerList, PeerPool, PeerSettings, PeerStatus, PiecesManager, Swarm};
+use crate::events::{self, Event, EVENT_CHANNEL_SIZE, EventKind, EventSender};
+use crate::hooks;
+use crate::limiter::RateLimiter;
+use crate::listener::IncomingPeer;
+use crate::magnet::{self, MagnetLink};
+use crate::messages::build_peer_handshake;
+use crate::metadata::{self, MetadataCache};
+use crate::metrics;
+use crate::pieces::Pieces;
+use crate::resume::ResumeStore;
+use crate::storage::{self, FileStorage};
+use crate::tracker::{self, ExternalIp, TrackerState, Trackers};
+use crate::speed::{estimate_eta, HISTORY_LEN, SpeedHistory};
+use crate::stats::{LifetimeStats, StatsStore, SESSION_STATS};
+use crate::ticks::{self, Tick, TickSender, TICK_CHANNEL_SIZE};
+use crate::utils::{to_hex, Peer};
+use crate::utils::torrents::Torrent;
+
+/// Peers which connected to us waiting to be picked up by the download of their torrent.
+const INCOMING_CHANNEL_SIZE: usize = 16;
+
+/// Snapshot of the state of a torrent within the session.
+#[derive(Debug, Clone)]
+pub struct TorrentStatus {
+    pub info_hash: [u8; 20],
+    pub name: String,
+    pub size: u64,
+    pub downloaded: u64,
+    pub progress: f32,
+    pub finished: bool,
+    pub label: Option<String>,
+    /// Directory the data of the torrent is in, which changes when it's moved once finished.
+    pub save_path: PathBuf,
+    /// Smoothed rates in bytes per second.
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    /// Estimated time left, None while nothing is being downloaded.
+    pub eta: Option<Duration>,
+    /// Totals over every session the torrent was in.
+    pub lifetime: LifetimeStats,
+}
+
+/// Options used when adding a torrent to the session.
+#[derive(Debug, Clone, Default)]
+pub struct AddTorrentOptions {
+    /// Directory the torrent is downloaded into, defaults to the save path of the config.
+    pub save_path: Option<PathBuf>,
+    pub label: Option<String>,
+    /// Indexes of the files which aren't downloaded, they're never created on disk.
+    pub skip_files: Vec<usize>,
+    /// Peers connected to right away, before the trackers give any.
+    pub peers: Vec<Peer>,
+}
+
+/// What adding a torrent to the session did.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AddedTorrent {
+    pub info_hash: [u8; 20],
+    /// Whether the torrent was already in the session, it isn't downloaded twice and only gets the new trackers and
+    /// web seeds.
+    pub duplicate: bool,
+    pub new_trackers: usize,
+    pub new_web_seeds: usize,
+}
+
+/// A single file within a torrent.
+#[derive(Debug, Clone)]
+pub struct FileStatus {
+    /// Index of the file within the torrent, counting pad files.
+    pub index: usize,
+    pub path: String,
+    pub length: u64,
+    /// Bytes of the file within the pieces received so far, always 0 for a skipped file.
+    pub downloaded: u64,
+    /// Percentage of the file which has been received.
+    pub progress: f32,
+}
+
+/// Totals for the whole session.
+#[derive(Debug, Clone)]
+pub struct SessionStats {
+    pub torrents: usize,
+    pub downloaded: u64,
+    pub download_rate_limit: u64,
+    pub upload_rate_limit: u64,
+    /// Port announced to trackers.
+    pub listen_port: u16,
+    /// Totals over every run of the session.
+    pub lifetime: LifetimeStats,
+}
+
+struct TorrentEntry {
+    torrent: Arc<Torrent>,
+    pieces: PiecesManager,
+    label: Option<String>,
+    /// Folder the files are in, `<save path>/<name>` unless it's renamed or moved.
+    content_path: Arc<Mutex<PathBuf>>,
+    /// Files with their names once renamed, and which of them are skipped.
+    storage: FileStorage,
+    disk: DiskIo,
+    history: SpeedHistory,
+    /// Peers which connected to us for this torrent, handed to its download.
+    incoming: mpsc::Sender<IncomingPeer>,
+    peers: PeerList,
+    trackers: Trackers,
+    lifetime: LifetimeStats,
+    /// Bytes of the pieces received as of the last sample, the lifetime stats only get what was received since.
+    last_downloaded: u64,
+    /// Bytes sent to the peers of the torrent as of the last sample.
+    last_uploaded: u64,
+}
+
+/// Keeps track of every torrent being downloaded and the limits shared between them.
+pub struct Session {
+    peer_id: ByteBuffer,
+    config: Config,
+    torrents: Mutex<HashMap<[u8; 20], TorrentEntry>>,
+    download_limiter: Arc<RateLimiter>,
+    upload_limiter: Arc<RateLimiter>,
+    events: EventSender,
+    history: Mutex<SpeedHistory>,
+    /// Addresses peers connect to with the ports announced for them, which are only known once they're bound.
+    listeners: Mutex<Vec<(SocketAddr, u16)>>,
+    /// Addresses of the trackers of every torrent.
+    dns: Arc<DnsCache>,
+    external_ip: Arc<ExternalIp>,
+    /// Random key every announce of the session is made with.
+    announce_key: u32,
+    http_client: reqwest::Client,
+    /// Ticks of the session timer, shared with every torrent.
+    ticks: TickSender,
+    /// Our DHT node, once it's bound.
+    dht: OnceLock<Arc<Dht>>,
+    /// Torrent files of the torrents added, None without a data directory.
+    metadata_cache: Option<MetadataCache>,
+    /// Verified pieces of the torrents, None without a data directory.
+    resume: Option<ResumeStore>,
+    /// Lifetime stats of the session and the torrents, None without a data directory.
+    stats_store: Option<StatsStore>,
+    lifetime: Mutex<LifetimeStats>,
+    /// Bytes uploaded by the session as of the last sample, the lifetime stats only get what was sent since.
+    last_uploaded: AtomicU64,
+}
+
+impl Session {
+    pub fn new(peer_id: ByteBuffer, config: Config) -> Session {
+        let stats_store = config.data_dir().map(|dir| StatsStore::new(dir.join("stats")));
+        let lifetime = stats_store.as_ref().map(|store| store.load(SESSION_STATS)).unwrap_or_default();
+
+        Session {
+            peer_id,
+            torrents: Mutex::new(HashMap::new()),
+            download_limiter: Arc::new(RateLimiter::new(config.download_rate_limit)),
+            upload_limiter: Arc::new(RateLimiter::new(config.upload_rate_limit)),
+            events: broadcast::channel(EVENT_CHANNEL_SIZE).0,
+            history: Mutex::new(SpeedHistory::new(HISTORY_LEN)),
+            listeners: Mutex::new(Vec::new()),
+            dns: Arc::new(DnsCache::new(DNS_TTL, config.address_family)),
+            external_ip: Arc::new(ExternalIp::new(config.external_ip)),
+            announce_key: rand::random(),
+            // main fails to start when the client can't be built, before the session is created.
+            http_client: config.http_client().unwrap_or_default(),
+            ticks: broadcast::channel(TICK_CHANNEL_SIZE).0,
+            dht: OnceLock::new(),
+            metadata_cache: config.data_dir().map(|dir| MetadataCache::new(dir.join("metadata"))),
+            resume: config.data_dir().map(|dir| ResumeStore::new(dir.join("resume"))),
+            stats_store,
+            lifetime: Mutex::new(lifetime),
+            last_uploaded: AtomicU64::new(metrics::UPLOADED_BYTES.get()),
+            config,
+        }
+    }
+
+    /// Port announced to trackers, that of the first IPv4 listener or else of the first listener.
+    pub fn listen_port(&self) -> u16 {
+        let listeners = self.listeners.lock().unwrap();
+        return listeners.iter().find(|(addr, _)| addr.is_ipv4()).or(listeners.first())
+            .map_or(self.config.listen_port, |&(_, port)| port);
+    }
+
+    /// Port announced to trackers reached over IPv6, that of the first IPv6 listener if there is one.
+    pub fn listen_port_v6(&self) -> u16 {
+        let listener = self.listeners.lock().unwrap().iter().find(|(addr, _)| addr.is_ipv6()).copied();
+        return match listener {
+            Some((_, port)) => port,
+            None => self.listen_port(),
+        };
+    }
+
+    /// Add a listener once it's bound, with the port announced for it. Torrents added afterwards announce it.
+    pub fn add_listener(&self, addr: SocketAddr, announce_port: u16) {
+        self.listeners.lock().unwrap().push((addr, announce_port));
+    }
+
+    /// Set the DHT node once it's bound, torrents added afterwards bootstrap it with their nodes.
+    pub fn set_dht(&self, dht: Arc<Dht>) {
+        let _ = self.dht.set(dht);
+    }
+
+    /// Our external address, shared with the DHT node.
+    pub fn external_ip(&self) -> Arc<ExternalIp> {
+        return self.external_ip.clone();
+    }
+
+    /// Receive the events of every torrent in the session from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        return self.events.subscribe();
+    }
+
+    /// Load a torrent file and start downloading it in the background.
+    pub fn add_torrent(&self, file_path: &str, options: AddTorrentOptions) -> anyhow::Result<AddedTorrent> {
+        let buffer = fs::read(file_path).context("Could not load the file")?;
+        return self.add_torrent_bytes(&buffer, options);
+    }
+
+    /// Same as `add_torrent` but with the raw bytes of a torrent file, downloaded from a feed for example.
+    pub fn add_torrent_bytes(&self, buffer: &[u8], options: AddTorrentOptions) -> anyhow::Result<AddedTorrent> {
+        let torrent = Torrent::from_bytes(buffer)?;
+        self.cache_metainfo(&torrent.info_hash, buffer);
+        return self.add(torrent, options);
+    }
+
+    /// Keep the torrent file of a torrent for when its magnet link is added again.
+    fn cache_metainfo(&self, info_hash: &[u8; 20], metainfo: &[u8]) {
+        if let Some(cache) = &self.metadata_cache {
+            if let Err(e) = cache.store(info_hash, metainfo) {
+                warn!(info_hash = %to_hex(info_hash), "Unable to cache the torrent file: {:#}", e);
+            }
+        }
+    }
+
+    /// Get the metadata of a magnet link from its peers and start downloading it like a torrent file.
+    ///
+    /// The `x.pe` peers of the link and the peers the DHT has for it are asked for the metadata, and connected to again
+    /// for the download along with the peers of its trackers. Only the files selected with `so` are downloaded, on top
+    /// of the ones skipped in the options. The metadata is cached, adding the link again after a restart uses it.
+    pub async fn add_magnet(&self, link: &str, mut options: AddTorrentOptions) -> anyhow::Result<AddedTorrent> {
+        let magnet = MagnetLink::parse(link)?;
+        let info_hash = magnet.info_hash;
+        if let Some(entry) = self.torrents.lock().unwrap().get_mut(&info_hash) {
+            return Ok(merge(entry, &magnet.trackers, &magnet.web_seeds));
+        }
+
+        let cached = self.metadata_cache.as_ref().and_then(|cache| cache.load(&info_hash));
+        let (torrent, peers) = match cached {
+            Some(metainfo) => {
+                info!(info_hash = %to_hex(&info_hash), "Using the cached metadata of a magnet link");
+                let resolving = magnet.clone();
+                (Torrent::from_bytes(&metainfo)?, tokio::task::spawn_blocking(move || resolving.resolve_peers()).await?)
+            }
+            None => {
+                info!(info_hash = %to_hex(&info_hash), name = ?magnet.name, "Getting the metadata of a magnet link");
+                let handshake = build_peer_handshake(&info_hash, &self.peer_id).to_bytes();
+                let (info, peers) = metadata::fetch_magnet(&magnet, self.dht.get(), handshake, PeerSettings::from_config(&self.config)).await?;
+
+                let metainfo = metadata::build_metainfo(&info, &magnet.trackers, &magnet.web_seeds);
+                let torrent = Torrent::from_bytes(&metainfo)?;
+                self.cache_metainfo(&info_hash, &metainfo);
+                (torrent, peers)
+            }
+        };
+
+        options.skip_files.extend(magnet.skip_files(storage::torrent_files(&torrent).len()));
+        options.peers.extend(peers);
+        return self.add(torrent, options);
+    }
+
+    /// Start downloading a torrent in the background.
+    ///
+    /// Adding a torrent which is already in the session doesn't start a second download, its trackers and web seeds
+    /// are added to the torrent instead.
+    fn add(&self, torrent: Torrent, options: AddTorrentOptions) -> anyhow::Result<AddedTorrent> {
+        let torrent = Arc::new(torrent);
+        let info_hash = torrent.info_hash;
+
+        let mut torrents = self.torrents.lock().unwrap();
+        if let Some(entry) = torrents.get_mut(&info_hash) {
+            return Ok(merge(entry, &torrent.trackers(), &torrent.web_seeds()));
+        }
+
+        let save_path = options.save_path.clone().unwrap_or_else(|| self.config.save_path.clone());
+        prepare_save_path(&save_path)?;
+        let content_path = save_path.join(&torrent.info.name);
+        let mut storage = FileStorage::from_torrent(&torrent);
+        for &index in &options.skip_files {
+            storage.skip(index)?;
+        }
+        disk::check_free_space(&content_path, &storage.wanted_files())?;
+        let disk = DiskIo::start(torrent.clone(), content_path.to_string_lossy().into_owned(), storage.clone(), &self.config.disk)?;
+
+        // Private torrents stay off the DHT (BEP 27).
+        if let Some(dht) = self.dht.get().filter(|_| !torrent.nodes.is_empty() && torrent.info.private != Some(1)) {
+            let nodes = torrent.nodes.iter().map(|node| (node.0.clone(), node.1)).collect();
+            tokio::spawn(dht.clone().bootstrap(nodes));
+        }
+
+        let mut pieces = Pieces::new(&torrent);
+        pieces.skip(&storage);
+        if let Some(verified) = self.resume.as_ref().and_then(|resume| resume.load(&info_hash, torrent.info.pieces.len() / 20)) {
+            info!(info_hash = %to_hex(&info_hash), pieces = pieces.resume(&torrent, &verified), "Resuming from the verified pieces of the last session");
+        }
+        let resumed = pieces.downloaded();
+        let pieces = Arc::new(Mutex::new(pieces));
+        let (incoming_sender, incoming) = mpsc::channel(INCOMING_CHANNEL_SIZE);
+        let peers = PeerList::default();
+        let trackers = tracker::torrent_trackers(&torrent);
+        let shared_content_path = Arc::new(Mutex::new(content_path));
+        torrents.insert(info_hash, TorrentEntry {
+            torrent: torrent.clone(),
+            pieces: pieces.clone(),
+            label: options.label,
+            content_path: shared_content_path.clone(),
+            storage,
+            disk: disk.clone(),
+            history: SpeedHistory::new(HISTORY_LEN),
+            incoming: incoming_sender,
+            peers: peers.clone(),
+            trackers: trackers.clone(),
+            lifetime: self.stats_store.as_ref().map(|store| store.load(&to_hex(&info_hash))).unwrap_or_default(),
+            last_downloaded: resumed,
+            last_uploaded: 0,
+        });
+
+        let peer_id = ByteBuffer::from_bytes(&self.peer_id.to_bytes());
+        let swarm = Swarm {
+            pieces,
+            download_limiter: self.download_limiter.clone(),
+            upload_limiter: self.upload_limiter.clone(),
+            settings: PeerSettings {
+                listen_port: self.listen_port(),
+                listen_port_v6: self.listen_port_v6(),
+                announce_key: self.announce_key,
+                ..PeerSettings::from_config(&self.config)
+            },
+            peers,
+            dns: self.dns.clone(),
+            external_ip: self.external_ip.clone(),
+            events: self.events.clone(),
+            trackers,
+            http: self.http_client.clone(),
+            tracker_auth: Arc::new(self.config.tracker_auth.clone()),
+            disk: disk.clone(),
+            pool: PeerPool::default(),
+            ticks: self.ticks.clone(),
+            resume: self.resume.clone(),
+            dht: self.dht.get().cloned(),
+        };
+        swarm.pool.add(options.peers, torrent.info.private == Some(1));
+        let complete_path = self.config.complete_path.clone();
+        let symlinks = self.config.disk.symlinks;
+        let on_complete = self.config.on_complete.clone();
+        let write_checksums = self.config.write_checksums;
+        let event_sender = self.events.clone();
+
+        events::emit(&event_sender, Event::new(EventKind::Added, info_hash, &torrent.info.name));
+
+        let span = info_span!("torrent", torrent = %torrent.info.name, info_hash = %to_hex(&info_hash));
+
+        tokio::spawn(async move {
+            match download_torrent(peer_id, torrent.clone(), swarm, incoming).await {
+                Ok(_) => {
+                    let content_path = shared_content_path.lock().unwrap().clone();
+                    let name = content_path.file_name().unwrap_or_default().to_os_string();
+                    let mut save_path = content_path.parent().unwrap_or(Path::new("")).to_path_buf();
+
+                    if let Some(complete_path) = complete_path.filter(|path| *path != save_path) {
+                        let to = complete_path.join(&name);
+                        match disk.move_to(to.clone()).await {
+                            Ok(()) => {
+                                info!("Moved to {:?}", complete_path);
+                                *shared_content_path.lock().unwrap() = to;
+                                save_path = complete_path;
+                            }
+                            Err(e) => {
+                                error!("Unable to move to {:?}: {:#}", complete_path, e);
+                                let event = Event::new(EventKind::Error, info_hash, &torrent.info.name).with_message(format!("{:#}", e));
+                                events::emit(&event_sender, event);
+                            }
+                        }
+                    }
+
+                    if let Err(e) = disk.apply_attributes(symlinks).await {
+                        warn!("Unable to apply file attributes: {:#}", e);
+                    }
+
+                    events::emit(&event_sender, Event::new(EventKind::Completed, info_hash, &torrent.info.name));
+
+                    if let Some(command) = on_complete {
+                        hooks::run_on_complete(&command, &name.to_string_lossy(), &info_hash, &save_path);
+                    }
+
+                    if write_checksums {
+                        let (torrent, save_path) = (torrent.clone(), save_path.clone());
+                        match tokio::task::spawn_blocking(move || check::write_checksum_files(&torrent, &save_path)).await {
+                            Ok(Ok(paths)) => info!("Wrote checksums to {:?}", paths),
+                            Ok(Err(e)) => warn!("Unable to write checksums: {:#}", e),
+                            Err(e) => warn!("Unable to write checksums: {}", e),
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Download failed: {:#}", e);
+                    let event = Event::new(EventKind::Error, info_hash, &torrent.info.name).with_message(format!("{:#}", e));
+                    events::emit(&event_sender, event);
+                }
+            }
+        }.instrument(span));
+
+        return Ok(AddedTorrent { info_hash, duplicate: false, new_trackers: 0, new_web_seeds: 0 });
+    }
+
+    /// Get the status of a single torrent.
+    pub fn status(&self, info_hash: &[u8; 20]) -> Option<TorrentStatus> {
+        let torrents = self.torrents.lock().unwrap();
+        return torrents.get(info_hash).map(build_status);
+    }
+
+    /// Get the status of every torrent in the session.
+    pub fn list(&self) -> Vec<TorrentStatus> {
+        let torrents = self.torrents.lock().unwrap();
+        return torrents.values().map(build_status).collect();
+    }
+
+    /// Info hashes of every torrent in the session, the ones peers can connect to us for.
+    pub fn info_hashes(&self) -> Vec<[u8; 20]> {
+        return self.torrents.lock().unwrap().keys().copied().collect();
+    }
+
+    /// Hand a peer which connected to us to the download of its torrent.
+    pub fn add_peer(&self, peer: IncomingPeer) -> anyhow::Result<()> {
+        let torrents = self.torrents.lock().unwrap();
+        let entry = torrents.get(&peer.info_hash()).context("Error: The peer wants a torrent which isn't in the session")?;
+
+        return entry.incoming.try_send(peer).map_err(|_| anyhow::anyhow!("Error: The torrent isn't taking peers"));
+    }
+
+    /// Peers connected to a torrent, with where they were found.
+    pub fn peers(&self, info_hash: &[u8; 20]) -> Option<Vec<PeerStatus>> {
+        return self.torrents.lock().unwrap().get(info_hash).map(|entry| entry.peers.list());
+    }
+
+    /// Change how many bytes per second a peer of a torrent can send, None when the torrent isn't in the session and
+    /// false when the peer isn't connected.
+    pub fn set_peer_rate_limit(&self, info_hash: &[u8; 20], addr: SocketAddr, rate: u64) -> Option<bool> {
+        return self.torrents.lock().unwrap().get(info_hash).map(|entry| entry.peers.set_rate_limit(addr, rate));
+    }
+
+    /// Trackers of a torrent with how announcing to them is going.
+    pub fn trackers(&self, info_hash: &[u8; 20]) -> Option<Vec<TrackerState>> {
+        return self.torrents.lock().unwrap().get(info_hash).map(|entry| entry.trackers.lock().unwrap().clone());
+    }
+
+    /// Magnet link of a torrent in the session.
+    pub fn magnet_link(&self, info_hash: &[u8; 20]) -> Option<String> {
+        let torrents = self.torrents.lock().unwrap();
+        return Some(magnet::magnet_link(&torrents.get(info_hash)?.torrent));
+    }
+
+
+    /// Get the files of a torrent.
+    ///
+    /// A single file torrent is returned as one file named after the torrent, until it's renamed. Pad files are left out.
+    pub fn files(&self, info_hash: &[u8; 20]) -> Option<Vec<FileStatus>> {
+        let torrents = self.torrents.lock().unwrap();
+
+        let entry = torrents.get(info_hash)?;
+        let pieces = entry.pieces.lock().unwrap();
+
+        let files = entry.storage.files().iter().enumerate().filter(|(_, f)| !f.is_pad()).map(|(index, f)| {
+            let downloaded = if entry.storage.is_skipped(index) { 0 } else { pieces.file_downloaded(&entry.storage, index) };
+            let progress = if f.length == 0 { 100.0 } else { downloaded as f32 / f.length as f32 * 100.0 };
+
+            FileStatus {
+                index,
+                path: f.path.join("/"),
+                length: f.length,
+                downloaded,
+                progress,
+            }
+        }).collect();
+
+        return Some(files);
+    }
+
+    /// Rename a file of a torrent or its folder, which can be done while it's downloading.
+    pub async fn rename(&self, info_hash: &[u8; 20], rename: Rename) -> anyhow::Result<()> {
+        let disk = match self.torrents.lock().unwrap().get(info_hash) {
+            Some(entry) => entry.disk.clone(),
+            None => anyhow::bail!("Error: Torrent {} isn't in the session", to_hex(info_hash)),
+        };
+
+        disk.rename(rename.clone()).await?;
+
+        if let Some(entry) = self.torrents.lock().unwrap().get_mut(info_hash) {
+            match rename {
+                Rename::File { index, path } => entry.storage.set_path(index, path),
+                Rename::Root(name) => entry.content_path.lock().unwrap().set_file_name(name),
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Get the totals for the whole session.
+    pub fn stats(&self) -> SessionStats {
+        let statuses = self.list();
+
+        SessionStats {
+            torrents: statuses.len(),
+            downloaded: statuses.iter().map(|s| s.downloaded).sum(),
+            download_rate_limit: self.download_limiter.rate(),
+            upload_rate_limit: self.upload_limiter.rate(),
+            listen_port: self.listen_port(),
+            lifetime: *self.lifetime.lock().unwrap(),
+        }
+    }
+
+    /// Run the session timer forever, recording the download and upload rates of every torrent and of the whole
+    /// session every second, writing their lifetime stats on announce ticks and driving the timers of the torrents.
+    pub fn start_ticks(session: Arc<Session>) {
+        tokio::spawn(async move {
+            let ticks = session.ticks.clone();
+            ticks::run(ticks, |tick| match tick {
+                Tick::Second => session.sample_speeds(),
+                Tick::Announce => {
+                    session.save_stats();
+                    if let Some(dht) = session.dht.get().filter(|dht| dht.needs_bootstrap()) {
+                        tokio::spawn(dht.clone().bootstrap(Vec::new()));
+                    }
+                }
+                _ => {}
+            }).await;
+        });
+    }
+
+    fn sample_speeds(&self) {
+        let mut torrents = self.torrents.lock().unwrap();
+        let mut total_downloaded = 0;
+        let mut received = 0;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+
+        for entry in torrents.values_mut() {
+            let pieces = entry.pieces.lock().unwrap();
+            let downloaded = pieces.downloaded();
+            let uploaded = pieces.uploaded();
+            total_downloaded += downloaded;
+            entry.history.sample(downloaded, uploaded);
+
+            let since = downloaded.saturating_sub(entry.last_downloaded);
+            let sent = uploaded.saturating_sub(entry.last_uploaded);
+            entry.last_downloaded = downloaded;
+            entry.last_uploaded = uploaded;
+            entry.lifetime.add(since, sent, 1);
+            received += since;
+            if entry.lifetime.completed_at.is_none() && pieces.is_upload_only() {
+                entry.lifetime.completed_at = Some(now);
+            }
+        }
+
+        let uploaded = metrics::UPLOADED_BYTES.get();
+        let sent = uploaded.saturating_sub(self.last_uploaded.swap(uploaded, Ordering::Relaxed));
+        self.history.lock().unwrap().sample(total_downloaded, uploaded);
+        self.lifetime.lock().unwrap().add(received, sent, 1);
+    }
+
+    /// Write the lifetime stats of the session and of every torrent in the background.
+    fn save_stats(&self) {
+        if let Some((store, stats)) = self.stats_to_save() {
+            tokio::task::spawn_blocking(move || store_stats(&store, stats));
+        }
+    }
+
+    /// Write the lifetime stats of the session and of every torrent before returning, for when the session stops.
+    pub fn flush_stats(&self) {
+        if let Some((store, stats)) = self.stats_to_save() {
+            store_stats(&store, stats);
+        }
+    }
+
+    fn stats_to_save(&self) -> Option<(StatsStore, Vec<(String, LifetimeStats)>)> {
+        let store = self.stats_store.clone()?;
+
+        let mut stats: Vec<(String, LifetimeStats)> = self.torrents.lock().unwrap().iter()
+            .map(|(info_hash, entry)| (to_hex(info_hash), entry.lifetime))
+            .collect();
+        stats.push((String::from(SESSION_STATS), *self.lifetime.lock().unwrap()));
+        return Some((store, stats));
+    }
+
+    /// Get the speed history of a torrent, or of the whole session when no info hash is given.
+    pub fn speed_history(&self, info_hash: Option<&[u8; 20]>) -> Option<SpeedHistory> {
+        return match info_hash {
+            Some(info_hash) => self.torrents.lock().unwrap().get(info_hash).map(|entry| entry.history.clone()),
+            None => Some(self.history.lock().unwrap().clone()),
+        };
+    }
+
+    /// Set the session wide limits in bytes per second, 0 means unlimited.
+    pub fn set_limits(&self, download_rate: u64, upload_rate: u64) {
+        self.download_limiter.set_rate(download_rate);
+        self.upload_limiter.set_rate(upload_rate);
+    }
+}
+
+/// Make sure a save path can be downloaded into, creating it when it doesn't exist.
+fn prepare_save_path(save_path: &Path) -> anyhow::Result<()> {
+    // An empty path is the current directory.
+    if save_path.as_os_str().is_empty() {
+        return Ok(());
+    }
+
+    if save_path.exists() && !save_path.is_dir() {
+        anyhow::bail!("Error: The save path {:?} isn't a directory", save_path);
+    }
+
+    fs::create_dir_all(save_path).with_context(|| format!("Unable to create the save path {:?}", save_path))?;
+    return Ok(());
+}
+
+
+/// Write lifetime stats by name, a torrent whose stats can't be written doesn't keep the others from being written.
+fn store_stats(store: &StatsStore, stats: Vec<(String, LifetimeStats)>) {
+    for (name, stats) in stats {
+        if let Err(e) = store.store(&name, &stats) {
+            warn!("Unable to write the lifetime stats of {}: {:#}", name, e);
+        }
+    }
+}
+
+
+/// Add the trackers and web seeds of a torrent added again to the one in the session, the new trackers are announced
+/// to right away.
+fn merge(entry: &mut TorrentEntry, trackers: &[String], web_seeds: &[String]) -> AddedTorrent {
+    let mut torrent = (*entry.torrent).clone();
+    let new_trackers = torrent.add_trackers(trackers);
+    let new_web_seeds = torrent.add_web_seeds(web_seeds);
+
+    let now = Instant::now();
+    entry.trackers.lock().unwrap().extend(new_trackers.iter().map(|url| TrackerState::new(url.clone(), now)));
+    info!(info_hash = %to_hex(&torrent.info_hash), trackers = new_trackers.len(), web_seeds = new_web_seeds, "The torrent is already in the session, adding its new trackers and web seeds");
+    entry.torrent = Arc::new(torrent);
+
+    return AddedTorrent {
+        info_hash: entry.torrent.info_hash,
+        duplicate: true,
+        new_trackers: new_trackers.len(),
+        new_web_seeds,
+    };
+}
+
+fn build_status(entry: &TorrentEntry) -> TorrentStatus {
+    let pieces = entry.pieces.lock().unwrap();
+    let size = entry.torrent.content_size();
+    let download_rate = entry.history.download_rate();
+
+    TorrentStatus {
+        info_hash: entry.torrent.info_hash,
+        name: entry.torrent.info.name.clone(),
+        size,
+        downloaded: pieces.downloaded(),
+        progress: pieces.progress(),
+        finished: pieces.is_done(),
+        label: entry.label.clone(),
+        save_path: entry.content_path.lock().unwrap().parent().map(Path::to_path_buf).unwrap_or_default(),
+        download_rate,
+        upload_rate: entry.history.upload_rate(),
+        eta: estimate_eta(size.saturating_sub(pieces.downloaded()), download_rate),
+        lifetime: entry.lifetime,
+    }
+}
+
+
+#[test]
+fn test_prepare_save_path() {
+    let dir = Path::new("test-files/prepare-save-path");
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir).unwrap();
+
+    // Missing directories are created, all the way down.
+    let nested = dir.join("isos/debian");
+    prepare_save_path(&nested).unwrap();
+    assert!(nested.is_dir());
+    prepare_save_path(&nested).unwrap();
+
+    // A file can't be downloaded into.
+    let file = dir.join("file.txt");
+    fs::write(&file, b"not a directory").unwrap();
+    let error = prepare_save_path(&file).unwrap_err();
+    assert!(error.to_string().contains("isn't a directory"));
+    assert!(prepare_save_path(&file.join("below")).is_err());
+
+    assert!(prepare_save_path(Path::new("")).is_ok());
+
+    let _ = fs::remove_dir_all(dir);
+}
@@ -0,0 +1,192 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Time between two samples.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Amount of samples kept, 10 minutes at one sample per second.
+pub const HISTORY_LEN: usize = 600;
+
+/// Weight of the newest sample in the smoothed rates, roughly averaging the last 10 samples.
+const SMOOTHING: f64 = 0.1;
+
+/// Ring buffer of download and upload rates in bytes per second, used to draw speed graphs.
+#[derive(Debug, Clone)]
+pub struct SpeedHistory {
+    download: VecDeque<u64>,
+    upload: VecDeque<u64>,
+    capacity: usize,
+    last_downloaded: Option<u64>,
+    last_uploaded: Option<u64>,
+    smoothed_download: f64,
+    smoothed_upload: f64,
+}
+
+impl SpeedHistory {
+    pub fn new(capacity: usize) -> SpeedHistory {
+        SpeedHistory {
+            download: VecDeque::with_capacity(capacity),
+            upload: VecDeque::with_capacity(capacity),
+            capacity,
+            last_downloaded: None,
+            last_uploaded: None,
+            smoothed_download: 0.0,
+            smoothed_upload: 0.0,
+        }
+    }
+
+    /// Record a sample from the total amount of bytes downloaded and uploaded so far.
+    ///
+    /// The rate is the difference with the previous totals, so the first call only sets the starting point.
+    pub fn sample(&mut self, downloaded: u64, uploaded: u64) {
+        if let (Some(last_downloaded), Some(last_uploaded)) = (self.last_downloaded, self.last_uploaded) {
+            let secs = SAMPLE_INTERVAL.as_secs_f64();
+            let download = downloaded.saturating_sub(last_downloaded) as f64 / secs;
+            let upload = uploaded.saturating_sub(last_uploaded) as f64 / secs;
+
+            push(&mut self.download, self.capacity, download as u64);
+            push(&mut self.upload, self.capacity, upload as u64);
+
+            self.smoothed_download = smooth(self.smoothed_download, download);
+            self.smoothed_upload = smooth(self.smoothed_upload, upload);
+        }
+
+        self.last_downloaded = Some(downloaded);
+        self.last_uploaded = Some(uploaded);
+    }
+
+    /// Exponentially smoothed download rate in bytes per second.
+    pub fn download_rate(&self) -> u64 {
+        return self.smoothed_download.round() as u64;
+    }
+
+    /// Exponentially smoothed upload rate in bytes per second.
+    pub fn upload_rate(&self) -> u64 {
+        return self.smoothed_upload.round() as u64;
+    }
+
+    /// Download rates from the oldest to the newest sample.
+    pub fn download(&self) -> Vec<u64> {
+        return self.download.iter().copied().collect();
+    }
+
+    /// Upload rates from the oldest to the newest sample.
+    pub fn upload(&self) -> Vec<u64> {
+        return self.upload.iter().copied().collect();
+    }
+}
+
+fn smooth(previous: f64, sample: f64) -> f64 {
+    return previous + SMOOTHING * (sample - previous);
+}
+
+/// Bytes received over the last `window`, for the rate of a single peer.
+#[derive(Debug, Clone)]
+pub struct WindowRate {
+    window: Duration,
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl WindowRate {
+    pub fn new(window: Duration) -> WindowRate {
+        return WindowRate { window, samples: VecDeque::new() };
+    }
+
+    pub fn add(&mut self, now: Instant, bytes: u64) {
+        while self.samples.front().is_some_and(|&(time, _)| now.duration_since(time) > self.window) {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((now, bytes));
+    }
+
+    /// Bytes per second over the window.
+    pub fn rate(&self, now: Instant) -> u64 {
+        let bytes: u64 = self.samples.iter().filter(|&&(time, _)| now.duration_since(time) <= self.window).map(|&(_, bytes)| bytes).sum();
+        return (bytes as f64 / self.window.as_secs_f64()) as u64;
+    }
+}
+
+/// Estimate how long it will take to download the remaining bytes at the given rate.
+///
+/// There is no estimate while nothing is being downloaded.
+pub fn estimate_eta(remaining: u64, rate: u64) -> Option<Duration> {
+    if remaining == 0 {
+        return Some(Duration::from_secs(0));
+    }
+    if rate == 0 {
+        return None;
+    }
+
+    return Some(Duration::from_secs((remaining + rate - 1) / rate));
+}
+
+fn push(samples: &mut VecDeque<u64>, capacity: usize, sample: u64) {
+    if samples.len() == capacity {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+
+#[test]
+fn test_speed_history() {
+    let mut history = SpeedHistory::new(3);
+
+    history.sample(0, 0);
+    assert!(history.download().is_empty());
+
+    history.sample(100, 10);
+    history.sample(300, 10);
+    history.sample(600, 40);
+    assert_eq!(history.download(), vec![100, 200, 300]);
+    assert_eq!(history.upload(), vec![10, 0, 30]);
+
+    // Oldest samples are dropped once full.
+    history.sample(1000, 40);
+    assert_eq!(history.download(), vec![200, 300, 400]);
+}
+
+
+#[test]
+fn test_smoothed_rates() {
+    let mut history = SpeedHistory::new(HISTORY_LEN);
+    history.sample(0, 0);
+
+    // A single burst only moves the smoothed rate a little.
+    history.sample(1000, 0);
+    assert_eq!(history.download_rate(), 100);
+
+    history.sample(1000, 0);
+    assert_eq!(history.download_rate(), 90);
+
+    // A steady rate converges towards the real rate.
+    for i in 0..100 {
+        history.sample(2000 + i * 500, 0);
+    }
+    assert_eq!(history.download_rate(), 500);
+}
+
+
+#[test]
+fn test_estimate_eta() {
+    assert_eq!(estimate_eta(1000, 100), Some(Duration::from_secs(10)));
+    assert_eq!(estimate_eta(1001, 100), Some(Duration::from_secs(11)));
+    assert_eq!(estimate_eta(0, 0), Some(Duration::from_secs(0)));
+    assert_eq!(estimate_eta(1000, 0), None);
+}
+
+
+#[test]
+fn test_window_rate() {
+    let start = Instant::now();
+    let mut rate = WindowRate::new(Duration::from_secs(10));
+
+    rate.add(start, 1000);
+    rate.add(start + Duration::from_secs(5), 4000);
+    assert_eq!(rate.rate(start + Duration::from_secs(5)), 500);
+
+    // Bytes older than the window don't count anymore.
+    assert_eq!(rate.rate(start + Duration::from_secs(11)), 400);
+    rate.add(start + Duration::from_secs(20), 1000);
+    assert_eq!(rate.rate(start + Duration::from_secs(20)), 100);
+}
@@ -0,0 +1,127 @@
+use std::fs;
+use std::path::PathBuf;
+
+use torrenter::bencode::{Encoder, Value};
+use crate::resume::{seal, unseal, write_atomic};
+
+/// Name the totals of the whole session are stored under, torrents are stored under their hex info hash.
+pub const SESSION_STATS: &str = "session";
+
+/// Totals of a torrent, or of the whole session, over every time it ran.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LifetimeStats {
+    /// Bytes received from and sent to peers, the ones resumed from disk aren't counted again.
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Seconds the torrent has been in the session, or the session has been running.
+    pub active_secs: u64,
+    /// Unix time the torrent had every wanted piece, None for the session and for unfinished torrents.
+    pub completed_at: Option<u64>,
+}
+
+impl LifetimeStats {
+    /// Uploaded bytes for every downloaded byte, 0 until something is downloaded.
+    pub fn ratio(&self) -> f64 {
+        if self.downloaded == 0 {
+            return 0.0;
+        }
+        return self.uploaded as f64 / self.downloaded as f64;
+    }
+
+    /// Add what was transferred over `secs` seconds of activity.
+    pub fn add(&mut self, downloaded: u64, uploaded: u64, secs: u64) {
+        self.downloaded += downloaded;
+        self.uploaded += uploaded;
+        self.active_secs += secs;
+    }
+
+    ///     {"active secs": 3600, "completed at": 1700000000, "downloaded": 1024, "uploaded": 512}
+    fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.begin_dict().bytes(b"active secs").int(self.active_secs as i64);
+        if let Some(completed_at) = self.completed_at {
+            encoder.bytes(b"completed at").int(completed_at as i64);
+        }
+        encoder.bytes(b"downloaded").int(self.downloaded as i64).bytes(b"uploaded").int(self.uploaded as i64).end();
+
+        return encoder.finish();
+    }
+
+    fn decode(payload: &[u8]) -> Option<LifetimeStats> {
+        let value = Value::decode(payload).ok()?;
+        let dict = value.as_dict()?;
+        let int = |key: &[u8]| dict.get(key).and_then(|value| value.as_int()).filter(|&value| value >= 0).map(|value| value as u64);
+
+        return Some(LifetimeStats {
+            downloaded: int(b"downloaded")?,
+            uploaded: int(b"uploaded")?,
+            active_secs: int(b"active secs")?,
+            completed_at: int(b"completed at"),
+        });
+    }
+}
+
+/// The lifetime stats of the session and its torrents, kept in the data directory across restarts.
+#[derive(Debug, Clone)]
+pub struct StatsStore {
+    dir: PathBuf,
+}
+
+impl StatsStore {
+    pub fn new(dir: PathBuf) -> StatsStore {
+        return StatsStore { dir };
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        return self.dir.join(format!("{}.stats", name));
+    }
+
+    /// The stats stored under a name, starting from nothing when there are none or the file can't be trusted.
+    pub fn load(&self, name: &str) -> LifetimeStats {
+        let stats = fs::read(self.path(name)).ok()
+            .and_then(|data| LifetimeStats::decode(unseal(&data).ok()?));
+        return stats.unwrap_or_default();
+    }
+
+    pub fn store(&self, name: &str, stats: &LifetimeStats) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        return write_atomic(&self.path(name), &seal(&stats.encode()));
+    }
+}
+
+
+#[test]
+fn test_lifetime_stats() {
+    let mut stats = LifetimeStats::default();
+    assert_eq!(stats.ratio(), 0.0);
+
+    stats.add(1000, 0, 1);
+    stats.add(1000, 3000, 1);
+    assert_eq!(stats, LifetimeStats { downloaded: 2000, uploaded: 3000, active_secs: 2, completed_at: None });
+    assert_eq!(stats.ratio(), 1.5);
+}
+
+
+#[test]
+fn test_stats_store() {
+    use std::path::Path;
+
+    let dir = Path::new("test-files/stats");
+    let _ = fs::remove_dir_all(dir);
+    let store = StatsStore::new(dir.to_path_buf());
+    assert_eq!(store.load(SESSION_STATS), LifetimeStats::default());
+
+    let session = LifetimeStats { downloaded: 5, uploaded: 7, active_secs: 60, completed_at: None };
+    let torrent = LifetimeStats { completed_at: Some(1700000000), ..session };
+    store.store(SESSION_STATS, &session).unwrap();
+    store.store("0a0b", &torrent).unwrap();
+    assert_eq!(store.load(SESSION_STATS), session);
+    assert_eq!(store.load("0a0b"), torrent);
+
+    // A torn file starts over rather than giving wrong totals.
+    let data = fs::read(store.path("0a0b")).unwrap();
+    fs::write(store.path("0a0b"), &data[..data.len() - 1]).unwrap();
+    assert_eq!(store.load("0a0b"), LifetimeStats::default());
+
+    let _ = fs::remove_dir_all(dir);
+}
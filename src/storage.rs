@@ -0,0 +1,209 @@
+use crate::utils::torrents::{DlFile, Torrent};
+
+/// Where a range of the torrent is in one of its files.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Segment {
+    /// Index of the file in the torrent.
+    pub(crate) file: usize,
+    /// Position in the file.
+    pub(crate) file_offset: u64,
+    /// Position in the range.
+    pub(crate) start: usize,
+    pub(crate) len: usize,
+}
+
+/// The files of a torrent laid out one after the other, mapping pieces to the files they're stored in and back.
+///
+/// The lengths of the files never change, only their paths can when they're renamed.
+#[derive(Debug, Clone)]
+pub(crate) struct FileStorage {
+    files: Vec<DlFile>,
+    /// Position of the first byte of every file within the torrent.
+    offsets: Vec<u64>,
+    piece_length: u64,
+    size: u64,
+    /// Files which aren't downloaded, they're never created and their data is never written.
+    skipped: Vec<bool>,
+}
+
+impl FileStorage {
+    pub(crate) fn new(files: Vec<DlFile>, piece_length: u64) -> FileStorage {
+        let mut offsets = Vec::with_capacity(files.len());
+        let mut size = 0;
+        for file in &files {
+            offsets.push(size);
+            size += file.length;
+        }
+
+        let skipped = vec![false; files.len()];
+        return FileStorage { files, offsets, piece_length, size, skipped };
+    }
+
+    pub(crate) fn from_torrent(torrent: &Torrent) -> FileStorage {
+        return FileStorage::new(torrent_files(torrent), torrent.info.piece_length);
+    }
+
+    pub(crate) fn files(&self) -> &[DlFile] {
+        return &self.files;
+    }
+
+    pub(crate) fn file(&self, index: usize) -> &DlFile {
+        return &self.files[index];
+    }
+
+    pub(crate) fn set_path(&mut self, index: usize, path: Vec<String>) {
+        self.files[index].path = path;
+    }
+
+    /// Don't download a file.
+    pub(crate) fn skip(&mut self, index: usize) -> anyhow::Result<()> {
+        if index >= self.files.len() {
+            anyhow::bail!("Error: The torrent has no file {}", index);
+        }
+        self.skipped[index] = true;
+        return Ok(());
+    }
+
+    pub(crate) fn is_skipped(&self, index: usize) -> bool {
+        return self.skipped[index];
+    }
+
+    /// Whether the data of a file is on disk, which isn't the case for pad files and skipped files.
+    pub(crate) fn is_stored(&self, index: usize) -> bool {
+        return !self.skipped[index] && !self.files[index].is_pad();
+    }
+
+    /// The files which aren't skipped.
+    pub(crate) fn wanted_files(&self) -> Vec<DlFile> {
+        return self.files.iter().enumerate().filter(|&(index, _)| !self.skipped[index]).map(|(_, file)| file.clone()).collect();
+    }
+
+    /// Start and end of every skipped file within the torrent, leaving out skipped pad files.
+    pub(crate) fn skipped_ranges(&self) -> Vec<(u64, u64)> {
+        return (0..self.files.len())
+            .filter(|&index| self.skipped[index] && !self.files[index].is_pad())
+            .map(|index| (self.offsets[index], self.offsets[index] + self.files[index].length))
+            .collect();
+    }
+
+    /// Pieces with nothing but skipped and pad files in them, which don't have to be downloaded.
+    pub(crate) fn unwanted_pieces(&self) -> Vec<u64> {
+        return (0..self.size.div_ceil(self.piece_length))
+            .filter(|&piece| self.map_block(piece, 0, self.piece_length).iter().all(|segment| !self.is_stored(segment.file)))
+            .collect();
+    }
+
+    /// Split a range of the torrent into the parts of each file it covers, leaving out empty files.
+    pub(crate) fn map_range(&self, offset: u64, length: u64) -> Vec<Segment> {
+        let end = offset.saturating_add(length).min(self.size);
+
+        // The first file ending after the start of the range.
+        let first = self.offsets.partition_point(|&file_offset| file_offset <= offset).saturating_sub(1);
+
+        let mut segments = Vec::new();
+        for (index, file) in self.files.iter().enumerate().skip(first) {
+            let file_offset = self.offsets[index];
+            if file_offset >= end {
+                break;
+            }
+
+            let file_end = file_offset + file.length;
+            if file.length > 0 && file_end > offset {
+                let start = offset.max(file_offset);
+                segments.push(Segment {
+                    file: index,
+                    file_offset: start - file_offset,
+                    start: (start - offset) as usize,
+                    len: (end.min(file_end) - start) as usize,
+                });
+            }
+        }
+
+        return segments;
+    }
+
+    /// Same as `map_range` with the range given within a piece.
+    pub(crate) fn map_block(&self, piece: u64, offset: u64, length: u64) -> Vec<Segment> {
+        return self.map_range(piece * self.piece_length + offset, length);
+    }
+
+    /// Piece and position within it of a byte of a file.
+    pub(crate) fn map_file(&self, file: usize, offset: u64) -> (u64, u64) {
+        let offset = self.offsets[file] + offset;
+        return (offset / self.piece_length, offset % self.piece_length);
+    }
+
+    /// First and last piece of a file, None for an empty file which isn't in any piece.
+    pub(crate) fn file_pieces(&self, file: usize) -> Option<(u64, u64)> {
+        let length = self.files[file].length;
+        if length == 0 {
+            return None;
+        }
+
+        return Some((self.map_file(file, 0).0, self.map_file(file, length - 1).0));
+    }
+}
+
+/// Files of the torrent with their paths relative to the download folder.
+pub(crate) fn torrent_files(torrent: &Torrent) -> Vec<DlFile> {
+    return match &torrent.info.files {
+        Some(files) => files.clone(),
+        None => vec![DlFile {
+            path: vec![torrent.info.name.clone()],
+            length: torrent.size,
+            md5sum: None,
+            attr: None,
+            symlink_path: None,
+        }],
+    };
+}
+
+
+#[test]
+fn test_map_range() {
+    let file = |length| DlFile { path: vec![String::from("f")], length, md5sum: None, attr: None, symlink_path: None };
+    let storage = FileStorage::new(vec![file(10), file(0), file(5), file(20)], 16);
+
+    assert_eq!(storage.map_range(8, 10), vec![
+        Segment { file: 0, file_offset: 8, start: 0, len: 2 },
+        Segment { file: 2, file_offset: 0, start: 2, len: 5 },
+        Segment { file: 3, file_offset: 0, start: 7, len: 3 },
+    ]);
+    assert_eq!(storage.map_range(30, 5), vec![Segment { file: 3, file_offset: 15, start: 0, len: 5 }]);
+    assert_eq!(storage.map_block(1, 0, 4), vec![Segment { file: 3, file_offset: 1, start: 0, len: 4 }]);
+
+    // Ranges stop at the end of the torrent.
+    assert_eq!(storage.map_range(33, 10), vec![Segment { file: 3, file_offset: 18, start: 0, len: 2 }]);
+    assert!(storage.map_range(35, 10).is_empty());
+}
+
+
+#[test]
+fn test_unwanted_pieces() {
+    let file = |length| DlFile { path: vec![String::from("f")], length, md5sum: None, attr: None, symlink_path: None };
+    let mut storage = FileStorage::new(vec![file(10), file(40), file(14)], 16);
+    storage.skip(1).unwrap();
+
+    // Pieces 0 and 3 are shared with the files around the skipped one.
+    assert_eq!(storage.unwanted_pieces(), vec![1, 2]);
+    assert_eq!(storage.skipped_ranges(), vec![(10, 50)]);
+    assert_eq!(storage.wanted_files().len(), 2);
+    assert!(storage.skip(3).is_err());
+}
+
+
+#[test]
+fn test_map_tiny_files() {
+    let file = |length| DlFile { path: vec![String::from("f")], length, md5sum: None, attr: None, symlink_path: None };
+    let storage = FileStorage::new(vec![file(3), file(1), file(2), file(16384), file(1)], 16384);
+
+    // Several files within a single block.
+    assert_eq!(storage.map_block(0, 0, 16384).iter().map(|segment| (segment.file, segment.len)).collect::<Vec<_>>(), vec![(0, 3), (1, 1), (2, 2), (3, 16378)]);
+    assert_eq!(storage.map_range(3, 1), vec![Segment { file: 1, file_offset: 0, start: 0, len: 1 }]);
+
+    assert_eq!(storage.map_file(3, 16378), (1, 0));
+    assert_eq!(storage.file_pieces(1), Some((0, 0)));
+    assert_eq!(storage.file_pieces(3), Some((0, 1)));
+    assert_eq!(storage.file_pieces(4), Some((1, 1)));
+    assert_eq!(FileStorage::new(vec![file(0)], 16384).file_pieces(0), None);
+}
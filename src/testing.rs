@@ -0,0 +1,515 @@
+//! Scripted peers and trackers running in the process, so the way the client deals with them can be tested with
+//! nothing but localhost sockets. Built for the tests and with the `testing` feature.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Context;
+use bytebuffer::ByteBuffer;
+use torrenter::bencode::Encoder;
+
+use crate::messages::build_peer_handshake;
+
+/// How long a mock peer waits for a message it expects, and the trackers for requests before checking if they're
+/// dropped.
+const MOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Magic number starting UDP tracker connect requests (BEP 15).
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+
+/// What a mock peer does, one step after the other.
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// Send raw bytes, like a message made with `message`.
+    Send(Vec<u8>),
+    /// Read messages until one with the id arrives, failing when the connection closes or goes quiet first.
+    Expect(u8),
+    Sleep(Duration),
+    /// Close the connection, the steps after it aren't run.
+    Close,
+}
+
+/// A length prefixed peer wire message.
+pub fn message(id: u8, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(5 + payload.len());
+    message.extend_from_slice(&(1 + payload.len() as u32).to_be_bytes());
+    message.push(id);
+    message.extend_from_slice(payload);
+
+    return message;
+}
+
+pub fn choke() -> Vec<u8> {
+    return message(0, &[]);
+}
+
+pub fn unchoke() -> Vec<u8> {
+    return message(1, &[]);
+}
+
+pub fn have(piece: u32) -> Vec<u8> {
+    return message(4, &piece.to_be_bytes());
+}
+
+pub fn bitfield(bitfield: &[u8]) -> Vec<u8> {
+    return message(5, bitfield);
+}
+
+pub fn piece(index: u32, begin: u32, block: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(8 + block.len());
+    payload.extend_from_slice(&index.to_be_bytes());
+    payload.extend_from_slice(&begin.to_be_bytes());
+    payload.extend_from_slice(block);
+
+    return message(7, &payload);
+}
+
+/// A peer on localhost taking one connection, answering its handshake and running a script.
+pub struct MockPeer {
+    addr: SocketAddr,
+    script: thread::JoinHandle<anyhow::Result<Vec<Vec<u8>>>>,
+}
+
+impl MockPeer {
+    /// Listen for a connection for the torrent, the handshake of the client has to be for its info hash.
+    pub fn start(info_hash: [u8; 20], script: Vec<Step>) -> anyhow::Result<MockPeer> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+
+        let script = thread::spawn(move || {
+            let (mut stream, _) = listener.accept()?;
+            stream.set_read_timeout(Some(MOCK_TIMEOUT))?;
+
+            let mut handshake = [0; 68];
+            stream.read_exact(&mut handshake).context("No handshake from the client")?;
+            if handshake[28..48] != info_hash {
+                anyhow::bail!("Error: The handshake is for another torrent");
+            }
+            stream.write_all(&build_peer_handshake(&info_hash, &ByteBuffer::from_bytes(b"-MK0001-mockmockmock")).to_bytes())?;
+
+            return run_script(&mut stream, script);
+        });
+
+        return Ok(MockPeer { addr, script });
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        return self.addr;
+    }
+
+    /// Wait for the script to be done, returning the messages the client sent without their length, keep-alives
+    /// left out.
+    pub fn finish(self) -> anyhow::Result<Vec<Vec<u8>>> {
+        return self.script.join().map_err(|_| anyhow::anyhow!("Error: The mock peer panicked"))?;
+    }
+}
+
+fn run_script(stream: &mut TcpStream, script: Vec<Step>) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut received = Vec::new();
+
+    for step in script {
+        match step {
+            Step::Send(bytes) => stream.write_all(&bytes)?,
+            Step::Expect(id) => loop {
+                let message = read_message(stream).with_context(|| format!("Expected message {}", id))?;
+                let done = message.first() == Some(&id);
+                if !message.is_empty() {
+                    received.push(message);
+                }
+                if done {
+                    break;
+                }
+            },
+            Step::Sleep(duration) => thread::sleep(duration),
+            Step::Close => break,
+        }
+    }
+
+    return Ok(received);
+}
+
+fn read_message(stream: &mut TcpStream) -> anyhow::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+
+    let mut message = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut message)?;
+    return Ok(message);
+}
+
+/// What a mock tracker answers announces with.
+#[derive(Debug, Clone, Default)]
+pub struct MockResponse {
+    pub interval: u32,
+    pub seeders: u32,
+    pub leechers: u32,
+    pub peers: Vec<SocketAddrV4>,
+}
+
+/// An announce a mock tracker got.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockAnnounce {
+    pub info_hash: [u8; 20],
+    pub port: u16,
+}
+
+/// A UDP tracker on localhost answering every announce and scrape with the same response (BEP 15), until it's dropped.
+pub struct MockUdpTracker {
+    addr: SocketAddr,
+    announces: Arc<Mutex<Vec<MockAnnounce>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl MockUdpTracker {
+    pub fn start(response: MockResponse) -> anyhow::Result<MockUdpTracker> {
+        let socket = UdpSocket::bind("127.0.0.1:0")?;
+        socket.set_read_timeout(Some(MOCK_TIMEOUT))?;
+        let tracker = MockUdpTracker {
+            addr: socket.local_addr()?,
+            announces: Arc::default(),
+            stopped: Arc::default(),
+        };
+
+        let (announces, stopped) = (tracker.announces.clone(), tracker.stopped.clone());
+        thread::spawn(move || {
+            let mut buf = [0; 1500];
+            while !stopped.load(Ordering::Relaxed) {
+                let Ok((len, from)) = socket.recv_from(&mut buf) else {
+                    continue;
+                };
+                if let Some(answer) = answer_udp(&buf[..len], &response, &announces) {
+                    let _ = socket.send_to(&answer, from);
+                }
+            }
+        });
+
+        return Ok(tracker);
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        return self.addr;
+    }
+
+    /// `udp://` URL of the tracker.
+    pub fn url(&self) -> String {
+        return format!("udp://{}/announce", self.addr);
+    }
+
+    pub fn announces(&self) -> Vec<MockAnnounce> {
+        return self.announces.lock().unwrap().clone();
+    }
+}
+
+impl Drop for MockUdpTracker {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The answer to a connect, announce or scrape request, nothing for anything else.
+fn answer_udp(request: &[u8], response: &MockResponse, announces: &Mutex<Vec<MockAnnounce>>) -> Option<Vec<u8>> {
+    if request.len() < 16 {
+        return None;
+    }
+    let action = u32::from_be_bytes(request[8..12].try_into().unwrap());
+    let transaction_id = &request[12..16];
+
+    let mut answer = Vec::new();
+    match action {
+        0 if request[..8] == UDP_PROTOCOL_ID.to_be_bytes() => {
+            answer.extend_from_slice(&0u32.to_be_bytes());
+            answer.extend_from_slice(transaction_id);
+            answer.extend_from_slice(&rand::random::<u64>().to_be_bytes());
+        }
+        1 if request.len() >= 98 => {
+            announces.lock().unwrap().push(MockAnnounce {
+                info_hash: request[16..36].try_into().unwrap(),
+                port: u16::from_be_bytes(request[96..98].try_into().unwrap()),
+            });
+
+            answer.extend_from_slice(&1u32.to_be_bytes());
+            answer.extend_from_slice(transaction_id);
+            answer.extend_from_slice(&response.interval.to_be_bytes());
+            answer.extend_from_slice(&response.leechers.to_be_bytes());
+            answer.extend_from_slice(&response.seeders.to_be_bytes());
+            answer.extend_from_slice(&compact_peers(&response.peers));
+        }
+        2 if request.len() >= 36 => {
+            answer.extend_from_slice(&2u32.to_be_bytes());
+            answer.extend_from_slice(transaction_id);
+            answer.extend_from_slice(&response.seeders.to_be_bytes());
+            answer.extend_from_slice(&0u32.to_be_bytes());
+            answer.extend_from_slice(&response.leechers.to_be_bytes());
+        }
+        _ => return None,
+    }
+
+    return Some(answer);
+}
+
+/// An HTTP tracker on localhost answering every announce and scrape with the same bencoded response, until it's
+/// dropped.
+pub struct MockHttpTracker {
+    addr: SocketAddr,
+    /// Path and query of every request.
+    requests: Arc<Mutex<Vec<String>>>,
+    /// Head of every request, its headers included.
+    heads: Arc<Mutex<Vec<String>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl MockHttpTracker {
+    pub fn start(response: MockResponse) -> anyhow::Result<MockHttpTracker> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+        let tracker = MockHttpTracker {
+            addr: listener.local_addr()?,
+            requests: Arc::default(),
+            heads: Arc::default(),
+            stopped: Arc::default(),
+        };
+
+        let mut body = Encoder::new();
+        body.begin_dict()
+            .bytes(b"complete").int(i64::from(response.seeders))
+            .bytes(b"incomplete").int(i64::from(response.leechers))
+            .bytes(b"interval").int(i64::from(response.interval))
+            .bytes(b"peers").bytes(&compact_peers(&response.peers))
+            .end();
+        let body = body.finish();
+
+        let (requests, heads, stopped) = (tracker.requests.clone(), tracker.heads.clone(), tracker.stopped.clone());
+        thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if let Some((path, head)) = answer_http(stream, &body, &response) {
+                            requests.lock().unwrap().push(path);
+                            heads.lock().unwrap().push(head);
+                        }
+                    }
+                    Err(_) => thread::sleep(Duration::from_millis(10)),
+                }
+            }
+        });
+
+        return Ok(tracker);
+    }
+
+    /// `http://` announce URL of the tracker.
+    pub fn url(&self) -> String {
+        return format!("http://{}/announce", self.addr);
+    }
+
+    pub fn requests(&self) -> Vec<String> {
+        return self.requests.lock().unwrap().clone();
+    }
+
+    pub fn heads(&self) -> Vec<String> {
+        return self.heads.lock().unwrap().clone();
+    }
+}
+
+impl Drop for MockHttpTracker {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Read the head of a request and answer it with the announce body, or the size of the swarm for a scrape, returning
+/// the path and the head of the request.
+fn answer_http(mut stream: TcpStream, announce_body: &[u8], response: &MockResponse) -> Option<(String, String)> {
+    stream.set_nonblocking(false).ok()?;
+    stream.set_read_timeout(Some(MOCK_TIMEOUT)).ok()?;
+
+    let mut head = Vec::new();
+    let mut byte = [0; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).ok()?;
+        head.push(byte[0]);
+    }
+
+    let head = String::from_utf8_lossy(&head).into_owned();
+    let path = head.split_whitespace().nth(1)?.to_owned();
+    let scrape_body;
+    let body = match path.strip_prefix("/scrape?") {
+        Some(query) => {
+            scrape_body = http_scrape_body(query, response)?;
+            &scrape_body
+        }
+        None => announce_body,
+    };
+    let answer = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+    stream.write_all(answer.as_bytes()).ok()?;
+    stream.write_all(body).ok()?;
+
+    return Some((path, head));
+}
+
+/// The scrape response for the info hash in the query.
+fn http_scrape_body(query: &str, response: &MockResponse) -> Option<Vec<u8>> {
+    let encoded = query.split('&').find_map(|param| param.strip_prefix("info_hash="))?;
+    let mut info_hash = Vec::new();
+    let mut bytes = encoded.bytes();
+    while let Some(byte) = bytes.next() {
+        info_hash.push(match byte {
+            b'%' => u8::from_str_radix(std::str::from_utf8(&[bytes.next()?, bytes.next()?]).ok()?, 16).ok()?,
+            byte => byte,
+        });
+    }
+
+    let mut body = Encoder::new();
+    body.begin_dict()
+        .bytes(b"files").begin_dict()
+        .bytes(&info_hash).begin_dict()
+        .bytes(b"complete").int(i64::from(response.seeders))
+        .bytes(b"downloaded").int(0)
+        .bytes(b"incomplete").int(i64::from(response.leechers))
+        .end()
+        .end()
+        .end();
+    return Some(body.finish());
+}
+
+/// An HTTP server on localhost with the files of a web seed, answering range requests, until it's dropped.
+pub struct MockWebSeed {
+    addr: SocketAddr,
+    /// Path of every request followed by the range it asked for, if any.
+    requests: Arc<Mutex<Vec<String>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl MockWebSeed {
+    /// Serve the files at their paths, like `/files/name/a.bin`.
+    pub fn start(files: HashMap<String, Vec<u8>>) -> anyhow::Result<MockWebSeed> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+        let web_seed = MockWebSeed {
+            addr: listener.local_addr()?,
+            requests: Arc::default(),
+            stopped: Arc::default(),
+        };
+
+        let (requests, stopped) = (web_seed.requests.clone(), web_seed.stopped.clone());
+        thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if let Some(request) = answer_web_seed(stream, &files) {
+                            requests.lock().unwrap().push(request);
+                        }
+                    }
+                    Err(_) => thread::sleep(Duration::from_millis(10)),
+                }
+            }
+        });
+
+        return Ok(web_seed);
+    }
+
+    /// `http://` URL of the root of the server.
+    pub fn url(&self) -> String {
+        return format!("http://{}/", self.addr);
+    }
+
+    pub fn requests(&self) -> Vec<String> {
+        return self.requests.lock().unwrap().clone();
+    }
+}
+
+impl Drop for MockWebSeed {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Answer a request for a file with the range it asks for, or all of it, returning the path and the range.
+fn answer_web_seed(mut stream: TcpStream, files: &HashMap<String, Vec<u8>>) -> Option<String> {
+    stream.set_nonblocking(false).ok()?;
+    stream.set_read_timeout(Some(MOCK_TIMEOUT)).ok()?;
+
+    let mut head = Vec::new();
+    let mut byte = [0; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).ok()?;
+        head.push(byte[0]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let path = head.split_whitespace().nth(1)?.to_owned();
+    let range = head.lines()
+        .find_map(|line| line.split_once(':').filter(|(name, _)| name.eq_ignore_ascii_case("range")))
+        .map(|(_, value)| value.trim().to_owned());
+    let request = match &range {
+        Some(range) => format!("{} {}", path, range),
+        None => path.clone(),
+    };
+
+    let Some(file) = files.get(&path) else {
+        stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").ok()?;
+        return Some(request);
+    };
+    let (status, body) = match range.as_deref().and_then(|range| range.strip_prefix("bytes=")?.split_once('-')) {
+        Some((start, end)) => {
+            let (start, end): (usize, usize) = (start.parse().ok()?, end.parse().ok()?);
+            ("206 Partial Content", file.get(start..=end.min(file.len() - 1))?)
+        }
+        None => ("200 OK", &file[..]),
+    };
+    let answer = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len());
+    stream.write_all(answer.as_bytes()).ok()?;
+    stream.write_all(body).ok()?;
+
+    return Some(request);
+}
+
+fn compact_peers(peers: &[SocketAddrV4]) -> Vec<u8> {
+    let mut compact = Vec::with_capacity(peers.len() * 6);
+    for peer in peers {
+        compact.extend_from_slice(&peer.ip().octets());
+        compact.extend_from_slice(&peer.port().to_be_bytes());
+    }
+
+    return compact;
+}
+
+
+#[test]
+fn test_mock_peer() {
+    use crate::download::{connect_peer, PeerSettings};
+    use crate::utils::{Peer, PeerSource};
+
+    let info_hash = [7; 20];
+    let mock = MockPeer::start(info_hash, vec![
+        Step::Send(bitfield(&[0b1000_0000])),
+        Step::Send(unchoke()),
+        Step::Expect(2),
+        Step::Send(choke()),
+        Step::Close,
+    ]).unwrap();
+
+    let mut peer = Peer::from_addr(mock.addr(), PeerSource::Manual);
+    let handshake = build_peer_handshake(&info_hash, &ByteBuffer::from_bytes(&[1; 20])).to_bytes();
+    let (mut stream, received) = connect_peer(&mut peer, &info_hash, &handshake, &PeerSettings::default()).unwrap();
+    assert_eq!(received[28..48], info_hash);
+
+    let mut messages = [0; 6 + 5];
+    stream.read_exact(&mut messages).unwrap();
+    assert_eq!(messages[..6], bitfield(&[0b1000_0000])[..]);
+    assert_eq!(messages[6..], unchoke()[..]);
+
+    // The peer waits for interested before choking again.
+    stream.write_all(&message(2, &[])).unwrap();
+    let mut choked = [0; 5];
+    stream.read_exact(&mut choked).unwrap();
+    assert_eq!(choked[..], choke()[..]);
+
+    assert_eq!(mock.finish().unwrap(), vec![vec![2]]);
+}
+
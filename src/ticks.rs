@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+
+/// Time between two ticks of the session timer, every other period is a multiple of it.
+pub const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Ticks waiting for a torrent or a peer, the ones falling further behind skip the oldest.
+pub const TICK_CHANNEL_SIZE: usize = 16;
+
+pub type TickSender = broadcast::Sender<Tick>;
+
+/// What's due on a tick of the session timer, everything periodic in the session runs from these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tick {
+    /// Every second, speeds are sampled and more peers are connected to.
+    Second,
+    /// Every 5 seconds, blocks stalled at slow peers are handed out again and slow peers are dropped.
+    Requests,
+    /// Every 10 seconds, the peers we upload to are picked again.
+    Choke,
+    /// Every 30 seconds, trackers which are due are announced to.
+    Announce,
+    /// Every 2 minutes, peers are sent a keep-alive.
+    KeepAlive,
+}
+
+impl Tick {
+    /// How many `TICK_INTERVAL`s apart the tick comes.
+    fn period(&self) -> u64 {
+        return match self {
+            Tick::Second => 1,
+            Tick::Requests => 5,
+            Tick::Choke => 10,
+            Tick::Announce => 30,
+            Tick::KeepAlive => 120,
+        };
+    }
+}
+
+/// The ticks due on the `count`th tick of the timer, all of them on the first one.
+pub fn due(count: u64) -> Vec<Tick> {
+    return vec![Tick::Second, Tick::Requests, Tick::Choke, Tick::Announce, Tick::KeepAlive]
+        .into_iter()
+        .filter(|tick| count.is_multiple_of(tick.period()))
+        .collect();
+}
+
+/// Run the session timer forever, calling `on_tick` with every tick before it's sent to the torrents and peers.
+pub async fn run(ticks: TickSender, mut on_tick: impl FnMut(Tick)) {
+    let mut interval = tokio::time::interval(TICK_INTERVAL);
+    let mut count = 0;
+
+    loop {
+        interval.tick().await;
+        for tick in due(count) {
+            on_tick(tick);
+            let _ = ticks.send(tick);
+        }
+        count += 1;
+    }
+}
+
+
+#[test]
+fn test_due() {
+    assert_eq!(due(0), vec![Tick::Second, Tick::Requests, Tick::Choke, Tick::Announce, Tick::KeepAlive]);
+    assert_eq!(due(1), vec![Tick::Second]);
+    assert_eq!(due(5), vec![Tick::Second, Tick::Requests]);
+    assert_eq!(due(20), vec![Tick::Second, Tick::Requests, Tick::Choke]);
+    assert_eq!(due(90), vec![Tick::Second, Tick::Requests, Tick::Choke, Tick::Announce]);
+    assert_eq!(due(120).last(), Some(&Tick::KeepAlive));
+}
@@ -1,17 +1,79 @@
+use std::collections::HashSet;
 use std::fmt::Debug;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read};
 
+use anyhow::Context;
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
+use crypto::sha2::Sha256;
 use serde_bencode::{de, ser};
 use serde_bytes::ByteBuf;
 use serde_derive::{Deserialize, Serialize};
+use torrenter::bencode;
+
+use crate::error::{self, TorrenterError};
 
 pub static BLOCK_LEN: u64 = 2_u64.pow(14) as u64;
 
-#[derive(Debug, Deserialize, Clone)]
-struct Node(String, i64);
+/// Longest file or folder name most filesystems allow, in bytes.
+const MAX_NAME_LEN: usize = 255;
+
+/// Names Windows reserves for devices, with or without an extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Bounds of the piece length accepted when loading a torrent.
+pub const MIN_PIECE_LEN: u64 = 16 * 1024;
+pub const MAX_PIECE_LEN: u64 = 256 * 1024 * 1024;
+
+/// DHT node given by the torrent as a host and a port (BEP 5).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node(pub(crate) String, pub(crate) u16);
+
+/// Read as a list rather than a tuple, serde_bencode doesn't consume the end of a list
+/// deserialized as a tuple which ends the surrounding dictionary early.
+impl<'de> serde::Deserialize<'de> for Node {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Node, D::Error> {
+        struct NodeVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for NodeVisitor {
+            type Value = Node;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a list of a host and a port")
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Node, A::Error> {
+                let host = seq.next_element()?.ok_or_else(|| serde::de::Error::invalid_length(0, &self))?;
+                let port = seq.next_element()?.ok_or_else(|| serde::de::Error::invalid_length(1, &self))?;
+                while seq.next_element::<serde::de::IgnoredAny>()?.is_some() {}
+
+                Ok(Node(host, port))
+            }
+        }
+
+        return deserializer.deserialize_seq(NodeVisitor);
+    }
+}
+
+/// `url-list` is either a single URL or a list of them.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum UrlList {
+    One(String),
+    Many(Vec<String>),
+}
+
+fn deserialize_url_list<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    return Ok(match serde::Deserialize::deserialize(deserializer)? {
+        UrlList::One(url) => vec![url],
+        UrlList::Many(urls) => urls,
+    });
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DlFile {
@@ -19,6 +81,41 @@ pub struct DlFile {
     pub length: u64,
     #[serde(default)]
     pub(crate) md5sum: Option<String>,
+    /// BEP 47 attributes, `p` marks a pad file, `x` an executable, `h` a hidden file and `l` a symlink.
+    #[serde(default)]
+    pub(crate) attr: Option<String>,
+    /// Target of a symlink, relative to the folder of the torrent.
+    #[serde(default)]
+    #[serde(rename = "symlink path")]
+    pub(crate) symlink_path: Option<Vec<String>>,
+}
+
+impl DlFile {
+    /// Whether this is a pad file (BEP 47), only there to align the next file to a piece and always zeros.
+    ///
+    ///     Older torrents don't set the attribute, their pad files are in `.pad` or named as BitComet does.
+    pub fn is_pad(&self) -> bool {
+        return self.has_attr('p')
+            || self.path.len() == 2 && self.path[0] == ".pad"
+            || self.path.last().is_some_and(|name| name.starts_with("_____padding_file_"));
+    }
+
+    pub fn is_executable(&self) -> bool {
+        return self.has_attr('x');
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        return self.has_attr('h');
+    }
+
+    /// Whether this is a symlink, which has no data of its own.
+    pub fn is_symlink(&self) -> bool {
+        return self.has_attr('l') && self.symlink_path.is_some();
+    }
+
+    fn has_attr(&self, attr: char) -> bool {
+        return self.attr.as_deref().is_some_and(|attrs| attrs.contains(attr));
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
@@ -28,59 +125,184 @@ pub struct Info {
     #[serde(rename = "piece length")]
     pub(crate) piece_length: u64,
     #[serde(default)]
-    md5sum: Option<String>,
+    pub(crate) md5sum: Option<String>,
     #[serde(default)]
     pub length: Option<u64>,
     #[serde(default)]
     pub files: Option<Vec<DlFile>>,
     #[serde(default)]
-    private: Option<u8>,
+    pub(crate) private: Option<u8>,
     #[serde(default)]
-    path: Option<Vec<String>>,
+    pub(crate) path: Option<Vec<String>>,
     #[serde(default)]
     #[serde(rename = "root hash")]
-    root_hash: Option<String>,
+    pub(crate) root_hash: Option<String>,
+    /// 2 for v2 and hybrid torrents (BEP 52).
+    #[serde(default)]
+    #[serde(rename = "meta version")]
+    pub(crate) meta_version: Option<u8>,
+}
+
+/// The name or a file path of a torrent as it was in the torrent file, before it was changed to be valid everywhere.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenamedPath {
+    /// Index of the file, None for the name of the torrent.
+    pub(crate) file: Option<usize>,
+    pub(crate) original: Vec<String>,
 }
 
+/// A loaded torrent file.
+///
+///     Everything but `info` is optional in a torrent file, missing lists are left empty.
+///     `size` and `info_hash` aren't part of the file and are computed when loading it.
 #[derive(Debug, Deserialize, Default, Clone)]
 pub struct Torrent {
     pub info: Info,
     #[serde(default)]
     pub announce: Option<String>,
     #[serde(default)]
-    nodes: Option<Vec<Node>>,
+    #[serde(rename = "announce-list")]
+    pub(crate) announce_list: Vec<Vec<String>>,
     #[serde(default)]
-    encoding: Option<String>,
+    pub(crate) nodes: Vec<Node>,
     #[serde(default)]
-    httpseeds: Option<Vec<String>>,
+    pub(crate) httpseeds: Vec<String>,
     #[serde(default)]
-    #[serde(rename = "announce-list")]
-    announce_list: Option<Vec<Vec<String>>>,
+    #[serde(rename = "url-list")]
+    #[serde(deserialize_with = "deserialize_url_list")]
+    pub(crate) url_list: Vec<String>,
     #[serde(default)]
     #[serde(rename = "creation date")]
-    creation_date: Option<i64>,
-    #[serde(rename = "comment")]
-    comment: Option<String>,
+    pub(crate) creation_date: Option<i64>,
+    #[serde(default)]
+    pub(crate) comment: Option<String>,
     #[serde(default)]
     #[serde(rename = "created by")]
-    created_by: Option<String>,
-    pub(crate) size: Option<u64>,
-    pub(crate) info_hash: Option<[u8; 20]>,
+    pub(crate) created_by: Option<String>,
+    #[serde(default)]
+    pub(crate) encoding: Option<String>,
+    #[serde(skip)]
+    pub(crate) size: u64,
+    #[serde(skip)]
+    pub(crate) info_hash: [u8; 20],
+    /// SHA-256 of the info dictionary, only for v2 and hybrid torrents.
+    #[serde(skip)]
+    pub(crate) info_hash_v2: Option<[u8; 32]>,
+    /// Paths changed by `sanitize_paths`, the data is stored under the changed ones.
+    #[serde(skip)]
+    pub(crate) renamed_paths: Vec<RenamedPath>,
 }
 
 impl Torrent {
     /// Take a torrent file path and convert it into a Torrent struct.
-    pub fn new(file_path: &str) -> Torrent {
-        let mut handle = File::open(file_path).expect("Could not load the file");
+    ///
+    /// A file which can't be read is an `Io` error, one which isn't a valid torrent a `Bencode` error.
+    pub fn load(file_path: &str) -> error::Result<Torrent> {
         let mut buffer = Vec::new();
+        File::open(file_path).and_then(|mut handle| handle.read_to_end(&mut buffer))
+            .map_err(|e| io::Error::new(e.kind(), format!("Unable to read {}: {}", file_path, e)))?;
+
+        return Torrent::from_bytes(&buffer).map_err(|e| TorrenterError::Bencode(format!("{:#}", e)));
+    }
+
+
+    /// Convert the raw bytes of a torrent file into a Torrent struct.
+    pub fn from_bytes(buffer: &[u8]) -> anyhow::Result<Torrent> {
+        let mut torrent = de::from_bytes::<Torrent>(buffer).context("Couldn't load the torrent into the torrent struct")?;
+        torrent.size = validate_info(&torrent.info)?;
+        torrent.renamed_paths = sanitize_paths(&mut torrent.info)?;
+
+        // Hash the info dictionary as it was encoded, re-encoding it would drop the keys `Info` doesn't know about.
+        let raw_info = bencode::raw_dict_entries(buffer)?.into_iter()
+            .find(|(key, _)| *key == b"info")
+            .map(|(_, raw)| raw)
+            .context("The torrent has no info dictionary")?;
+
+        let mut hasher = Sha1::new();
+        hasher.input(raw_info);
+        let mut info_hash: [u8; 20] = [0; 20];
+        hasher.result(&mut info_hash);
+        torrent.info_hash = info_hash;
+
+        if torrent.info.meta_version == Some(2) {
+            let mut hasher = Sha256::new();
+            hasher.input(raw_info);
+            let mut info_hash_v2: [u8; 32] = [0; 32];
+            hasher.result(&mut info_hash_v2);
+            torrent.info_hash_v2 = Some(info_hash_v2);
+        }
+
+        return Ok(torrent);
+    }
+
+
+    /// Size of the files of the torrent without its pad files, what's reported as its size.
+    pub fn content_size(&self) -> u64 {
+        let padding: u64 = self.info.files.iter().flatten().filter(|file| file.is_pad()).map(|file| file.length).sum();
+        return self.size - padding;
+    }
+
+
+    /// The v2 info hash truncated to 20 bytes, used in place of the v1 hash by trackers and the DHT for v2 swarms.
+    pub fn truncated_info_hash_v2(&self) -> Option<[u8; 20]> {
+        let info_hash_v2 = self.info_hash_v2?;
+
+        let mut truncated: [u8; 20] = [0; 20];
+        truncated.copy_from_slice(&info_hash_v2[..20]);
+        return Some(truncated);
+    }
 
-        handle.read_to_end(&mut buffer).expect("Couldn't read all of the torrent file");
 
-        let mut torrent = de::from_bytes::<Torrent>(&buffer).expect("Couldn't load the torrent into the torrent struct");
-        torrent.size = Some(calculate_torrent_size(&torrent.info));
-        torrent.info_hash = Some(hash_torrent_info(&torrent.info));
+    /// Every tracker of the torrent, the announce-list tiers in order followed by `announce` if it isn't in them.
+    pub fn trackers(&self) -> Vec<String> {
+        let mut trackers: Vec<String> = Vec::new();
 
-        return torrent;
+        for tracker in self.announce_list.iter().flatten().chain(self.announce.iter()) {
+            if !trackers.contains(tracker) {
+                trackers.push(tracker.clone());
+            }
+        }
+
+        return trackers;
+    }
+
+
+    /// Web seed URLs from `url-list` (BEP 19).
+    pub fn web_seeds(&self) -> Vec<String> {
+        return self.url_list.clone();
+    }
+
+
+    /// Add the trackers the torrent doesn't have yet, each in a tier of its own after the others, returning them.
+    pub fn add_trackers(&mut self, trackers: &[String]) -> Vec<String> {
+        let existing = self.trackers();
+        let mut added: Vec<String> = Vec::new();
+        for tracker in trackers {
+            if !existing.contains(tracker) && !added.contains(tracker) {
+                added.push(tracker.clone());
+            }
+        }
+
+        // `announce` is only used without an announce list, so it becomes the first tier of the new one.
+        if self.announce_list.is_empty() && !added.is_empty() {
+            self.announce_list.extend(self.announce.iter().map(|announce| vec![announce.clone()]));
+        }
+        self.announce_list.extend(added.iter().map(|tracker| vec![tracker.clone()]));
+
+        return added;
+    }
+
+
+    /// Add the web seeds the torrent doesn't have yet, returning how many there were.
+    pub fn add_web_seeds(&mut self, web_seeds: &[String]) -> usize {
+        let before = self.url_list.len();
+        for web_seed in web_seeds {
+            if !self.url_list.contains(web_seed) {
+                self.url_list.push(web_seed.clone());
+            }
+        }
+
+        return self.url_list.len() - before;
     }
 
 
@@ -88,7 +310,7 @@ impl Torrent {
     /// If it's not the last piece, we return the length,
     /// Otherwise it might be smaller.
     pub fn get_piece_len(&self, piece_index: u64) -> u64 {
-        let total_length = self.size.unwrap();
+        let total_length = self.size;
         let piece_length = self.info.piece_length;
 
         let last_piece_length = total_length % piece_length;
@@ -131,12 +353,11 @@ impl Torrent {
         println!("name:\t\t{}", self.info.name);
         println!("announce:\t{:?}", self.announce);
         println!("nodes:\t\t{:?}", self.nodes);
-        if let &Some(ref al) = &self.announce_list {
-            for a in al {
-                println!("announce list:\t{}", a[0]);
-            }
+        for tier in &self.announce_list {
+            println!("announce list:\t{:?}", tier);
         }
         println!("httpseeds:\t{:?}", self.httpseeds);
+        println!("url list:\t{:?}", self.url_list);
         println!("creation date:\t{:?}", self.creation_date);
         println!("comment:\t{:?}", self.comment);
         println!("created by:\t{:?}", self.created_by);
@@ -153,7 +374,7 @@ impl Torrent {
                 println!("file md5sum:\t{:?}", f.md5sum);
             }
         }
-        println!("size:\t\t{:?}", self.size);
+        println!("size:\t\t{}", self.size);
     }
 
     pub fn print_info(&self) {
@@ -166,9 +387,58 @@ impl Torrent {
 }
 
 
+#[test]
+fn test_from_bytes_metainfo() {
+    let metainfo = b"d8:announce15:udp://a.test:8013:announce-listll15:udp://a.test:80el15:udp://b.test:80ee\
+7:comment5:hello10:created by9:torrenter13:creation datei1700000000e8:encoding5:UTF-8\
+4:infod6:lengthi5e4:name5:a.txt12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae\
+5:nodesll9:127.0.0.1i6881eee8:url-list19:https://seed.test/ae";
+
+    let torrent = Torrent::from_bytes(metainfo).unwrap();
+    assert_eq!(torrent.size, 5);
+    assert_eq!(torrent.trackers(), vec!["udp://a.test:80", "udp://b.test:80"]);
+    assert_eq!(torrent.nodes, vec![Node(String::from("127.0.0.1"), 6881)]);
+    assert_eq!(torrent.creation_date, Some(1700000000));
+    assert_eq!(torrent.comment.as_deref(), Some("hello"));
+    assert_eq!(torrent.created_by.as_deref(), Some("torrenter"));
+    assert_eq!(torrent.encoding.as_deref(), Some("UTF-8"));
+
+    // A single web seed can be given as a string rather than a list.
+    assert_eq!(torrent.web_seeds(), vec!["https://seed.test/a"]);
+}
+
+
+#[test]
+fn test_add_trackers() {
+    let mut torrent = Torrent { announce: Some(String::from("udp://a.test:80")), ..Default::default() };
+    let trackers = [String::from("udp://b.test:80"), String::from("udp://a.test:80"), String::from("udp://b.test:80")];
+    assert_eq!(torrent.add_trackers(&trackers), vec!["udp://b.test:80"]);
+    assert_eq!(torrent.trackers(), vec!["udp://a.test:80", "udp://b.test:80"]);
+    assert!(torrent.add_trackers(&trackers).is_empty());
+
+    let web_seeds = [String::from("https://seed.test/a"), String::from("https://seed.test/a")];
+    assert_eq!(torrent.add_web_seeds(&web_seeds), 1);
+    assert_eq!(torrent.add_web_seeds(&web_seeds), 0);
+    assert_eq!(torrent.web_seeds(), vec!["https://seed.test/a"]);
+}
+
+
+#[test]
+fn test_is_pad() {
+    let file = |path: &[&str], attr: Option<&str>| DlFile { path: path.iter().map(|c| c.to_string()).collect(), length: 1, md5sum: None, attr: attr.map(String::from), symlink_path: None };
+
+    assert!(file(&[".pad", "100"], Some("p")).is_pad());
+    assert!(file(&["a"], Some("xp")).is_pad());
+    assert!(file(&[".pad", "100"], None).is_pad());
+    assert!(file(&["_____padding_file_0_if you see this file, please update to BitComet 0.85 or above____"], None).is_pad());
+    assert!(!file(&["a"], Some("x")).is_pad());
+    assert!(!file(&[".pad"], None).is_pad());
+}
+
+
 #[test]
 fn test_get_piece_len() {
-    let torrent = Torrent::new("test-tor.torrent");
+    let torrent = Torrent::load("test-tor.torrent").unwrap();
 
     // Test length of last piece
     let piece_len = torrent.get_piece_len(14);
@@ -182,7 +452,7 @@ fn test_get_piece_len() {
 
 #[test]
 fn test_get_block_len() {
-    let torrent = Torrent::new("test-tor.torrent");
+    let torrent = Torrent::load("test-tor.torrent").unwrap();
 
     // Test length of all the other pieces
     let piece_len = torrent.get_block_len(14, 0);
@@ -198,17 +468,269 @@ fn test_get_block_len() {
 fn test_blocks_per_piece() {
 
     // Test that the last piece has two blocks and isn't missing a block.
-    let torrent = Torrent::new("test-tor.torrent");
+    let torrent = Torrent::load("test-tor.torrent").unwrap();
     let piece_len = torrent.get_blocks_per_piece(14);
     assert_eq!(piece_len, 2);
 
 
-    let torrent = Torrent::new("test-tor.torrent");
+    let torrent = Torrent::load("test-tor.torrent").unwrap();
     let piece_len = torrent.get_blocks_per_piece(13);
     assert_eq!(piece_len, 2);
 }
 
 
+/// Check the info dictionary makes sense before anything relies on it, and return the total size.
+///
+///     The piece length has to be a power of two within bounds, there has to be one hash per piece
+///     and every file needs a path.
+pub fn validate_info(info: &Info) -> anyhow::Result<u64> {
+    if !info.piece_length.is_power_of_two() || info.piece_length < MIN_PIECE_LEN || info.piece_length > MAX_PIECE_LEN {
+        anyhow::bail!("Error: Invalid piece length {}, it must be a power of two between {} and {}", info.piece_length, MIN_PIECE_LEN, MAX_PIECE_LEN);
+    }
+
+    if info.pieces.len() % 20 != 0 {
+        anyhow::bail!("Error: The pieces are {} bytes long, which isn't a multiple of 20", info.pieces.len());
+    }
+
+    if info.name.is_empty() {
+        anyhow::bail!("Error: The torrent has no name");
+    }
+
+    let size = match (&info.files, info.length) {
+        (Some(files), None) => {
+            if files.is_empty() {
+                anyhow::bail!("Error: The torrent has an empty file list");
+            }
+
+            let mut size: u64 = 0;
+            for file in files {
+                if file.path.is_empty() || file.path.iter().any(|component| component.is_empty()) {
+                    anyhow::bail!("Error: The torrent has a file with an empty path {:?}", file.path);
+                }
+                size = size.checked_add(file.length).context("Error: The total size of the files overflows")?;
+            }
+            size
+        }
+        (None, Some(length)) => length,
+        (Some(_), Some(_)) => anyhow::bail!("Error: The torrent has both a length and a file list"),
+        (None, None) => anyhow::bail!("Error: The torrent has neither a length nor a file list"),
+    };
+
+    let num_pieces = size.div_ceil(info.piece_length);
+    if num_pieces != info.pieces.len() as u64 / 20 {
+        anyhow::bail!("Error: {} bytes need {} pieces but the torrent has {} hashes", size, num_pieces, info.pieces.len() / 20);
+    }
+
+    return Ok(size);
+}
+
+#[test]
+fn test_validate_info() {
+    let valid = Info {
+        name: String::from("a"),
+        pieces: ByteBuf::from(vec![0; 40]),
+        piece_length: 16384,
+        files: Some(vec![
+            DlFile { path: vec![String::from("a")], length: 16384, md5sum: None, attr: None, symlink_path: None },
+            DlFile { path: vec![String::from("b"), String::from("c")], length: 10, md5sum: None, attr: None, symlink_path: None },
+        ]),
+        ..Default::default()
+    };
+    assert_eq!(validate_info(&valid).unwrap(), 16394);
+
+    let invalid = vec![
+        Info { piece_length: 20000, ..valid.clone() },
+        Info { piece_length: 1024, ..valid.clone() },
+        Info { pieces: ByteBuf::from(vec![0; 39]), ..valid.clone() },
+        Info { pieces: ByteBuf::from(vec![0; 60]), ..valid.clone() },
+        Info { length: Some(10), ..valid.clone() },
+        Info { files: None, ..valid.clone() },
+        Info { files: Some(vec![DlFile { path: vec![], length: 10, md5sum: None, attr: None, symlink_path: None }]), pieces: ByteBuf::from(vec![0; 20]), ..valid.clone() },
+        Info { files: Some(vec![DlFile { path: vec![String::from("a")], length: u64::MAX, md5sum: None, attr: None, symlink_path: None }; 2]), ..valid.clone() },
+    ];
+
+    for info in invalid {
+        assert!(validate_info(&info).is_err(), "{:?} should be invalid", info);
+    }
+}
+
+
+/// Make the name and file paths safe to join onto the download directory, and valid on every platform.
+///
+///     `.` and `..` are dropped, characters which aren't allowed in file names on Windows or are
+///     separators are replaced, as are trailing dots and spaces, reserved device names get a `_`
+///     in front and names which are too long are shortened. The same torrent always ends up with
+///     the same paths, and the original ones are returned so they can still be shown.
+pub fn sanitize_paths(info: &mut Info) -> anyhow::Result<Vec<RenamedPath>> {
+    let mut renamed = Vec::new();
+
+    let name = sanitize_component(&info.name)
+        .with_context(|| format!("Error: Invalid torrent name {:?}", info.name))?;
+    if name != info.name {
+        renamed.push(RenamedPath { file: None, original: vec![std::mem::replace(&mut info.name, name)] });
+    }
+
+    // Lowercased so files which only differ in case don't overwrite each other on case insensitive file systems.
+    let mut taken: HashSet<String> = HashSet::new();
+
+    for (index, file) in info.files.iter_mut().flatten().enumerate() {
+        let mut path: Vec<String> = file.path.iter().filter_map(|component| sanitize_component(component)).collect();
+        if path.is_empty() {
+            anyhow::bail!("Error: Invalid file path {:?}", file.path);
+        }
+
+        // Files sanitized to the path of an earlier one get a hash of their original path.
+        let mut attempt = 0;
+        while !taken.insert(path.join("/").to_lowercase()) {
+            let last = path.last_mut().unwrap();
+            *last = disambiguate_name(last, &format!("{}/{}", file.path.join("/"), attempt));
+            attempt += 1;
+        }
+
+        if path != file.path {
+            renamed.push(RenamedPath { file: Some(index), original: std::mem::replace(&mut file.path, path) });
+        }
+
+        // Symlinks can't point outside the torrent either.
+        if let Some(target) = &file.symlink_path {
+            let target: Vec<String> = target.iter().filter_map(|component| sanitize_component(component)).collect();
+            file.symlink_path = Some(target).filter(|target| !target.is_empty());
+        }
+    }
+
+    return Ok(renamed);
+}
+
+/// A single path component which is a valid file name everywhere, None when nothing usable is left.
+fn sanitize_component(component: &str) -> Option<String> {
+    if component.is_empty() || component == "." || component == ".." {
+        return None;
+    }
+
+    let mut name: String = component.chars()
+        .map(|c| if c.is_control() || "/\\<>:\"|?*".contains(c) { '_' } else { c })
+        .collect();
+
+    // Windows drops trailing dots and spaces, which would make a different name than the one asked for.
+    let trimmed = name.trim_end_matches(['.', ' ']).len();
+    let trailing = name.len() - trimmed;
+    name.replace_range(trimmed.., &"_".repeat(trailing));
+
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.contains(&stem.to_ascii_uppercase().as_str()) {
+        name.insert(0, '_');
+    }
+
+    if name.len() > MAX_NAME_LEN {
+        name = shorten_name(&name);
+    }
+
+    return Some(name);
+}
+
+/// Add a hash of `original` to a name before its extension, for a file whose sanitized path is taken.
+fn disambiguate_name(name: &str, original: &str) -> String {
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot <= 16 => name.split_at(dot),
+        _ => (name, ""),
+    };
+
+    let mut hasher = Sha1::new();
+    hasher.input_str(original);
+    let name = format!("{}~{}{}", stem, &hasher.result_str()[..8], extension);
+
+    if name.len() > MAX_NAME_LEN {
+        return shorten_name(&name);
+    }
+    return name;
+}
+
+/// Cut a name to `MAX_NAME_LEN` bytes, keeping a short extension and a hash of the whole name so
+/// names which only differ past the cut stay different.
+fn shorten_name(name: &str) -> String {
+    let extension = match name.rfind('.') {
+        Some(dot) if name.len() - dot <= 16 => &name[dot..],
+        _ => "",
+    };
+
+    let mut hasher = Sha1::new();
+    hasher.input_str(name);
+    let suffix = format!("~{}{}", &hasher.result_str()[..8], extension);
+
+    let mut end = MAX_NAME_LEN - suffix.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    return format!("{}{}", &name[..end], suffix);
+}
+
+#[test]
+fn test_sanitize_component() {
+    assert_eq!(sanitize_component("nul.tar.gz").unwrap(), "_nul.tar.gz");
+    assert_eq!(sanitize_component("console").unwrap(), "console");
+    assert_eq!(sanitize_component("a.. ").unwrap(), "a___");
+    assert_eq!(sanitize_component("..."), Some(String::from("___")));
+    assert_eq!(sanitize_component(".."), None);
+
+    let long = format!("{}.mkv", "é".repeat(200));
+    let short = sanitize_component(&long).unwrap();
+    assert!(short.len() <= MAX_NAME_LEN);
+    assert!(short.ends_with(".mkv"));
+    assert_eq!(short, sanitize_component(&long).unwrap());
+    assert_ne!(short, sanitize_component(&format!("{}é.mkv", "é".repeat(200))).unwrap());
+}
+
+
+#[test]
+fn test_sanitize_paths() {
+    let file = |path: &[&str]| DlFile { path: path.iter().map(|c| c.to_string()).collect(), length: 1, md5sum: None, attr: None, symlink_path: None };
+    let mut info = Info {
+        name: String::from("../data"),
+        files: Some(vec![
+            file(&["..", "..", "etc", "passwd"]),
+            file(&["/absolute", ".", "a.txt"]),
+            file(&["sub", "b.txt"]),
+        ]),
+        ..Default::default()
+    };
+    sanitize_paths(&mut info).unwrap();
+
+    assert_eq!(info.name, ".._data");
+    let paths: Vec<Vec<String>> = info.files.unwrap().into_iter().map(|f| f.path).collect();
+    assert_eq!(paths, vec![vec!["etc", "passwd"], vec!["_absolute", "a.txt"], vec!["sub", "b.txt"]]);
+
+    assert!(sanitize_paths(&mut Info { name: String::from(".."), ..Default::default() }).is_err());
+
+    let mut info = Info { name: String::from("a"), files: Some(vec![file(&["a"]), file(&["CON", "b:c?.txt "])]), ..Default::default() };
+    let renamed = sanitize_paths(&mut info).unwrap();
+    assert_eq!(info.files.unwrap()[1].path, vec!["_CON", "b_c_.txt_"]);
+    assert_eq!(renamed, vec![RenamedPath { file: Some(1), original: vec![String::from("CON"), String::from("b:c?.txt ")] }]);
+
+    let link = DlFile { attr: Some(String::from("l")), symlink_path: Some(vec![String::from(".."), String::from("..")]), ..file(&["link"]) };
+    let mut info = Info { name: String::from("a"), files: Some(vec![link]), ..Default::default() };
+    sanitize_paths(&mut info).unwrap();
+    assert!(!info.files.unwrap()[0].is_symlink());
+    assert!(sanitize_paths(&mut Info { name: String::from("a"), files: Some(vec![file(&["..", "."])]), ..Default::default() }).is_err());
+
+    // Files which end up on the same path, or on paths only differing in case, are kept apart.
+    let mut info = Info {
+        name: String::from("a"),
+        files: Some(vec![file(&["a_b.txt"]), file(&["a:b.txt"]), file(&["x", "..", "y"]), file(&["x", "y"]), file(&["con"]), file(&["CON"])]),
+        ..Default::default()
+    };
+    let renamed = sanitize_paths(&mut info).unwrap();
+    let paths: Vec<String> = info.files.unwrap().into_iter().map(|f| f.path.join("/")).collect();
+    assert_eq!(paths.iter().map(|path| path.to_lowercase()).collect::<HashSet<_>>().len(), 6);
+    assert_eq!(paths[0], "a_b.txt");
+    assert!(paths[1].starts_with("a_b~") && paths[1].ends_with(".txt"));
+    assert!(paths[3].starts_with("x/y~"));
+    assert_eq!(paths[4], "_con");
+    assert!(paths[5].starts_with("_CON~"));
+    assert_eq!(renamed.iter().filter_map(|renamed| renamed.file).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5]);
+}
+
+
 /// Calculate the size of the torrent.
 ///
 /// If many files add up the length of each of each file
@@ -228,7 +750,7 @@ pub fn calculate_torrent_size(torrent_info: &Info) -> u64 {
 
 #[test]
 fn test_calculate_torrent_size() {
-    let torrent = Torrent::new("test-tor.torrent");
+    let torrent = Torrent::load("test-tor.torrent").unwrap();
     let torrent_size = calculate_torrent_size(&torrent.info);
     assert_eq!(torrent_size, 479502);
 }
@@ -255,9 +777,37 @@ pub fn hash_torrent_info(torrent_info: &Info) -> [u8; 20] {
 
 #[test]
 fn test_hash_torrent_info() {
-    let torrent = Torrent::new("test-tor.torrent");
+    let torrent = Torrent::load("test-tor.torrent").unwrap();
     let hashed_info = hash_torrent_info(&torrent.info);
 
     let expected: [u8; 20] = [0x06, 0xcb, 0x06, 0x12, 0x40, 0xb2, 0x4f, 0x73, 0x0f, 0xbe, 0xf7, 0xea, 0xd1, 0xb3, 0x48, 0xd8, 0x86, 0x52, 0x44, 0xaf];
     assert_eq!(hashed_info, expected);
 }
+
+
+/// A torrent of `files` for the tests, each a `/` separated path and its length, in pieces of `piece_length`.
+///
+///     Files under `.pad` are pad files. The piece hashes are zeros, so nothing verifies.
+#[cfg(test)]
+pub(crate) fn test_torrent(piece_length: u64, files: &[(&str, u64)]) -> Torrent {
+    let files: Vec<DlFile> = files.iter().map(|&(path, length)| DlFile {
+        path: path.split('/').map(String::from).collect(),
+        length,
+        md5sum: None,
+        attr: None,
+        symlink_path: None,
+    }).collect();
+    let size: u64 = files.iter().map(|file| file.length).sum();
+
+    return Torrent {
+        info: Info {
+            name: String::from("test"),
+            piece_length,
+            pieces: ByteBuf::from(vec![0; size.div_ceil(piece_length) as usize * 20]),
+            files: Some(files),
+            ..Default::default()
+        },
+        size,
+        ..Default::default()
+    };
+}
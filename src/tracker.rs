@@ -1,77 +1,968 @@
-use std::net::UdpSocket;
-use std::time::Duration;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use anyhow::Context;
 use bytebuffer::ByteBuffer;
+use serde_derive::Deserialize;
+use tokio::net::UdpSocket;
+use torrenter::bencode::Value;
+use tracing::{debug, warn};
 use url::Url;
 
-use crate::{messages, PORT, utils};
-use crate::utils::torrents;
+use crate::{messages, utils};
+use crate::dns::DnsCache;
+use crate::download::Swarm;
+use crate::events::{self, Event, EventKind};
+use crate::interface::Interface;
+use crate::messages::{AnnounceEvent, AnnounceParams};
+use crate::utils::{is_local_addr, torrents, PeerSource};
 use crate::utils::torrents::Torrent;
 
-pub fn get_torrent_peers(
+/// Our address as trackers should publish it, set in the config or learned from how peers see us.
+#[derive(Debug, Default)]
+pub struct ExternalIp {
+    configured: Option<IpAddr>,
+    /// How many peers gave each address as our `yourip` in their extended handshake.
+    votes: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl ExternalIp {
+    pub fn new(configured: Option<IpAddr>) -> ExternalIp {
+        ExternalIp {
+            configured,
+            votes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A peer told us the address it sees us on, a local one doesn't say anything about our external address.
+    pub fn vote(&self, ip: IpAddr) {
+        if !is_local_addr(ip) {
+            *self.votes.lock().unwrap().entry(ip).or_default() += 1;
+        }
+    }
+
+    /// Our IPv4 or IPv6 address, the configured one or else the one most peers see us on.
+    pub fn get(&self, ipv6: bool) -> Option<IpAddr> {
+        if let Some(ip) = self.configured.filter(|ip| ip.is_ipv6() == ipv6) {
+            return Some(ip);
+        }
+
+        return self.votes.lock().unwrap().iter()
+            .filter(|(ip, _)| ip.is_ipv6() == ipv6)
+            .max_by_key(|&(ip, votes)| (votes, ip))
+            .map(|(&ip, _)| ip);
+    }
+}
+
+/// Peers to ask a tracker for, only as many as it takes to get to the maximum of connected peers.
+fn num_want(wanted: u32, connected: usize, max_peers: usize) -> i32 {
+    return wanted.min(max_peers.saturating_sub(connected) as u32) as i32;
+}
+
+/// Parameter of an HTTP announce giving our address, `ip` for an IPv4 address or `ipv6` (BEP 7).
+pub fn announce_ip_param(ip: IpAddr) -> (&'static str, String) {
+    return match ip {
+        IpAddr::V4(ip) => ("ip", ip.to_string()),
+        IpAddr::V6(ip) => ("ipv6", ip.to_string()),
+    };
+}
+
+/// UDP tracker action of an error response, with the message in place of the rest of the response.
+const ACTION_ERROR: i32 = 3;
+
+/// How long an HTTP tracker has to answer an announce.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long a UDP tracker has to answer each message.
+const UDP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Delay before retrying a tracker after its first failure, doubled with every failure after that.
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Longest delay between retries of a failing tracker.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+/// Failures in a row after which a tracker is given up on for the rest of the session.
+const MAX_FAILURES: u32 = 10;
+
+/// The trackers of a torrent, shared between its download and the session.
+pub type Trackers = Arc<Mutex<Vec<TrackerState>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackerHealth {
+    /// Not announced to yet.
+    Pending,
+    Working,
+    /// The last announce failed, it's retried after a backoff.
+    Failing,
+    /// Given up on for the rest of the session.
+    Disabled,
+}
+
+impl TrackerHealth {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            TrackerHealth::Pending => "pending",
+            TrackerHealth::Working => "working",
+            TrackerHealth::Failing => "failing",
+            TrackerHealth::Disabled => "disabled",
+        };
+    }
+}
+
+/// What a tracker answered to an announce.
+#[derive(Debug, Clone, Default)]
+pub struct Announced {
+    pub peers: Vec<utils::Peer>,
+    /// How long to wait before the next announce.
+    pub interval: Duration,
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+}
+
+/// How announces to a tracker have been going.
+#[derive(Debug, Clone)]
+pub struct TrackerState {
+    pub url: String,
+    pub health: TrackerHealth,
+    /// Announces made, the failed ones included.
+    pub announces: u32,
+    /// Failures in a row since the last announce which went through.
+    pub failures: u32,
+    /// Amount of peers the last announce returned, or why it failed.
+    pub last_result: Option<Result<usize, String>>,
+    /// How long the tracker took to answer the last announce which went through.
+    pub response_time: Option<Duration>,
+    /// Size of the swarm as of the last announce which gave it, or of the scrape made when the announce didn't.
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+    pub next_announce: Instant,
+}
+
+impl TrackerState {
+    pub fn new(url: String, now: Instant) -> TrackerState {
+        TrackerState {
+            url,
+            health: TrackerHealth::Pending,
+            announces: 0,
+            failures: 0,
+            last_result: None,
+            response_time: None,
+            seeders: None,
+            leechers: None,
+            next_announce: now,
+        }
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        return self.health != TrackerHealth::Disabled && now >= self.next_announce;
+    }
+
+    /// The tracker answered, it's announced to again after the interval it asked for.
+    pub fn succeeded(&mut self, announced: &Announced, response_time: Duration, now: Instant) {
+        self.health = TrackerHealth::Working;
+        self.announces += 1;
+        self.failures = 0;
+        self.last_result = Some(Ok(announced.peers.len()));
+        self.response_time = Some(response_time);
+        self.seeders = announced.seeders.or(self.seeders);
+        self.leechers = announced.leechers.or(self.leechers);
+        self.next_announce = now + announced.interval;
+    }
+
+    /// The announce failed, the tracker is retried after a backoff unless it's failed too often or can't ever work.
+    pub fn failed(&mut self, error: &anyhow::Error, now: Instant) {
+        self.announces += 1;
+        self.failures += 1;
+        self.last_result = Some(Err(format!("{:#}", error)));
+
+        let permanent = matches!(error.downcast_ref::<TrackerError>(), Some(TrackerError::InvalidUrl(_)));
+        if permanent || self.failures >= MAX_FAILURES {
+            self.health = TrackerHealth::Disabled;
+        } else {
+            self.health = TrackerHealth::Failing;
+            self.next_announce = now + retry_delay(self.failures);
+        }
+    }
+}
+
+/// Every tracker of a torrent, none of them announced to yet.
+pub fn torrent_trackers(torrent: &Torrent) -> Trackers {
+    let now = Instant::now();
+    return Arc::new(Mutex::new(torrent.trackers().into_iter().map(|url| TrackerState::new(url, now)).collect()));
+}
+
+fn retry_delay(failures: u32) -> Duration {
+    return RETRY_DELAY.saturating_mul(1 << failures.saturating_sub(1).min(16)).min(MAX_RETRY_DELAY);
+}
+
+/// A tracker turning an announce down, as opposed to one which can't be reached.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrackerError {
+    /// The `failure reason` of an HTTP tracker, or the message of a UDP tracker's error response.
+    Failure(String),
+    /// The announce URL can't be used, it's never retried.
+    InvalidUrl(String),
+}
+
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            TrackerError::Failure(reason) => write!(f, "Error: The tracker refused the announce: {}", reason),
+            TrackerError::InvalidUrl(reason) => write!(f, "Error: Invalid tracker URL: {}", reason),
+        };
+    }
+}
+
+impl std::error::Error for TrackerError {}
+
+/// Credentials sent with the announces and scrapes to the HTTP trackers of a host, set in the config under `[[tracker_auth]]`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrackerAuth {
+    /// Host name of the tracker, like `tracker.example.com`, the port doesn't matter.
+    pub host: String,
+    /// HTTP Basic auth, sent when there's a username.
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Value of the Cookie header.
+    #[serde(default)]
+    pub cookie: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+impl TrackerAuth {
+    /// The credentials for the host of a tracker URL.
+    pub fn find<'a>(auths: &'a [TrackerAuth], tracker_url: &Url) -> Option<&'a TrackerAuth> {
+        let host = tracker_url.host_str()?;
+        return auths.iter().find(|auth| auth.host.eq_ignore_ascii_case(host));
+    }
+
+    fn apply(&self, mut request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        if let Some(username) = &self.username {
+            request = request.basic_auth(username, self.password.as_ref());
+        }
+        if let Some(cookie) = &self.cookie {
+            request = request.header(reqwest::header::COOKIE, cookie);
+        }
+        for (name, value) in &self.headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+
+        return request;
+    }
+}
+
+/// Response of an HTTP tracker to an announce.
+#[derive(Debug, Clone)]
+pub struct HttpAnnounce {
+    /// Seconds to wait before the next announce.
+    pub interval: u64,
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+    pub peers: Vec<utils::Peer>,
+    /// Something the tracker wants us to know, the announce still went through.
+    pub warning: Option<String>,
+}
+
+/// Parse the bencoded response of an HTTP tracker, a `failure reason` is returned as a `TrackerError`.
+pub fn parse_http_announce(body: &[u8]) -> anyhow::Result<HttpAnnounce> {
+    let response = Value::decode(body).context("Error: The tracker response isn't bencoded")?;
+    let dict = response.as_dict().context("Error: The tracker response isn't a dictionary")?;
+    let string = |key: &[u8]| dict.get(key).and_then(|value| value.as_bytes()).map(|value| String::from_utf8_lossy(value).into_owned());
+    let int = |key: &[u8]| dict.get(key).and_then(|value| value.as_int()).and_then(|value| u64::try_from(value).ok());
+
+    if let Some(reason) = string(b"failure reason") {
+        return Err(TrackerError::Failure(reason).into());
+    }
+
+    let mut peers = Vec::new();
+    match dict.get(b"peers".as_slice()) {
+        // Compact peers, 4 bytes of address and 2 of port each.
+        Some(Value::Bytes(compact)) => {
+            for peer in compact.chunks_exact(6) {
+                peers.push(tracker_peer(Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]).into(), u16::from_be_bytes([peer[4], peer[5]])));
+            }
+        }
+        Some(Value::List(list)) => {
+            for peer in list.iter().filter_map(|peer| peer.as_dict()) {
+                let ip = peer.get(b"ip".as_slice()).and_then(|ip| ip.as_bytes()).and_then(|ip| std::str::from_utf8(ip).ok()?.parse::<IpAddr>().ok());
+                let port = peer.get(b"port".as_slice()).and_then(|port| port.as_int()).and_then(|port| u16::try_from(port).ok());
+                if let (Some(ip), Some(port)) = (ip, port) {
+                    peers.push(tracker_peer(ip, port));
+                }
+            }
+        }
+        _ => {}
+    }
+    // IPv6 peers, 16 bytes of address and 2 of port each (BEP 7).
+    if let Some(compact) = dict.get(b"peers6".as_slice()).and_then(|peers6| peers6.as_bytes()) {
+        for peer in compact.chunks_exact(18) {
+            let ip = Ipv6Addr::from(u128::from_be_bytes(peer[..16].try_into().unwrap()));
+            peers.push(tracker_peer(ip.into(), u16::from_be_bytes([peer[16], peer[17]])));
+        }
+    }
+
+    return Ok(HttpAnnounce {
+        interval: int(b"interval").context("Error: The tracker response has no interval")?,
+        seeders: int(b"complete"),
+        leechers: int(b"incomplete"),
+        peers,
+        warning: string(b"warning message"),
+    });
+}
+
+fn tracker_peer(ip: IpAddr, port: u16) -> utils::Peer {
+    return utils::Peer::from_addr(SocketAddr::new(ip, port), PeerSource::Tracker);
+}
+
+/// Announce to every tracker of a torrent which is due, collecting the peers they return.
+///
+/// Trackers which fail are retried later and later, and one refusing the announce emits a tracker failure event.
+pub async fn get_torrent_peers(
     torrent: &torrents::Torrent,
     peer_id: &ByteBuffer,
+    swarm: &Swarm,
 ) -> anyhow::Result<Vec<utils::Peer>> {
-    let tracker_url = Url::parse(&torrent.announce.as_ref().unwrap()).unwrap();
-    let base_tracker_url = format!(
-        "{}:{}",
-        tracker_url.host_str().unwrap(),
-        tracker_url.port().unwrap()
-    );
+    let now = Instant::now();
+    let due: Vec<String> = swarm.trackers.lock().unwrap().iter().filter(|tracker| tracker.is_due(now)).map(|tracker| tracker.url.clone()).collect();
+
+    let mut peers = Vec::new();
+    for url in due {
+        let started = Instant::now();
+        let result = announce(&url, torrent, peer_id, swarm).await;
 
-    let socket = UdpSocket::bind(format!("0.0.0.0:{}", PORT)).unwrap();
-    socket.set_read_timeout(Some(Duration::new(5, 0)))?;
+        if let Err(e) = &result {
+            debug!(tracker = %url, "Announce failed: {:#}", e);
+            if let Some(TrackerError::Failure(reason)) = e.downcast_ref::<TrackerError>() {
+                warn!(tracker = %url, %reason, "Tracker refused the announce");
+                let event = Event::new(EventKind::TrackerFailure, torrent.info_hash, &torrent.info.name).with_message(reason.clone());
+                events::emit(&swarm.events, event);
+            }
+        }
 
-    let conn_resp = connect_tracker(&socket, base_tracker_url);
+        let mut trackers = swarm.trackers.lock().unwrap();
+        let Some(tracker) = trackers.iter_mut().find(|tracker| tracker.url == url) else {
+            continue;
+        };
+        match result {
+            Ok(announced) => {
+                tracker.succeeded(&announced, started.elapsed(), Instant::now());
+                peers.extend(announced.peers);
+            }
+            Err(e) => tracker.failed(&e, Instant::now()),
+        }
+    }
+
+    return Ok(peers);
+}
+
+/// Announce to a UDP or HTTP tracker.
+async fn announce(
+    url: &str,
+    torrent: &torrents::Torrent,
+    peer_id: &ByteBuffer,
+    swarm: &Swarm,
+) -> anyhow::Result<Announced> {
+    let tracker_url = Url::parse(url).map_err(|e| TrackerError::InvalidUrl(e.to_string()))?;
+    let partial_seed = swarm.pieces.lock().unwrap().is_partial_seed();
+    let params = AnnounceParams {
+        port: swarm.settings.listen_port,
+        event: if partial_seed { AnnounceEvent::Paused } else { AnnounceEvent::None },
+        ip: match swarm.external_ip.get(false) {
+            Some(IpAddr::V4(ip)) => Some(ip),
+            _ => None,
+        },
+        num_want: num_want(swarm.settings.num_want, swarm.peers.list().len(), swarm.settings.max_peers),
+        key: swarm.settings.announce_key,
+        url_data: url_data(&tracker_url),
+    };
 
-    let announce_resp = announce_tracker(&socket, &torrent, peer_id, conn_resp)
-        .expect("Not able to get peers from tracker");
+    return match tracker_url.scheme() {
+        "udp" => {
+            let announce_resp = announce_udp(&tracker_url, torrent, peer_id, swarm, &params).await?;
+            Ok(Announced {
+                interval: Duration::from_secs(announce_resp.interval.max(0) as u64),
+                seeders: u64::try_from(announce_resp.seeders).ok(),
+                leechers: u64::try_from(announce_resp.leechers).ok(),
+                peers: announce_resp.peers,
+            })
+        }
+        "http" | "https" => {
+            let ips: Vec<IpAddr> = vec![swarm.external_ip.get(false), swarm.external_ip.get(true)].into_iter().flatten().collect();
+            let announce_url = http_announce_url(&tracker_url, torrent, &peer_id.to_bytes(), &params, &ips);
+            let auth = TrackerAuth::find(&swarm.tracker_auth, &tracker_url);
+            let http_announce = announce_http(&swarm.http, &announce_url, auth).await?;
+            if let Some(warning) = &http_announce.warning {
+                warn!(tracker = %url, %warning, "Tracker warning");
+            }
 
-    if announce_resp.seeders == 0 {
-        anyhow::bail!("No peers at the moment");
-    } else {
-        return Ok(announce_resp.peers);
+            // Trackers leaving the size of the swarm out of their announces can still give it to a scrape.
+            let (mut seeders, mut leechers) = (http_announce.seeders, http_announce.leechers);
+            if seeders.is_none() || leechers.is_none() {
+                match scrape(url, &torrent.info_hash, &swarm.http, &swarm.settings.interface, &swarm.dns, &swarm.tracker_auth).await {
+                    Ok(scraped) => {
+                        seeders = seeders.or(Some(scraped.seeders));
+                        leechers = leechers.or(Some(scraped.leechers));
+                    }
+                    Err(e) => debug!(tracker = %url, "Scrape failed: {:#}", e),
+                }
+            }
+
+            Ok(Announced {
+                peers: http_announce.peers,
+                interval: Duration::from_secs(http_announce.interval),
+                seeders,
+                leechers,
+            })
+        }
+        scheme => Err(TrackerError::InvalidUrl(format!("{} trackers aren't supported", scheme)).into()),
+    };
+}
+
+/// The announce URL of an HTTP tracker with our parameters added after the ones already in it, like a passkey.
+///
+/// The info hash and peer id are raw bytes, every one of them which isn't unreserved is percent-encoded.
+pub fn http_announce_url(tracker_url: &Url, torrent: &Torrent, peer_id: &[u8], params: &AnnounceParams, ips: &[IpAddr]) -> String {
+    let mut query = String::from(tracker_url.query().unwrap_or(""));
+    let mut add = |name: &str, value: &str| {
+        if !query.is_empty() && !query.ends_with('&') {
+            query.push('&');
+        }
+        query.push_str(name);
+        query.push('=');
+        query.push_str(value);
+    };
+
+    add("info_hash", &percent_encode(&torrent.info_hash));
+    add("peer_id", &percent_encode(peer_id));
+    add("port", &params.port.to_string());
+    add("uploaded", "0");
+    add("downloaded", "0");
+    add("left", &torrent.size.to_string());
+    add("compact", "1");
+    add("numwant", &params.num_want.to_string());
+    add("key", &format!("{:08x}", params.key));
+    if let Some(event) = params.event.http_name() {
+        add("event", event);
     }
+    for &ip in ips {
+        let (name, value) = announce_ip_param(ip);
+        add(name, &percent_encode(value.as_bytes()));
+    }
+
+    let mut announce_url = tracker_url.clone();
+    announce_url.set_fragment(None);
+    announce_url.set_query(Some(&query));
+    return announce_url.into_string();
 }
 
-fn connect_tracker(socket: &UdpSocket, tracker_url: String) -> utils::ConnResp {
+/// Percent-encode every byte but the unreserved characters of RFC 3986.
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len() * 3);
+    for &byte in bytes {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+
+    return encoded;
+}
+
+async fn announce_http(client: &reqwest::Client, announce_url: &str, auth: Option<&TrackerAuth>) -> anyhow::Result<HttpAnnounce> {
+    let mut request = client.get(announce_url).timeout(HTTP_TIMEOUT);
+    if let Some(auth) = auth {
+        request = auth.apply(request);
+    }
+
+    let response = request.send().await.context("Couldn't reach the tracker")?;
+    let status = response.status();
+    let body = response.bytes().await.context("Couldn't read the tracker response")?;
+
+    // Trackers send their failure reason with error statuses too.
+    return match parse_http_announce(&body) {
+        Err(e) if !status.is_success() && e.downcast_ref::<TrackerError>().is_none() => {
+            anyhow::bail!("Error: The tracker answered with {}", status)
+        }
+        result => result,
+    };
+}
+
+async fn announce_udp(
+    tracker_url: &Url,
+    torrent: &torrents::Torrent,
+    peer_id: &ByteBuffer,
+    swarm: &Swarm,
+    params: &AnnounceParams,
+) -> anyhow::Result<utils::AnnounceResp> {
+    let settings = &swarm.settings;
+    let dns = &swarm.dns;
+
+    let host = tracker_url.host_str().ok_or_else(|| TrackerError::InvalidUrl(String::from("no host")))?;
+    let tracker_port = tracker_url.port().ok_or_else(|| TrackerError::InvalidUrl(String::from("no port")))?;
+    let tracker_addr = dns.resolve(host, tracker_port).await?;
+    let params = match tracker_addr {
+        SocketAddr::V6(_) => &AnnounceParams { port: settings.listen_port_v6, ..params.clone() },
+        SocketAddr::V4(_) => params,
+    };
+
+    // The listen port is taken by the DHT node.
+    let socket = settings.interface.bind_udp_for(tracker_addr)?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket)?;
+
+    // The next address of the tracker is tried on the next announce.
+    let conn_resp = match connect_tracker(&socket, tracker_addr).await {
+        Ok(conn_resp) => conn_resp,
+        Err(e) => {
+            dns.failed(host, tracker_port);
+            return Err(e);
+        }
+    };
+
+    return announce_tracker(&socket, &torrent, peer_id, conn_resp, params).await
+        .context("Not able to get peers from tracker");
+}
+
+/// Path and query of a UDP tracker URL, which private trackers put the passkey in. Nothing for a bare host.
+fn url_data(tracker_url: &Url) -> String {
+    let mut url_data = String::from(tracker_url.path());
+    if let Some(query) = tracker_url.query() {
+        url_data.push('?');
+        url_data.push_str(query);
+    }
+
+    if url_data == "/" {
+        return String::new();
+    }
+    return url_data;
+}
+
+async fn connect_tracker(socket: &UdpSocket, tracker_addr: SocketAddr) -> anyhow::Result<utils::ConnResp> {
     let conn_req = messages::build_conn_req();
 
     socket
-        .connect(tracker_url)
-        .expect("couldn't connect to address");
+        .connect(tracker_addr)
+        .await
+        .with_context(|| format!("Couldn't connect to {}", tracker_addr))?;
 
     socket
         .send(&conn_req.to_bytes())
-        .expect("couldn't send message");
+        .await
+        .context("Couldn't send message")?;
 
-    let mut recv_buf = [0; 16];
+    let mut recv_buf = [0; 1000];
 
-    socket.recv(&mut recv_buf).expect("Couldn't receive tracker message");
+    let received = recv_tracker(socket, &mut recv_buf).await.context("Couldn't receive tracker message")?;
+    check_action(&recv_buf[..received], 0)?;
+    if received < 16 {
+        anyhow::bail!("Error: The connect response is too short");
+    }
 
-    return utils::parse_conn_resp(&recv_buf);
+    return Ok(utils::parse_conn_resp(recv_buf[..16].try_into().unwrap()));
 }
 
-fn announce_tracker(
+async fn announce_tracker(
     socket: &UdpSocket,
     torrent: &Torrent,
     peer_id: &ByteBuffer,
     conn_resp: utils::ConnResp,
+    params: &AnnounceParams,
 ) -> anyhow::Result<utils::AnnounceResp> {
     let announce_req =
-        messages::build_announce_req(torrent, conn_resp.connection_id, &peer_id, PORT);
+        messages::build_announce_req(torrent, conn_resp.connection_id, &peer_id, params)?;
 
     socket
         .send(&announce_req.to_bytes())
-        .expect("Couldn't send annouce req");
+        .await
+        .context("Couldn't send annouce req")?;
 
     let mut recv_buf = [0; 1000];
-    let recieved = socket
-        .recv(&mut recv_buf)
-        .expect("Couldn't recieve announce response");
+    let recieved = recv_tracker(socket, &mut recv_buf)
+        .await
+        .context("Couldn't recieve announce response")?;
+    check_action(&recv_buf[..recieved], 1)?;
 
+    // Trackers reached over IPv6 give IPv6 peers.
+    let ipv6 = socket.peer_addr()?.is_ipv6();
     let announce_resp =
-        utils::parse_announce_resp(&recv_buf, recieved).expect("Couldn't parse the announce resp");
+        utils::parse_announce_resp(&recv_buf, recieved, ipv6).context("Couldn't parse the announce resp")?;
 
     Ok(announce_resp)
 }
+
+/// Wait for the answer of a UDP tracker, for up to `UDP_TIMEOUT`.
+async fn recv_tracker(socket: &UdpSocket, recv_buf: &mut [u8]) -> anyhow::Result<usize> {
+    return match tokio::time::timeout(UDP_TIMEOUT, socket.recv(recv_buf)).await {
+        Ok(received) => Ok(received?),
+        Err(_) => anyhow::bail!("Error: The tracker didn't answer within {} seconds", UDP_TIMEOUT.as_secs()),
+    };
+}
+
+/// Size of the swarm of a torrent as a tracker's scrape gives it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scrape {
+    pub seeders: u64,
+    pub leechers: u64,
+    /// Times the torrent has been downloaded.
+    pub downloads: u64,
+}
+
+/// The scrape URL of an HTTP tracker, made by replacing `announce` at the start of the last part of its path with
+/// `scrape`. Trackers whose URL doesn't have it can't be scraped.
+pub fn scrape_url(tracker_url: &Url) -> Option<Url> {
+    let path = tracker_url.path();
+    let (dir, last) = path.rsplit_once('/')?;
+    let rest = last.strip_prefix("announce")?;
+
+    let mut scrape_url = tracker_url.clone();
+    scrape_url.set_path(&format!("{}/scrape{}", dir, rest));
+    return Some(scrape_url);
+}
+
+/// Read the entry of a torrent from the bencoded scrape response of an HTTP tracker.
+pub fn parse_http_scrape(body: &[u8], info_hash: &[u8; 20]) -> anyhow::Result<Scrape> {
+    let response = Value::decode(body).context("Error: The scrape response isn't bencoded")?;
+    let dict = response.as_dict().context("Error: The scrape response isn't a dictionary")?;
+
+    if let Some(reason) = dict.get(b"failure reason".as_slice()).and_then(|reason| reason.as_bytes()) {
+        return Err(TrackerError::Failure(String::from_utf8_lossy(reason).into_owned()).into());
+    }
+
+    let file = dict.get(b"files".as_slice())
+        .and_then(|files| files.as_dict())
+        .and_then(|files| files.get(info_hash.as_slice()))
+        .and_then(|file| file.as_dict())
+        .context("Error: The tracker doesn't know the torrent")?;
+    let int = |key: &[u8]| file.get(key).and_then(|value| value.as_int()).and_then(|value| u64::try_from(value).ok()).unwrap_or(0);
+
+    return Ok(Scrape { seeders: int(b"complete"), leechers: int(b"incomplete"), downloads: int(b"downloaded") });
+}
+
+fn parse_udp_scrape(response: &[u8]) -> anyhow::Result<Scrape> {
+    check_action(response, 2)?;
+    if response.len() < 20 {
+        anyhow::bail!("Error: The scrape response is too short");
+    }
+
+    let int = |offset: usize| u32::from_be_bytes(response[offset..offset + 4].try_into().unwrap()) as u64;
+    return Ok(Scrape { seeders: int(8), downloads: int(12), leechers: int(16) });
+}
+
+/// Scrape a tracker for the size of the swarm of a torrent, without announcing to it.
+///
+/// UDP trackers are scraped from the interface like they're announced to, at their address of the family of the DNS cache.
+/// HTTP trackers get the credentials of their host.
+pub async fn scrape(
+    url: &str,
+    info_hash: &[u8; 20],
+    client: &reqwest::Client,
+    interface: &Interface,
+    dns: &DnsCache,
+    tracker_auth: &[TrackerAuth],
+) -> anyhow::Result<Scrape> {
+    let tracker_url = Url::parse(url).map_err(|e| TrackerError::InvalidUrl(e.to_string()))?;
+
+    return match tracker_url.scheme() {
+        "udp" => {
+            let host = tracker_url.host_str().ok_or_else(|| TrackerError::InvalidUrl(String::from("no host")))?;
+            let port = tracker_url.port().ok_or_else(|| TrackerError::InvalidUrl(String::from("no port")))?;
+            let tracker_addr = dns.resolve(host, port).await?;
+
+            let socket = interface.bind_udp_for(tracker_addr)?;
+            socket.set_nonblocking(true)?;
+            let socket = UdpSocket::from_std(socket)?;
+            let conn_resp = match connect_tracker(&socket, tracker_addr).await {
+                Ok(conn_resp) => conn_resp,
+                Err(e) => {
+                    dns.failed(host, port);
+                    return Err(e);
+                }
+            };
+
+            socket.send(&messages::build_scrape_req(conn_resp.connection_id, info_hash).to_bytes()).await.context("Couldn't send scrape req")?;
+            let mut recv_buf = [0; 1000];
+            let received = recv_tracker(&socket, &mut recv_buf).await.context("Couldn't receive scrape response")?;
+            parse_udp_scrape(&recv_buf[..received])
+        }
+        "http" | "https" => {
+            let mut scrape_url = scrape_url(&tracker_url).context("Error: The tracker can't be scraped")?;
+            let query = match tracker_url.query() {
+                Some(query) if !query.is_empty() => format!("{}&info_hash={}", query, percent_encode(info_hash)),
+                _ => format!("info_hash={}", percent_encode(info_hash)),
+            };
+            scrape_url.set_query(Some(&query));
+
+            let mut request = client.get(scrape_url.as_str()).timeout(HTTP_TIMEOUT);
+            if let Some(auth) = TrackerAuth::find(tracker_auth, &tracker_url) {
+                request = auth.apply(request);
+            }
+
+            let body = request.send().await.context("Couldn't reach the tracker")?
+                .bytes().await.context("Couldn't read the scrape response")?;
+            parse_http_scrape(&body, info_hash)
+        }
+        scheme => Err(TrackerError::InvalidUrl(format!("{} trackers aren't supported", scheme)).into()),
+    };
+}
+
+/// Check a UDP tracker answered with the action we expect, turning an error response into a `TrackerError`.
+fn check_action(response: &[u8], expected: i32) -> anyhow::Result<()> {
+    if response.len() < 8 {
+        anyhow::bail!("Error: The tracker response is too short");
+    }
+
+    let action = i32::from_be_bytes(response[..4].try_into().unwrap());
+    if action == ACTION_ERROR {
+        return Err(TrackerError::Failure(String::from_utf8_lossy(&response[8..]).into_owned()).into());
+    }
+    if action != expected {
+        anyhow::bail!("Error: Expected action {} from the tracker but got {}", expected, action);
+    }
+
+    return Ok(());
+}
+
+
+#[test]
+fn test_external_ip() {
+    let external_ip = ExternalIp::new(None);
+    assert_eq!(external_ip.get(false), None);
+
+    external_ip.vote("198.51.100.7".parse().unwrap());
+    external_ip.vote("203.0.113.9".parse().unwrap());
+    external_ip.vote("203.0.113.9".parse().unwrap());
+    external_ip.vote("192.168.1.2".parse().unwrap());
+    external_ip.vote("192.168.1.2".parse().unwrap());
+    external_ip.vote("192.168.1.2".parse().unwrap());
+    external_ip.vote("2001:db8::7".parse().unwrap());
+
+    // Most peers win and local addresses don't count.
+    assert_eq!(external_ip.get(false), Some("203.0.113.9".parse().unwrap()));
+    assert_eq!(external_ip.get(true), Some("2001:db8::7".parse().unwrap()));
+
+    // The configured address takes precedence for its family.
+    let configured = ExternalIp::new(Some("198.51.100.1".parse().unwrap()));
+    configured.vote("203.0.113.9".parse().unwrap());
+    assert_eq!(configured.get(false), Some("198.51.100.1".parse().unwrap()));
+
+    assert_eq!(announce_ip_param("203.0.113.9".parse().unwrap()), ("ip", String::from("203.0.113.9")));
+    assert_eq!(announce_ip_param("2001:db8::7".parse().unwrap()), ("ipv6", String::from("2001:db8::7")));
+}
+
+
+#[test]
+fn test_num_want() {
+    assert_eq!(num_want(50, 0, 30), 30);
+    assert_eq!(num_want(20, 0, 30), 20);
+    assert_eq!(num_want(50, 25, 30), 5);
+    assert_eq!(num_want(50, 40, 30), 0);
+}
+
+
+#[test]
+fn test_parse_http_announce() {
+    let announce = parse_http_announce(b"d8:completei5e10:incompletei3e8:intervali1800e5:peers12:\xc6\x33\x64\x07\x1a\xe1\x0a\x00\x00\x01\x1a\xe215:warning message13:Slow down plse").unwrap();
+    assert_eq!(announce.interval, 1800);
+    assert_eq!((announce.seeders, announce.leechers), (Some(5), Some(3)));
+    assert_eq!(announce.peers.iter().map(|peer| peer.addrs[0]).collect::<Vec<_>>(),
+        vec![SocketAddr::from(([198, 51, 100, 7], 6881)), SocketAddr::from(([10, 0, 0, 1], 6882))]);
+    assert_eq!(announce.warning.as_deref(), Some("Slow down pls"));
+
+    // Peers given as dictionaries.
+    let announce = parse_http_announce(b"d8:intervali60e5:peersld2:ip11:203.0.113.94:porti51413eed2:ip11:2001:db8::14:porti6881eeee").unwrap();
+    assert_eq!(announce.peers[0].addrs, vec![SocketAddr::from(([203, 0, 113, 9], 51413))]);
+    assert_eq!(announce.peers[1].addrs, vec!["[2001:db8::1]:6881".parse().unwrap()]);
+
+    // IPv6 peers next to the IPv4 ones.
+    let mut response = b"d8:intervali60e5:peers0:6:peers618:".to_vec();
+    response.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+    response.extend_from_slice(&[0x1a, 0xe1, b'e']);
+    let announce = parse_http_announce(&response).unwrap();
+    assert_eq!(announce.peers[0].addrs, vec!["[2001:db8::2]:6881".parse().unwrap()]);
+    assert_eq!(announce.warning, None);
+
+    let error = parse_http_announce(b"d14:failure reason17:Torrent not founde").unwrap_err();
+    assert_eq!(error.downcast_ref::<TrackerError>(), Some(&TrackerError::Failure(String::from("Torrent not found"))));
+    assert!(parse_http_announce(b"d5:peers0:e").is_err());
+}
+
+
+#[test]
+fn test_url_data() {
+    assert_eq!(url_data(&Url::parse("udp://tracker.example.com:80").unwrap()), "");
+    assert_eq!(url_data(&Url::parse("udp://tracker.example.com:80/").unwrap()), "");
+    assert_eq!(url_data(&Url::parse("udp://tracker.example.com:80/abc123/announce?x=1").unwrap()), "/abc123/announce?x=1");
+}
+
+
+#[test]
+fn test_http_announce_url() {
+    let torrent = Torrent { info_hash: [0x00, 0xff, b'a', b' ', b'~', b'%', b'&', 0x80, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12], size: 1000, ..Default::default() };
+    let params = AnnounceParams { port: 6881, event: AnnounceEvent::Paused, num_want: 20, key: 0xbeef, ..Default::default() };
+    let tracker_url = Url::parse("https://tracker.example.com/announce?passkey=abc123#top").unwrap();
+
+    let announce_url = http_announce_url(&tracker_url, &torrent, b"-TR0001-xyz~.AB_1234", &params, &["2001:db8::1".parse().unwrap()]);
+    assert_eq!(announce_url, "https://tracker.example.com/announce?passkey=abc123\
+        &info_hash=%00%FFa%20~%25%26%80%01%02%03%04%05%06%07%08%09%0A%0B%0C&peer_id=-TR0001-xyz~.AB_1234\
+        &port=6881&uploaded=0&downloaded=0&left=1000&compact=1&numwant=20&key=0000beef&event=paused&ipv6=2001%3Adb8%3A%3A1");
+
+    // A URL without a query gets one.
+    let announce_url = http_announce_url(&Url::parse("http://tracker.example.com:6969/announce").unwrap(), &torrent, &[1; 20], &params, &[]);
+    assert!(announce_url.starts_with("http://tracker.example.com:6969/announce?info_hash=%00%FF"));
+}
+
+
+#[test]
+fn test_tracker_auth() {
+    let auths = vec![
+        TrackerAuth { host: String::from("tracker.example.com"), username: Some(String::from("me")), ..Default::default() },
+        TrackerAuth { host: String::from("other.example.com"), cookie: Some(String::from("uid=1")), ..Default::default() },
+    ];
+
+    let auth = TrackerAuth::find(&auths, &Url::parse("https://Tracker.Example.com:8443/announce").unwrap()).unwrap();
+    assert_eq!(auth.username.as_deref(), Some("me"));
+    assert!(TrackerAuth::find(&auths, &Url::parse("https://example.com/announce").unwrap()).is_none());
+
+    let auth = TrackerAuth { headers: HashMap::from([(String::from("X-Api-Key"), String::from("abc"))]), ..auths[0].clone() };
+    let request = auth.apply(reqwest::Client::new().get("https://tracker.example.com/announce")).build().unwrap();
+    assert_eq!(request.headers()["X-Api-Key"], "abc");
+    assert!(request.headers()[reqwest::header::AUTHORIZATION].to_str().unwrap().starts_with("Basic "));
+}
+
+
+#[test]
+fn test_scrape_url() {
+    let scrape = |url: &str| scrape_url(&Url::parse(url).unwrap()).map(|url| url.into_string());
+    assert_eq!(scrape("http://example.com/announce").as_deref(), Some("http://example.com/scrape"));
+    assert_eq!(scrape("http://example.com/x/announce.php?passkey=abc").as_deref(), Some("http://example.com/x/scrape.php?passkey=abc"));
+    assert_eq!(scrape("http://example.com/a"), None);
+    assert_eq!(scrape("http://example.com/announce/x"), None);
+}
+
+
+#[test]
+fn test_parse_scrape() {
+    let info_hash = [b'a'; 20];
+    let body = b"d5:filesd20:aaaaaaaaaaaaaaaaaaaad8:completei5e10:downloadedi50e10:incompletei3eeee";
+    assert_eq!(parse_http_scrape(body, &info_hash).unwrap(), Scrape { seeders: 5, leechers: 3, downloads: 50 });
+    assert!(parse_http_scrape(body, &[b'b'; 20]).is_err());
+
+    let response = [&2i32.to_be_bytes()[..], &[0; 4], &5u32.to_be_bytes(), &50u32.to_be_bytes(), &3u32.to_be_bytes()].concat();
+    assert_eq!(parse_udp_scrape(&response).unwrap(), Scrape { seeders: 5, leechers: 3, downloads: 50 });
+    assert!(parse_udp_scrape(&response[..16]).is_err());
+}
+
+
+#[test]
+fn test_check_action() {
+    let mut error = vec![0, 0, 0, 3, 1, 2, 3, 4];
+    error.extend(b"Unregistered torrent");
+    let error = check_action(&error, 1).unwrap_err();
+    assert_eq!(error.downcast_ref::<TrackerError>(), Some(&TrackerError::Failure(String::from("Unregistered torrent"))));
+
+    assert!(check_action(&[0, 0, 0, 1, 1, 2, 3, 4], 1).is_ok());
+    assert!(check_action(&[0, 0, 0, 0, 1, 2, 3, 4], 1).is_err());
+    assert!(check_action(&[0, 0, 0, 1], 1).is_err());
+}
+
+
+#[test]
+fn test_tracker_state() {
+    let now = Instant::now();
+    let mut tracker = TrackerState::new(String::from("udp://tracker.example:80"), now);
+    assert!(tracker.is_due(now));
+
+    // Failures back off exponentially up to the maximum.
+    tracker.failed(&anyhow::anyhow!("timed out"), now);
+    assert_eq!((tracker.health, tracker.next_announce - now), (TrackerHealth::Failing, RETRY_DELAY));
+    tracker.failed(&anyhow::anyhow!("timed out"), now);
+    assert_eq!(tracker.next_announce - now, RETRY_DELAY * 2);
+    assert!(!tracker.is_due(now));
+    assert_eq!(retry_delay(9), MAX_RETRY_DELAY);
+
+    let announced = Announced { peers: vec![tracker_peer(Ipv4Addr::LOCALHOST.into(), 6881); 12], interval: Duration::from_secs(1800), seeders: Some(3), leechers: None };
+    tracker.succeeded(&announced, Duration::from_millis(80), now);
+    assert_eq!((tracker.health, tracker.failures, tracker.last_result.clone()), (TrackerHealth::Working, 0, Some(Ok(12))));
+    assert_eq!(tracker.next_announce - now, Duration::from_secs(1800));
+    assert_eq!((tracker.announces, tracker.response_time, tracker.seeders, tracker.leechers), (3, Some(Duration::from_millis(80)), Some(3), None));
+
+    for _ in 0..MAX_FAILURES {
+        tracker.failed(&anyhow::anyhow!("timed out"), now);
+    }
+    assert_eq!(tracker.health, TrackerHealth::Disabled);
+    assert!(!tracker.is_due(now + MAX_RETRY_DELAY));
+
+    // A URL which can't work is given up on straight away.
+    let mut invalid = TrackerState::new(String::from("wss://tracker.example"), now);
+    invalid.failed(&TrackerError::InvalidUrl(String::from("wss trackers aren't supported")).into(), now);
+    assert_eq!(invalid.health, TrackerHealth::Disabled);
+}
+
+
+#[tokio::test]
+async fn test_announce_mock_trackers() {
+    use crate::testing::{MockAnnounce, MockHttpTracker, MockResponse, MockUdpTracker};
+
+    let peer = "10.0.0.1:6881".parse().unwrap();
+    let response = MockResponse { interval: 900, seeders: 3, leechers: 1, peers: vec![peer] };
+    let torrent = Torrent { info_hash: [7; 20], size: 1000, ..Default::default() };
+    let params = AnnounceParams { port: 6882, num_want: -1, ..Default::default() };
+
+    let udp = MockUdpTracker::start(response.clone()).unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let conn_resp = connect_tracker(&socket, udp.addr()).await.unwrap();
+    let announced = announce_tracker(&socket, &torrent, &ByteBuffer::from_bytes(&[1; 20]), conn_resp, &params).await.unwrap();
+    assert_eq!((announced.interval, announced.seeders, announced.leechers), (900, 3, 1));
+    assert_eq!(announced.peers[0].addrs, vec![SocketAddr::from(([10, 0, 0, 1], 6881))]);
+    assert_eq!(udp.announces(), vec![MockAnnounce { info_hash: [7; 20], port: 6882 }]);
+
+    let http = MockHttpTracker::start(response).unwrap();
+    let announce_url = http_announce_url(&Url::parse(&http.url()).unwrap(), &torrent, &[1; 20], &params, &[]);
+    let announced = announce_http(&reqwest::Client::new(), &announce_url, None).await.unwrap();
+    assert_eq!((announced.interval, announced.seeders, announced.leechers), (900, Some(3), Some(1)));
+    assert_eq!(announced.peers[0].addrs[0].port(), 6881);
+    assert!(http.requests()[0].starts_with("/announce?info_hash=%07%07"));
+}
+
+
+#[tokio::test]
+async fn test_scrape_mock_udp_tracker() {
+    use crate::dns::DNS_TTL;
+    use crate::interface::AddressFamily;
+    use crate::testing::{MockResponse, MockUdpTracker};
+
+    let udp = MockUdpTracker::start(MockResponse { seeders: 4, leechers: 2, ..Default::default() }).unwrap();
+    let client = reqwest::Client::new();
+    let scraped = scrape(&udp.url(), &[7; 20], &client, &Interface::Any, &DnsCache::new(DNS_TTL, AddressFamily::Any), &[]).await.unwrap();
+    assert_eq!(scraped, Scrape { seeders: 4, leechers: 2, downloads: 0 });
+
+    // The tracker has no address in the family of the session.
+    let ipv6_only = DnsCache::new(DNS_TTL, AddressFamily::Ipv6);
+    assert!(scrape(&udp.url(), &[7; 20], &client, &Interface::Any, &ipv6_only, &[]).await.is_err());
+}
+
+
+#[tokio::test]
+async fn test_scrape_mock_http_tracker() {
+    use crate::dns::DNS_TTL;
+    use crate::testing::{MockHttpTracker, MockResponse};
+
+    let http = MockHttpTracker::start(MockResponse { seeders: 4, leechers: 2, ..Default::default() }).unwrap();
+    let auths = vec![TrackerAuth { host: String::from("127.0.0.1"), cookie: Some(String::from("uid=1")), ..Default::default() }];
+    let dns = DnsCache::new(DNS_TTL, Default::default());
+    let scraped = scrape(&http.url(), &[7; 20], &reqwest::Client::new(), &Interface::Any, &dns, &auths).await.unwrap();
+    assert_eq!(scraped, Scrape { seeders: 4, leechers: 2, downloads: 0 });
+
+    assert!(http.requests()[0].starts_with("/scrape?info_hash=%07%07"));
+    assert!(http.heads()[0].to_lowercase().contains("cookie: uid=1\r\n"));
+}
@@ -0,0 +1,230 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use tokio::sync::mpsc;
+use tokio_uring::fs::{File, OpenOptions};
+use tracing::error;
+
+use crate::cache::{ReadCache, WriteRun};
+use crate::disk::{apply_attributes, cache_block, coalesce, file_path, hash_for_check, hash_for_reply, move_data, rename_files, DiskJob, DiskState, SkippedBlocks};
+use crate::metrics;
+use crate::storage::FileStorage;
+use crate::utils::torrents::{DlFile, Torrent};
+
+/// Run jobs on an io_uring runtime until every `DiskIo` handle is dropped.
+pub fn run(torrent: &Torrent, download_folder: &str, mut receiver: mpsc::Receiver<DiskJob>, state: DiskState) {
+    let DiskState { mut storage, mut skipped, mut cache, mut read_cache, mut syncer, mut verified, mut hashes, pending, jobs: job_sender } = state;
+    let mut download_folder = PathBuf::from(download_folder);
+
+    tokio_uring::start(async {
+        while let Some(job) = receiver.recv().await {
+            let mut jobs = vec![job];
+            while let Ok(job) = receiver.try_recv() {
+                jobs.push(job);
+            }
+
+            for job in coalesce(jobs) {
+                let (ready, job) = match job {
+                    DiskJob::Write(payload) => {
+                        read_cache.invalidate(payload.offset, payload.block.len() as u64);
+                        (cache_block(&mut cache, &mut hashes, payload), None)
+                    }
+                    // Anything else needs the cached blocks to be on disk first.
+                    job => (cache.drain(), Some(job)),
+                };
+
+                for run in ready {
+                    skipped.keep(&storage, run.offset(), run.length(), |start, len| run.slices(start, len).concat());
+
+                    let timer = metrics::DISK_WRITE_SECONDS.start_timer();
+                    if let Err(e) = write_run(&download_folder, &storage, &run).await {
+                        error!("Unable to write {} bytes at {}: {:#}", run.length(), run.offset(), e);
+                        pending.failed(&e);
+                    }
+                    timer.observe_duration();
+                    pending.written(run.length());
+
+                    syncer.written(&storage, run.offset(), run.length());
+                    if let Err(e) = sync_files(&download_folder, storage.files(), &syncer.due()).await {
+                        error!("Unable to sync files: {:#}", e);
+                    }
+                }
+
+                let Some(job) = job else {
+                    continue;
+                };
+
+                match job {
+                    DiskJob::Write(_) => unreachable!(),
+                    DiskJob::Read { offset, length, reply } => {
+                        let _ = reply.send(read_cached(&mut read_cache, torrent, &download_folder, &storage, &skipped, offset, length).await);
+                    }
+                    DiskJob::HashPiece { index, reply } => {
+                        let piece = read_block(&download_folder, &storage, &skipped, index * torrent.info.piece_length, torrent.get_piece_len(index)).await;
+                        hash_for_reply(piece, reply);
+                    }
+                    DiskJob::VerifyPiece { index, reply } => {
+                        if let Some(hash) = hashes.take(index) {
+                            if !verified.read_back(torrent, index, hash) {
+                                skipped.discard(index * torrent.info.piece_length, torrent.get_piece_len(index));
+                                let _ = reply.send(verified.check(torrent, &download_folder, storage.files(), index, hash));
+                                continue;
+                            }
+                        }
+
+                        let piece = read_block(&download_folder, &storage, &skipped, index * torrent.info.piece_length, torrent.get_piece_len(index)).await;
+                        skipped.discard(index * torrent.info.piece_length, torrent.get_piece_len(index));
+                        hash_for_check(&job_sender, index, piece, reply);
+                    }
+                    DiskJob::PieceHashed { index, hash, reply } => {
+                        let _ = reply.send(verified.check(torrent, &download_folder, storage.files(), index, hash));
+                    }
+                    DiskJob::Rename { rename, reply } => {
+                        let _ = reply.send(rename_files(&mut download_folder, &mut storage, rename));
+                    }
+                    DiskJob::Move { to, reply } => {
+                        let result = move_data(&download_folder, &to);
+                        if result.is_ok() {
+                            download_folder = to;
+                        }
+                        let _ = reply.send(result);
+                    }
+                    DiskJob::Flush { reply } => {
+                        let _ = reply.send(());
+                    }
+                    DiskJob::Sync { reply } => {
+                        let _ = reply.send(sync_files(&download_folder, storage.files(), &syncer.take()).await);
+                    }
+                    DiskJob::ApplyAttributes { symlinks, reply } => {
+                        let _ = reply.send(apply_attributes(&download_folder, storage.files(), symlinks));
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Write a run of blocks with one vectored write for each file it covers.
+async fn write_run(download_folder: &Path, storage: &FileStorage, run: &WriteRun) -> anyhow::Result<()> {
+    for segment in storage.map_range(run.offset(), run.length()) {
+        let file = storage.file(segment.file);
+        if !storage.is_stored(segment.file) {
+            continue;
+        }
+
+        let path = file_path(download_folder, file);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new().write(true).create(true).open(&path).await
+            .with_context(|| format!("Unable to open {:?}", path))?;
+
+        // The buffers are owned by the ring until the write is done.
+        let buffers: Vec<Vec<u8>> = run.slices(segment.start, segment.len).into_iter().map(|slice| slice.to_vec()).collect();
+        let (result, _) = file.writev_at_all(buffers, Some(segment.file_offset)).await;
+        result?;
+        file.close().await?;
+    }
+
+    return Ok(());
+}
+
+/// fsync some of the files of the torrent.
+async fn sync_files(download_folder: &Path, files: &[DlFile], indexes: &[usize]) -> anyhow::Result<()> {
+    for &index in indexes {
+        let path = file_path(download_folder, &files[index]);
+        let file = OpenOptions::new().write(true).open(&path).await.with_context(|| format!("Unable to open {:?}", path))?;
+        file.sync_all().await.with_context(|| format!("Unable to sync {:?}", path))?;
+        file.close().await?;
+    }
+
+    return Ok(());
+}
+
+/// Read a range through the read cache, reading and keeping its whole piece when it isn't cached.
+async fn read_cached(cache: &mut ReadCache, torrent: &Torrent, download_folder: &Path, storage: &FileStorage, skipped: &SkippedBlocks, offset: u64, length: u64) -> anyhow::Result<Vec<u8>> {
+    if let Some(block) = cache.get(offset, length) {
+        return Ok(block);
+    }
+
+    // A piece which isn't all on disk yet is left out of the cache.
+    if let Some(index) = cache.piece_of(offset, length) {
+        if let Ok(piece) = read_block(download_folder, storage, skipped, index * torrent.info.piece_length, torrent.get_piece_len(index)).await {
+            cache.insert(index, piece);
+            if let Some(block) = cache.get(offset, length) {
+                return Ok(block);
+            }
+        }
+    }
+
+    return read_block(download_folder, storage, skipped, offset, length).await;
+}
+
+async fn read_block(download_folder: &Path, storage: &FileStorage, skipped: &SkippedBlocks, offset: u64, length: u64) -> anyhow::Result<Vec<u8>> {
+    let mut block = Vec::with_capacity(length as usize);
+
+    for segment in storage.map_range(offset, length) {
+        let file = storage.file(segment.file);
+        if file.is_pad() {
+            block.resize(block.len() + segment.len, 0);
+            continue;
+        }
+        if storage.is_skipped(segment.file) {
+            block.extend(skipped.read(offset + segment.start as u64, segment.len as u64).context("Error: The range is in a skipped file")?);
+            continue;
+        }
+
+        let path = file_path(download_folder, file);
+        let file = File::open(&path).await.with_context(|| format!("Unable to open {:?}", path))?;
+
+        let (result, buffer) = file.read_exact_at(Vec::with_capacity(segment.len), segment.file_offset).await;
+        result.with_context(|| format!("Unable to read {} bytes of {:?}", segment.len, path))?;
+        block.extend(buffer);
+        file.close().await?;
+    }
+
+    if block.len() as u64 != length {
+        anyhow::bail!("Error: Only {} of the {} bytes at {} are on disk", block.len(), length, offset);
+    }
+
+    return Ok(block);
+}
+
+
+#[tokio::test]
+async fn test_uring_disk_io() {
+    use std::sync::Arc;
+    use crate::config::DiskConfig;
+    use crate::disk::{DiskBackend, DiskIo};
+    use crate::message_handlers::PieceChannelPayload;
+
+    let dir = Path::new("test-files/uring");
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir).unwrap();
+
+    let torrent = Arc::new(Torrent {
+        info: crate::utils::torrents::Info {
+            name: String::from("data"),
+            piece_length: 16384,
+            files: Some(vec![
+                DlFile { path: vec![String::from("a.bin")], length: 10, md5sum: None, attr: None, symlink_path: None },
+                DlFile { path: vec![String::from("sub"), String::from("b.bin")], length: 10, md5sum: None, attr: None, symlink_path: None },
+            ]),
+            ..Default::default()
+        },
+        size: 20,
+        ..Default::default()
+    });
+
+    let storage = FileStorage::from_torrent(&torrent);
+    let disk = DiskIo::start(torrent, dir.join("data").to_string_lossy().into_owned(), storage, &DiskConfig { backend: DiskBackend::IoUring, ..Default::default() }).unwrap();
+    disk.write(PieceChannelPayload { offset: 5, block: vec![1; 10] }).await.unwrap();
+    disk.sync().await.unwrap();
+
+    assert_eq!(disk.read(0, 20).await.unwrap(), [vec![0; 5], vec![1; 10], vec![0; 5]].concat());
+    assert_eq!(fs::read(dir.join("data/sub/b.bin")).unwrap(), [vec![1; 5], vec![0; 5]].concat());
+
+    let _ = fs::remove_dir_all(dir);
+}
@@ -1,9 +1,12 @@
 use core::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use anyhow;
 use bytebuffer::ByteBuffer;
 use rand::Rng;
 
+use crate::encryption::PeerCrypto;
+
 #[path = "./torrents.rs"]
 pub mod torrents;
 
@@ -26,19 +29,78 @@ pub struct AnnounceResp {
 
 #[derive(Debug, Clone)]
 pub struct Peer {
-    pub ip_addr: u32,
-    pub port: u16,
+    /// Where the peer can be reached, usually one address, a peer with more is connected to on whichever answers first.
+    pub addrs: Vec<SocketAddr>,
+    pub crypto: PeerCrypto,
+    pub source: PeerSource,
+}
+
+impl Peer {
+    /// A peer at `addr` we know nothing else of.
+    pub fn from_addr(addr: SocketAddr, source: PeerSource) -> Peer {
+        return Peer { addrs: vec![addr], crypto: PeerCrypto::Unknown, source };
+    }
+}
+
+/// Where a peer was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    Pex,
+    /// Local service discovery.
+    Lsd,
+    /// The peer connected to us.
+    Incoming,
+    /// Added by hand.
+    Manual,
+}
+
+impl PeerSource {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            PeerSource::Tracker => "tracker",
+            PeerSource::Dht => "dht",
+            PeerSource::Pex => "pex",
+            PeerSource::Lsd => "lsd",
+            PeerSource::Incoming => "incoming",
+            PeerSource::Manual => "manual",
+        };
+    }
+
+    /// Whether peers from this source can be used for a torrent, private torrents only take peers from their
+    /// trackers and the ones added by hand or connecting to us.
+    pub fn is_allowed(&self, private: bool) -> bool {
+        return !private || !matches!(self, PeerSource::Dht | PeerSource::Pex | PeerSource::Lsd);
+    }
 }
 
 
-pub fn gen_peer_id() -> ByteBuffer {
+/// Whether an address is on the local network: private, loopback and link-local addresses.
+pub fn is_local_addr(addr: IpAddr) -> bool {
+    return match addr {
+        IpAddr::V4(addr) => addr.is_private() || addr.is_loopback() || addr.is_link_local(),
+        IpAddr::V6(addr) => match addr.to_ipv4_mapped() {
+            Some(addr) => is_local_addr(addr.into()),
+            None => addr.is_loopback() || addr.is_unique_local() || addr.is_unicast_link_local(),
+        },
+    };
+}
+
+
+/// Length of a peer id.
+pub const PEER_ID_LEN: usize = 20;
+
+/// A peer id starting with the client prefix, random after that.
+pub fn gen_peer_id(prefix: &str) -> ByteBuffer {
     let mut peer_id = ByteBuffer::new();
-    let peer_prefix = "-R~0001-";
     let mut rng = rand::thread_rng();
 
-    peer_id.write_bytes(peer_prefix.as_bytes());
-    peer_id.write_i64(rng.gen::<i64>());
-    peer_id.write_i32(rng.gen::<i32>());
+    let prefix = &prefix.as_bytes()[..prefix.len().min(PEER_ID_LEN)];
+    peer_id.write_bytes(prefix);
+    for _ in prefix.len()..PEER_ID_LEN {
+        peer_id.write_u8(rng.gen::<u8>());
+    }
 
     return peer_id;
 }
@@ -55,7 +117,8 @@ pub fn parse_conn_resp(buf: &[u8; 16]) -> ConnResp {
 }
 
 
-pub fn parse_announce_resp(buf: &[u8; 1000], received: usize) -> anyhow::Result<AnnounceResp> {
+/// Parse the announce response of a UDP tracker, trackers reached over IPv6 answer with IPv6 peers.
+pub fn parse_announce_resp(buf: &[u8; 1000], received: usize, ipv6: bool) -> anyhow::Result<AnnounceResp> {
     if received < 20 {
         anyhow::bail!("Error: Not able to announce to tracker");
     } else {
@@ -68,16 +131,108 @@ pub fn parse_announce_resp(buf: &[u8; 1000], received: usize) -> anyhow::Result<
             peers: Vec::new(),
         };
 
-        let mut offset = 20;
-        for _ in 0..announce_resp.seeders {
-            announce_resp.peers.push(Peer {
-                ip_addr: u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap()),
-                port: u16::from_be_bytes(buf[offset + 4..offset + 6].try_into().unwrap()),
-            });
-
-            offset += 4;
+        // 6 bytes for each peer after the header, or 18 over IPv6, as many as the tracker sent rather than the amount
+        // of seeders.
+        let addr_len = if ipv6 { 16 } else { 4 };
+        for peer in buf[20..received].chunks_exact(addr_len + 2) {
+            let ip = match ipv6 {
+                true => IpAddr::from(Ipv6Addr::from(u128::from_be_bytes(peer[..16].try_into().unwrap()))),
+                false => IpAddr::from(Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3])),
+            };
+            let port = u16::from_be_bytes([peer[addr_len], peer[addr_len + 1]]);
+            announce_resp.peers.push(Peer::from_addr(SocketAddr::new(ip, port), PeerSource::Tracker));
         }
 
         return Ok(announce_resp);
     }
 }
+
+
+/// Encode bytes as a lowercase hex string, used to display info hashes.
+pub fn to_hex(bytes: &[u8]) -> String {
+    return bytes.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+
+/// Decode a 40 character hex string into an info hash.
+pub fn info_hash_from_hex(hex: &str) -> anyhow::Result<[u8; 20]> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        anyhow::bail!("Error: An info hash must be 40 hex characters");
+    }
+
+    let mut info_hash: [u8; 20] = [0; 20];
+    for i in 0..20 {
+        info_hash[i] = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+    }
+
+    return Ok(info_hash);
+}
+
+#[test]
+fn test_info_hash_hex() {
+    let info_hash: [u8; 20] = [0x06, 0xcb, 0x06, 0x12, 0x40, 0xb2, 0x4f, 0x73, 0x0f, 0xbe, 0xf7, 0xea, 0xd1, 0xb3, 0x48, 0xd8, 0x86, 0x52, 0x44, 0xaf];
+    let hex = to_hex(&info_hash);
+    assert_eq!(hex, "06cb061240b24f730fbef7ead1b348d8865244af");
+    assert_eq!(info_hash_from_hex(&hex).unwrap(), info_hash);
+
+    assert!(info_hash_from_hex("06cb").is_err());
+    assert!(info_hash_from_hex("zzcb061240b24f730fbef7ead1b348d8865244af").is_err());
+}
+
+
+#[test]
+fn test_peer_source_allowed() {
+    assert!(PeerSource::Dht.is_allowed(false));
+    assert!(!PeerSource::Dht.is_allowed(true));
+    assert!(!PeerSource::Pex.is_allowed(true));
+    assert!(!PeerSource::Lsd.is_allowed(true));
+    assert!(PeerSource::Tracker.is_allowed(true));
+    assert!(PeerSource::Incoming.is_allowed(true));
+    assert!(PeerSource::Manual.is_allowed(true));
+}
+
+
+#[test]
+fn test_is_local_addr() {
+    assert!(is_local_addr("192.168.1.20".parse().unwrap()));
+    assert!(is_local_addr("10.0.0.1".parse().unwrap()));
+    assert!(is_local_addr("172.16.5.4".parse().unwrap()));
+    assert!(is_local_addr("169.254.0.1".parse().unwrap()));
+    assert!(is_local_addr("127.0.0.1".parse().unwrap()));
+    assert!(is_local_addr("fd00::1".parse().unwrap()));
+    assert!(is_local_addr("fe80::1".parse().unwrap()));
+    assert!(is_local_addr("::ffff:192.168.1.20".parse().unwrap()));
+
+    assert!(!is_local_addr("172.32.0.1".parse().unwrap()));
+    assert!(!is_local_addr("8.8.8.8".parse().unwrap()));
+    assert!(!is_local_addr("2001:db8::1".parse().unwrap()));
+}
+
+
+#[test]
+fn test_parse_announce_resp() {
+    let mut buf = [0; 1000];
+    buf[..20].copy_from_slice(&[0, 0, 0, 1, 0, 0, 0, 9, 0, 0, 7, 8, 0, 0, 0, 5, 0, 0, 0, 1]);
+    buf[20..32].copy_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2]);
+
+    // Two peers, even though the tracker says there's one seeder and five leechers.
+    let announce_resp = parse_announce_resp(&buf, 32, false).unwrap();
+    assert_eq!((announce_resp.interval, announce_resp.leechers, announce_resp.seeders), (1800, 5, 1));
+    assert_eq!(announce_resp.peers.iter().map(|peer| peer.addrs[0]).collect::<Vec<_>>(), vec![SocketAddr::from(([10, 0, 0, 1], 6881)), SocketAddr::from(([10, 0, 0, 2], 6882))]);
+    assert!(parse_announce_resp(&buf, 12, false).is_err());
+
+    // A tracker reached over IPv6 gives IPv6 peers.
+    buf[20..36].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+    buf[36..38].copy_from_slice(&[0x1a, 0xe1]);
+    let announce_resp = parse_announce_resp(&buf, 38, true).unwrap();
+    assert_eq!(announce_resp.peers.iter().map(|peer| peer.addrs[0]).collect::<Vec<_>>(), vec!["[2001:db8::1]:6881".parse().unwrap()]);
+}
+
+
+#[test]
+fn test_gen_peer_id() {
+    let peer_id = gen_peer_id("-qB4650-").to_bytes();
+    assert_eq!(peer_id.len(), PEER_ID_LEN);
+    assert_eq!(&peer_id[..8], b"-qB4650-");
+    assert_eq!(gen_peer_id("").to_bytes().len(), PEER_ID_LEN);
+}
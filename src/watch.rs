@@ -0,0 +1,96 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{error, info, warn};
+
+use crate::session::{AddTorrentOptions, Session};
+
+/// How often the watch directory is scanned for new torrent files.
+const SCAN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Suffix added to a torrent file once it has been added to the session.
+pub const ADDED_SUFFIX: &str = ".added";
+
+/// Suffix added to a torrent file which couldn't be loaded, so it isn't retried on every scan.
+pub const INVALID_SUFFIX: &str = ".invalid";
+
+/// Watch a directory and add any `.torrent` file dropped into it to the session.
+///
+/// Once loaded, the file is renamed with the `.added` suffix, or `.invalid` if it couldn't be loaded.
+pub async fn watch_dir(session: Arc<Session>, dir: PathBuf) {
+    if let Err(e) = fs::create_dir_all(&dir) {
+        error!("Unable to create watch directory {:?}: {}", dir, e);
+        return;
+    }
+
+    info!("Watching {:?} for torrent files", dir);
+
+    let mut interval = tokio::time::interval(SCAN_INTERVAL);
+    loop {
+        interval.tick().await;
+        scan_dir(&session, &dir);
+    }
+}
+
+/// Add every torrent file currently in the directory.
+fn scan_dir(session: &Session, dir: &Path) {
+    for path in find_torrent_files(dir) {
+        let suffix = match session.add_torrent(&path.to_string_lossy(), AddTorrentOptions::default()) {
+            Ok(added) if added.duplicate => {
+                info!("{:?} from the watch directory is already in the session", path);
+                ADDED_SUFFIX
+            }
+            Ok(_) => {
+                info!("Added {:?} from the watch directory", path);
+                ADDED_SUFFIX
+            }
+            Err(e) => {
+                warn!("Unable to add {:?}: {:#}", path, e);
+                INVALID_SUFFIX
+            }
+        };
+
+        let mut renamed = path.clone().into_os_string();
+        renamed.push(suffix);
+
+        if let Err(e) = fs::rename(&path, &renamed) {
+            warn!("Unable to rename {:?}: {}", path, e);
+        }
+    }
+}
+
+/// Find the files ending with `.torrent` in a directory, ignoring sub directories.
+fn find_torrent_files(dir: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && path.extension().map_or(false, |ext| ext == "torrent"))
+        .collect();
+
+    files.sort();
+    return files;
+}
+
+
+#[test]
+fn test_find_torrent_files() {
+    let dir = PathBuf::from("test-files/watch/");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("nested.torrent")).unwrap();
+
+    for name in &["b.torrent", "a.torrent", "c.torrent.added", "d.txt"] {
+        fs::write(dir.join(name), b"").unwrap();
+    }
+
+    let files = find_torrent_files(&dir);
+    assert_eq!(files, vec![dir.join("a.torrent"), dir.join("b.torrent")]);
+
+    let _ = fs::remove_dir_all(&dir);
+}
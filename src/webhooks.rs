@@ -0,0 +1,133 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+use crate::events::Event;
+use crate::utils::to_hex;
+
+/// Header holding the HMAC-SHA256 of the body when the webhook has a secret.
+pub const SIGNATURE_HEADER: &str = "X-Torrenter-Signature";
+
+/// Amount of times a payload is sent before giving up.
+const MAX_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+
+    /// Shared secret used to sign the payloads.
+    #[serde(default)]
+    pub secret: Option<String>,
+
+    /// Events sent to this webhook, every event when empty.
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct WebhookPayload<'a> {
+    event: &'a str,
+    info_hash: String,
+    name: &'a str,
+    message: Option<&'a str>,
+    timestamp: u64,
+}
+
+/// POST every event of the session to the webhooks which are interested in it.
+pub async fn run_webhooks(mut events: broadcast::Receiver<Event>, client: reqwest::Client, webhooks: Vec<WebhookConfig>) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Webhooks missed {} events", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        let body = build_payload(&event);
+
+        for webhook in &webhooks {
+            if !webhook.events.is_empty() && !webhook.events.iter().any(|e| e == event.kind.as_str()) {
+                continue;
+            }
+
+            tokio::spawn(deliver(client.clone(), webhook.clone(), body.clone()));
+        }
+    }
+}
+
+/// Send the payload, retrying with an exponential backoff when the webhook fails.
+async fn deliver(client: reqwest::Client, webhook: WebhookConfig, body: String) {
+    let mut backoff = Duration::from_secs(1);
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        let mut request = client.post(&webhook.url)
+            .header("Content-Type", "application/json")
+            .body(body.clone());
+
+        if let Some(secret) = &webhook.secret {
+            request = request.header(SIGNATURE_HEADER, format!("sha256={}", sign(secret, body.as_bytes())));
+        }
+
+        match request.send().await.and_then(|resp| resp.error_for_status()) {
+            Ok(_) => return,
+            Err(e) => warn!("Webhook {} failed (attempt {}/{}): {}", webhook.url, attempt, MAX_ATTEMPTS, e),
+        }
+
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+}
+
+fn build_payload(event: &Event) -> String {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+
+    let payload = WebhookPayload {
+        event: event.kind.as_str(),
+        info_hash: to_hex(&event.info_hash),
+        name: &event.name,
+        message: event.message.as_deref(),
+        timestamp,
+    };
+
+    return serde_json::to_string(&payload).unwrap();
+}
+
+/// Hex encoded HMAC-SHA256 of the body using the shared secret.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+    hmac.input(body);
+    return to_hex(hmac.result().code());
+}
+
+
+#[test]
+fn test_sign() {
+    // Test case 2 of RFC 4231
+    let signature = sign("Jefe", b"what do ya want for nothing?");
+    assert_eq!(signature, "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+}
+
+
+#[test]
+fn test_build_payload() {
+    use crate::events::EventKind;
+
+    let event = Event::new(EventKind::Error, [0xab; 20], "Test torrent").with_message(String::from("Disk full"));
+    let payload: serde_json::Value = serde_json::from_str(&build_payload(&event)).unwrap();
+
+    assert_eq!(payload["event"], "error");
+    assert_eq!(payload["info_hash"], "ab".repeat(20));
+    assert_eq!(payload["name"], "Test torrent");
+    assert_eq!(payload["message"], "Disk full");
+}
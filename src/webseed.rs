@@ -0,0 +1,273 @@
+//! Web seeds (BEP 19), HTTP servers with the files of a torrent. Their blocks are fetched with range requests and
+//! picked from the same registry as the blocks requested from peers, so a web seed takes what nobody else is
+//! downloading.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::sync::mpsc::Sender;
+use tracing::{debug, info};
+use url::Url;
+
+use crate::download::{PiecesManager, Swarm};
+use crate::message_handlers::PieceChannelPayload;
+use crate::metrics;
+use crate::queue::{ConnectionId, PieceBlock, Queue};
+use crate::storage::FileStorage;
+use crate::utils::torrents::{BLOCK_LEN, Torrent};
+
+/// How long a web seed has to send a block.
+const WEB_SEED_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Failed requests in a row after which a web seed is given up on until the torrent is started again.
+const MAX_FAILURES: u32 = 5;
+
+/// How long to wait after a failed request.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// How long to wait for blocks to pick while every one which is left is requested from the peers.
+const IDLE_DELAY: Duration = Duration::from_secs(1);
+
+/// URL of a file of the torrent on a web seed.
+///
+/// The URL of a single file torrent is the file itself unless it ends with a `/`, otherwise it's the folder the
+/// torrent is in. The server has the files under their paths in the torrent file, not the ones they're stored under.
+pub fn file_url(web_seed: &str, torrent: &Torrent, file: usize) -> anyhow::Result<Url> {
+    let mut url = Url::parse(web_seed).with_context(|| format!("Error: Invalid web seed {}", web_seed))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        anyhow::bail!("Error: {} web seeds aren't supported", url.scheme());
+    }
+    if torrent.info.files.is_none() && !web_seed.ends_with('/') {
+        return Ok(url);
+    }
+
+    let original = |index: Option<usize>| torrent.renamed_paths.iter()
+        .find(|renamed| renamed.file == index)
+        .map(|renamed| renamed.original.clone());
+
+    {
+        let Ok(mut segments) = url.path_segments_mut() else {
+            anyhow::bail!("Error: Invalid web seed {}", web_seed);
+        };
+        segments.pop_if_empty().extend(original(None).unwrap_or_else(|| vec![torrent.info.name.clone()]));
+        if let Some(files) = &torrent.info.files {
+            segments.extend(original(Some(file)).unwrap_or_else(|| files[file].path.clone()));
+        }
+    }
+
+    return Ok(url);
+}
+
+/// The web seed in the registry of the blocks in flight, its blocks go back to the peers once it's dropped.
+struct WebSeedConnection {
+    pieces: PiecesManager,
+    id: ConnectionId,
+}
+
+impl WebSeedConnection {
+    fn new(pieces: PiecesManager) -> WebSeedConnection {
+        let id = {
+            let mut pieces = pieces.lock().unwrap();
+            let id = pieces.connect();
+            // Web seeds never choke us, so they're idle whenever they have nothing to fetch.
+            pieces.set_unchoked(id, true);
+            id
+        };
+        return WebSeedConnection { pieces, id };
+    }
+
+    /// Give back the blocks requested from the web seed and carry on under a new id.
+    fn reconnect(&mut self) {
+        let mut pieces = self.pieces.lock().unwrap();
+        pieces.disconnect(self.id);
+        self.id = pieces.connect();
+        pieces.set_unchoked(self.id, true);
+    }
+}
+
+impl Drop for WebSeedConnection {
+    fn drop(&mut self) {
+        if let Ok(mut pieces) = self.pieces.lock() {
+            pieces.disconnect(self.id);
+        }
+    }
+}
+
+/// Download blocks from a web seed until the torrent is done, or until the web seed has failed too often in a row.
+pub async fn download_from_web_seed(web_seed: String, torrent: Arc<Torrent>, file_sender: Sender<PieceChannelPayload>, swarm: Swarm) -> anyhow::Result<()> {
+    let storage = FileStorage::from_torrent(&torrent);
+    let urls = (0..storage.files().len())
+        .map(|file| file_url(&web_seed, &torrent, file))
+        .collect::<anyhow::Result<Vec<Url>>>()?;
+    let all_pieces = || (0..torrent.info.pieces.len() as u64 / 20).collect();
+
+    info!("Downloading from web seed");
+    let mut connection = WebSeedConnection::new(swarm.pieces.clone());
+    let mut queue = Queue::new(&torrent);
+    queue.pieces = all_pieces();
+    let pending = swarm.disk.pending();
+    let mut failures = 0;
+
+    loop {
+        let piece_block = {
+            let mut pieces = swarm.pieces.lock().unwrap();
+            if pieces.is_done() {
+                return Ok(());
+            }
+            let piece_block = queue.next(&pieces, connection.id);
+            if let Some(piece_block) = piece_block {
+                pieces.add_requested(piece_block, connection.id);
+            }
+            piece_block
+        };
+
+        // Pieces which failed their hash check are left out of the queue by then, so it starts over.
+        let Some(piece_block) = piece_block else {
+            tokio::time::sleep(IDLE_DELAY).await;
+            queue.pieces = all_pieces();
+            continue;
+        };
+
+        let block = match fetch_block(&swarm.http, &urls, &storage, piece_block).await {
+            Ok(block) => block,
+            Err(e) => {
+                failures += 1;
+                if failures >= MAX_FAILURES {
+                    return Err(e);
+                }
+                debug!("Web seed request failed: {:#}", e);
+                connection.reconnect();
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+        };
+        failures = 0;
+
+        let block_len = block.len() as u64;
+        {
+            let mut pieces = swarm.pieces.lock().unwrap();
+            pieces.add_received_from(piece_block, connection.id)?;
+            pieces.add_downloaded(connection.id, block_len);
+        }
+        metrics::DOWNLOADED_BYTES.inc_by(block_len);
+
+        let offset = piece_block.index * torrent.info.piece_length + piece_block.begin;
+        if file_sender.send(PieceChannelPayload { offset, block }).await.is_err() {
+            return Ok(());
+        }
+
+        let wait = swarm.download_limiter.consume(block_len);
+        if wait.as_nanos() > 0 {
+            tokio::time::sleep(wait).await;
+        }
+        if pending.is_behind() {
+            pending.wait_to_request().await;
+        }
+    }
+}
+
+/// Fetch a block from the files it's in, one request for each of them. Pad files are zeros and aren't on the server.
+async fn fetch_block(client: &reqwest::Client, urls: &[Url], storage: &FileStorage, piece_block: PieceBlock) -> anyhow::Result<Vec<u8>> {
+    let length = piece_block.length.unwrap_or(BLOCK_LEN);
+    let mut block = vec![0; length as usize];
+
+    for segment in storage.map_block(piece_block.index, piece_block.begin, length) {
+        if storage.file(segment.file).is_pad() {
+            continue;
+        }
+
+        let range = format!("bytes={}-{}", segment.file_offset, segment.file_offset + segment.len as u64 - 1);
+        let response = client.get(urls[segment.file].as_str())
+            .header(reqwest::header::RANGE, range)
+            .timeout(WEB_SEED_TIMEOUT)
+            .send().await.context("Couldn't reach the web seed")?;
+        let status = response.status();
+        if !status.is_success() {
+            anyhow::bail!("Error: The web seed answered with {}", status);
+        }
+        let body = response.bytes().await.context("Couldn't read the web seed response")?;
+
+        // Servers which don't do ranges send the whole file.
+        let data = match status {
+            reqwest::StatusCode::PARTIAL_CONTENT => &body[..],
+            _ => body.get(segment.file_offset as usize..).unwrap_or_default(),
+        };
+        if data.len() < segment.len {
+            anyhow::bail!("Error: The web seed sent {} bytes instead of {}", data.len(), segment.len);
+        }
+        block[segment.start..segment.start + segment.len].copy_from_slice(&data[..segment.len]);
+    }
+
+    return Ok(block);
+}
+
+
+#[test]
+fn test_file_url() {
+    use crate::utils::torrents::{DlFile, Info, RenamedPath};
+
+    let single = Torrent { info: Info { name: String::from("a b.iso"), ..Default::default() }, ..Default::default() };
+    assert_eq!(file_url("https://example.com/isos/a.iso", &single, 0).unwrap().as_str(), "https://example.com/isos/a.iso");
+    assert_eq!(file_url("https://example.com/isos/", &single, 0).unwrap().as_str(), "https://example.com/isos/a%20b.iso");
+    assert!(file_url("ftp://example.com/isos/", &single, 0).is_err());
+
+    let file = |path: &[&str]| DlFile { path: path.iter().map(|c| c.to_string()).collect(), length: 1, md5sum: None, attr: None, symlink_path: None };
+    let multi = Torrent {
+        info: Info { name: String::from("album"), files: Some(vec![file(&["cd1", "01.flac"]), file(&["cover_.jpg"])]), ..Default::default() },
+        renamed_paths: vec![RenamedPath { file: Some(1), original: vec![String::from("cover?.jpg")] }],
+        ..Default::default()
+    };
+    assert_eq!(file_url("https://example.com/music", &multi, 0).unwrap().as_str(), "https://example.com/music/album/cd1/01.flac");
+    assert_eq!(file_url("https://example.com/music/", &multi, 1).unwrap().as_str(), "https://example.com/music/album/cover%3F.jpg");
+}
+
+
+#[tokio::test]
+async fn test_download_from_web_seed() {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+    use bytebuffer::ByteBuffer;
+    use tokio::sync::mpsc;
+    use crate::config::DiskConfig;
+    use crate::create::{create_torrent, CreateOptions};
+    use crate::disk::DiskIo;
+    use crate::download::download_torrent;
+    use crate::pieces::Pieces;
+    use crate::testing::MockWebSeed;
+
+    let dir = Path::new("test-files/web-seed");
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir.join("source/data")).unwrap();
+    let (a, b) = (vec![1; 20000], vec![2; 12000]);
+    fs::write(dir.join("source/data/a.bin"), &a).unwrap();
+    fs::write(dir.join("source/data/b.bin"), &b).unwrap();
+
+    // The second piece is at the end of the first file and in all of the second one.
+    let web_seed = MockWebSeed::start(HashMap::from([
+        (String::from("/data/a.bin"), a.clone()),
+        (String::from("/data/b.bin"), b.clone()),
+    ])).unwrap();
+    let metainfo = create_torrent(&CreateOptions {
+        path: dir.join("source/data"),
+        piece_length: Some(16384),
+        web_seeds: vec![web_seed.url()],
+        ..Default::default()
+    }).unwrap();
+    let torrent = Arc::new(Torrent::from_bytes(&metainfo).unwrap());
+
+    let swarm = Swarm {
+        disk: DiskIo::start(torrent.clone(), dir.join("download").to_string_lossy().into_owned(), FileStorage::from_torrent(&torrent), &DiskConfig::default()).unwrap(),
+        ..Swarm::test(Pieces::new(&torrent))
+    };
+    let download = download_torrent(ByteBuffer::from_bytes(b"-TR0001-testtesttest"), torrent.clone(), swarm.clone(), mpsc::channel(1).1);
+    tokio::time::timeout(Duration::from_secs(10), download).await.unwrap().unwrap();
+
+    assert!(swarm.pieces.lock().unwrap().has_verified(1));
+    assert_eq!(fs::read(dir.join("download/a.bin")).unwrap(), a);
+    assert_eq!(fs::read(dir.join("download/b.bin")).unwrap(), b);
+    assert!(web_seed.requests().contains(&String::from("/data/b.bin bytes=0-11999")));
+
+    let _ = fs::remove_dir_all(dir);
+}